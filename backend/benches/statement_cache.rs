@@ -0,0 +1,95 @@
+//! Per-call cost of the hot timestamp queries with the prepared statement cache off and at
+//! STATEMENT_CACHE_CAPACITY, through the same query() the handlers call.
+//!
+//!     cargo bench --bench statement_cache
+//!
+//! Each run gets its own sqlite file with a day's scans at one location.
+
+use chrono::Utc;
+use scan_mvcf::app_config::{self, config};
+use scan_mvcf::database::db::{connection_manager, query, Pool, Query};
+use scan_mvcf::models::timestamps::{PostTimestamp, ScanFilter};
+use std::time::{Duration, Instant};
+
+const RFID: &str = "999000999000999";
+const LOCATION: usize = 9001;
+const SCANS: usize = 50;
+const CALLS: u32 = 2000;
+
+fn main() {
+    // the bench never serves requests, but the configuration is checked as a whole
+    std::env::set_var("AUTH", "off");
+    std::env::set_var("SCAN_DEDUP_SECONDS", "0");
+    app_config::init().expect("configuration is valid");
+    actix_web::rt::System::new().block_on(async {
+        let capacity = config().statement_cache_capacity;
+        let uncached = run(0).await;
+        let cached = run(capacity).await;
+        println!();
+        for ((name, off), (_, on)) in uncached.iter().zip(&cached) {
+            let saved = 100.0 * (1.0 - on.as_secs_f64() / off.as_secs_f64());
+            println!("{:<32} {:>9.1?} -> {:>9.1?} per call ({:.0}% less)", name, *off, *on, saved);
+        }
+    });
+}
+
+// the average per call of each query, on a fresh database whose connection caches
+// `capacity` statements
+async fn run(capacity: usize) -> Vec<(&'static str, Duration)> {
+    let path = std::env::temp_dir().join(format!("mvscanner-bench-{}-{}.db", std::process::id(), capacity));
+    let _ = std::fs::remove_file(&path);
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .build(connection_manager(&path, capacity))
+        .expect("bench database opens");
+    query(&pool, Query::Migrations).await.expect("migrations run");
+    seed(&pool);
+    let today = Utc::now().date_naive();
+    let scan = PostTimestamp::new(RFID.to_string(), LOCATION);
+    let unfiltered = ScanFilter::default();
+    let mut averages = Vec::new();
+    averages.push(("ShowLocationTimestamps", time(&pool, || Query::ShowLocationTimestamps(LOCATION, &unfiltered, None, None)).await));
+    averages.push((
+        "ShowLocationTimestampsRange",
+        time(&pool, || Query::ShowLocationTimestampsRange(LOCATION, &today, &today, &unfiltered, None, None)).await,
+    ));
+    averages.push((
+        "ShowLocationTimestampsRange page",
+        time(&pool, || Query::ShowLocationTimestampsRange(LOCATION, &today, &today, &unfiltered, None, Some((20, 0)))).await,
+    ));
+    averages.push(("StoreTimestamp", time(&pool, || Query::StoreTimestamp(&scan)).await));
+    println!("statement cache capacity {}:", capacity);
+    for (name, average) in &averages {
+        println!("  {:<32} {:>9.1?} per call", name, average);
+    }
+    drop(pool);
+    let _ = std::fs::remove_file(&path);
+    averages
+}
+
+fn seed(pool: &Pool) {
+    let conn = pool.get().expect("a connection");
+    conn.execute("INSERT INTO locations (id, name) VALUES (?1, 'Bench Hall')", [LOCATION])
+        .expect("location stored");
+    conn.execute(
+        "INSERT INTO residents (rfid, name, doc, room, unit, current_location) VALUES (?1, 'Bench, Resident', '1', 'B-1', ?2, ?2)",
+        rusqlite::params![RFID, LOCATION],
+    )
+    .expect("resident stored");
+    for _ in 0..SCANS {
+        conn.execute("INSERT INTO timestamps (rfid, location) VALUES (?1, ?2)", rusqlite::params![RFID, LOCATION])
+            .expect("scan stored");
+    }
+}
+
+// a warm up so the first prepare isn't counted in either run, then the average of CALLS
+async fn time<'a>(pool: &Pool, make: impl Fn() -> Query<'a>) -> Duration {
+    for _ in 0..CALLS / 10 {
+        query(pool, make()).await.expect("query runs");
+    }
+    let started = Instant::now();
+    for _ in 0..CALLS {
+        query(pool, make()).await.expect("query runs");
+    }
+    started.elapsed() / CALLS
+}
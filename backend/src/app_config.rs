@@ -0,0 +1,287 @@
+use chrono_tz::Tz;
+use once_cell::sync::OnceCell;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Every setting the server reads from the environment, parsed and checked once at startup
+/// by `init`. A value that's set but malformed stops the server rather than falling back to
+/// the default, so a typo in a deployment shows up straight away.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The sqlite file, see `sqlite_path`
+    pub database_path: PathBuf,
+    /// Address and port the server listens on, HOST and PORT
+    pub host: String,
+    pub port: u16,
+    /// Most connections the pool opens, POOL_MAX_SIZE
+    pub pool_max_size: u32,
+    /// Idle connections the pool keeps open, POOL_MIN_IDLE. None keeps pool_max_size of them.
+    pub pool_min_idle: Option<u32>,
+    /// Seconds an idle connection above pool_min_idle is kept before it's closed,
+    /// POOL_IDLE_TIMEOUT_SECONDS. None, or 0 in the environment, keeps them open.
+    pub pool_idle_timeout_seconds: Option<u64>,
+    /// Milliseconds a request waits for a free pooled connection before giving up with a 503.
+    /// r2d2's own default of 30 seconds is far longer than a scanner will wait on a response.
+    pub pool_timeout_ms: u64,
+    /// Prepared statements each pooled connection keeps, STATEMENT_CACHE_CAPACITY. Enough for
+    /// every cached query so the hot ones aren't evicted by the rest, 0 turns the cache off.
+    pub statement_cache_capacity: usize,
+    /// Milliseconds a single query() call may take, including the wait for a connection,
+    /// before it is abandoned and the request gets a 504
+    pub query_timeout_ms: u64,
+    /// query() calls that take longer than this are logged as slow, 0 turns the warning off
+    pub slow_query_ms: u64,
+    /// How many times a read or update that hit a transient database error is retried
+    pub query_retries: u32,
+    /// Milliseconds before the first retry, doubled for each one after
+    pub query_retry_backoff_ms: u64,
+    /// Seconds in-flight requests are given to finish after a SIGTERM or SIGINT before the
+    /// server stops anyway, new connections are refused as soon as the signal arrives
+    pub shutdown_timeout_seconds: u64,
+    /// Seconds within which a repeat scan of the same resident at the same location is treated
+    /// as an accidental double-wave and not stored. SCAN_DEDUP_SECONDS=0 stores every scan.
+    pub scan_dedup_seconds: i64,
+    /// Scan uploads each scanner key, or each address without one, may make a second on
+    /// average, SCAN_RATE_PER_SECOND. Past it they're a 429, 0 turns the limit off.
+    pub scan_rate_per_second: u32,
+    /// Uploads a caller may make at once on top of the rate, SCAN_RATE_BURST, for a scanner
+    /// flushing what it buffered while offline
+    pub scan_rate_burst: u32,
+    /// Longest span, in days, a date range in a URL may cover. Checked before the query runs
+    /// so a request for years of scans is a 400 rather than a table scan.
+    pub max_range_days: i64,
+    /// Seconds the locations index is served from memory before it's read again, 0 (the
+    /// default) always reads the table
+    pub locations_cache_ttl_seconds: u64,
+    /// The Cache-Control max-age of the locations index and show, LOCATIONS_MAX_AGE_SECONDS, so
+    /// a CDN can answer the boards' polling. 0 sends them `no-store` like everything else.
+    pub locations_max_age_seconds: u64,
+    /// Whether the location timestamp lists are always paged, PAGINATE_TIMESTAMPS=on. Off, a
+    /// request without ?limit= or ?offset= gets every matching row.
+    pub paginate_timestamps: bool,
+    /// Seconds an Idempotency-Key on a scan upload is remembered, a retry after that is
+    /// stored as a new upload
+    pub idempotency_ttl_seconds: u64,
+    /// Most bytes a request body may hold, MAX_BODY_BYTES. A larger one is a 413.
+    pub max_body_bytes: usize,
+    /// The same for the batch scan upload and the CSV imports, MAX_BULK_BODY_BYTES, whose
+    /// bodies are many records at once
+    pub max_bulk_body_bytes: usize,
+    /// Whether responses are gzip/deflate/brotli/zstd compressed per the client's
+    /// Accept-Encoding. COMPRESSION=off disables it entirely.
+    pub compression_enabled: bool,
+    /// Responses smaller than this many bytes are sent uncompressed, since the CPU spent on
+    /// them isn't worth the few bytes saved
+    pub compress_min_bytes: u64,
+    /// Whether /api requests need a bearer token. AUTH=off turns it off for local development.
+    pub auth_enabled: bool,
+    /// Whether GET requests need a token when auth is on. AUTH_READS=off leaves reads open.
+    pub auth_reads: bool,
+    /// Whether POST, PUT, PATCH and DELETE requests need a token when auth is on
+    pub auth_writes: bool,
+    /// HS256 key bearer tokens are signed with, required unless AUTH=off
+    pub jwt_secret: Option<String>,
+    /// Seconds a token issued by POST /api/auth/login is good for, TOKEN_TTL_SECONDS
+    pub token_ttl_seconds: u64,
+    /// APP_ENV=production tightens the checks that are relaxed for local development
+    pub is_production: bool,
+    /// Origins allowed to call the API from a browser, comma separated. `*` allows any and is
+    /// the default outside production.
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: Vec<String>,
+    pub cors_allowed_headers: Vec<String>,
+    /// Where every recorded scan is POSTed as JSON, leaving WEBHOOK_URL unset sends nothing
+    pub webhook_url: Option<String>,
+    /// Key the webhook body is signed with in X-Webhook-Signature, unsigned when unset
+    pub webhook_secret: Option<String>,
+    /// Times a webhook delivery is retried before it's written to the dead-letter log
+    pub webhook_retries: u32,
+    /// Milliseconds before the first webhook retry, doubled for each one after
+    pub webhook_retry_backoff_ms: u64,
+    /// The facility's timezone, FACILITY_TZ as an IANA name, UTC when unset. Every local day
+    /// and hour a range, bucket or "today" is counted in comes from it.
+    pub facility_tz: Tz,
+    /// Scan times are rendered as RFC 3339 in UTC, TIMESTAMP_TZ=local renders them in the
+    /// facility's timezone with its offset instead
+    pub timestamps_local: bool,
+    /// LOG_FORMAT=json for one JSON object per log line, anything else logs `key=value` pairs
+    pub log_json: bool,
+}
+
+impl Config {
+    /// Reads and checks every setting, Err names the first one that's missing or malformed
+    pub fn from_env() -> Result<Self, String> {
+        let is_production = matches!(
+            std::env::var("APP_ENV").as_deref(),
+            Ok("production") | Ok("prod")
+        );
+        let config = Config {
+            database_path: sqlite_path()?,
+            host: env_string("HOST").unwrap_or("127.0.0.1".to_string()),
+            port: env_parse("PORT", 8080)?,
+            pool_max_size: env_parse("POOL_MAX_SIZE", 10)?,
+            pool_min_idle: env_parse_opt("POOL_MIN_IDLE")?,
+            pool_idle_timeout_seconds: Some(env_parse("POOL_IDLE_TIMEOUT_SECONDS", 600)?).filter(|secs| *secs > 0),
+            pool_timeout_ms: env_parse("POOL_TIMEOUT_MS", 5000)?,
+            statement_cache_capacity: env_parse("STATEMENT_CACHE_CAPACITY", 64)?,
+            query_timeout_ms: env_parse("QUERY_TIMEOUT_MS", 10000)?,
+            slow_query_ms: env_parse("SLOW_QUERY_MS", 500)?,
+            query_retries: env_parse("QUERY_RETRIES", 2)?,
+            query_retry_backoff_ms: env_parse("QUERY_RETRY_BACKOFF_MS", 50)?,
+            shutdown_timeout_seconds: env_parse("SHUTDOWN_TIMEOUT_SECONDS", 30)?,
+            scan_dedup_seconds: env_parse("SCAN_DEDUP_SECONDS", 30)?,
+            scan_rate_per_second: env_parse("SCAN_RATE_PER_SECOND", 50)?,
+            scan_rate_burst: env_parse("SCAN_RATE_BURST", 500)?,
+            max_range_days: env_parse("MAX_RANGE_DAYS", 90)?,
+            locations_cache_ttl_seconds: env_parse("LOCATIONS_CACHE_TTL_SECONDS", 0)?,
+            locations_max_age_seconds: env_parse("LOCATIONS_MAX_AGE_SECONDS", 30)?,
+            paginate_timestamps: env_flag("PAGINATE_TIMESTAMPS", false)?,
+            idempotency_ttl_seconds: env_parse("IDEMPOTENCY_TTL_SECONDS", 86400)?,
+            max_body_bytes: env_parse("MAX_BODY_BYTES", 65536)?,
+            max_bulk_body_bytes: env_parse("MAX_BULK_BODY_BYTES", 4 * 1024 * 1024)?,
+            compression_enabled: env_flag("COMPRESSION", true)?,
+            compress_min_bytes: env_parse("COMPRESS_MIN_BYTES", 1024)?,
+            auth_enabled: env_flag("AUTH", true)?,
+            auth_reads: env_flag("AUTH_READS", true)?,
+            auth_writes: env_flag("AUTH_WRITES", true)?,
+            jwt_secret: env_string("JWT_SECRET"),
+            token_ttl_seconds: env_parse("TOKEN_TTL_SECONDS", 43200)?,
+            is_production,
+            cors_allowed_origins: env_list("CORS_ALLOWED_ORIGINS", if is_production { "" } else { "*" }),
+            cors_allowed_methods: env_list("CORS_ALLOWED_METHODS", "GET,POST,PUT,PATCH,DELETE"),
+            cors_allowed_headers: env_list("CORS_ALLOWED_HEADERS", "Authorization,Content-Type,Accept,X-Api-Key,Idempotency-Key"),
+            webhook_url: env_string("WEBHOOK_URL"),
+            webhook_secret: env_string("WEBHOOK_SECRET"),
+            webhook_retries: env_parse("WEBHOOK_RETRIES", 3)?,
+            webhook_retry_backoff_ms: env_parse("WEBHOOK_RETRY_BACKOFF_MS", 500)?,
+            facility_tz: env_tz("FACILITY_TZ")?,
+            timestamps_local: std::env::var("TIMESTAMP_TZ")
+                .map(|tz| tz.eq_ignore_ascii_case("local"))
+                .unwrap_or(false),
+            log_json: std::env::var("LOG_FORMAT").is_ok_and(|format| format.eq_ignore_ascii_case("json")),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // the settings that are each well-formed but don't make sense as they are
+    fn validate(&self) -> Result<(), String> {
+        if self.auth_enabled && self.jwt_secret.is_none() {
+            return Err("JWT_SECRET must be set, or run with AUTH=off for local development".to_string());
+        }
+        if self.token_ttl_seconds == 0 {
+            return Err("TOKEN_TTL_SECONDS must be at least 1".to_string());
+        }
+        if self.pool_max_size == 0 {
+            return Err("POOL_MAX_SIZE must be at least 1".to_string());
+        }
+        if self.pool_min_idle.is_some_and(|min_idle| min_idle > self.pool_max_size) {
+            return Err("POOL_MIN_IDLE may not be more than POOL_MAX_SIZE".to_string());
+        }
+        // r2d2 panics on a zero timeout
+        if self.pool_timeout_ms == 0 {
+            return Err("POOL_TIMEOUT_MS must be at least 1".to_string());
+        }
+        if self.max_bulk_body_bytes < self.max_body_bytes {
+            return Err("MAX_BULK_BODY_BYTES may not be less than MAX_BODY_BYTES".to_string());
+        }
+        if self.scan_rate_per_second > 0 && self.scan_rate_burst == 0 {
+            return Err("SCAN_RATE_BURST must be at least 1 while SCAN_RATE_PER_SECOND is set".to_string());
+        }
+        if self.max_range_days < 0 {
+            return Err("MAX_RANGE_DAYS may not be negative".to_string());
+        }
+        self.check_cors()
+    }
+
+    /// Production needs an explicit allowlist of origins
+    fn check_cors(&self) -> Result<(), String> {
+        if !self.is_production {
+            return Ok(());
+        }
+        if self.cors_allowed_origins.is_empty() {
+            return Err("CORS_ALLOWED_ORIGINS must be set when APP_ENV=production".to_string());
+        }
+        if self.cors_allowed_origins.iter().any(|origin| origin == "*") {
+            return Err("CORS_ALLOWED_ORIGINS may not contain * when APP_ENV=production".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Loads the configuration, once, before anything reads it
+pub fn init() -> Result<&'static Config, String> {
+    let config = Config::from_env()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The configuration `init` loaded
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("app_config::init must run before the configuration is read")
+}
+
+/// `mvcf_scan.db` in the user's local data dir
+fn sqlite_path() -> Result<PathBuf, String> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("mvcf_scan.db"))
+        .ok_or_else(|| "no local data directory to keep the database in".to_string())
+}
+
+// a setting that's unset or blank is None
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+}
+
+// a number, `default` when unset and an Err when it doesn't parse
+fn env_parse<T: FromStr>(name: &str, default: T) -> Result<T, String> {
+    Ok(env_parse_opt(name)?.unwrap_or(default))
+}
+
+fn env_parse_opt<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    env_string(name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("{} must be a whole number, got '{}'", name, value))
+        })
+        .transpose()
+}
+
+// an IANA timezone name like America/New_York, UTC when unset
+fn env_tz(name: &str) -> Result<Tz, String> {
+    env_string(name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| format!("{} must be an IANA timezone name like America/New_York, got '{}'", name, value))
+        })
+        .transpose()
+        .map(|tz| tz.unwrap_or(Tz::UTC))
+}
+
+// an on/off switch, `default` when unset
+fn env_flag(name: &str, default: bool) -> Result<bool, String> {
+    match env_string(name).map(|value| value.trim().to_lowercase()).as_deref() {
+        None => Ok(default),
+        Some("on") | Some("true") | Some("1") => Ok(true),
+        Some("off") | Some("false") | Some("0") => Ok(false),
+        Some(value) => Err(format!("{} must be on or off, got '{}'", name, value)),
+    }
+}
+
+// a comma separated setting with blanks dropped
+fn env_list(name: &str, default: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or(default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
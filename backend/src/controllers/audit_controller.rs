@@ -0,0 +1,27 @@
+use crate::controllers::locations_controller::{Pagination, TOTAL_COUNT_HEADER};
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::models::audit::AuditResponse;
+use crate::models::facilities::Facility;
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+// the facility's recorded mutations newest first, a page at a time (default 50), admins only
+#[rustfmt::skip]
+#[get("/api/audit")]
+pub async fn index(req: HttpRequest, db: web::Data<Pool>, facility: Facility, page: web::Query<Pagination>) -> Result<HttpResponse, ApiError> {
+    log::info!("GET: audit controller");
+    page.validate().map_err(ApiError::Validation)?;
+    match query(&db, Query::IndexAuditLog(facility, page.limit(), page.offset())).await {
+        Ok(QueryResult::AuditLog(entries, total)) => {
+            let mut builder = HttpResponse::Ok();
+            builder.insert_header((TOTAL_COUNT_HEADER, total.to_string()));
+            if let Some(links) = page.links(&req, total) {
+                builder.insert_header((header::LINK, links));
+            }
+            let response = AuditResponse::from_entries(entries);
+            Ok(builder.insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve the audit log")),
+    }
+}
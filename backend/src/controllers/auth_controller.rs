@@ -0,0 +1,62 @@
+use crate::app_config::config;
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::middleware::auth::Claims;
+use crate::models::users::{hash_password, verify_password, Credentials, LoginResponse};
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse};
+use chrono::{Duration, SecondsFormat, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use once_cell::sync::Lazy;
+
+// checked against when the username is unknown, so the answer takes as long as for a
+// wrong password and doesn't give away which usernames exist
+static UNKNOWN_USER_HASH: Lazy<String> = Lazy::new(|| hash_password(""));
+
+/// POST: /api/auth/login  body={"username": "...", "password": "..."}
+/// Swaps a user's password for a bearer token carrying their role and facility, good for
+/// TOKEN_TTL_SECONDS. An unknown username and a wrong password are the same 401.
+#[rustfmt::skip]
+#[post("/api/auth/login")]
+pub async fn login(req: HttpRequest, db: web::Data<Pool>, credentials: web::Json<Credentials>) -> Result<HttpResponse, ApiError> {
+    let credentials = credentials.into_inner();
+    let secret = config()
+        .jwt_secret
+        .as_deref()
+        .ok_or_else(|| ApiError::Unavailable("logins need JWT_SECRET to sign tokens with".to_string()))?;
+    let user = match query(&db, Query::FindUser(&credentials.username)).await {
+        Ok(QueryResult::User(user)) => Some(user),
+        Ok(QueryResult::NotFound) => None,
+        res => return Err(ApiError::from_query(res, "Unable to log in")),
+    };
+    let stored = user.as_ref().map_or(UNKNOWN_USER_HASH.clone(), |user| user.password_hash.clone());
+    // the hash is deliberately slow, keep it off the worker
+    let password = credentials.password;
+    let verified = web::block(move || verify_password(&password, &stored))
+        .await
+        .map_err(|err| ApiError::Db(format!("Unable to check the password: {}", err)))?;
+    let Some(user) = user.filter(|_| verified) else {
+        let source = req.peer_addr().map_or("unknown".to_string(), |addr| addr.ip().to_string());
+        log::warn!("Failed login for {} from {}", credentials.username, source);
+        return Err(ApiError::Unauthorized("invalid username or password".to_string()));
+    };
+    let expires_at = Utc::now() + Duration::seconds(config().token_ttl_seconds as i64);
+    let claims = Claims {
+        sub: user.username.clone(),
+        exp: expires_at.timestamp() as usize,
+        role: user.role,
+        facility_id: user.facility_id,
+    };
+    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).map_err(|err| {
+        log::error!("Unable to sign a token for {}: {}", user.username, err);
+        ApiError::Db("Unable to issue a token".to_string())
+    })?;
+    log::info!("Issued a {} token to {}", user.role.as_str(), user.username);
+    let response = LoginResponse {
+        token,
+        token_type: "Bearer".to_string(),
+        expires_at: expires_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+        role: user.role,
+        facility_id: user.facility_id,
+    };
+    Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+}
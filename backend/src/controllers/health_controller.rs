@@ -0,0 +1,43 @@
+use crate::database::db::{query, query_retry_count, Pool, Query, QueryResult};
+use actix_web::{get, http::header::ContentType, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::time::Duration;
+
+/// How long the readiness check waits on the pool before reporting unavailable
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    pub status: &'static str,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use_connections: u32,
+    pub query_retries: u64,
+}
+
+/// GET: /healthz  200 when a pooled connection can run a query, 503 otherwise
+#[get("/healthz")]
+pub async fn healthz(db: web::Data<Pool>) -> impl Responder {
+    let healthy = matches!(
+        tokio::time::timeout(PING_TIMEOUT, query(&db, Query::Ping)).await,
+        Ok(Ok(QueryResult::Success))
+    );
+    let state = db.state();
+    let response = HealthResponse {
+        status: if healthy { "ok" } else { "unavailable" },
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        in_use_connections: state.connections - state.idle_connections,
+        query_retries: query_retry_count(),
+    };
+    if healthy {
+        HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .json(response)
+    } else {
+        log::error!("Health check failed: {:?}", response);
+        HttpResponse::ServiceUnavailable()
+            .insert_header(ContentType::json())
+            .json(response)
+    }
+}
@@ -1,140 +1,1153 @@
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
 
-use crate::controllers::timestamps_controller::TimestampResponse;
-use crate::database::db::{query, Pool, Query, QueryResult};
-use crate::models::locations::{Location, LocationsResponse};
-use crate::models::residents::ResidentResponse;
+use crate::app_config::config;
+use crate::database::cache::LOCATIONS_CACHE;
+use crate::database::db::{
+    query, stream_location_timestamps_range, Pool, Query, QueryResult, StreamItem,
+};
+use crate::controllers::negotiation::{csv_response, envelope_response, negotiate, Format};
+use crate::controllers::responses::created;
+use crate::errors::ApiError;
+use crate::metrics::SCANS_STORED;
+use crate::reports::{export_response, ExportFormat, FormatParams};
+use crate::live::{self, LiveScans};
+use crate::webhooks;
+use crate::controllers::uploads::uploaded_text;
+use crate::models::csv::parse_csv;
+use crate::models::envelope::Envelope;
+use crate::models::facilities::{Facility, Record};
+use crate::middleware::audit::audit_before;
+use crate::middleware::auth::Claims;
+use crate::middleware::facility::ensure_own;
+use crate::middleware::roles::Role;
+use crate::models::locations::{
+    locations_from_csv, Location, LocationBatch, LocationImportResult, LocationStatus, LocationSort, LocationsResponse,
+    MergeOutcome, PathOutcome, SortOrder, StatusBatchPayload, StatusPayload, UpdateOutcome,
+};
+use crate::models::residents::{ResidentFilter, ResidentResponse};
+use crate::models::timestamps::{
+    parse_range, BatchScanResult, PostTimestamp, ResidentScansOutcome, ScanFilter, ScanPayload, TimestampResponse,
+};
+use actix_http::ws;
+use actix_web::http::header::EntityTag;
 use actix_web::http::{header, StatusCode};
-use actix_web::{get, post, web, HttpResponse};
-use actix_web::{Responder, ResponseError};
+use actix_web::{delete, get, post, put, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::Responder;
 use chrono::NaiveDate;
-use serde::{Deserialize, Deserializer};
+use futures_util::StreamExt;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::num::IntErrorKind;
 
 #[derive(Debug, Deserialize)]
 pub struct LocationRange {
+    #[serde(deserialize_with = "location_id")]
     location_id: usize,
-    #[serde(deserialize_with = "deserialize_date")]
-    start_date: NaiveDate,
-    #[serde(deserialize_with = "deserialize_date")]
-    end_date: NaiveDate,
+    start_date: String,
+    end_date: String,
 }
 
-// Deserialize date strings into NaiveDate
-fn deserialize_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let date_str = String::deserialize(deserializer)?;
-    NaiveDate::parse_from_str(&date_str, "%Y-%m-%d").map_err(serde::de::Error::custom)
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+pub const DEFAULT_PAGE_LIMIT: usize = 50;
+pub const MAX_PAGE_LIMIT: usize = 500;
+/// Largest limit or offset sqlite can be given, its integers are i64
+const MAX_SQL_INTEGER: usize = i64::MAX as usize;
+
+// the params a page is picked with, left out of the rest of the query a Link carries over
+const PAGE_PARAMS: &[&str] = &["limit", "offset", "page", "per_page"];
+
+/// Optional ?limit=&offset= query params, or ?page=&per_page= for the same counted in pages
+/// from 1. Leaving all of them out keeps the unpaged behavior.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Pagination {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
 }
+
+impl Pagination {
+    pub fn is_requested(&self) -> bool {
+        self.limit.is_some() || self.offset.is_some() || self.page.is_some() || self.per_page.is_some()
+    }
+    pub fn limit(&self) -> usize {
+        self.limit.or(self.per_page).unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT)
+    }
+    pub fn offset(&self) -> usize {
+        match (self.offset, self.page) {
+            (Some(offset), _) => offset,
+            // validate has turned away a page too far along to have an offset
+            (None, Some(page)) => self.page_offset(page).unwrap_or(usize::MAX),
+            (None, None) => 0,
+        }
+    }
+
+    // the first row of `page`, None when it's past any offset there could be
+    fn page_offset(&self, page: usize) -> Option<usize> {
+        page.saturating_sub(1).checked_mul(self.limit())
+    }
+
+    /// Err for a page before the first or too far along to have an offset, a limit or offset
+    /// bigger than sqlite takes, or the two ways of asking for a page mixed
+    pub fn validate(&self) -> Result<(), String> {
+        if self.limit.or(self.per_page).is_some_and(|limit| limit > MAX_SQL_INTEGER) {
+            return Err(format!("limit: may be at most {}", MAX_SQL_INTEGER));
+        }
+        if self.offset.is_some_and(|offset| offset > MAX_SQL_INTEGER) {
+            return Err(format!("offset: may be at most {}", MAX_SQL_INTEGER));
+        }
+        if self.page == Some(0) {
+            return Err("page: pages count from 1".to_string());
+        }
+        if self.page.is_some_and(|page| self.page_offset(page).is_none_or(|offset| offset > MAX_SQL_INTEGER)) {
+            return Err(format!("page: is past the last page of {} there could be", self.limit()));
+        }
+        if self.page.is_some() && self.offset.is_some() {
+            return Err("page: give either page or offset, not both".to_string());
+        }
+        if self.per_page.is_some() && self.limit.is_some() {
+            return Err("per_page: give either per_page or limit, not both".to_string());
+        }
+        Ok(())
+    }
+
+    // a request that asked by page gets its links by page too
+    fn by_page(&self) -> bool {
+        self.page.is_some() || self.per_page.is_some()
+    }
+
+    /// The (limit, offset) a location's timestamps are read with, None for all of them unless
+    /// PAGINATE_TIMESTAMPS insists on a page
+    pub fn for_timestamps(&self) -> Option<(usize, usize)> {
+        (self.is_requested() || config().paginate_timestamps).then(|| (self.limit(), self.offset()))
+    }
+
+    /// `items` in the v2 envelope, with this page in its meta when they're a page out of `total`
+    pub fn envelope<T: Serialize>(&self, items: Vec<T>, total: Option<usize>) -> Envelope<Vec<T>> {
+        match total {
+            Some(total) => Envelope::paged(items, total, self.limit(), self.offset()),
+            None => Envelope::list(items),
+        }
+    }
+
+    /// A 200 for a page of `req` out of `total`, with the X-Total-Count and Link headers set
+    pub fn response(&self, req: &HttpRequest, total: usize) -> HttpResponseBuilder {
+        let mut builder = HttpResponse::Ok();
+        builder.insert_header((TOTAL_COUNT_HEADER, total.to_string()));
+        if let Some(links) = self.links(req, total) {
+            builder.insert_header((header::LINK, links));
+        }
+        builder
+    }
+
+    /// RFC 8288 `Link` header value pointing at the first, previous, next and last pages of
+    /// `req`, keeping its filters and ordering. prev is left out on the first page and next on
+    /// the last.
+    pub fn links(&self, req: &HttpRequest, total: usize) -> Option<String> {
+        let (limit, offset) = (self.limit(), self.offset());
+        if limit == 0 {
+            return None;
+        }
+        let last = total.saturating_sub(1) / limit * limit;
+        let mut links = vec![(0, "first")];
+        if offset > 0 {
+            links.push((offset.saturating_sub(limit).min(last), "prev"));
+        }
+        if offset + limit < total {
+            links.push((offset + limit, "next"));
+        }
+        links.push((last, "last"));
+        let rest = req
+            .query_string()
+            .split('&')
+            .filter(|pair| !pair.is_empty() && !PAGE_PARAMS.contains(&pair.split('=').next().unwrap_or_default()))
+            .map(|pair| format!("&{}", pair))
+            .collect::<String>();
+        let page = |offset: usize| {
+            if self.by_page() {
+                format!("page={}&per_page={}", offset / limit + 1, limit)
+            } else {
+                format!("limit={}&offset={}", limit, offset)
+            }
+        };
+        Some(
+            links
+                .into_iter()
+                .map(|(offset, rel)| format!("<{}?{}{}>; rel=\"{}\"", req.path(), page(offset), rest, rel))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+}
+
+/// Optional ?order=asc|desc on a list that's always sorted the same way but for its direction
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListOrder {
+    pub order: Option<String>,
+}
+
+impl ListOrder {
+    /// The requested direction, None when it's left out, Err for one that isn't asc or desc
+    pub fn direction(&self) -> Result<Option<SortOrder>, String> {
+        self.order.as_deref().map(SortOrder::parse).transpose()
+    }
+}
+
+/// Optional ?q= name filter for the index, ?include_deleted=true to list soft-deleted
+/// locations too and ?sort=&order= to order it
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LocationSearch {
+    pub q: Option<String>,
+    #[serde(default)]
+    pub include_deleted: bool,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+}
+
+impl LocationSearch {
+    // a blank q is the same as leaving it out
+    pub fn term(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+
+    /// The requested ordering, Err for a field or direction that isn't one, or an order
+    /// without a sort, rather than quietly listing by id
+    pub fn ordering(&self) -> Result<Option<(LocationSort, SortOrder)>, String> {
+        let order = self.order.as_deref().map(SortOrder::parse).transpose()?;
+        match self.sort.as_deref() {
+            Some(sort) => Ok(Some((LocationSort::parse(sort)?, order.unwrap_or(SortOrder::Asc)))),
+            None if order.is_some() => Err("order: needs a sort to apply to".to_string()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MergePath {
+    #[serde(deserialize_with = "location_id")]
+    pub src_id: usize,
+    #[serde(deserialize_with = "location_id")]
+    pub dst_id: usize,
+}
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub struct Id {
+    #[serde(deserialize_with = "location_id")]
     pub location_id: usize,
 }
 
-impl Display for Id {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Location Id: {}", self.location_id)
+/// Largest id sqlite's INTEGER holds, a bigger one in a path could never match a row
+const MAX_LOCATION_ID: u64 = i64::MAX as u64;
+
+// a {location_id} segment is a whole number from 1 to MAX_LOCATION_ID, anything else is a 400
+// before a query runs. 0 is SIGNED_OUT, which has no page of its own.
+fn location_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    let segment = String::deserialize(deserializer)?;
+    let id = segment.parse::<u64>().map_err(|err| match err.kind() {
+        IntErrorKind::PosOverflow => de::Error::custom(format!("location_id: {} is out of range", segment)),
+        _ => de::Error::custom(format!("location_id: '{}' is not a whole number", segment)),
+    })?;
+    if id == 0 {
+        return Err(de::Error::custom("location_id: must be at least 1"));
     }
+    if id > MAX_LOCATION_ID {
+        return Err(de::Error::custom(format!("location_id: {} is out of range", segment)));
+    }
+    usize::try_from(id).map_err(|_| de::Error::custom(format!("location_id: {} is out of range", segment)))
 }
 
+/// Most ids one batch show may ask for
+pub const MAX_SHOW_BATCH: usize = 200;
+
+/// ?ids=1,2,3 for the batch show
 #[derive(Debug, Deserialize)]
-pub struct LocationsError(pub String);
-impl ResponseError for LocationsError {}
+pub struct BatchIds {
+    ids: Option<String>,
+}
+
+impl BatchIds {
+    /// The ids in the order given with any repeats dropped, each held to the same rules as a
+    /// {location_id} in a path
+    pub fn ids(&self) -> Result<Vec<usize>, String> {
+        let raw = self.ids.as_deref().map(str::trim).unwrap_or_default();
+        if raw.is_empty() {
+            return Err("ids: is required, a comma separated list of location ids".to_string());
+        }
+        let mut ids = Vec::new();
+        for segment in raw.split(',').map(str::trim) {
+            let id = location_id(de::value::StrDeserializer::<de::value::Error>::new(segment))
+                .map_err(|err| format!("ids: {}", err.to_string().trim_start_matches("location_id: ")))?;
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        if ids.len() > MAX_SHOW_BATCH {
+            return Err(format!("ids: a batch may ask for at most {} locations", MAX_SHOW_BATCH));
+        }
+        Ok(ids)
+    }
+}
 
-impl std::fmt::Display for LocationsError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "A validation error occured on the input: {}", self.0)
+impl Display for Id {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Location Id: {}", self.location_id)
     }
 }
 
-// index all locations
+// index all locations, optionally paged with ?limit=&offset= or filtered by name with ?q=
 #[rustfmt::skip]
 #[get("/api/locations")]
-pub async fn index(db: web::Data<Pool>) -> impl Responder {
+pub async fn index(req: HttpRequest, db: web::Data<Pool>, facility: Facility, page: web::Query<Pagination>, search: web::Query<LocationSearch>, claims: Option<web::ReqData<Claims>>) -> impl Responder {
     log::info!("GET: locations controller");
-    if let Ok(res) = query(&db, Query::IndexLocations).await {
-        match res {
-        QueryResult::Locations(locations) => {
-                let response = LocationsResponse::from_locations(locations);
-            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
-            } 
-        _ => {
-    let response = LocationsResponse::from_error("Error retrieving locations");
-            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
-    } 
+    let page = page.into_inner();
+    let term = search.term();
+    let include_deleted = search.include_deleted;
+    // deleted locations are for staff auditing the history, not the scanners
+    if include_deleted && config().auth_enabled && claims.as_ref().is_none_or(|claims| claims.role < Role::Staff) {
+        return Err(ApiError::Forbidden("include_deleted needs the Staff role".to_string()));
+    }
+    page.validate().map_err(ApiError::Validation)?;
+    let ordering = search.ordering().map_err(ApiError::Validation)?;
+    let index_query = if let Some(term) = term {
+        if ordering.is_some() {
+            return Err(ApiError::Validation("sort: search results are always ordered by name, leave out sort with q".to_string()));
         }
+        Query::SearchLocations(facility, term, include_deleted)
+    } else if let Some((sort, order)) = ordering {
+        let paged = page.is_requested().then(|| (page.limit(), page.offset()));
+        Query::IndexLocationsSorted(facility, sort, order, include_deleted, paged)
+    } else if page.is_requested() {
+        Query::IndexLocationsPaged(facility, page.limit(), page.offset(), include_deleted)
+    } else if include_deleted {
+        Query::IndexAllLocations(facility)
     } else {
-        Err(LocationsError("Unable to retrieve locations".to_string()))
+        Query::IndexLocations(facility)
+    };
+    let format = negotiate(&req, &[Format::Json, Format::Envelope, Format::Csv])?;
+    let cacheable = index_query == Query::IndexLocations(facility);
+    if cacheable {
+        if let Some(locations) = LOCATIONS_CACHE.get(facility) {
+            return locations_response(&req, format, HttpResponse::Ok(), locations, None);
+        }
+    }
+    let generation = LOCATIONS_CACHE.generation();
+    match query(&db, index_query).await {
+        Ok(QueryResult::Locations(locations)) => {
+            if cacheable {
+                LOCATIONS_CACHE.put(generation, facility, &locations);
+            }
+            locations_response(&req, format, HttpResponse::Ok(), locations, None)
+        }
+        Ok(QueryResult::LocationsPage(locations, total)) => {
+            let builder = page.response(&req, total);
+            locations_response(&req, format, builder, locations, Some((&page, total)))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve locations")),
+    }
+}
+
+// the index as CSV rows, or as the usual JSON response or the v2 envelope with an ETag. `page`
+// is the requested page and the total it's out of when the index was paged.
+#[rustfmt::skip]
+fn locations_response(req: &HttpRequest, format: Format, builder: HttpResponseBuilder, locations: Vec<Location>, page: Option<(&Pagination, usize)>) -> Result<HttpResponse, ApiError> {
+    match (format, page) {
+        (Format::Csv, _) => Ok(csv_response(builder, &locations)),
+        (Format::Envelope, Some((page, total))) => conditional_json(req, builder, format, &page.envelope(locations, Some(total))),
+        (Format::Envelope, None) => conditional_json(req, builder, format, &Envelope::list(locations)),
+        _ => conditional_json(req, builder, Format::Json, &LocationsResponse::from_locations(locations)),
     }
 }
-// add a new location
+
+/// Serializes the body once and tags it with a weak ETag hashed from those bytes, answering
+/// 304 Not Modified with no body when the request's If-None-Match already has it
+fn conditional_json(req: &HttpRequest, mut builder: HttpResponseBuilder, format: Format, body: &impl Serialize) -> Result<HttpResponse, ApiError> {
+    let bytes = serde_json::to_vec(body).map_err(|err| ApiError::Db(err.to_string()))?;
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    let etag = EntityTag::new_weak(format!("{:016x}", hasher.finish()));
+    let fresh = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    builder.insert_header(header::ETag(etag));
+    if fresh {
+        return Ok(builder.status(StatusCode::NOT_MODIFIED).finish());
+    }
+    Ok(builder.content_type(format.mime()).body(bytes))
+}
+
+// add a new location, responding with the stored record and where to find it
 #[rustfmt::skip]
 #[post("/api/locations")]
-pub async fn store(db: web::Data<Pool>, loc: web::Json<Location>) -> Result<HttpResponse, LocationsError> {
+pub async fn store(db: web::Data<Pool>, facility: Facility, loc: web::Json<Location>) -> Result<HttpResponse, ApiError> {
     log::info!("POST: locations controller");
-    if let Ok(QueryResult::Success) = query(&db, Query::StoreLocation(&loc.into_inner())).await {
-        let response = LocationsResponse::from_success("Location successfully added");
-        Ok(HttpResponse::Ok().status(StatusCode::CREATED).insert_header(header::ContentType::json()).json(response))
-    } else {
-        Err(LocationsError("Unable to add location".to_string()))
+    let loc = loc.into_inner().with_facility(facility);
+    loc.validate().map_err(ApiError::Validation)?;
+    match query(&db, Query::StoreLocation(&loc)).await {
+        Ok(QueryResult::Location(loc)) => {
+            LOCATIONS_CACHE.invalidate();
+            let location = format!("/api/locations/{}", loc.id);
+            Ok(created(&location, &LocationsResponse::from_created(loc)))
+        }
+        Ok(QueryResult::Conflict(field)) => Err(location_conflict(&field, &loc)),
+        Ok(QueryResult::Invalid(msg)) => Err(ApiError::Validation(msg)),
+        res => Err(ApiError::from_query(res, "Unable to add location")),
+    }
+}
+
+fn location_conflict(field: &str, loc: &Location) -> ApiError {
+    ApiError::Conflict(conflict_message(field, loc))
+}
+
+// names the field another location already has the same value for
+fn conflict_message(field: &str, loc: &Location) -> String {
+    match field {
+        "name" => format!("A location named {} already exists", loc.name),
+        _ => format!("A location with id {} already exists", loc.id),
+    }
+}
+
+/// Most rows one import may hold
+pub const MAX_IMPORT_ROWS: usize = 1000;
+
+// create locations in bulk from a CSV file, reporting what happened to each row
+#[rustfmt::skip]
+#[post("/api/locations/import")]
+pub async fn import(req: HttpRequest, db: web::Data<Pool>, facility: Facility, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    log::info!("POST: locations controller import");
+    let records = parse_csv(uploaded_text(&req, &body)?).map_err(ApiError::Validation)?;
+    let rows = locations_from_csv(&records).map_err(ApiError::Validation)?;
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(ApiError::Validation(format!("an import may hold at most {} rows", MAX_IMPORT_ROWS)));
+    }
+    // same rules as a single create, only rows that pass reach the database
+    let mut results = Vec::with_capacity(rows.len());
+    let mut valid = Vec::new();
+    for (row, parsed) in rows {
+        match parsed.and_then(|loc| loc.validate().map(|()| loc.with_facility(facility))) {
+            Ok(loc) => valid.push((row, loc)),
+            Err(msg) => results.push(LocationImportResult::skipped(row, msg)),
+        }
+    }
+    let batch = valid.iter().map(|(_, loc)| loc.clone()).collect::<Vec<Location>>();
+    match query(&db, Query::StoreLocationsBatch(&batch)).await {
+        Ok(QueryResult::StoredLocations(stored)) => {
+            for ((row, loc), stored) in valid.into_iter().zip(stored) {
+                results.push(match stored {
+                    Ok(location) => LocationImportResult::created(row, location),
+                    Err(field) => LocationImportResult::skipped(row, conflict_message(&field, &loc)),
+                });
+            }
+            if results.iter().any(|res| res.created) {
+                LOCATIONS_CACHE.invalidate();
+            }
+            results.sort_by_key(|res| res.row);
+            let response = LocationsResponse::from_import(results);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to import locations, nothing was stored")),
     }
 }
 
 // Get location name from ID
+#[rustfmt::skip]
 #[get("/api/locations/{location_id}")]
-pub async fn show(db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, LocationsError> {
+pub async fn show(req: HttpRequest, db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
     log::info!("GET: locations controller with id: {}", id.location_id);
-    if let Ok(QueryResult::Location(loc)) = query(&db, Query::ShowLocation(id.location_id)).await {
-        let loc = LocationsResponse::from_location(loc);
-        Ok(HttpResponse::Ok()
-            .insert_header(header::ContentType::json())
-            .json(loc))
-    } else {
-        Err(LocationsError("Unable to retrieve location".to_string()))
+    let format = negotiate(&req, &[Format::Json, Format::Envelope])?;
+    match query(&db, Query::ShowLocation(id.location_id)).await {
+        Ok(QueryResult::Location(loc)) if format == Format::Envelope => {
+            Ok(envelope_response(HttpResponse::Ok(), &Envelope::new(loc)))
+        }
+        Ok(QueryResult::Location(loc)) => {
+            let loc = LocationsResponse::from_location(loc);
+            Ok(HttpResponse::Ok()
+                .insert_header(header::ContentType::json())
+                .json(loc))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!(
+            "No location with id {}",
+            id.location_id
+        ))),
+        res => Err(ApiError::from_query(res, "Unable to retrieve location")),
+    }
+}
+
+// several locations in one call rather than one show each, ids that aren't a location are
+// listed as missing instead of failing the rest
+#[rustfmt::skip]
+#[get("/api/locations/batch")]
+pub async fn show_batch(db: web::Data<Pool>, facility: Facility, params: web::Query<BatchIds>) -> Result<HttpResponse, ApiError> {
+    let ids = params.ids().map_err(ApiError::Validation)?;
+    log::info!("GET: locations controller batch of {} ids", ids.len());
+    match query(&db, Query::ShowLocationsByIds(facility, &ids)).await {
+        Ok(QueryResult::Locations(found)) => {
+            let response = LocationsResponse::from_batch(LocationBatch::arrange(&ids, found));
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve locations")),
+    }
+}
+
+// replace an existing location, the ID in the path always wins over the body. The body's
+// version is the one the edit was made from, a location changed since is a 409 to reload
+#[rustfmt::skip]
+#[put("/api/locations/{location_id}")]
+pub async fn update(req: HttpRequest, db: web::Data<Pool>, facility: Facility, id: web::Path<Id>, loc: web::Json<Location>) -> Result<HttpResponse, ApiError> {
+    log::info!("PUT: locations controller with id: {}", id.location_id);
+    let mut loc = loc.into_inner().with_facility(facility);
+    loc.id = id.location_id;
+    loc.validate().map_err(ApiError::Validation)?;
+    if loc.version == 0 {
+        return Err(ApiError::Validation("version: is required, the version of the location being edited".to_string()));
+    }
+    match query(&db, Query::UpdateLocation(id.location_id, &loc)).await {
+        Ok(QueryResult::LocationUpdate(UpdateOutcome::Updated(before, loc))) => {
+            LOCATIONS_CACHE.invalidate();
+            audit_before(&req, &before);
+            let response = LocationsResponse::from_location(loc);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::LocationUpdate(UpdateOutcome::NoLocation)) => Err(ApiError::NotFound(format!("No location with id {}", id.location_id))),
+        Ok(QueryResult::LocationUpdate(UpdateOutcome::Stale(current))) => Err(ApiError::Conflict(format!(
+            "Location {} was changed by someone else, it's at version {} not {}, reload it and try again", id.location_id, current, loc.version
+        ))),
+        Ok(QueryResult::Conflict(field)) => Err(location_conflict(&field, &loc)),
+        Ok(QueryResult::Invalid(msg)) => Err(ApiError::Validation(msg)),
+        res => Err(ApiError::from_query(res, "Unable to update location")),
+    }
+}
+
+// open, close or lock down a location, kept apart from PUT so a full edit can't change it
+#[rustfmt::skip]
+#[put("/api/locations/{location_id}/status")]
+pub async fn update_status(db: web::Data<Pool>, id: web::Path<Id>, payload: web::Json<StatusPayload>) -> Result<HttpResponse, ApiError> {
+    let status = payload.into_inner().status;
+    log::info!("PUT: locations controller status {} for id: {}", status.as_str(), id.location_id);
+    match query(&db, Query::SetLocationStatus(id.location_id, status)).await {
+        Ok(QueryResult::Location(loc)) => {
+            LOCATIONS_CACHE.invalidate();
+            let response = LocationsResponse::from_location(loc);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No location with id {}", id.location_id))),
+        res => Err(ApiError::from_query(res, "Unable to update location status")),
+    }
+}
+
+/// Most ids one status batch may name
+pub const MAX_STATUS_BATCH: usize = 500;
+
+// set many locations' status at once, for declaring or lifting a facility-wide lockdown
+#[rustfmt::skip]
+#[post("/api/locations/status/batch")]
+pub async fn update_status_batch(db: web::Data<Pool>, facility: Facility, payload: web::Json<StatusBatchPayload>) -> Result<HttpResponse, ApiError> {
+    let StatusBatchPayload { ids, status } = payload.into_inner();
+    log::info!("POST: locations controller status {} for {} ids", status.as_str(), ids.len());
+    if ids.is_empty() {
+        return Err(ApiError::Validation("ids: name at least one location".to_string()));
+    }
+    if ids.len() > MAX_STATUS_BATCH {
+        return Err(ApiError::Validation(format!("ids: a batch may hold at most {} locations", MAX_STATUS_BATCH)));
+    }
+    match query(&db, Query::SetLocationStatusBatch(facility, &ids, status)).await {
+        Ok(QueryResult::StatusBatch(result)) => {
+            LOCATIONS_CACHE.invalidate();
+            let response = LocationsResponse::from_status_batch(result);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to update location statuses, none were changed")),
+    }
+}
+
+// delete a location by ID
+#[rustfmt::skip]
+#[delete("/api/locations/{location_id}")]
+pub async fn destroy(req: HttpRequest, db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
+    log::info!("DELETE: locations controller with id: {}", id.location_id);
+    match query(&db, Query::DeleteLocation(id.location_id)).await {
+        Ok(QueryResult::Location(before)) => {
+            LOCATIONS_CACHE.invalidate();
+            audit_before(&req, &before);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No location with id {}", id.location_id))),
+        res => Err(ApiError::from_query(res, "Unable to delete location")),
+    }
+}
+
+// fold a duplicate location into another, everything that pointed at the source points at
+// the destination afterwards and the source is deleted
+#[rustfmt::skip]
+#[post("/api/locations/{src_id}/merge/{dst_id}")]
+pub async fn merge(db: web::Data<Pool>, facility: Facility, path: web::Path<MergePath>) -> Result<HttpResponse, ApiError> {
+    let MergePath { src_id, dst_id } = path.into_inner();
+    log::info!("POST: locations controller merging {} into {}", src_id, dst_id);
+    if src_id == dst_id {
+        return Err(ApiError::Validation("dst_id: a location can't be merged into itself".to_string()));
+    }
+    // the facility middleware only reads {location_id}, so both are checked here
+    ensure_own(&db, facility, Record::Location(src_id)).await?;
+    ensure_own(&db, facility, Record::Location(dst_id)).await?;
+    match query(&db, Query::MergeLocations(src_id, dst_id)).await {
+        Ok(QueryResult::Merge(MergeOutcome::Merged(merge))) => {
+            LOCATIONS_CACHE.invalidate();
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(LocationsResponse::from_merge(merge)))
+        }
+        Ok(QueryResult::Merge(MergeOutcome::NoSource)) => Err(ApiError::NotFound(format!("No location with id {}", src_id))),
+        Ok(QueryResult::Merge(MergeOutcome::NoDestination)) => Err(ApiError::NotFound(format!("No location with id {}", dst_id))),
+        Ok(QueryResult::Merge(MergeOutcome::Nested)) => Err(ApiError::Validation(format!(
+            "dst_id: location {} is inside location {}, move it out before merging", dst_id, src_id
+        ))),
+        res => Err(ApiError::from_query(res, "Unable to merge locations, nothing was changed")),
     }
 }
 
 // include range in url to show timestamps from /start/end
 #[rustfmt::skip]
 #[get("/api/locations/{location_id}/timestamps/{start_date}/{end_date}")]
-pub async fn show_location_timestamps_range(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, LocationsError> {
+pub async fn show_location_timestamps_range(db: web::Data<Pool>, id: web::Path<LocationRange>, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ScanFilter>, req: HttpRequest) -> Result<HttpResponse, ApiError> {
     let loc_range = id.into_inner();
     log::info!("GET: Locations controller timestamps with range for ID");
-    if let Ok(QueryResult::TimeStamps(ts)) = query(&db, Query::ShowLocationTimestampsRange(loc_range.location_id, &loc_range.start_date, &loc_range.end_date)).await {
-        let response = TimestampResponse::from_db(ts);
-        Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
-    } else {
-        Err(LocationsError("Unable to retrieve timestamps".to_string()))
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    page.validate().map_err(ApiError::Validation)?;
+    let direction = order.direction().map_err(ApiError::Validation)?;
+    let format = negotiate(&req, &[Format::Json, Format::Envelope, Format::Csv, Format::Ndjson])?;
+    // streamed rows don't pile up in memory, so the stream is never paged
+    if format == Format::Ndjson {
+        if !filter.is_empty() || direction.is_some() {
+            return Err(ApiError::Validation("rfid, doc and order don't apply to the NDJSON stream".to_string()));
+        }
+        let rows = stream_location_timestamps_range(&db, loc_range.location_id, start, end);
+        return Ok(HttpResponse::Ok().content_type(Format::Ndjson.mime()).streaming(rows.map(ndjson_line)));
+    }
+    let res = query(&db, Query::ShowLocationTimestampsRange(loc_range.location_id, &start, &end, &filter, direction, page.for_timestamps())).await;
+    timestamps_response(&req, format, &page, res)
+}
+
+// the same rows as the range above, unpaged, as a spreadsheet to download
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/timestamps/{start_date}/{end_date}/export.xlsx")]
+pub async fn export_location_timestamps(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    location_timestamps_export(&db, id.into_inner(), ExportFormat::Xlsx).await
+}
+
+/// GET: /api/locations/{location_id}/timestamps/{start_date}/{end_date}/export?format=csv|xlsx
+/// The same rows as the range above as a download. A CSV is streamed and may cover any range,
+/// a workbook is held to MAX_RANGE_DAYS.
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/timestamps/{start_date}/{end_date}/export")]
+pub async fn export_location_timestamps_as(db: web::Data<Pool>, id: web::Path<LocationRange>, params: web::Query<FormatParams>) -> Result<HttpResponse, ApiError> {
+    let format = params.format().map_err(ApiError::Validation)?;
+    location_timestamps_export(&db, id.into_inner(), format).await
+}
+
+async fn location_timestamps_export(db: &Pool, loc_range: LocationRange, format: ExportFormat) -> Result<HttpResponse, ApiError> {
+    log::info!("GET: Locations controller timestamps export for ID: {}", loc_range.location_id);
+    let (start, end) = format.range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    let name = format!("location-{}-timestamps-{}-to-{}", loc_range.location_id, start, end);
+    let rows = stream_location_timestamps_range(db, loc_range.location_id, start, end);
+    export_response(format, &name, rows).await
+}
+
+// one JSON object per line
+fn ndjson_line<T: Serialize>(item: StreamItem<T>) -> Result<web::Bytes, actix_web::Error> {
+    let item = item.map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut line = serde_json::to_vec(&item)?;
+    line.push(b'\n');
+    Ok(web::Bytes::from(line))
+}
+
+// total seconds each resident spent at a location within /start/end
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/dwell/{start_date}/{end_date}")]
+pub async fn show_location_dwell(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    let loc_range = id.into_inner();
+    log::info!("GET: Locations controller dwell with range for ID");
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::ShowLocationDwell(loc_range.location_id, &start, &end)).await {
+        Ok(QueryResult::Dwell(dwell)) => {
+            let response = TimestampResponse::from_dwell(dwell);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve dwell times")),
+    }
+}
+
+// each resident's separate stays at a location within /start/end, the intervals behind the dwell totals
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/visits/{start_date}/{end_date}")]
+pub async fn show_location_visits(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    let loc_range = id.into_inner();
+    log::info!("GET: Locations controller visits with range for ID");
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::LocationVisits(loc_range.location_id, &start, &end)).await {
+        Ok(QueryResult::Visits(visits)) => {
+            let response = TimestampResponse::from_visits(visits);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve visits")),
+    }
+}
+
+// scans per local hour at a location across the range, empty hours included as zeros
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/occupancy/hourly/{start_date}/{end_date}")]
+pub async fn show_occupancy_by_hour(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    let loc_range = id.into_inner();
+    log::info!("GET: Locations controller hourly occupancy for ID: {}", loc_range.location_id);
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::OccupancyByHour(loc_range.location_id, &start, &end)).await {
+        Ok(QueryResult::Hourly(hours)) => {
+            let response = TimestampResponse::from_hourly(hours);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve hourly occupancy")),
+    }
+}
+
+// scans per local day at a location across the range, empty days included as zeros
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/scans/daily/{start_date}/{end_date}")]
+pub async fn show_daily_scan_counts(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    let loc_range = id.into_inner();
+    log::info!("GET: Locations controller daily scan counts for ID: {}", loc_range.location_id);
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::DailyScanCounts(loc_range.location_id, &start, &end)).await {
+        Ok(QueryResult::Daily(days)) => {
+            let response = TimestampResponse::from_daily(days);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve daily scan counts")),
+    }
+}
+
+// the most residents at a location at once across the range, and when it was first reached
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/peak/{start_date}/{end_date}")]
+pub async fn show_peak_occupancy(db: web::Data<Pool>, id: web::Path<LocationRange>) -> Result<HttpResponse, ApiError> {
+    let loc_range = id.into_inner();
+    log::info!("GET: Locations controller peak occupancy for ID: {}", loc_range.location_id);
+    let (start, end) = parse_range(&loc_range.start_date, &loc_range.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::PeakOccupancy(loc_range.location_id, &start, &end)).await {
+        Ok(QueryResult::Peak(peak)) => {
+            let response = TimestampResponse::from_peak(peak);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve peak occupancy")),
     }
 }
 
 // show timestamps from today for a location
 #[rustfmt::skip]
 #[get("/api/locations/{location_id}/timestamps")]
-pub async fn show_location_timestamps(db: web::Data<Pool>, id: web::Path<Id>) -> impl Responder {
+pub async fn show_location_timestamps(req: HttpRequest, db: web::Data<Pool>, id: web::Path<Id>, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ScanFilter>) -> Result<HttpResponse, ApiError> {
     let id = id.into_inner().location_id;
     log::info!("GET: Locations controller timestamps for ID");
-    if let Ok(QueryResult::TimeStamps(ts)) = query(&db, Query::ShowLocationTimestamps(id)).await {
-        let response = TimestampResponse::from_db(ts);
-        Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
-    } else {
-        Err(LocationsError("Unable to retrieve timestamps".to_string()))
+    page.validate().map_err(ApiError::Validation)?;
+    let direction = order.direction().map_err(ApiError::Validation)?;
+    let format = negotiate(&req, &[Format::Json, Format::Envelope, Format::Csv])?;
+    let res = query(&db, Query::ShowLocationTimestamps(id, &filter, direction, page.for_timestamps())).await;
+    timestamps_response(&req, format, &page, res)
+}
+
+// timestamps as CSV rows, JSON or the v2 envelope, a page with its total and links in the headers
+#[rustfmt::skip]
+fn timestamps_response(req: &HttpRequest, format: Format, page: &Pagination, res: Result<QueryResult, Box<dyn std::error::Error>>) -> Result<HttpResponse, ApiError> {
+    let (mut builder, ts, total) = match res {
+        Ok(QueryResult::TimeStamps(ts)) => (HttpResponse::Ok(), ts, None),
+        Ok(QueryResult::TimeStampsPage(ts, total)) => (page.response(req, total), ts, Some(total)),
+        res => return Err(ApiError::from_query(res, "Unable to retrieve timestamps")),
+    };
+    match format {
+        Format::Csv => Ok(csv_response(builder, &ts)),
+        Format::Envelope => Ok(envelope_response(builder, &page.envelope(ts, total))),
+        _ => Ok(builder.insert_header(header::ContentType::json()).json(TimestampResponse::from_db(ts))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocationResident {
+    #[serde(deserialize_with = "location_id")]
+    location_id: usize,
+    resident_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocationResidentRange {
+    #[serde(deserialize_with = "location_id")]
+    location_id: usize,
+    resident_id: String,
+    start_date: String,
+    end_date: String,
+}
+
+// every scan one resident made at a location, oldest first
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/residents/{resident_id}/timestamps")]
+pub async fn show_location_resident_timestamps(db: web::Data<Pool>, facility: Facility, path: web::Path<LocationResident>) -> Result<HttpResponse, ApiError> {
+    let LocationResident { location_id, resident_id } = path.into_inner();
+    log::info!("GET: Locations controller timestamps of {} at ID: {}", resident_id, location_id);
+    resident_timestamps_response(&db, facility, location_id, &resident_id, None).await
+}
+
+// the same, kept to the range
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/residents/{resident_id}/timestamps/{start_date}/{end_date}")]
+pub async fn show_location_resident_timestamps_range(db: web::Data<Pool>, facility: Facility, path: web::Path<LocationResidentRange>) -> Result<HttpResponse, ApiError> {
+    let LocationResidentRange { location_id, resident_id, start_date, end_date } = path.into_inner();
+    log::info!("GET: Locations controller timestamps of {} at ID: {} from {} to {}", resident_id, location_id, start_date, end_date);
+    let (start, end) = parse_range(&start_date, &end_date).map_err(ApiError::Validation)?;
+    resident_timestamps_response(&db, facility, location_id, &resident_id, Some((&start, &end))).await
+}
+
+// the facility middleware only reads {location_id}, so the resident is checked here
+#[rustfmt::skip]
+async fn resident_timestamps_response(db: &Pool, facility: Facility, location_id: usize, rfid: &str, range: Option<(&NaiveDate, &NaiveDate)>) -> Result<HttpResponse, ApiError> {
+    ensure_own(db, facility, Record::Resident(rfid)).await?;
+    match query(db, Query::ShowLocationResidentTimestamps(location_id, rfid, range)).await {
+        Ok(QueryResult::ResidentScans(ResidentScansOutcome::Scans(ts))) => {
+            let response = TimestampResponse::from_db(ts);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::ResidentScans(ResidentScansOutcome::NoLocation)) => {
+            Err(ApiError::NotFound(format!("No location with id {}", location_id)))
+        }
+        Ok(QueryResult::ResidentScans(ResidentScansOutcome::NoResident)) => {
+            Err(ApiError::NotFound(format!("No resident with rfid {}", rfid)))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve timestamps")),
+    }
+}
+
+// upgrade to a WebSocket that pushes each scan recorded at the location as it happens
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/live")]
+pub async fn live_timestamps(req: HttpRequest, db: web::Data<Pool>, live: web::Data<LiveScans>, id: web::Path<Id>, payload: web::Payload) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    log::info!("GET: Locations controller live scans for ID: {}", id);
+    match query(&db, Query::ShowLocation(id)).await {
+        Ok(QueryResult::Location(_)) => {}
+        Ok(QueryResult::NotFound) => return Err(ApiError::NotFound(format!("No location with id {}", id))),
+        res => return Err(ApiError::from_query(res, "Unable to retrieve location")),
+    }
+    ws::verify_handshake(req.head()).map_err(|err| ApiError::Validation(err.to_string()))?;
+    // verify_handshake has checked the key is there
+    let key = req.headers().get(header::SEC_WEBSOCKET_KEY).map_or(&[][..], |key| key.as_bytes());
+    let accept = header::HeaderValue::from_bytes(&ws::hash_key(key)).map_err(|err| ApiError::Validation(err.to_string()))?;
+    Ok(HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((header::SEC_WEBSOCKET_ACCEPT, accept))
+        .streaming(live::socket(&live, id, payload)))
+}
+
+/// GET: /api/locations/{location_id}/events
+/// The same feed as the socket above as Server-Sent Events, for a browser's EventSource or a
+/// display behind a proxy that won't pass an upgrade
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/events")]
+pub async fn live_events(db: web::Data<Pool>, live: web::Data<LiveScans>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    log::info!("GET: Locations controller event stream for ID: {}", id);
+    match query(&db, Query::ShowLocation(id)).await {
+        Ok(QueryResult::Location(_)) => {}
+        Ok(QueryResult::NotFound) => return Err(ApiError::NotFound(format!("No location with id {}", id))),
+        res => return Err(ApiError::from_query(res, "Unable to retrieve location")),
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        // Compress would hold events back until it had enough to flush
+        .insert_header(header::ContentEncoding::Identity)
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // nginx buffers a proxied response unless told not to
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(live::events(&live, id)))
+}
+
+// record a scan at a location, the time defaults to now when omitted
+#[rustfmt::skip]
+#[post("/api/locations/{location_id}/timestamps")]
+pub async fn store_timestamp(db: web::Data<Pool>, live: web::Data<LiveScans>, facility: Facility, id: web::Path<Id>, scan: web::Json<ScanPayload>, claims: Option<web::ReqData<Claims>>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    let scan = scan.into_inner();
+    log::info!("POST: Locations controller timestamp for ID: {}", id);
+    scan.validate().map_err(ApiError::Validation)?;
+    admit_scan(&db, facility, id, claims.as_deref()).await?;
+    let mut ts = PostTimestamp::with_time(scan.rfid, id, scan.time);
+    match query(&db, Query::StoreTimestamp(&ts)).await {
+        Ok(QueryResult::PostTimestamp(timestamp)) => {
+            if !timestamp.duplicate {
+                SCANS_STORED.with_label_values(&["single"]).inc();
+                ts.restricted = timestamp.restricted;
+                webhooks::notify_scan(&ts);
+                live.publish(&ts);
+            }
+            let response = TimestampResponse::from_ts(&timestamp);
+            match timestamp.id {
+                Some(id) if !timestamp.duplicate => Ok(created(&format!("/api/timestamps/{}", id), &response)),
+                _ => Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response)),
+            }
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No resident with rfid {}", ts.rfid))),
+        res => Err(ApiError::from_query(res, "Unable to store timestamp")),
+    }
+}
+
+/// Most scans accepted in a single batch upload
+pub const MAX_BATCH_SIZE: usize = 500;
+
+// record a buffered batch of scans from an offline scanner in one transaction
+#[rustfmt::skip]
+#[post("/api/locations/{location_id}/timestamps/batch")]
+pub async fn store_timestamps_batch(db: web::Data<Pool>, live: web::Data<LiveScans>, facility: Facility, id: web::Path<Id>, scans: web::Json<Vec<ScanPayload>>, claims: Option<web::ReqData<Claims>>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    let scans = scans.into_inner();
+    log::info!("POST: Locations controller timestamp batch of {} for ID: {}", scans.len(), id);
+    if scans.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::Validation(format!("a batch may hold at most {} scans", MAX_BATCH_SIZE)));
+    }
+    admit_scan(&db, facility, id, claims.as_deref()).await?;
+    // only scans that pass validation reach the database, keep their original index
+    let mut results = Vec::with_capacity(scans.len());
+    let mut valid = Vec::new();
+    for (pos, scan) in scans.into_iter().enumerate() {
+        match scan.validate() {
+            Ok(()) => valid.push((pos, PostTimestamp::with_time(scan.rfid, id, scan.time))),
+            Err(msg) => results.push(BatchScanResult::rejected(pos, scan.rfid, msg)),
+        }
+    }
+    let batch = valid.iter().map(|(_, ts)| ts.clone()).collect::<Vec<PostTimestamp>>();
+    match query(&db, Query::StoreTimestampsBatch(&batch)).await {
+        Ok(QueryResult::PostTimestamps(stored)) => {
+            for ((pos, mut ts), stored) in valid.into_iter().zip(stored) {
+                results.push(match stored {
+                    Some(timestamp) => {
+                        if !timestamp.duplicate {
+                            ts.restricted = timestamp.restricted;
+                            webhooks::notify_scan(&ts);
+                            live.publish(&ts);
+                        }
+                        BatchScanResult::accepted(pos, timestamp)
+                    }
+                    None => BatchScanResult::rejected(pos, ts.rfid, "no resident with this rfid".to_string()),
+                });
+            }
+            let stored = results.iter().filter(|res| res.accepted && !res.duplicate).count();
+            SCANS_STORED.with_label_values(&["batch"]).inc_by(stored as u64);
+            results.sort_by_key(|res| res.index);
+            let response = TimestampResponse::from_batch(results);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to store timestamps, batch rolled back")),
     }
 }
 
-// show all residents for a given location
+/// Checks the location a scan is headed into exists, is the caller's facility's and takes
+/// scans. A lockdown refuses it with a 423 unless the caller's token carries the supervisor
+/// role, a closed location lets it through with a warning.
+pub async fn admit_scan(db: &Pool, facility: Facility, id: usize, claims: Option<&Claims>) -> Result<(), ApiError> {
+    ensure_own(db, facility, Record::Location(id)).await?;
+    let location = match query(db, Query::ShowLocation(id)).await {
+        Ok(QueryResult::Location(location)) => location,
+        Ok(QueryResult::NotFound) => {
+            return Err(ApiError::NotFound(format!("No location with id {}", id)))
+        }
+        res => return Err(ApiError::from_query(res, "Unable to retrieve location")),
+    };
+    let caller = claims.map_or("a scanner", |claims| claims.sub.as_str());
+    match location.status {
+        LocationStatus::Open => Ok(()),
+        LocationStatus::Closed => {
+            log::warn!("Scan by {} into closed location {}", caller, location.name);
+            Ok(())
+        }
+        LocationStatus::Lockdown if claims.is_some_and(|claims| claims.role >= Role::Supervisor) => {
+            log::warn!("Supervisor {} overrode the lockdown of {}", caller, location.name);
+            Ok(())
+        }
+        LocationStatus::Lockdown => Err(ApiError::Locked(format!(
+            "{} is locked down, only a supervisor can record scans there",
+            location.name
+        ))),
+    }
+}
+
+// the locations directly inside a location, a building's wings or a wing's cells
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/children")]
+pub async fn show_location_children(db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    log::info!("GET: Locations controller children for ID: {}", id);
+    match query(&db, Query::ShowLocationChildren(id)).await {
+        Ok(QueryResult::Locations(children)) => {
+            let response = LocationsResponse::from_locations(children);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No location with id {}", id))),
+        res => Err(ApiError::from_query(res, "Unable to retrieve child locations")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RoutePath {
+    #[serde(deserialize_with = "location_id")]
+    pub from_id: usize,
+    #[serde(deserialize_with = "location_id")]
+    pub to_id: usize,
+}
+
+// the route between two locations for an escort, up to the nearest location containing both
+// and back down, starting at from_id and ending at to_id
+#[rustfmt::skip]
+#[get("/api/locations/{from_id}/path/{to_id}")]
+pub async fn show_location_path(db: web::Data<Pool>, facility: Facility, path: web::Path<RoutePath>) -> Result<HttpResponse, ApiError> {
+    let RoutePath { from_id, to_id } = path.into_inner();
+    log::info!("GET: Locations controller path from {} to {}", from_id, to_id);
+    // the facility middleware only reads {location_id}, so both are checked here
+    ensure_own(&db, facility, Record::Location(from_id)).await?;
+    ensure_own(&db, facility, Record::Location(to_id)).await?;
+    match query(&db, Query::LocationPath(from_id, to_id)).await {
+        Ok(QueryResult::Path(PathOutcome::Route(route))) => {
+            let response = LocationsResponse::from_path(route);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::Path(PathOutcome::NoFrom)) => Err(ApiError::NotFound(format!("No location with id {}", from_id))),
+        Ok(QueryResult::Path(PathOutcome::NoTo)) => Err(ApiError::NotFound(format!("No location with id {}", to_id))),
+        Ok(QueryResult::Path(PathOutcome::NoCommonAncestor)) => Err(ApiError::NotFound(format!(
+            "No path from location {} to location {}, no location contains them both", from_id, to_id
+        ))),
+        res => Err(ApiError::from_query(res, "Unable to find the path between the locations")),
+    }
+}
+
+/// Locations a nearby search returns when ?count= is left out
+pub const DEFAULT_NEARBY_COUNT: usize = 3;
+
+/// Most locations one nearby search may ask for
+pub const MAX_NEARBY_COUNT: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct NearbyParams {
+    // taken as text so a bad value gets our 400 rather than the extractor's
+    count: Option<String>,
+}
+
+impl NearbyParams {
+    pub fn count(&self) -> Result<usize, String> {
+        match self.count.as_deref().map(str::trim) {
+            None | Some("") => Ok(DEFAULT_NEARBY_COUNT),
+            Some(count) => match count.parse::<usize>() {
+                Ok(n) if (1..=MAX_NEARBY_COUNT).contains(&n) => Ok(n),
+                _ => Err(format!("count: '{}' must be a whole number from 1 to {}", count, MAX_NEARBY_COUNT)),
+            },
+        }
+    }
+}
+
+// the locations closest to this one on the floor plan, e.g. the nearest medical station
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/nearby")]
+pub async fn show_nearby_locations(db: web::Data<Pool>, id: web::Path<Id>, params: web::Query<NearbyParams>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    let count = params.count().map_err(ApiError::Validation)?;
+    log::info!("GET: Locations controller {} nearest to ID: {}", count, id);
+    match query(&db, Query::NearbyLocations(id, count)).await {
+        Ok(QueryResult::Nearby(nearby)) => {
+            let response = LocationsResponse::from_nearby(nearby);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No location with id {}", id))),
+        Ok(QueryResult::Conflict(msg)) => Err(ApiError::Conflict(msg)),
+        res => Err(ApiError::from_query(res, "Unable to retrieve nearby locations")),
+    }
+}
+
+// show all residents for a given location, optionally narrowed with ?doc=, ordered by name
+// with ?order= and paged
 #[rustfmt::skip]
 #[get("/api/locations/{location_id}/residents")]
-pub async fn show_location_residents(db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, LocationsError> {
+pub async fn show_location_residents(req: HttpRequest, db: web::Data<Pool>, id: web::Path<Id>, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ResidentFilter>) -> Result<HttpResponse, ApiError> {
     let id = id.into_inner().location_id;
     log::info!("GET: Locations controller residents for ID");
-    if let Ok(QueryResult::Residents(res)) = query(&db, Query::ShowLocationResidents(id)).await {
-        let response = ResidentResponse::from_vec(res);
-        Ok(HttpResponse::Ok()
-            .insert_header(header::ContentType::json())
-            .json(response))
-    } else {
-        Err(LocationsError("Unable to retrieve residents".to_string()))
+    page.validate().map_err(ApiError::Validation)?;
+    let direction = order.direction().map_err(ApiError::Validation)?;
+    let paged = page.is_requested().then(|| (page.limit(), page.offset()));
+    let format = negotiate(&req, &[Format::Json, Format::Envelope])?;
+    let res = query(&db, Query::ShowLocationResidents(id, &filter, direction, paged)).await;
+    residents_response(&req, format, &page, res)
+}
+
+/// A list of residents as the usual JSON response or the v2 envelope, a page with its total
+/// and links in the headers
+pub fn residents_response(req: &HttpRequest, format: Format, page: &Pagination, res: Result<QueryResult, Box<dyn std::error::Error>>) -> Result<HttpResponse, ApiError> {
+    let (mut builder, residents, total) = match res {
+        Ok(QueryResult::Residents(residents)) => (HttpResponse::Ok(), residents, None),
+        Ok(QueryResult::ResidentsPage(residents, total)) => (page.response(req, total), residents, Some(total)),
+        res => return Err(ApiError::from_query(res, "Unable to retrieve residents")),
+    };
+    match format {
+        Format::Envelope => Ok(envelope_response(builder, &page.envelope(residents, total))),
+        _ => Ok(builder.insert_header(header::ContentType::json()).json(ResidentResponse::from_vec(residents))),
+    }
+}
+
+// how many residents are assigned to each location, including empty ones
+#[rustfmt::skip]
+#[get("/api/locations/residents/counts")]
+pub async fn show_resident_counts(db: web::Data<Pool>, facility: Facility) -> Result<HttpResponse, ApiError> {
+    log::info!("GET: Locations controller resident counts");
+    match query(&db, Query::CountResidentsPerLocation(facility)).await {
+        Ok(QueryResult::ResidentCounts(counts)) => {
+            let response = LocationsResponse::from_counts(counts);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve resident counts")),
+    }
+}
+
+// each location's assigned residents against those present, biggest discrepancy first
+#[rustfmt::skip]
+#[get("/api/locations/reconcile")]
+pub async fn show_reconciliation(db: web::Data<Pool>, facility: Facility) -> Result<HttpResponse, ApiError> {
+    log::info!("GET: Locations controller headcount reconciliation");
+    match query(&db, Query::ReconcileHeadcount(facility)).await {
+        Ok(QueryResult::Headcounts(headcounts)) => {
+            let response = LocationsResponse::from_reconciliation(headcounts);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to reconcile headcounts")),
+    }
+}
+
+// how many residents are presently at a location and everywhere inside it, split by child
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/occupancy/rollup")]
+pub async fn show_occupancy_rollup(db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    log::info!("GET: Locations controller occupancy rollup for ID: {}", id);
+    match query(&db, Query::OccupancyRollup(id)).await {
+        Ok(QueryResult::Rollup(rollup)) => {
+            let response = LocationsResponse::from_rollup(rollup);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No location with id {}", id))),
+        res => Err(ApiError::from_query(res, "Unable to retrieve occupancy")),
+    }
+}
+
+// show the residents who are presently at a location
+#[rustfmt::skip]
+#[get("/api/locations/{location_id}/occupancy")]
+pub async fn show_current_occupancy(db: web::Data<Pool>, id: web::Path<Id>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner().location_id;
+    log::info!("GET: Locations controller occupancy for ID: {}", id);
+    match query(&db, Query::ShowCurrentOccupancy(id)).await {
+        Ok(QueryResult::Residents(res)) => {
+            let response = ResidentResponse::from_vec(res);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve occupancy")),
     }
 }
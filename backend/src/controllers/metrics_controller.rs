@@ -0,0 +1,16 @@
+use crate::metrics::render;
+use actix_web::{get, HttpResponse, Responder};
+
+/// GET: /metrics  Prometheus scrape endpoint
+#[get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    match render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(err) => {
+            log::error!("Error rendering metrics: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
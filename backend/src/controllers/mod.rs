@@ -3,3 +3,21 @@ pub mod residents_controller;
 pub mod locations_controller;
 
 pub mod timestamps_controller;
+
+pub mod health_controller;
+
+pub mod metrics_controller;
+
+pub mod negotiation;
+
+pub mod uploads;
+
+pub mod audit_controller;
+
+pub mod summary_controller;
+
+pub mod responses;
+
+pub mod auth_controller;
+
+pub mod reports_controller;
@@ -0,0 +1,109 @@
+use crate::errors::ApiError;
+use crate::models::csv::{to_csv, ToCsv};
+use crate::models::envelope::{Envelope, ENVELOPE_MIME};
+use crate::models::xlsx::{to_xlsx, ToXlsx, XLSX_MIME};
+use actix_web::http::header::{self, Quality, QualityItem};
+use actix_web::{mime, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde::Serialize;
+
+/// Response bodies an endpoint can produce, picked from the request's Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson,
+    /// JSON inside the v2 envelope, see models::envelope
+    Envelope,
+}
+
+impl Format {
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Envelope => ENVELOPE_MIME,
+        }
+    }
+
+    // compared on the whole essence so the envelope's `+json` suffix has to be asked for by name
+    fn matches(self, range: &mime::Mime) -> bool {
+        let type_ = self.mime().split_once('/').map_or("", |(type_, _)| type_);
+        (range.type_() == mime::STAR || range.type_() == type_)
+            && (range.subtype() == mime::STAR || range.essence_str() == self.mime())
+    }
+}
+
+/// The first of `supported` the client accepts, highest q-value first. A request without an
+/// Accept header gets the first format, one that accepts none of them is a 406.
+pub fn negotiate(req: &HttpRequest, supported: &[Format]) -> Result<Format, ApiError> {
+    let default = supported[0];
+    let accept = match req.get_header::<header::Accept>() {
+        Some(accept) if !accept.is_empty() => accept,
+        _ => return Ok(default),
+    };
+    // q=0 means the client refuses that type outright
+    let acceptable: Vec<QualityItem<mime::Mime>> = accept
+        .iter()
+        .filter(|item| item.quality > Quality::ZERO)
+        .cloned()
+        .collect();
+    header::Accept(acceptable)
+        .ranked()
+        .iter()
+        .find_map(|range| supported.iter().copied().find(|format| format.matches(range)))
+        .ok_or_else(|| {
+            let offered: Vec<&str> = supported.iter().map(|format| format.mime()).collect();
+            ApiError::NotAcceptable(format!("this endpoint responds with {}", offered.join(", ")))
+        })
+}
+
+/// Refuses an Accept header with a media range that doesn't parse. actix skips those when it
+/// reads the header, which would answer a garbled one as if it had asked for anything.
+pub fn check_accept(req: &HttpRequest) -> Result<(), ApiError> {
+    for value in req.headers().get_all(header::ACCEPT) {
+        let value = value
+            .to_str()
+            .map_err(|_| ApiError::Validation("Accept: must be ASCII".to_string()))?;
+        for range in value.split(',').map(str::trim).filter(|range| !range.is_empty()) {
+            range
+                .parse::<QualityItem<mime::Mime>>()
+                .map_err(|_| ApiError::Validation(format!("Accept: {:?} is not a media range", range)))?;
+        }
+    }
+    Ok(())
+}
+
+/// `rows` as a text/csv body on `builder`, which carries any status and headers already set
+pub fn csv_response<T: ToCsv>(mut builder: HttpResponseBuilder, rows: &[T]) -> HttpResponse {
+    builder
+        .content_type("text/csv; charset=utf-8")
+        .body(to_csv(rows))
+}
+
+/// `rows` as a one sheet .xlsx download named `filename` on `builder`, which carries any
+/// status and headers already set
+pub fn xlsx_response<T: ToXlsx>(mut builder: HttpResponseBuilder, filename: &str, rows: &[T]) -> Result<HttpResponse, ApiError> {
+    let workbook = to_xlsx("Export", rows).map_err(|err| {
+        log::error!("Unable to write {}: {}", filename, err);
+        ApiError::Db(format!("Unable to write {}", filename))
+    })?;
+    Ok(builder
+        .content_type(XLSX_MIME)
+        .insert_header(header::ContentDisposition::attachment(filename))
+        .body(workbook))
+}
+
+/// Whether the client opted in to the v2 envelope, which errors are wrapped in as well
+pub fn accepts_envelope(req: &HttpRequest) -> bool {
+    req.get_header::<header::Accept>().is_some_and(|accept| {
+        accept
+            .iter()
+            .any(|item| item.quality > Quality::ZERO && item.item.essence_str() == ENVELOPE_MIME)
+    })
+}
+
+/// `envelope` as the body on `builder`, which carries any status and headers already set
+pub fn envelope_response<T: Serialize>(mut builder: HttpResponseBuilder, envelope: &Envelope<T>) -> HttpResponse {
+    builder.content_type(ENVELOPE_MIME).json(envelope)
+}
@@ -0,0 +1,47 @@
+use crate::database::db::{query, stream_timestamps_range, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::middleware::facility::ensure_own;
+use crate::models::facilities::{Facility, Record};
+use crate::reports::{export_response, ExportParams};
+use actix_web::{get, web, HttpResponse};
+use futures_util::stream;
+
+/// GET: /api/reports/timestamps/export?start=&end=&location_id=&format=csv|xlsx
+/// The facility's scans from start to end as a download, optionally at one location. A CSV
+/// is streamed from the cursor as the client reads it and may cover any range, a workbook is
+/// held to MAX_RANGE_DAYS.
+#[rustfmt::skip]
+#[get("/api/reports/timestamps/export")]
+pub async fn export_timestamps(db: web::Data<Pool>, facility: Facility, params: web::Query<ExportParams>) -> Result<HttpResponse, ApiError> {
+    let format = params.format().map_err(ApiError::Validation)?;
+    let (start, end) = params.range(format).map_err(ApiError::Validation)?;
+    log::info!("GET: Reports controller timestamps export from {} to {}", start, end);
+    let name = match params.location_id {
+        Some(location) => {
+            // the middleware only reads {location_id} from the path
+            ensure_own(&db, facility, Record::Location(location)).await?;
+            format!("location-{}-timestamps-{}-to-{}", location, start, end)
+        }
+        None => format!("timestamps-{}-to-{}", start, end),
+    };
+    export_response(format, &name, stream_timestamps_range(&db, facility, start, end, params.location_id)).await
+}
+
+/// GET: /api/reports/counts/export?start=&end=&format=csv|xlsx
+/// Scans per location per local day from start to end as a download, a row for each
+/// location and day it was scanned at. There's a row per location-day at most, so the
+/// counts are read whole and only written out as a stream.
+#[rustfmt::skip]
+#[get("/api/reports/counts/export")]
+pub async fn export_counts(db: web::Data<Pool>, facility: Facility, params: web::Query<ExportParams>) -> Result<HttpResponse, ApiError> {
+    let format = params.format().map_err(ApiError::Validation)?;
+    let (start, end) = params.range(format).map_err(ApiError::Validation)?;
+    log::info!("GET: Reports controller counts export from {} to {}", start, end);
+    match query(&db, Query::DailyCountsByLocation(facility, &start, &end)).await {
+        Ok(QueryResult::LocationDaily(counts)) => {
+            let name = format!("scan-counts-{}-to-{}", start, end);
+            export_response(format, &name, stream::iter(counts.into_iter().map(Ok))).await
+        }
+        res => Err(ApiError::from_query(res, "Unable to count scans")),
+    }
+}
@@ -1,80 +1,274 @@
+use crate::controllers::locations_controller::{admit_scan, residents_response, ListOrder, Pagination, MAX_IMPORT_ROWS};
+use crate::controllers::negotiation::{negotiate, Format};
+use crate::controllers::uploads::uploaded_text;
+use crate::metrics::SCANS_STORED;
+use crate::middleware::audit::audit_before;
+use crate::middleware::auth::Claims;
+use crate::middleware::facility::ensure_own;
+use crate::models::csv::parse_csv;
+use crate::models::facilities::{Facility, Record};
 use crate::models::residents::{
-    ErrorType, PathParams, Resident, ResidentResponse, ResidentsError, Rfid,
+    residents_from_csv, ErrorType, PathParams, Resident, ResidentFilter, ResidentImportResult, ResidentResponse,
+    ResidentsError, RestrictionOutcome, RestrictionPayload, Rfid, StatusChange, TransferOutcome,
+    TransferPayload,
 };
-use crate::models::timestamps::TimestampResponse;
+use crate::controllers::responses::created;
+use crate::models::timestamps::{parse_range, RangeParams, TimestampResponse};
 use crate::{
     database::db::{query, Pool, Query, QueryResult},
+    errors::ApiError,
     models::residents::UpdateResident,
 };
 use actix_web::Responder;
+use chrono::Duration;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use actix_web::{
     delete, get,
     http::{header, StatusCode},
-    patch, post, web, HttpResponse,
+    patch, post, web, HttpRequest, HttpResponse,
 };
 
+/// Optional ?q= for the index, part of a name or the start of an rfid, and ?fuzzy=true to
+/// fall back to names spelled like it when nothing matches exactly
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResidentSearch {
+    pub q: Option<String>,
+    #[serde(default)]
+    pub fuzzy: bool,
+}
+
+impl ResidentSearch {
+    // a blank q is the same as leaving it out
+    pub fn term(&self) -> Option<&str> {
+        self.q.as_deref().map(str::trim).filter(|q| !q.is_empty())
+    }
+}
+
+// every resident, narrowed with ?doc=&unit=, ordered by name with ?order= and paged, or with
+// ?q= the best matches for a typeahead
+#[rustfmt::skip]
 #[get("/api/residents")]
-pub async fn index(db: web::Data<Pool>) -> impl Responder {
-    if let Ok(res) = query(&db, Query::IndexResidents).await {
-        match res {
-            QueryResult::Residents(residents) => {
-                let response = ResidentResponse::from_vec(residents);
-                Ok(actix_web::HttpResponse::Ok()
-                    .insert_header(header::ContentType::json())
-                    .json(response))
-            }
-            _ => Ok(HttpResponse::Ok()
+pub async fn index(req: HttpRequest, db: web::Data<Pool>, facility: Facility, search: web::Query<ResidentSearch>, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ResidentFilter>) -> Result<HttpResponse, ApiError> {
+    page.validate().map_err(ApiError::Validation)?;
+    let direction = order.direction().map_err(ApiError::Validation)?;
+    let format = negotiate(&req, &[Format::Json, Format::Envelope])?;
+    let index_query = match search.term() {
+        Some(_) if page.is_requested() || direction.is_some() || !filter.is_empty() => {
+            let msg = "q: search results are ranked by how well they match, leave out paging, order, doc and unit with q";
+            return Err(ApiError::Validation(msg.to_string()));
+        }
+        Some(term) => Query::SearchResidents(facility, term, search.fuzzy),
+        None => {
+            let paged = page.is_requested().then(|| (page.limit(), page.offset()));
+            Query::IndexResidents(facility, &filter, direction, paged)
+        }
+    };
+    residents_response(&req, format, &page, query(&db, index_query).await)
+}
+
+// every resident's latest scan and where it was, for the control room board
+#[get("/api/residents/last-seen")]
+pub async fn last_seen(db: web::Data<Pool>, facility: Facility) -> Result<HttpResponse, ApiError> {
+    match query(&db, Query::LastSeenPerResident(facility)).await {
+        Ok(QueryResult::LastSeen(last_seen)) => {
+            let response = ResidentResponse::from_last_seen(last_seen);
+            Ok(HttpResponse::Ok()
                 .insert_header(header::ContentType::json())
-                .json(ResidentResponse::from_error("Error retrieving residents"))),
+                .json(response))
         }
-    } else {
-        Err(ResidentsError::get(ErrorType::Database))
+        res => Err(ApiError::from_query(res, "Unable to retrieve last seen locations")),
+    }
+}
+
+/// Hours without a scan before a resident is reported missing when ?hours= is left out
+pub const DEFAULT_MISSING_HOURS: f64 = 4.0;
+
+#[derive(Debug, Deserialize)]
+pub struct MissingParams {
+    // taken as text so a bad value gets our 400 rather than the extractor's
+    hours: Option<String>,
+}
+
+impl MissingParams {
+    pub fn window(&self) -> Result<Duration, String> {
+        let hours = match self.hours.as_deref().map(str::trim) {
+            None | Some("") => return Ok(hours_to_duration(DEFAULT_MISSING_HOURS)),
+            Some(hours) => hours,
+        };
+        match hours.parse::<f64>() {
+            Ok(hours) if hours.is_finite() && hours > 0.0 && hours <= (24 * 366) as f64 => Ok(hours_to_duration(hours)),
+            _ => Err(format!("hours: '{}' must be a positive number of hours, at most a year", hours)),
+        }
+    }
+}
+
+fn hours_to_duration(hours: f64) -> Duration {
+    Duration::seconds((hours * 3600.0).round() as i64)
+}
+
+// residents who haven't scanned anywhere within ?hours= (default 4), never scanned first
+#[get("/api/residents/missing")]
+pub async fn missing(db: web::Data<Pool>, facility: Facility, params: web::Query<MissingParams>) -> Result<HttpResponse, ApiError> {
+    let window = params.window().map_err(ApiError::Validation)?;
+    match query(&db, Query::ResidentsNotSeenSince(facility, window)).await {
+        Ok(QueryResult::LastSeen(missing)) => {
+            let response = ResidentResponse::from_last_seen(missing);
+            Ok(HttpResponse::Ok()
+                .insert_header(header::ContentType::json())
+                .json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve missing residents")),
+    }
+}
+
+/// Residents the leaderboard lists when ?limit= is left out
+pub const DEFAULT_MOST_ACTIVE_LIMIT: usize = 20;
+
+/// Most residents one leaderboard may list
+pub const MAX_MOST_ACTIVE_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct MostActiveParams {
+    // taken as text so a bad value gets our 400 rather than the extractor's
+    limit: Option<String>,
+}
+
+impl MostActiveParams {
+    pub fn limit(&self) -> Result<usize, String> {
+        match self.limit.as_deref().map(str::trim) {
+            None | Some("") => Ok(DEFAULT_MOST_ACTIVE_LIMIT),
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(n) if (1..=MAX_MOST_ACTIVE_LIMIT).contains(&n) => Ok(n),
+                _ => Err(format!("limit: '{}' must be a whole number from 1 to {}", limit, MAX_MOST_ACTIVE_LIMIT)),
+            },
+        }
+    }
+}
+
+// the residents scanned the most from /start to /end, most first, for behavioral review
+#[rustfmt::skip]
+#[get("/api/residents/most-active/{start_date}/{end_date}")]
+pub async fn most_active(db: web::Data<Pool>, facility: Facility, range: web::Path<RangeParams>, params: web::Query<MostActiveParams>) -> Result<HttpResponse, ApiError> {
+    let (start, end) = parse_range(&range.start_date, &range.end_date).map_err(ApiError::Validation)?;
+    let limit = params.limit().map_err(ApiError::Validation)?;
+    log::info!("GET: Residents controller {} most active from {} to {}", limit, start, end);
+    match query(&db, Query::MostActiveResidents(facility, &start, &end, limit)).await {
+        Ok(QueryResult::MostActive(residents)) => {
+            let response = ResidentResponse::from_most_active(residents);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve the most active residents")),
     }
 }
 
 #[rustfmt::skip]
 #[get("/api/residents/{rfid}")]
-pub async fn show(db: web::Data<Pool>, rfid: actix_web::web::Path<Rfid>) -> impl Responder {
-    if let Ok(res) = query(&db, Query::ShowResident(&rfid.into_inner().rfid)).await {
-        match res {
-            QueryResult::Resident(resident) => {
-                let response = ResidentResponse::from_resident(resident);
-                Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
-            }
-            _ => {
-                let error = ResidentResponse::from_error("Error retrieving resident");
-                Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(error))
-            }
+pub async fn show(db: web::Data<Pool>, rfid: actix_web::web::Path<Rfid>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    match query(&db, Query::ShowResident(&rfid)).await {
+        Ok(QueryResult::Resident(resident)) => {
+            let response = ResidentResponse::from_resident(resident);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
         }
-        } else {
-            Err(ResidentsError::get(ErrorType::Database))
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        res => Err(ApiError::from_query(res, "Unable to retrieve resident")),
     }
 }
 
 #[rustfmt::skip]
 #[post("/api/residents")]
-pub async fn store(db: web::Data<Pool>, resident: web::Json<Resident>) -> impl Responder {
-    if let Ok(res) = query(&db, Query::StoreResident(&resident.into_inner())).await {
-        match res {
-            QueryResult::Success => {
-                let response = ResidentResponse::from_success("Resident successfully added");
-                HttpResponse::Ok().status(StatusCode::CREATED).insert_header(header::ContentType::json()).json(response)
+pub async fn store(db: web::Data<Pool>, facility: Facility, resident: web::Json<Resident>) -> Result<HttpResponse, ApiError> {
+    let resident = Resident { facility_id: facility, ..resident.into_inner() };
+    resident.validate().map_err(ApiError::Validation)?;
+    ensure_own_locations(&db, facility, &resident).await?;
+    match query(&db, Query::StoreResident(&resident)).await {
+        Ok(QueryResult::Success) => {
+            let location = format!("/api/residents/{}", resident.rfid);
+            Ok(created(&location, &ResidentResponse::from_created(resident)))
+        }
+        Ok(QueryResult::Conflict(field)) => {
+            let value = if field == "doc" { &resident.doc } else { &resident.rfid };
+            Err(ApiError::Conflict(format!("A resident with {} {} already exists", field, value)))
+        }
+        res => Err(ApiError::from_query(res, "Unable to add resident")),
+    }
+}
+
+// create residents in bulk from a CSV file, reporting each row as created, a duplicate of a
+// stored resident or invalid, so the same file can be uploaded again without doubling anyone
+#[rustfmt::skip]
+#[post("/api/residents/import")]
+pub async fn import(req: HttpRequest, db: web::Data<Pool>, facility: Facility, body: web::Bytes) -> Result<HttpResponse, ApiError> {
+    log::info!("POST: residents controller import");
+    let records = parse_csv(uploaded_text(&req, &body)?).map_err(ApiError::Validation)?;
+    let rows = residents_from_csv(&records).map_err(ApiError::Validation)?;
+    if rows.len() > MAX_IMPORT_ROWS {
+        return Err(ApiError::Validation(format!("an import may hold at most {} rows", MAX_IMPORT_ROWS)));
+    }
+    let mut results = Vec::with_capacity(rows.len());
+    let mut valid = Vec::new();
+    for (row, parsed) in rows {
+        let parsed = parsed.map(|resident| Resident { facility_id: facility, ..resident });
+        match parsed.and_then(|resident| resident.validate().map(|()| resident)) {
+            Ok(resident) => valid.push((row, resident)),
+            Err(msg) => results.push(ResidentImportResult::invalid(row, msg)),
+        }
+    }
+    // each location is checked once however many rows name it, a row housed in another
+    // facility's location is invalid rather than failing the whole import
+    let mut refused = BTreeMap::new();
+    for id in valid.iter().flat_map(|(_, resident)| [resident.unit, resident.current_location]) {
+        if refused.contains_key(&id) {
+            continue;
+        }
+        match ensure_own(&db, facility, Record::Location(id)).await {
+            Ok(()) => refused.insert(id, None),
+            Err(ApiError::Forbidden(msg)) => refused.insert(id, Some(msg)),
+            Err(err) => return Err(err),
+        };
+    }
+    let (valid, foreign): (Vec<_>, Vec<_>) = valid.into_iter().partition(|(_, resident)| {
+        refused[&resident.unit].is_none() && refused[&resident.current_location].is_none()
+    });
+    for (row, resident) in foreign {
+        let msg = refused[&resident.unit].clone().or_else(|| refused[&resident.current_location].clone()).unwrap_or_default();
+        results.push(ResidentImportResult::invalid(row, msg));
+    }
+    let batch = valid.iter().map(|(_, resident)| resident.clone()).collect::<Vec<Resident>>();
+    match query(&db, Query::StoreResidentsBatch(&batch)).await {
+        Ok(QueryResult::StoredResidents(stored)) => {
+            for ((row, resident), stored) in valid.into_iter().zip(stored) {
+                results.push(match stored {
+                    Ok(()) => ResidentImportResult::created(row, resident),
+                    Err(field) => {
+                        let value = if field == "doc" { &resident.doc } else { &resident.rfid };
+                        ResidentImportResult::duplicate(row, format!("A resident with {} {} already exists", field, value))
+                    }
+                });
             }
-            _ => {
-                let err = ResidentResponse::from_error("Error creating resident");
-                HttpResponse::Ok().insert_header(header::ContentType::json()).json(err)
-                }
+            results.sort_by_key(|res| res.row);
+            let response = ResidentResponse::from_import(results);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
         }
-    } else {
-        let resp = ResidentResponse::from_error("Error creating resident");
-         HttpResponse::from_error(resp)
+        res => Err(ApiError::from_query(res, "Unable to import residents, nothing was stored")),
     }
 }
 
+// a resident can only be housed in, or be at, a location of their own facility
+async fn ensure_own_locations(db: &Pool, facility: Facility, resident: &Resident) -> Result<(), ApiError> {
+    ensure_own(db, facility, Record::Location(resident.unit)).await?;
+    ensure_own(db, facility, Record::Location(resident.current_location)).await
+}
+
 #[rustfmt::skip]
 #[delete("/api/residents/{rfid}")]
 pub async fn destroy(db: web::Data<Pool>, rfid: web::Path<String>,) -> impl Responder {
-    if let Ok(res) = query(&db, Query::DestroyResident(rfid.into_inner())).await {
+    let res = query(&db, Query::DestroyResident(rfid.into_inner())).await;
+    if let Some(err) = ApiError::unavailable(&res) {
+        return HttpResponse::from_error(err);
+    }
+    if let Ok(res) = res {
         match res {
             QueryResult::Success => {
                 let response = ResidentResponse::from_success("Resident successfully deleted");
@@ -93,32 +287,143 @@ pub async fn destroy(db: web::Data<Pool>, rfid: web::Path<String>,) -> impl Resp
 
 #[rustfmt::skip]
 #[patch("/api/residents/{rfid}")]
-pub async fn update(db: web::Data<Pool>, rfid: actix_web::web::Path<Rfid>, resident: web::Json<UpdateResident>) -> impl Responder {
-    match query(&db, Query::ShowResident(&rfid.into_inner().rfid)).await {
+pub async fn update(req: HttpRequest, db: web::Data<Pool>, facility: Facility, rfid: actix_web::web::Path<Rfid>, resident: web::Json<UpdateResident>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    // We have to get the full resident from DB before we can update it
+    // so we can accept a JSON with only the fields they wish to update
+    match query(&db, Query::ShowResident(&rfid)).await {
         Ok(QueryResult::Resident(res)) => {
             log::info!("fetched resident for updating: {:?}", res);
-            let updated = resident.into_inner().apply_to(res.clone());
-            // We have to get the full resident from DB before we can update it
-            // so we can accept a JSON with only the fields they wish to update
+            let before = res.clone();
+            let updated = resident.into_inner().apply_to(res);
+            // the merged resident is held to the same rules as a new one
+            updated.validate().map_err(ApiError::Validation)?;
+            ensure_own_locations(&db, facility, &updated).await?;
             match query(&db, Query::UpdateResident(&updated)).await {
                 Ok(QueryResult::Success) => {
+                    audit_before(&req, &before);
                     let updated = ResidentResponse::from_resident(updated);
                     Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(updated))
                 }
-                _ => {
-                    let error = ResidentResponse::from_error("Error updating resident");
-                    Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(error))
-                },
+                Ok(QueryResult::Conflict(_)) => Err(ApiError::Conflict(format!("A resident with doc {} already exists", updated.doc))),
+                res => Err(ApiError::from_query(res, "Unable to update resident")),
             }
         }
-        _ => Err(ResidentsError::get(ErrorType::Database)),
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        res => Err(ApiError::from_query(res, "Unable to update resident")),
+    }
+}
+
+// move a resident to another location, recording them leaving and arriving together
+#[rustfmt::skip]
+#[post("/api/residents/{rfid}/transfer")]
+pub async fn transfer(db: web::Data<Pool>, facility: Facility, rfid: web::Path<Rfid>, body: web::Json<TransferPayload>, claims: Option<web::ReqData<Claims>>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    let to = body.to_location_id;
+    log::info!("POST: transferring resident {} to location {}", rfid, to);
+    // the in-scan is held to the same lockdown as any other scan
+    admit_scan(&db, facility, to, claims.as_deref()).await?;
+    match query(&db, Query::TransferResident(&rfid, to)).await {
+        Ok(QueryResult::Transfer(TransferOutcome::Moved(transfer))) => {
+            SCANS_STORED.with_label_values(&["transfer"]).inc_by(2);
+            let response = ResidentResponse::from_transfer(transfer);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::Transfer(TransferOutcome::NoResident)) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        Ok(QueryResult::Transfer(TransferOutcome::NoLocation)) => Err(ApiError::NotFound(format!("No location with id {}", to))),
+        Ok(QueryResult::Transfer(TransferOutcome::LocationUnknown)) => Err(ApiError::Conflict(format!(
+            "Resident {} is away, their current location is unknown so there is nothing to transfer from", rfid
+        ))),
+        Ok(QueryResult::Transfer(TransferOutcome::AlreadyThere)) => Err(ApiError::Conflict(format!("Resident {} is already at location {}", rfid, to))),
+        res => Err(ApiError::from_query(res, "Unable to transfer resident")),
+    }
+}
+
+// the locations a resident is barred from, a scan of theirs at one is flagged and alerted on
+#[rustfmt::skip]
+#[get("/api/residents/{rfid}/restrictions")]
+pub async fn show_restrictions(db: web::Data<Pool>, rfid: web::Path<Rfid>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    match query(&db, Query::ShowRestrictions(&rfid)).await {
+        Ok(QueryResult::Restrictions(restrictions)) => {
+            let response = ResidentResponse::from_restrictions(restrictions, "Restrictions successfully retrieved");
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        res => Err(ApiError::from_query(res, "Unable to retrieve restrictions")),
+    }
+}
+
+// bar a resident from a location, answered with all of their restrictions
+#[rustfmt::skip]
+#[post("/api/residents/{rfid}/restrictions")]
+pub async fn store_restriction(db: web::Data<Pool>, facility: Facility, rfid: web::Path<Rfid>, body: web::Json<RestrictionPayload>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    let location = body.location_id;
+    log::info!("POST: restricting resident {} from location {}", rfid, location);
+    if location == 0 {
+        return Err(ApiError::Validation("location_id: SIGNED_OUT can't be restricted".to_string()));
+    }
+    ensure_own(&db, facility, Record::Location(location)).await?;
+    match query(&db, Query::StoreRestriction(&rfid, location)).await {
+        Ok(QueryResult::Restriction(RestrictionOutcome::Added(restrictions))) => {
+            let response = ResidentResponse::from_restrictions(restrictions, "Restriction successfully added");
+            Ok(created(&format!("/api/residents/{}/restrictions", rfid), &response))
+        }
+        Ok(QueryResult::Restriction(RestrictionOutcome::AlreadyRestricted)) => {
+            Err(ApiError::Conflict(format!("Resident {} is already restricted from location {}", rfid, location)))
+        }
+        Ok(QueryResult::Restriction(RestrictionOutcome::NoResident)) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        Ok(QueryResult::Restriction(RestrictionOutcome::NoLocation)) => Err(ApiError::NotFound(format!("No location with id {}", location))),
+        res => Err(ApiError::from_query(res, "Unable to add restriction")),
+    }
+}
+
+// lift a resident's restriction from a location, answered with the ones left
+#[rustfmt::skip]
+#[delete("/api/residents/{rfid}/restrictions")]
+pub async fn destroy_restriction(db: web::Data<Pool>, rfid: web::Path<Rfid>, body: web::Json<RestrictionPayload>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    let location = body.location_id;
+    log::info!("DELETE: lifting restriction of resident {} from location {}", rfid, location);
+    match query(&db, Query::DeleteRestriction(&rfid, location)).await {
+        Ok(QueryResult::Restrictions(restrictions)) => {
+            let response = ResidentResponse::from_restrictions(restrictions, "Restriction successfully lifted");
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => {
+            Err(ApiError::NotFound(format!("Resident {} has no restriction from location {}", rfid, location)))
+        }
+        res => Err(ApiError::from_query(res, "Unable to lift restriction")),
+    }
+}
+
+// release a resident or transfer them out of the facility, or reactivate one who left
+#[rustfmt::skip]
+#[post("/api/residents/{rfid}/status")]
+pub async fn update_status(db: web::Data<Pool>, rfid: web::Path<Rfid>, body: web::Json<StatusChange>) -> Result<HttpResponse, ApiError> {
+    let rfid = rfid.into_inner().rfid;
+    let change = body.into_inner();
+    log::info!("POST: setting resident {} to {}", rfid, change.status.as_str());
+    match query(&db, Query::SetResidentStatus(&rfid, &change)).await {
+        Ok(QueryResult::Resident(resident)) => {
+            let response = ResidentResponse::from_status(resident);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound(format!("No resident with rfid {}", rfid))),
+        Ok(QueryResult::Conflict(msg)) => Err(ApiError::Conflict(msg)),
+        res => Err(ApiError::from_query(res, "Unable to set the resident's status")),
     }
 }
 
 #[rustfmt::skip]
 #[get("/api/residents/{rfid}/timestamps")]
 pub async fn show_resident_timestamps(db: web::Data<Pool>, rfid: actix_web::web::Path<Rfid>) -> impl Responder {
-    if let Ok(ts) = query(&db, Query::ShowResidentTimestamps(rfid.rfid.clone())).await {
+    let res = query(&db, Query::ShowResidentTimestamps(rfid.rfid.clone())).await;
+    if let Some(err) = ApiError::unavailable(&res) {
+        return Ok(HttpResponse::from_error(err));
+    }
+    if let Ok(ts) = res {
         match ts {
             QueryResult::TimeStamps(ts) => {
                 let response = TimestampResponse::from_db(ts);
@@ -139,10 +444,19 @@ pub async fn show_resident_timestamps(db: web::Data<Pool>, rfid: actix_web::web:
 pub async fn show_resident_timestamps_range(db: web::Data<Pool>, rfid: actix_web::web::Path<PathParams>) -> impl Responder {
     let id = rfid.into_inner();
     let rfid = id.rfid;
-    let start = id.start_date;
-    let end = id.end_date;
+    let (start, end) = match parse_range(&id.start_date, &id.end_date) {
+        Ok(range) => range,
+        Err(msg) => {
+            let error = ResidentResponse::from_error(&msg);
+            return Ok(HttpResponse::BadRequest().insert_header(header::ContentType::json()).json(error));
+        }
+    };
 
-    if let Ok(ts) = query(&db, Query::ShowResidentTimestampsRange(&rfid, &start, &end)).await {
+    let res = query(&db, Query::ShowResidentTimestampsRange(&rfid, &start, &end)).await;
+    if let Some(err) = ApiError::unavailable(&res) {
+        return Ok(HttpResponse::from_error(err));
+    }
+    if let Ok(ts) = res {
         match ts {
             QueryResult::TimeStamps(ts) => {
                 let response = TimestampResponse::from_db(ts);
@@ -157,3 +471,41 @@ pub async fn show_resident_timestamps_range(db: web::Data<Pool>, rfid: actix_web
         Err(ResidentsError::get(ErrorType::Database))
     }
 }
+
+// the locations a resident scanned at during the range, each once with their first and last scan there
+#[rustfmt::skip]
+#[get("/api/residents/{rfid}/locations/{start_date}/{end_date}")]
+pub async fn show_resident_locations(db: web::Data<Pool>, params: web::Path<PathParams>) -> Result<HttpResponse, ApiError> {
+    let params = params.into_inner();
+    let (start, end) = parse_range(&params.start_date, &params.end_date).map_err(ApiError::Validation)?;
+    match query(&db, Query::ResidentDistinctLocations(&params.rfid, &start, &end)).await {
+        Ok(QueryResult::Visited(visited)) => {
+            let response = TimestampResponse::from_visited(visited);
+            Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to retrieve visited locations")),
+    }
+}
+
+#[rustfmt::skip]
+#[get("/api/residents/{rfid}/movements/{start_date}/{end_date}")]
+pub async fn show_resident_movements(db: web::Data<Pool>, params: web::Path<PathParams>) -> impl Responder {
+    let params = params.into_inner();
+    let (start, end) = match parse_range(&params.start_date, &params.end_date) {
+        Ok(range) => range,
+        Err(msg) => {
+            let error = ResidentResponse::from_error(&msg);
+            return Ok(HttpResponse::BadRequest().insert_header(header::ContentType::json()).json(error));
+        }
+    };
+    let res = query(&db, Query::ShowResidentMovements(&params.rfid, &start, &end)).await;
+    if let Some(err) = ApiError::unavailable(&res) {
+        return Ok(HttpResponse::from_error(err));
+    }
+    if let Ok(QueryResult::Movements(movements)) = res {
+        let response = TimestampResponse::from_movements(movements);
+        Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(response))
+    } else {
+        Err(ResidentsError::get(ErrorType::Database))
+    }
+}
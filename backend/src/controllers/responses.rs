@@ -0,0 +1,12 @@
+use actix_web::http::header;
+use actix_web::HttpResponse;
+use serde::Serialize;
+
+/// A 201 Created for a resource that now lives at `resource_url`, its canonical path, with the
+/// stored entity as the body. Every POST that creates a single record responds through here.
+pub fn created(resource_url: &str, body: &impl Serialize) -> HttpResponse {
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, resource_url))
+        .insert_header(header::ContentType::json())
+        .json(body)
+}
@@ -0,0 +1,73 @@
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::models::facilities::Facility;
+use crate::models::timestamps::{facility_today, SummaryPart, TodaySummary, TIMESTAMP_FORMAT};
+use actix_web::http::header;
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+
+// today's scans across the facility's locations in one object for the briefing dashboard,
+// "today" being the local day in FACILITY_TZ. Each part is its own aggregate
+// query, all four run at once on their own connections.
+#[rustfmt::skip]
+#[get("/api/summary/today")]
+pub async fn today(db: web::Data<Pool>, facility: Facility) -> Result<HttpResponse, ApiError> {
+    log::info!("GET: summary controller today");
+    let date = facility_today();
+    let part = |part| query(&db, Query::TodaySummary(facility, &date, part));
+    let (total, busiest, moved, over) = futures_util::join!(
+        part(SummaryPart::TotalScans),
+        part(SummaryPart::BusiestLocation),
+        part(SummaryPart::ResidentsMoved),
+        part(SummaryPart::OverCapacityEvents),
+    );
+    let count = |res| match res {
+        Ok(QueryResult::Count(count)) => Ok(count),
+        res => Err(ApiError::from_query(res, "Unable to summarize today's scans")),
+    };
+    let busiest_location = match busiest {
+        Ok(QueryResult::Busiest(busiest)) => busiest,
+        res => return Err(ApiError::from_query(res, "Unable to summarize today's scans")),
+    };
+    let summary = TodaySummary {
+        date: date.format("%Y-%m-%d").to_string(),
+        total_scans: count(total)?,
+        busiest_location,
+        residents_moved: count(moved)?,
+        over_capacity_events: count(over)?,
+    };
+    Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(summary))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SnapshotParams {
+    at: Option<String>,
+}
+
+impl SnapshotParams {
+    /// The instant as a stored scan time, now when `at` is left out or blank. Takes the
+    /// YYYY-MM-DD HH:MM:SS UTC a scan is posted with or RFC 3339 as the API returns times.
+    pub fn at(&self) -> Result<String, String> {
+        let at = match self.at.as_deref().map(str::trim) {
+            None | Some("") => return Ok(Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string()),
+            Some(at) => at,
+        };
+        NaiveDateTime::parse_from_str(at, TIMESTAMP_FORMAT)
+            .or_else(|_| DateTime::parse_from_rfc3339(at).map(|time| time.naive_utc()))
+            .map(|time| time.format(TIMESTAMP_FORMAT).to_string())
+            .map_err(|_| format!("at: invalid time '{}', expected YYYY-MM-DD HH:MM:SS or RFC 3339", at))
+    }
+}
+
+// every occupied location and who was in it as of ?at= (default now), for shift handover
+#[rustfmt::skip]
+#[get("/api/snapshot")]
+pub async fn snapshot(db: web::Data<Pool>, facility: Facility, params: web::Query<SnapshotParams>) -> Result<HttpResponse, ApiError> {
+    let at = params.at().map_err(ApiError::Validation)?;
+    log::info!("GET: summary controller snapshot at {}", at);
+    match query(&db, Query::OccupancySnapshot(facility, &at)).await {
+        Ok(QueryResult::Snapshot(snapshot)) => Ok(HttpResponse::Ok().insert_header(header::ContentType::json()).json(snapshot)),
+        res => Err(ApiError::from_query(res, "Unable to take the occupancy snapshot")),
+    }
+}
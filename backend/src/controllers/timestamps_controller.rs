@@ -1,59 +1,305 @@
 use crate::{
-    database::db::{query, Pool, Query, QueryResult},
-    models::timestamps::{PostTimestamp, RangeParams, TimestampResponse},
+    controllers::locations_controller::{admit_scan, ListOrder, Pagination},
+    controllers::negotiation::{csv_response, envelope_response, negotiate, Format},
+    controllers::responses::created,
+    database::db::{query, stream_timestamps_range, Pool, Query, QueryResult},
+    errors::ApiError,
+    metrics::SCANS_STORED,
+    middleware::{audit::{audit_entry, AuditAfter, AuditWritten}, auth::Claims, facility::ensure_own},
+    models::facilities::{Facility, Record},
+    reports::{csv_body, ExportFormat, ExportParams},
+    live::LiveScans, webhooks,
+    models::timestamps::{day_bounds, facility_today, parse_range, BackfillScan, BatchScanResult, PostTimestamp, PurgeResult, RangeParams, ScanFilter, TimestampCorrection, TimestampResponse, TimestampVoid},
 };
 use actix_web::{
-    get,
-    http::{header::ContentType, StatusCode},
-    post, web, HttpResponse, Responder,
+    delete, get,
+    http::{header::{self, ContentType}, StatusCode},
+    patch, post, web, HttpMessage, HttpRequest, HttpResponse, Responder,
 };
+use chrono::{NaiveDate, Utc};
+use serde::Deserialize;
 
 /// GET: /api/timestamps  DEFAULT: Today
+/// Narrowed with ?rfid=&doc=, ordered by time with ?order= and paged like a location's
+#[rustfmt::skip]
 #[get("/api/timestamps")]
-pub async fn index_timestamps(db: web::Data<Pool>) -> impl Responder {
-    if let Ok(QueryResult::TimeStamps(ts)) = query(&db, Query::IndexTimestamps).await {
-        let response = TimestampResponse::from_db(ts);
-        HttpResponse::Ok()
-            .content_type(ContentType::json())
-            .json(response)
-    } else {
-        let error = TimestampResponse::from_error("Error Retrieving timestamps");
-        HttpResponse::Ok().json(error)
+pub async fn index_timestamps(req: HttpRequest, db: web::Data<Pool>, facility: Facility, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ScanFilter>) -> impl Responder {
+    let format = match negotiate(&req, &[Format::Json, Format::Envelope, Format::Csv]) {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::from_error(err),
+    };
+    let direction = match page.validate().and_then(|_| order.direction()) {
+        Ok(direction) => direction,
+        Err(msg) => return HttpResponse::from_error(ApiError::Validation(msg)),
+    };
+    let paged = page.is_requested().then(|| (page.limit(), page.offset()));
+    let res = query(&db, Query::IndexTimestamps(facility, &filter, direction, paged)).await;
+    let (mut builder, ts, total) = match res {
+        Ok(QueryResult::TimeStamps(ts)) => (HttpResponse::Ok(), ts, None),
+        Ok(QueryResult::TimeStampsPage(ts, total)) => (page.response(&req, total), ts, Some(total)),
+        res => return HttpResponse::from_error(ApiError::from_query(res, "Unable to retrieve timestamps")),
+    };
+    match format {
+        Format::Csv => return csv_response(builder, &ts),
+        Format::Envelope => return envelope_response(builder, &page.envelope(ts, total)),
+        _ => {}
     }
+    let response = TimestampResponse::from_db(ts);
+    builder.content_type(ContentType::json()).json(response)
 }
 
 /// POST: /api/timestamps/{timestamp}
 #[rustfmt::skip]
 #[post("/api/timestamps")]
-pub async fn store_timestamp(db: web::Data<Pool>, ts: web::Json<PostTimestamp>) -> impl Responder {
-    let ts = ts.into_inner();
+pub async fn store_timestamp(db: web::Data<Pool>, live: web::Data<LiveScans>, facility: Facility, ts: web::Json<PostTimestamp>, claims: Option<web::ReqData<Claims>>) -> impl Responder {
+    let mut ts = ts.into_inner();
     log::info!("Storing timestamp: {:?}", ts);
-    if let Ok(QueryResult::PostTimestamp(timestamp)) = query(&db, Query::StoreTimestamp(&ts)).await
-    {
+    if let Err(msg) = ts.validate() {
+        return HttpResponse::from_error(ApiError::Validation(msg));
+    }
+    if let Err(err) = admit_scan(&db, facility, ts.location, claims.as_deref()).await {
+        return HttpResponse::from_error(err);
+    }
+    let res = query(&db, Query::StoreTimestamp(&ts)).await;
+    if let Some(err) = ApiError::unavailable(&res) {
+        return HttpResponse::from_error(err);
+    }
+    if let Ok(QueryResult::PostTimestamp(timestamp)) = res {
+        if !timestamp.duplicate {
+            SCANS_STORED.with_label_values(&["single"]).inc();
+            ts.restricted = timestamp.restricted;
+            webhooks::notify_scan(&ts);
+            live.publish(&ts);
+        }
         let res = TimestampResponse::from_ts(&timestamp);
-        HttpResponse::Ok()
-            .status(StatusCode::CREATED)
-            .insert_header(ContentType::json())
-            .json(res)
+        match timestamp.id {
+            Some(id) if !timestamp.duplicate => created(&format!("/api/timestamps/{}", id), &res),
+            _ => HttpResponse::Ok().insert_header(ContentType::json()).json(res),
+        }
     } else {
         let error = TimestampResponse::from_error("Error storing timestamp");
         HttpResponse::from_error(error)
     }
 }
 
+/// Most scans one backfill request may hold, a larger import is sent in several
+pub const MAX_BACKFILL_SIZE: usize = 5000;
+
+/// POST: /api/timestamps/backfill
+/// Imports historical scans from an old system at the times they were made, see
+/// db::backfill_timestamps. Nothing is pushed to the webhook or the live sockets, they're
+/// history rather than something happening now.
+#[rustfmt::skip]
+#[post("/api/timestamps/backfill")]
+pub async fn backfill_timestamps(db: web::Data<Pool>, facility: Facility, scans: web::Json<Vec<BackfillScan>>) -> Result<HttpResponse, ApiError> {
+    let scans = scans.into_inner();
+    log::info!("POST: Timestamps controller backfill of {} scans", scans.len());
+    if scans.len() > MAX_BACKFILL_SIZE {
+        return Err(ApiError::Validation(format!("a backfill may hold at most {} scans", MAX_BACKFILL_SIZE)));
+    }
+    let now = Utc::now().naive_utc();
+    let mut results = Vec::with_capacity(scans.len());
+    let mut valid = Vec::new();
+    for (pos, scan) in scans.into_iter().enumerate() {
+        match scan.validate(now) {
+            Ok(()) => valid.push((pos, PostTimestamp::with_time(scan.rfid, scan.location, Some(scan.time)))),
+            Err(msg) => results.push(BatchScanResult::rejected(pos, scan.rfid, msg)),
+        }
+    }
+    let batch = valid.iter().map(|(_, ts)| ts.clone()).collect::<Vec<PostTimestamp>>();
+    match query(&db, Query::BackfillTimestamps(facility, &batch)).await {
+        Ok(QueryResult::Backfilled(stored)) => {
+            for ((pos, ts), stored) in valid.into_iter().zip(stored) {
+                results.push(match stored {
+                    Ok(timestamp) => BatchScanResult::accepted(pos, timestamp),
+                    Err(msg) => BatchScanResult::rejected(pos, ts.rfid, msg),
+                });
+            }
+            let stored = results.iter().filter(|res| res.accepted).count();
+            SCANS_STORED.with_label_values(&["backfill"]).inc_by(stored as u64);
+            results.sort_by_key(|res| res.index);
+            let response = TimestampResponse::from_batch(results);
+            Ok(HttpResponse::Ok().insert_header(ContentType::json()).json(response))
+        }
+        res => Err(ApiError::from_query(res, "Unable to backfill timestamps, rolled back")),
+    }
+}
+
+/// PATCH: /api/timestamps/{id}
+/// Corrects the time or location a scan was recorded with, the scan as it was is kept in the
+/// audit log's `before` and the required reason in its `after`. The entry is written in the
+/// correction's transaction, a correction that can't be recorded isn't made.
+#[rustfmt::skip]
+#[patch("/api/timestamps/{timestamp_id}")]
+pub async fn update_timestamp(req: HttpRequest, db: web::Data<Pool>, facility: Facility, id: web::Path<usize>, correction: web::Json<TimestampCorrection>) -> Result<HttpResponse, ApiError> {
+    let correction = correction.into_inner();
+    correction.validate().map_err(ApiError::Validation)?;
+    if let Some(location) = correction.location {
+        ensure_own(&db, facility, Record::Location(location)).await?;
+    }
+    let audit = audit_entry(&req, StatusCode::OK, serde_json::to_value(&correction).ok());
+    match query(&db, Query::UpdateTimestamp(id.into_inner(), &correction, &audit)).await {
+        Ok(QueryResult::Corrected(corrected)) => {
+            req.extensions_mut().insert(AuditWritten);
+            Ok(HttpResponse::Ok().json(TimestampResponse::from_correction(corrected)))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound("Timestamp not found".to_string())),
+        Ok(QueryResult::Invalid(msg)) => Err(ApiError::Validation(msg)),
+        res => Err(ApiError::from_query(res, "Unable to correct timestamp")),
+    }
+}
+
+/// Scans one batch of a purge deletes, each its own transaction
+pub const PURGE_BATCH_SIZE: usize = 5000;
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeParams {
+    before: Option<String>,
+}
+
+impl PurgeParams {
+    /// The local day to delete the scans before. Today at the latest, a purge never reaches
+    /// the scans of the day still going on.
+    pub fn before(&self) -> Result<NaiveDate, String> {
+        let before = self.before.as_deref().map(str::trim).unwrap_or_default();
+        if before.is_empty() {
+            return Err("before: is required, the date to delete the scans before as YYYY-MM-DD".to_string());
+        }
+        let date = NaiveDate::parse_from_str(before, "%Y-%m-%d")
+            .map_err(|_| format!("before: invalid date '{}', expected YYYY-MM-DD", before))?;
+        if date > facility_today() {
+            return Err(format!("before: {} is after today", date));
+        }
+        Ok(date)
+    }
+}
+
+/// DELETE: /api/timestamps?before=
+/// Deletes the facility's scans from before the local day for the retention policy and says
+/// how many went. It's done PURGE_BATCH_SIZE at a time, each batch its own query, so neither
+/// the write lock nor QUERY_TIMEOUT_MS is held across millions of rows. The date and count
+/// are the audit entry's `after`.
+#[rustfmt::skip]
+#[delete("/api/timestamps")]
+pub async fn purge_timestamps(req: HttpRequest, db: web::Data<Pool>, facility: Facility, params: web::Query<PurgeParams>) -> Result<HttpResponse, ApiError> {
+    let before = params.before().map_err(ApiError::Validation)?;
+    let (cutoff, _) = day_bounds(before, before);
+    log::warn!("DELETE: Timestamps controller purging facility {:?} scans before {}", facility, cutoff);
+    let mut deleted = 0;
+    loop {
+        match query(&db, Query::DeleteTimestampsBefore(facility, &cutoff, PURGE_BATCH_SIZE)).await {
+            Ok(QueryResult::Count(count)) => {
+                deleted += count;
+                if count < PURGE_BATCH_SIZE {
+                    break;
+                }
+            }
+            // the batches already deleted stay deleted, say how far it got
+            res => {
+                log::error!("Purge of scans before {} stopped after {} were deleted", cutoff, deleted);
+                return Err(ApiError::from_query(res, &format!("Unable to finish the purge, {} scans were deleted", deleted)));
+            }
+        }
+    }
+    log::info!("Purged {} scans before {}", deleted, cutoff);
+    let result = PurgeResult { before: before.format("%Y-%m-%d").to_string(), deleted };
+    if let Ok(after) = serde_json::to_value(&result) {
+        req.extensions_mut().insert(AuditAfter(after));
+    }
+    Ok(HttpResponse::Ok().insert_header(ContentType::json()).json(result))
+}
+
+/// GET: /api/timestamps/{id}
+/// A single scan, where a stored scan's Location header points
+#[rustfmt::skip]
+#[get("/api/timestamps/{timestamp_id}")]
+pub async fn show_timestamp(db: web::Data<Pool>, id: web::Path<usize>) -> Result<HttpResponse, ApiError> {
+    match query(&db, Query::ShowTimestamp(id.into_inner())).await {
+        Ok(QueryResult::TimeStamp(ts)) => {
+            Ok(HttpResponse::Ok().insert_header(ContentType::json()).json(TimestampResponse::from_timestamp(ts)))
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound("Timestamp not found".to_string())),
+        res => Err(ApiError::from_query(res, "Unable to retrieve timestamp")),
+    }
+}
+
+/// DELETE: /api/timestamps/{id}
+/// Voids a spurious scan, it's kept with a voided_at but no longer counted anywhere. The scan
+/// is kept in the audit log's `before` and the required reason in its `after`, written in the
+/// void's transaction like a correction's
+#[rustfmt::skip]
+#[delete("/api/timestamps/{timestamp_id}")]
+pub async fn destroy_timestamp(req: HttpRequest, db: web::Data<Pool>, id: web::Path<usize>, void: web::Json<TimestampVoid>) -> Result<HttpResponse, ApiError> {
+    void.validate().map_err(ApiError::Validation)?;
+    let audit = audit_entry(&req, StatusCode::NO_CONTENT, serde_json::to_value(&*void).ok());
+    match query(&db, Query::DeleteTimestamp(id.into_inner(), &audit)).await {
+        Ok(QueryResult::Voided(_)) => {
+            req.extensions_mut().insert(AuditWritten);
+            Ok(HttpResponse::NoContent().finish())
+        }
+        Ok(QueryResult::NotFound) => Err(ApiError::NotFound("Timestamp not found".to_string())),
+        res => Err(ApiError::from_query(res, "Unable to void timestamp")),
+    }
+}
+
 /// GET: /api/timestamps/{start}/{end}
 #[get("/api/timestamps/{start_date}/{end_date}")]
 #[rustfmt::skip]
-pub async fn show_range(db: web::Data<Pool>, range: web::Path<RangeParams>) -> impl Responder {
+pub async fn show_range(req: HttpRequest, db: web::Data<Pool>, facility: Facility, range: web::Path<RangeParams>, page: web::Query<Pagination>, order: web::Query<ListOrder>, filter: web::Query<ScanFilter>) -> impl Responder {
     let range = &range.into_inner();
-    if let Ok(QueryResult::TimeStamps(ts)) = query(&db, Query::ShowTimestamps(&range.start_date, &range.end_date)).await {
-        HttpResponse::Ok()
-            .insert_header(ContentType::json())
-            .json(ts)
-    } else {
-        let resp = TimestampResponse::from_error("Error retrieving timestamps");
-        HttpResponse::Ok()
-            .insert_header(ContentType::json())
-            .json(resp)
+    let format = match negotiate(&req, &[Format::Json, Format::Envelope, Format::Csv]) {
+        Ok(format) => format,
+        Err(err) => return HttpResponse::from_error(err),
+    };
+    let direction = match page.validate().and_then(|_| order.direction()) {
+        Ok(direction) => direction,
+        Err(msg) => return HttpResponse::from_error(ApiError::Validation(msg)),
+    };
+    let (start, end) = match parse_range(&range.start_date, &range.end_date) {
+        Ok(range) => range,
+        Err(msg) => {
+            let resp = TimestampResponse::from_error(&msg);
+            return HttpResponse::BadRequest().insert_header(ContentType::json()).json(resp);
+        }
+    };
+    let paged = page.is_requested().then(|| (page.limit(), page.offset()));
+    let res = query(&db, Query::ShowTimestamps(facility, &start, &end, &filter, direction, paged)).await;
+    let (mut builder, ts, total) = match res {
+        Ok(QueryResult::TimeStamps(ts)) => (HttpResponse::Ok(), ts, None),
+        Ok(QueryResult::TimeStampsPage(ts, total)) => (page.response(&req, total), ts, Some(total)),
+        res => return HttpResponse::from_error(ApiError::from_query(res, "Unable to retrieve timestamps")),
+    };
+    match format {
+        Format::Csv => return csv_response(builder, &ts),
+        Format::Envelope => return envelope_response(builder, &page.envelope(ts, total)),
+        _ => {}
     }
+    builder
+        .insert_header(ContentType::json())
+        .json(ts)
+}
+
+/// GET: /api/timestamps/export.csv?start=&end=&location_id=
+/// The facility's scans from start to end as a CSV download, optionally at one location. The
+/// rows are streamed from the cursor as the client reads them, so the whole set is never
+/// held in memory however long the range.
+#[rustfmt::skip]
+#[get("/api/timestamps/export.csv")]
+pub async fn export_timestamps(db: web::Data<Pool>, facility: Facility, params: web::Query<ExportParams>) -> Result<HttpResponse, ApiError> {
+    // always a CSV, /api/reports/timestamps/export offers the other formats
+    let (start, end) = params.range(ExportFormat::Csv).map_err(ApiError::Validation)?;
+    log::info!("GET: Timestamps controller export from {} to {}", start, end);
+    let filename = match params.location_id {
+        Some(location) => {
+            // the middleware only reads {location_id} from the path
+            ensure_own(&db, facility, Record::Location(location)).await?;
+            format!("location-{}-timestamps-{}-to-{}.csv", location, start, end)
+        }
+        None => format!("timestamps-{}-to-{}.csv", start, end),
+    };
+    let rows = stream_timestamps_range(&db, facility, start, end, params.location_id);
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(header::ContentDisposition::attachment(filename))
+        .streaming(csv_body(rows)))
 }
@@ -0,0 +1,72 @@
+use crate::errors::ApiError;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// Content-Types a file may be sent as the whole body with, a form is read for its file
+const UPLOAD_TYPES: &[&str] = &["text/csv", "text/plain"];
+
+/// The text of a file upload, sent either as the whole request body or as the first file
+/// field of a `multipart/form-data` form, which is what a browser file input posts. A body sent
+/// as anything but text/csv, text/plain or a form is a 415.
+pub fn uploaded_text<'a>(req: &HttpRequest, body: &'a [u8]) -> Result<&'a str, ApiError> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let file = if content_type.starts_with("multipart/form-data") {
+        let boundary = content_type
+            .split(';')
+            .find_map(|param| param.trim().strip_prefix("boundary="))
+            .map(|boundary| boundary.trim_matches('"'))
+            .ok_or_else(|| ApiError::Validation("the multipart body has no boundary".to_string()))?;
+        multipart_file(body, boundary)
+            .ok_or_else(|| ApiError::Validation("the form has no file field".to_string()))?
+    } else if UPLOAD_TYPES.iter().any(|type_| essence(content_type) == *type_) {
+        body
+    } else {
+        let msg = format!("the file must be sent as {} or multipart/form-data", UPLOAD_TYPES.join(" or "));
+        return Err(ApiError::UnsupportedMediaType(msg));
+    };
+    std::str::from_utf8(file).map_err(|_| ApiError::Validation("the file is not UTF-8 text".to_string()))
+}
+
+// the media type without its parameters, `text/csv; charset=utf-8` is text/csv
+fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+// the content of the first part with a filename, parts are separated by `--boundary` lines
+// and their headers end at the first blank line
+fn multipart_file<'a>(body: &'a [u8], boundary: &str) -> Option<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    split_on(body, delimiter.as_bytes())
+        .into_iter()
+        .skip(1)
+        .find_map(|part| {
+            // the closing delimiter is followed by `--` instead of a line break
+            let part = part.strip_prefix(b"\r\n")?;
+            let end = find(part, b"\r\n\r\n")?;
+            let headers = String::from_utf8_lossy(&part[..end]).to_ascii_lowercase();
+            let content = &part[end + 4..];
+            headers
+                .contains("filename=")
+                .then(|| content.strip_suffix(b"\r\n").unwrap_or(content))
+        })
+}
+
+fn split_on<'a>(mut bytes: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut pieces = Vec::new();
+    while let Some(at) = find(bytes, delimiter) {
+        pieces.push(&bytes[..at]);
+        bytes = &bytes[at + delimiter.len()..];
+    }
+    pieces.push(bytes);
+    pieces
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
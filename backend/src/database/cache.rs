@@ -0,0 +1,78 @@
+use crate::app_config::config;
+use crate::models::facilities::Facility;
+use crate::models::locations::Location;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// The unpaged, unfiltered locations index of each facility, shared by every worker
+pub static LOCATIONS_CACHE: Lazy<LocationsCache> =
+    Lazy::new(|| LocationsCache::new(Duration::from_secs(config().locations_cache_ttl_seconds)));
+
+/// A cached `Vec<Location>` per facility, each held for at most `ttl`. A zero ttl disables it.
+///
+/// Reads race writes: a request can query the table, lose the CPU to a store that commits and
+/// invalidates, then put its now stale rows back. So `put` takes the generation the caller saw
+/// before querying and drops the rows if an invalidation has happened since.
+pub struct LocationsCache {
+    ttl: Duration,
+    state: RwLock<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    generation: u64,
+    entries: HashMap<Facility, (Instant, Vec<Location>)>,
+}
+
+impl LocationsCache {
+    pub fn new(ttl: Duration) -> Self {
+        LocationsCache {
+            ttl,
+            state: RwLock::new(CacheState::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// The facility's cached locations, if there are any younger than the ttl
+    pub fn get(&self, facility: Facility) -> Option<Vec<Location>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let state = self.state.read().unwrap_or_else(|err| err.into_inner());
+        state
+            .entries
+            .get(&facility)
+            .filter(|(stored, _)| stored.elapsed() < self.ttl)
+            .map(|(_, locations)| locations.clone())
+    }
+
+    /// Read before querying and passed back to `put`
+    pub fn generation(&self) -> u64 {
+        self.state
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .generation
+    }
+
+    pub fn put(&self, generation: u64, facility: Facility, locations: &[Location]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut state = self.state.write().unwrap_or_else(|err| err.into_inner());
+        if state.generation == generation {
+            state.entries.insert(facility, (Instant::now(), locations.to_vec()));
+        }
+    }
+
+    /// Called once a write to the locations table has committed, drops every facility's
+    pub fn invalidate(&self) {
+        let mut state = self.state.write().unwrap_or_else(|err| err.into_inner());
+        state.generation = state.generation.wrapping_add(1);
+        state.entries.clear();
+    }
+}
@@ -1,46 +1,347 @@
-use crate::models::residents::Resident;
-use crate::models::timestamps::TimeStamp;
-use crate::models::{locations::Location, timestamps::PostTimestamp};
-use actix_web::{error, web};
-use chrono::NaiveDate;
-use rusqlite::{params, Result};
+use crate::app_config::config;
+use crate::metrics::{DB_ERRORS, DB_QUERY_LATENCY, SLOW_QUERIES};
+use crate::models::api_keys::{ApiKey, TEST_REVOKED_KEY, TEST_SCANNER_KEY};
+use crate::middleware::roles::Role;
+use crate::models::users::{hash_password, User, TEST_PASSWORD, TEST_USERNAME};
+use crate::models::audit::AuditEntry;
+use crate::models::facilities::{Facility, Record, DEFAULT_FACILITY};
+use crate::models::idempotency::{KeyClaim, StoredResponse};
+use crate::models::residents::{name_similarity, ActiveResident, LastSeen, Resident, ResidentFilter, Restriction, RestrictionOutcome, StatusChange, Transfer, TransferOutcome, FUZZY_MATCH_THRESHOLD};
+use crate::models::timestamps::{daily_counts, day_bounds, dwell_seconds, facility_today, hourly_counts, local_midnight, peak_occupancy, presence_changes, visit_intervals, BusiestLocation, CorrectedTimestamp, DailyCount, LocationDailyCount, HourlyCount, Movement, PeakOccupancy, ResidentScansOutcome, ScanFilter, SummaryPart, TimeStamp, TimestampCorrection, Visit, VisitedLocation, TIMESTAMP_FORMAT};
+use crate::models::{locations::{ChildOccupancy, Headcount, Location, LocationMerge, LocationSort, LocationStatus, MergeOutcome, NearbyLocation, OccupancyRollup, OccupancySnapshot, PathOutcome, ResidentCount, SnapshotLocation, SnapshotResident, SortOrder, StatusBatchResult, UpdateOutcome, route_between}, timestamps::PostTimestamp};
+use actix_web::web;
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use futures_util::{stream, Stream};
+use rusqlite::types::Value;
+use rusqlite::{params, OptionalExtension, Result, ToSql};
+use tokio::sync::mpsc;
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 
+/// Opens `path` with room on each connection for `statement_cache` prepared statements, so
+/// the queries here that use `prepare_cached` are parsed once per pooled connection instead of
+/// on every call. 0 turns the cache off, every statement is prepared afresh.
+pub fn connection_manager(path: &std::path::Path, statement_cache: usize) -> r2d2_sqlite::SqliteConnectionManager {
+    r2d2_sqlite::SqliteConnectionManager::file(path).with_init(move |conn| {
+        conn.set_prepared_statement_cache_capacity(statement_cache);
+        Ok(())
+    })
+}
+
 pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 #[derive(Debug, Clone, PartialEq)]
 pub enum Query<'a> {
-    IndexResidents,
+    /// The facility's residents narrowed by the filter. Ordered by name when an order or a page
+    /// of (limit, offset) is given, a page comes with its total.
+    IndexResidents(Facility, &'a ResidentFilter, Option<SortOrder>, Option<(usize, usize)>),
+    /// true falls back to approximate name matches when nothing matches the term as it is
+    SearchResidents(Facility, &'a str, bool),
     ShowResident(&'a str),
     StoreResident(&'a Resident),
+    StoreResidentsBatch(&'a [Resident]),
     UpdateResident(&'a Resident),
     DestroyResident(String),
     ShowResidentTimestamps(String),
     ShowResidentTimestampsRange(&'a str, &'a NaiveDate, &'a NaiveDate),
-    ShowLocationResidents(usize),
-    IndexLocations,
+    ShowResidentMovements(&'a str, &'a NaiveDate, &'a NaiveDate),
+    ResidentDistinctLocations(&'a str, &'a NaiveDate, &'a NaiveDate),
+    LastSeenPerResident(Facility),
+    ResidentsNotSeenSince(Facility, Duration),
+    /// At most the limit of the facility's residents with the most scans from start to end
+    MostActiveResidents(Facility, &'a NaiveDate, &'a NaiveDate, usize),
+    TransferResident(&'a str, usize),
+    SetResidentStatus(&'a str, &'a StatusChange),
+    ShowRestrictions(&'a str),
+    StoreRestriction(&'a str, usize),
+    DeleteRestriction(&'a str, usize),
+    /// The residents assigned to the location, narrowed, ordered and paged like IndexResidents
+    ShowLocationResidents(usize, &'a ResidentFilter, Option<SortOrder>, Option<(usize, usize)>),
+    CountResidentsPerLocation(Facility),
+    ReconcileHeadcount(Facility),
+    ShowCurrentOccupancy(usize),
+    OccupancyRollup(usize),
+    /// Each active resident's location as of the instant, in TIMESTAMP_FORMAT
+    OccupancySnapshot(Facility, &'a str),
+    IndexLocations(Facility),
+    IndexAllLocations(Facility),
+    IndexLocationsPaged(Facility, usize, usize, bool),
+    SearchLocations(Facility, &'a str, bool),
+    /// A page of (limit, offset) also gives the total, None is every location
+    IndexLocationsSorted(Facility, LocationSort, SortOrder, bool, Option<(usize, usize)>),
     ShowLocation(usize),
+    /// The facility's locations among the ids, in one query
+    ShowLocationsByIds(Facility, &'a [usize]),
+    ShowLocationChildren(usize),
+    /// The route from the first location to the second through the hierarchy
+    LocationPath(usize, usize),
+    /// The (location, count) nearest locations
+    NearbyLocations(usize, usize),
     StoreLocation(&'a Location),
-    ShowLocationTimestamps(usize),
-    ShowLocationTimestampsRange(usize, &'a NaiveDate, &'a NaiveDate),
-    IndexTimestamps,
-    ShowTimestamps(&'a NaiveDate, &'a NaiveDate),
+    StoreLocationsBatch(&'a [Location]),
+    UpdateLocation(usize, &'a Location),
+    SetLocationStatus(usize, LocationStatus),
+    SetLocationStatusBatch(Facility, &'a [usize], LocationStatus),
+    DeleteLocation(usize),
+    MergeLocations(usize, usize),
+    /// The scans narrowed by the filter. A page of (limit, offset) gives them newest first unless
+    /// an order says otherwise, and their total; None all of them, in the order asked for if any.
+    ShowLocationTimestamps(usize, &'a ScanFilter, Option<SortOrder>, Option<(usize, usize)>),
+    ShowLocationTimestampsRange(usize, &'a NaiveDate, &'a NaiveDate, &'a ScanFilter, Option<SortOrder>, Option<(usize, usize)>),
+    /// (location, rfid) and the range to keep to, None for every scan
+    ShowLocationResidentTimestamps(usize, &'a str, Option<(&'a NaiveDate, &'a NaiveDate)>),
+    ShowLocationDwell(usize, &'a NaiveDate, &'a NaiveDate),
+    LocationVisits(usize, &'a NaiveDate, &'a NaiveDate),
+    OccupancyByHour(usize, &'a NaiveDate, &'a NaiveDate),
+    DailyScanCounts(usize, &'a NaiveDate, &'a NaiveDate),
+    /// Each of the facility's locations' scans per local day, the days without any left out
+    DailyCountsByLocation(Facility, &'a NaiveDate, &'a NaiveDate),
+    PeakOccupancy(usize, &'a NaiveDate, &'a NaiveDate),
+    /// One part of the facility's summary of a local day
+    TodaySummary(Facility, &'a NaiveDate, SummaryPart),
+    /// Narrowed, ordered and paged like ShowLocationTimestamps
+    IndexTimestamps(Facility, &'a ScanFilter, Option<SortOrder>, Option<(usize, usize)>),
+    ShowTimestamps(Facility, &'a NaiveDate, &'a NaiveDate, &'a ScanFilter, Option<SortOrder>, Option<(usize, usize)>),
     StoreTimestamp(&'a PostTimestamp),
+    StoreTimestampsBatch(&'a [PostTimestamp]),
+    /// Historical scans stored at the time they carry, each checked against the facility
+    BackfillTimestamps(Facility, &'a [PostTimestamp]),
+    ShowTimestamp(usize),
+    // the entry is written with the correction, its `before` set to the scan as it was
+    UpdateTimestamp(usize, &'a TimestampCorrection, &'a AuditEntry),
+    // the entry is written with the void, its `before` set to the scan
+    DeleteTimestamp(usize, &'a AuditEntry),
+    /// Deletes up to the limit of the facility's scans from before the cutoff, oldest first
+    DeleteTimestampsBefore(Facility, &'a str, usize),
+    FindApiKey(&'a str),
+    StoreApiKey(Facility, &'a str, &'a str),
+    RevokeApiKey(&'a str),
+    /// A login's user, by username
+    FindUser(&'a str),
+    /// Username, role and password hash of a new user
+    StoreUser(Facility, &'a str, Role, &'a str),
+    DeleteUser(&'a str),
+    WriteAuditLog(&'a AuditEntry),
+    // (facility, key, path), see middleware::idempotency
+    ClaimIdempotencyKey(Facility, &'a str, &'a str),
+    StoreIdempotentResponse(Facility, &'a str, &'a StoredResponse),
+    ReleaseIdempotencyKey(Facility, &'a str),
+    IndexAuditLog(Facility, usize, usize),
+    FacilityOf(Record<'a>),
     Migrations,
     SeedTestData,
+    Ping,
+}
+
+impl Query<'_> {
+    /// The variant without its arguments, for metric labels and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Query::IndexResidents(..) => "IndexResidents",
+            Query::SearchResidents(..) => "SearchResidents",
+            Query::ShowResident(..) => "ShowResident",
+            Query::StoreResident(..) => "StoreResident",
+            Query::StoreResidentsBatch(..) => "StoreResidentsBatch",
+            Query::UpdateResident(..) => "UpdateResident",
+            Query::DestroyResident(..) => "DestroyResident",
+            Query::ShowResidentTimestamps(..) => "ShowResidentTimestamps",
+            Query::ShowResidentTimestampsRange(..) => "ShowResidentTimestampsRange",
+            Query::ShowResidentMovements(..) => "ShowResidentMovements",
+            Query::ResidentDistinctLocations(..) => "ResidentDistinctLocations",
+            Query::ShowRestrictions(..) => "ShowRestrictions",
+            Query::StoreRestriction(..) => "StoreRestriction",
+            Query::DeleteRestriction(..) => "DeleteRestriction",
+            Query::LastSeenPerResident(..) => "LastSeenPerResident",
+            Query::ResidentsNotSeenSince(..) => "ResidentsNotSeenSince",
+            Query::MostActiveResidents(..) => "MostActiveResidents",
+            Query::TransferResident(..) => "TransferResident",
+            Query::SetResidentStatus(..) => "SetResidentStatus",
+            Query::ShowLocationResidents(..) => "ShowLocationResidents",
+            Query::CountResidentsPerLocation(..) => "CountResidentsPerLocation",
+            Query::ReconcileHeadcount(..) => "ReconcileHeadcount",
+            Query::ShowCurrentOccupancy(..) => "ShowCurrentOccupancy",
+            Query::OccupancyRollup(..) => "OccupancyRollup",
+            Query::OccupancySnapshot(..) => "OccupancySnapshot",
+            Query::IndexLocations(..) => "IndexLocations",
+            Query::IndexAllLocations(..) => "IndexAllLocations",
+            Query::IndexLocationsPaged(..) => "IndexLocationsPaged",
+            Query::SearchLocations(..) => "SearchLocations",
+            Query::IndexLocationsSorted(..) => "IndexLocationsSorted",
+            Query::ShowLocation(..) => "ShowLocation",
+            Query::ShowLocationsByIds(..) => "ShowLocationsByIds",
+            Query::ShowLocationChildren(..) => "ShowLocationChildren",
+            Query::NearbyLocations(..) => "NearbyLocations",
+            Query::LocationPath(..) => "LocationPath",
+            Query::StoreLocation(..) => "StoreLocation",
+            Query::StoreLocationsBatch(..) => "StoreLocationsBatch",
+            Query::UpdateLocation(..) => "UpdateLocation",
+            Query::SetLocationStatus(..) => "SetLocationStatus",
+            Query::SetLocationStatusBatch(..) => "SetLocationStatusBatch",
+            Query::DeleteLocation(..) => "DeleteLocation",
+            Query::MergeLocations(..) => "MergeLocations",
+            Query::ShowLocationTimestamps(..) => "ShowLocationTimestamps",
+            Query::ShowLocationTimestampsRange(..) => "ShowLocationTimestampsRange",
+            Query::ShowLocationResidentTimestamps(..) => "ShowLocationResidentTimestamps",
+            Query::ShowLocationDwell(..) => "ShowLocationDwell",
+            Query::LocationVisits(..) => "LocationVisits",
+            Query::OccupancyByHour(..) => "OccupancyByHour",
+            Query::DailyScanCounts(..) => "DailyScanCounts",
+            Query::DailyCountsByLocation(..) => "DailyCountsByLocation",
+            Query::PeakOccupancy(..) => "PeakOccupancy",
+            Query::TodaySummary(..) => "TodaySummary",
+            Query::IndexTimestamps(..) => "IndexTimestamps",
+            Query::ShowTimestamps(..) => "ShowTimestamps",
+            Query::StoreTimestamp(..) => "StoreTimestamp",
+            Query::StoreTimestampsBatch(..) => "StoreTimestampsBatch",
+            Query::BackfillTimestamps(..) => "BackfillTimestamps",
+            Query::ShowTimestamp(..) => "ShowTimestamp",
+            Query::UpdateTimestamp(..) => "UpdateTimestamp",
+            Query::DeleteTimestamp(..) => "DeleteTimestamp",
+            Query::DeleteTimestampsBefore(..) => "DeleteTimestampsBefore",
+            Query::FindApiKey(..) => "FindApiKey",
+            Query::StoreApiKey(..) => "StoreApiKey",
+            Query::RevokeApiKey(..) => "RevokeApiKey",
+            Query::FindUser(..) => "FindUser",
+            Query::StoreUser(..) => "StoreUser",
+            Query::DeleteUser(..) => "DeleteUser",
+            Query::WriteAuditLog(..) => "WriteAuditLog",
+            Query::ClaimIdempotencyKey(..) => "ClaimIdempotencyKey",
+            Query::StoreIdempotentResponse(..) => "StoreIdempotentResponse",
+            Query::ReleaseIdempotencyKey(..) => "ReleaseIdempotencyKey",
+            Query::IndexAuditLog(..) => "IndexAuditLog",
+            Query::FacilityOf(..) => "FacilityOf",
+            Query::Migrations => "Migrations",
+            Query::SeedTestData => "SeedTestData",
+            Query::Ping => "Ping",
+        }
+    }
+
+    /// Whether running the query twice leaves the same state as running it once, only these
+    /// are retried. Inserts, migrations and the seed could double up so they never are.
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(
+            self,
+            Query::StoreResident(_)
+                | Query::StoreResidentsBatch(_)
+                | Query::DestroyResident(_)
+                | Query::StoreLocation(_)
+                | Query::StoreLocationsBatch(_)
+                // a compare-and-set on the version, a rerun after the first committed would
+                // find the version it bumped and report a conflict
+                | Query::UpdateLocation(..)
+                | Query::DeleteLocation(_)
+                // the source is gone after the first run, a rerun would report a 404
+                | Query::MergeLocations(..)
+                | Query::StoreTimestamp(_)
+                | Query::StoreTimestampsBatch(_)
+                | Query::BackfillTimestamps(..)
+                // a rerun would read back its own write as the scan's original
+                | Query::UpdateTimestamp(..)
+                | Query::DeleteTimestamp(..)
+                | Query::TransferResident(_, _)
+                // a rerun after the first committed would see the new status, and refuse
+                // a reactivation or report it as a change to itself
+                | Query::SetResidentStatus(..)
+                // a rerun would find the restriction it just added, or not the one it removed
+                | Query::StoreRestriction(..)
+                | Query::DeleteRestriction(..)
+                | Query::StoreApiKey(..)
+                | Query::StoreUser(..)
+                | Query::DeleteUser(_)
+                | Query::WriteAuditLog(_)
+                // a rerun would find its own claim and report the request as in flight
+                | Query::ClaimIdempotencyKey(..)
+                | Query::StoreIdempotentResponse(..)
+                | Query::ReleaseIdempotencyKey(..)
+                | Query::Migrations
+                | Query::SeedTestData
+        )
+    }
 }
 
 pub enum QueryResult {
     Resident(Resident),
     Residents(Vec<Resident>),
+    ResidentsPage(Vec<Resident>, usize),
     TimeStamps(Vec<TimeStamp>),
+    /// A page of timestamps and how many there are in all
+    TimeStampsPage(Vec<TimeStamp>, usize),
+    Movements(Vec<Movement>),
+    Visited(Vec<VisitedLocation>),
+    LastSeen(Vec<LastSeen>),
+    MostActive(Vec<ActiveResident>),
+    Transfer(TransferOutcome),
+    Restrictions(Vec<Restriction>),
+    Restriction(RestrictionOutcome),
+    Merge(MergeOutcome),
+    LocationUpdate(UpdateOutcome),
+    StatusBatch(StatusBatchResult),
+    Dwell(BTreeMap<String, i64>),
+    Visits(Vec<Visit>),
+    Hourly(Vec<HourlyCount>),
+    Daily(Vec<DailyCount>),
+    LocationDaily(Vec<LocationDailyCount>),
+    Peak(PeakOccupancy),
+    ResidentScans(ResidentScansOutcome),
+    Count(usize),
+    Busiest(Option<BusiestLocation>),
     Locations(Vec<Location>),
+    LocationsPage(Vec<Location>, usize),
+    ResidentCounts(Vec<ResidentCount>),
+    Headcounts(Vec<Headcount>),
+    Rollup(OccupancyRollup),
+    Snapshot(OccupancySnapshot),
     Location(Location),
+    Nearby(Vec<NearbyLocation>),
+    Path(PathOutcome),
+    /// Per location of a batch, stored or the column a unique constraint rejected it on
+    StoredLocations(Vec<std::result::Result<Location, String>>),
+    /// Per resident of a batch, stored or the column an existing resident already has it in
+    StoredResidents(Vec<std::result::Result<(), String>>),
     PostTimestamp(PostTimestamp),
+    PostTimestamps(Vec<Option<PostTimestamp>>),
+    /// Per scan of a backfill, stored or why it was rejected
+    Backfilled(Vec<std::result::Result<PostTimestamp, String>>),
+    Corrected(CorrectedTimestamp),
+    TimeStamp(TimeStamp),
+    Voided(TimeStamp),
+    ApiKey(ApiKey),
+    User(User),
+    Facility(Facility),
+    /// A page of audit entries and how many there are in all
+    AuditLog(Vec<AuditEntry>, usize),
+    KeyClaim(KeyClaim),
     Success,
     Failure,
     NotFound,
+    /// A unique constraint rejected the write, holds the column it was on
+    Conflict(String),
+    /// The write was refused before it was made, holds why as a validation message
+    Invalid(String),
+}
+
+/// Failures of query() that happen before any SQL runs
+#[derive(Debug)]
+pub enum DbError {
+    /// No pooled connection became free before the pool's checkout timeout
+    PoolExhausted(String),
+    /// The call ran past QUERY_TIMEOUT_MS, holds the deadline in milliseconds
+    Timeout(u64),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::PoolExhausted(msg) => write!(f, "No database connection available: {}", msg),
+            DbError::Timeout(ms) => write!(f, "Query did not finish within {}ms", ms),
+        }
+    }
 }
 
+impl std::error::Error for DbError {}
+
 // name: String::new(),
 // doc: String::new(),
 // rfid_tag: String::new(),
@@ -48,45 +349,170 @@ pub enum QueryResult {
 // signed_in: false,
 // Location: None,
 // timestamps: Vec::new(),
-#[rustfmt::skip]
-pub async fn query(pool: &Pool, query: Query<'_>,) -> Result<QueryResult, Box<dyn std::error::Error>> {
+pub async fn query(pool: &Pool, query: Query<'_>) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    let timeout = std::time::Duration::from_millis(config().query_timeout_ms);
+    let started = Instant::now();
+    let deadline = started + timeout;
+    let name = query.name();
+    let res = match tokio::time::timeout(timeout, run_query(pool, query, deadline)).await {
+        Ok(res) => res,
+        Err(_) => {
+            log::error!("Query timed out after {}ms", config().query_timeout_ms);
+            Err(Box::new(DbError::Timeout(config().query_timeout_ms)) as Box<dyn std::error::Error>)
+        }
+    };
+    if res.is_err() {
+        DB_ERRORS.with_label_values(&[name]).inc();
+    }
+    let elapsed = started.elapsed();
+    DB_QUERY_LATENCY.with_label_values(&[name]).observe(elapsed.as_secs_f64());
+    let elapsed = elapsed.as_millis() as u64;
+    if config().slow_query_ms > 0 && elapsed > config().slow_query_ms {
+        log::warn!("Slow query {} took {}ms (threshold {}ms)", name, elapsed, config().slow_query_ms);
+        SLOW_QUERIES.with_label_values(&[name]).inc();
+    }
+    res
+}
+
+/// Transient errors retried since startup
+static QUERY_RETRY_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub fn query_retry_count() -> u64 {
+    QUERY_RETRY_COUNT.load(Ordering::Relaxed)
+}
+
+// Retries idempotent queries that failed transiently, backing off QUERY_RETRY_BACKOFF_MS and
+// doubling each time. Every attempt shares the one deadline from query().
+async fn run_query(pool: &Pool, query: Query<'_>, deadline: Instant) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    loop {
+        match attempt_query(pool, query.clone(), deadline).await {
+            Err(err) if attempt < config().query_retries && query.is_idempotent() && is_transient(err.as_ref()) => {
+                let backoff = std::time::Duration::from_millis(config().query_retry_backoff_ms.saturating_mul(2u64.saturating_pow(attempt)));
+                attempt += 1;
+                let total = QUERY_RETRY_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "Retrying {:?} in {:?} after transient error: {} (attempt {}/{}, {} retries since startup)",
+                    query, backoff, err, attempt, config().query_retries, total
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+// sqlite reports a connection it couldn't take the lock on, or one that was reset under it,
+// with these codes. Anything else, including our own pool and deadline errors, is permanent.
+fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    use rusqlite::ErrorCode::{CannotOpen, DatabaseBusy, DatabaseLocked, SystemIoFailure};
+    match err.downcast_ref::<rusqlite::Error>() {
+        Some(rusqlite::Error::SqliteFailure(err, _)) => {
+            matches!(err.code, DatabaseBusy | DatabaseLocked | SystemIoFailure | CannotOpen)
+        }
+        _ => false,
+    }
+}
+
+// The SQL runs synchronously on the worker once a connection is checked out, where the
+// tokio timeout can't preempt it, so sqlite is also told to abort at the same deadline
+async fn attempt_query(pool: &Pool, query: Query<'_>, deadline: Instant) -> Result<QueryResult, Box<dyn std::error::Error>> {
     let pool = pool.clone();
     let conn = web::block(move || pool.get())
         .await?
-        .map_err(error::ErrorInternalServerError)?;
-    match query {
-        Query::ShowResident(id) => Ok(QueryResult::Resident(show_resident(id, conn)?)),
-        Query::IndexResidents => Ok(QueryResult::Residents(index_residents(conn)?)),
-        Query::StoreResident(resident) => {
-            if store_resident(resident, conn).is_ok() {
-                log::info!("Stored resident: {:?}", resident);
-                Ok(QueryResult::Success)
-            } else {
-                log::info!("ERRORRRRRR: ");
-                Err(Box::new(rusqlite::Error::InvalidQuery))
-            }
-        }
-        Query::ShowResidentTimestampsRange(rfid, start, end) => {
-            if let Ok(timestamps) = show_resident_timestamps_range(rfid, start, end, conn) {
-            Ok(QueryResult::TimeStamps(timestamps))
-            } else {
-            Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
+        .map_err(|err| DbError::PoolExhausted(err.to_string()))?;
+    let interrupted = interrupt_at(&conn, deadline);
+    let res = dispatch(query, conn);
+    if interrupted.load(Ordering::Relaxed) {
+        log::error!("Query interrupted after {}ms", config().query_timeout_ms);
+        return Err(Box::new(DbError::Timeout(config().query_timeout_ms)));
+    }
+    res
+}
+
+/// sqlite instructions run between deadline checks
+const PROGRESS_CHECK_OPS: i32 = 1000;
+
+// aborts whatever statement the connection is running once the deadline passes, the flag
+// tells the caller apart from an ordinary failure since several arms swallow the error
+fn interrupt_at(conn: &Connection, deadline: Instant) -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    conn.progress_handler(
+        PROGRESS_CHECK_OPS,
+        Some(move || {
+            let expired = Instant::now() >= deadline;
+            if expired {
+                flag.store(true, Ordering::Relaxed);
             }
+            expired
+        }),
+    );
+    interrupted
+}
+
+#[rustfmt::skip]
+fn dispatch(query: Query<'_>, conn: Connection) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    match query {
+        Query::ShowResident(id) => match show_resident(id, conn)? {
+            Some(resident) => Ok(QueryResult::Resident(resident)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::IndexResidents(facility, filter, order, page) => {
+            list_residents(&conn, "facility_id = ?1", &[&facility], filter, order, page)
         }
-        Query::UpdateResident(resident) => {
-            if update_resident(resident, conn).is_ok() {
+        Query::SearchResidents(facility, term, fuzzy) => Ok(QueryResult::Residents(search_residents(facility, term, fuzzy, conn)?)),
+        Query::StoreResident(resident) => match store_resident(resident, conn)? {
+            None => {
+                log::info!("Stored resident: {:?}", resident);
                 Ok(QueryResult::Success)
-            } else {
-                Err(Box::new(rusqlite::Error::InvalidQuery))
-            }
-        }
-        Query::ShowLocationResidents(id) => {
-            if let Ok(residents) = show_location_residents(id, conn) {
-                Ok(QueryResult::Residents(residents))
-            } else {
-                Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
             }
+            Some(column) => Ok(QueryResult::Conflict(column)),
+        },
+        Query::StoreResidentsBatch(residents) => Ok(QueryResult::StoredResidents(store_residents_batch(residents, conn)?)),
+        Query::ShowResidentTimestampsRange(rfid, start, end) => Ok(QueryResult::TimeStamps(
+            show_resident_timestamps_range(rfid, start, end, conn)?,
+        )),
+        Query::ResidentDistinctLocations(rfid, start, end) => Ok(QueryResult::Visited(
+            resident_distinct_locations(rfid, start, end, conn)?,
+        )),
+        Query::ShowResidentMovements(rfid, start, end) => Ok(QueryResult::Movements(
+            show_resident_movements(rfid, start, end, conn)?,
+        )),
+        Query::LastSeenPerResident(facility) => Ok(QueryResult::LastSeen(last_seen_per_resident(facility, conn)?)),
+        Query::ResidentsNotSeenSince(facility, window) => Ok(QueryResult::LastSeen(residents_not_seen_since(facility, window, conn)?)),
+        Query::MostActiveResidents(facility, start, end, limit) => Ok(QueryResult::MostActive(most_active_residents(facility, start, end, limit, conn)?)),
+        Query::TransferResident(rfid, to) => Ok(QueryResult::Transfer(transfer_resident(rfid, to, conn)?)),
+        Query::ShowRestrictions(rfid) => match show_restrictions(rfid, &conn)? {
+            Some(restrictions) => Ok(QueryResult::Restrictions(restrictions)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::StoreRestriction(rfid, location) => Ok(QueryResult::Restriction(store_restriction(rfid, location, conn)?)),
+        Query::DeleteRestriction(rfid, location) => match delete_restriction(rfid, location, conn)? {
+            Some(restrictions) => Ok(QueryResult::Restrictions(restrictions)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::SetResidentStatus(rfid, change) => match set_resident_status(rfid, change, conn)? {
+            Some(Ok(resident)) => Ok(QueryResult::Resident(resident)),
+            Some(Err(msg)) => Ok(QueryResult::Conflict(msg)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::UpdateResident(resident) => match update_resident(resident, conn) {
+            Ok(None) => Ok(QueryResult::Success),
+            Ok(Some(column)) => Ok(QueryResult::Conflict(column)),
+            Err(_) => Err(Box::new(rusqlite::Error::InvalidQuery)),
+        },
+        Query::ShowLocationResidents(id, filter, order, page) => {
+            list_residents(&conn, "unit = ?1", &[&id], filter, order, page)
         }
+        Query::CountResidentsPerLocation(facility) => Ok(QueryResult::ResidentCounts(count_residents_per_location(facility, conn)?)),
+        Query::ReconcileHeadcount(facility) => Ok(QueryResult::Headcounts(reconcile_headcount(facility, conn)?)),
+        Query::ShowCurrentOccupancy(id) => Ok(QueryResult::Residents(show_current_occupancy(id, conn)?)),
+        Query::OccupancySnapshot(facility, at) => Ok(QueryResult::Snapshot(occupancy_snapshot(facility, at, conn)?)),
+        Query::OccupancyRollup(id) => match occupancy_rollup(id, conn)? {
+            Some(rollup) => Ok(QueryResult::Rollup(rollup)),
+            None => Ok(QueryResult::NotFound),
+        },
         Query::DestroyResident(id) => {
             if delete_resident(&id, conn).is_ok() {
                 Ok(QueryResult::Success)
@@ -94,54 +520,190 @@ pub async fn query(pool: &Pool, query: Query<'_>,) -> Result<QueryResult, Box<dy
                 Err(Box::new(rusqlite::Error::InvalidQuery))
             }
         }
-        Query::ShowResidentTimestamps(rfid) => {
-            if let Ok(timestamps) = show_resident_timestamps(rfid, conn) {
-                Ok(QueryResult::TimeStamps(timestamps))
-            } else {
-                Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
-            }
-        }
-        Query::IndexLocations => {
-            if let Ok(locations) = index_locations(conn) {
-                Ok(QueryResult::Locations(locations))
-            } else {
-                Ok(QueryResult::Failure)
-            }
+        Query::ShowResidentTimestamps(rfid) => Ok(QueryResult::TimeStamps(show_resident_timestamps(rfid, conn)?)),
+        Query::IndexLocations(facility) => Ok(QueryResult::Locations(index_locations(facility, conn)?)),
+        Query::IndexAllLocations(facility) => Ok(QueryResult::Locations(index_all_locations(facility, conn)?)),
+        Query::IndexLocationsPaged(facility, limit, offset, include_deleted) => {
+            let (locations, total) = index_locations_paged(facility, limit, offset, include_deleted, conn)?;
+            Ok(QueryResult::LocationsPage(locations, total))
         }
-        Query::ShowLocation(id) => {
-            if let Ok(location) = show_location(id, conn) {
-                Ok(QueryResult::Location(location))
-            } else {
-                Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
+        Query::IndexLocationsSorted(facility, sort, order, include_deleted, page) => {
+            let (locations, total) = index_locations_sorted(facility, sort, order, include_deleted, page, conn)?;
+            match page {
+                Some(_) => Ok(QueryResult::LocationsPage(locations, total)),
+                None => Ok(QueryResult::Locations(locations)),
             }
         }
-        Query::StoreLocation(location) => {
-            store_location(location, conn)?;
-            Ok(QueryResult::Success)
+        Query::SearchLocations(facility, term, include_deleted) => Ok(QueryResult::Locations(search_locations(facility, term, include_deleted, conn)?)),
+        Query::ShowLocation(id) => match show_location(id, &conn)? {
+            Some(location) => Ok(QueryResult::Location(location)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::ShowLocationsByIds(facility, ids) => Ok(QueryResult::Locations(show_locations_by_ids(facility, ids, &conn)?)),
+        Query::ShowLocationChildren(id) => match show_location_children(id, conn)? {
+            Some(children) => Ok(QueryResult::Locations(children)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::LocationPath(from, to) => Ok(QueryResult::Path(location_path(from, to, conn)?)),
+        Query::NearbyLocations(id, count) => match show_location(id, &conn)? {
+            None => Ok(QueryResult::NotFound),
+            Some(origin) => match origin.coordinates() {
+                Some(at) => Ok(QueryResult::Nearby(nearby_locations(&origin, at, count, &conn)?)),
+                None => Ok(QueryResult::Conflict(format!("Location {} has no x and y coordinates to measure from", id))),
+            },
+        },
+        Query::StoreLocation(location) => match parent_problem(None, location, &conn)? {
+            Some(problem) => Ok(QueryResult::Invalid(problem)),
+            None => match store_location(location, conn) {
+                Ok(location) => Ok(QueryResult::Location(location)),
+                Err(err) => conflict_or(err),
+            },
+        },
+        Query::StoreLocationsBatch(locations) => Ok(QueryResult::StoredLocations(store_locations_batch(locations, conn)?)),
+        Query::UpdateLocation(id, location) => match parent_problem(Some(id), location, &conn)? {
+            Some(problem) => Ok(QueryResult::Invalid(problem)),
+            None => match update_location(id, location, conn) {
+                Ok(outcome) => Ok(QueryResult::LocationUpdate(outcome)),
+                Err(err) => conflict_or(err),
+            },
+        },
+        Query::SetLocationStatus(id, status) => match set_location_status(id, status, conn)? {
+            Some(location) => Ok(QueryResult::Location(location)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::SetLocationStatusBatch(facility, ids, status) => Ok(QueryResult::StatusBatch(
+            set_location_status_batch(facility, ids, status, conn)?,
+        )),
+        Query::DeleteLocation(id) => match delete_location(id, conn)? {
+            Some(before) => Ok(QueryResult::Location(before)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::MergeLocations(source, destination) => Ok(QueryResult::Merge(merge_locations(source, destination, conn)?)),
+        Query::ShowLocationTimestampsRange(id, start, end, filter, order, page) => {
+            let (from, to) = day_bounds(*start, *end);
+            list_timestamps(&conn, "location = ?1 AND ts >= ?2 AND ts < ?3", &[&id, &from, &to], filter, order, page)
         }
-        Query::ShowLocationTimestampsRange(id, start, end) => Ok(QueryResult::TimeStamps(
-            show_timestamps_location_range(id, start, end, conn)?,
+        Query::ShowLocationResidentTimestamps(id, rfid, range) => Ok(QueryResult::ResidentScans(
+            show_location_resident_timestamps(id, rfid, range, conn)?,
         )),
-        Query::ShowLocationTimestamps(id) => Ok(QueryResult::TimeStamps(
-            show_timestamps_location(id, conn)?,
+        Query::ShowLocationDwell(id, start, end) => Ok(QueryResult::Dwell(
+            show_location_dwell(id, start, end, conn)?,
         )),
-        Query::IndexTimestamps => Ok(QueryResult::TimeStamps(index_timestamps(conn)?)),
-        Query::ShowTimestamps(start, end) => Ok(QueryResult::TimeStamps(show_timestamps_range(
-            start, end, conn,
-        )?)),
-        Query::StoreTimestamp(ts) => {
-            if let Ok(timestamp) = store_timestamp(ts, conn) {
-                Ok(QueryResult::PostTimestamp(timestamp))
-            } else {
+        Query::LocationVisits(id, start, end) => Ok(QueryResult::Visits(
+            location_visits(id, start, end, conn)?,
+        )),
+        Query::OccupancyByHour(id, start, end) => Ok(QueryResult::Hourly(
+            occupancy_by_hour(id, start, end, conn)?,
+        )),
+        Query::DailyScanCounts(id, start, end) => Ok(QueryResult::Daily(
+            daily_scan_counts(id, start, end, conn)?,
+        )),
+        Query::DailyCountsByLocation(facility, start, end) => Ok(QueryResult::LocationDaily(
+            daily_counts_by_location(facility, start, end, conn)?,
+        )),
+        Query::PeakOccupancy(id, start, end) => Ok(QueryResult::Peak(
+            show_peak_occupancy(id, start, end, conn)?,
+        )),
+        Query::TodaySummary(facility, date, part) => today_summary(facility, date, part, conn),
+        Query::ShowLocationTimestamps(id, filter, order, page) => {
+            let (from, to) = day_bounds(facility_today(), facility_today());
+            list_timestamps(&conn, "location = ?1 AND ts >= ?2 AND ts < ?3", &[&id, &from, &to], filter, order, page)
+        }
+        Query::IndexTimestamps(facility, filter, order, page) => {
+            let (from, to) = day_bounds(facility_today(), facility_today());
+            list_timestamps(&conn, FACILITY_SCANS, &[&facility, &from, &to], filter, order, page)
+        }
+        Query::ShowTimestamps(facility, start, end, filter, order, page) => {
+            let (from, to) = day_bounds(*start, *end);
+            list_timestamps(&conn, FACILITY_SCANS, &[&facility, &from, &to], filter, order, page)
+        }
+        Query::ShowTimestamp(id) => match show_timestamp(id, conn)? {
+            Some(ts) => Ok(QueryResult::TimeStamp(ts)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::StoreTimestamp(ts) => match store_timestamp(ts, conn) {
+            Ok(Some(timestamp)) => Ok(QueryResult::PostTimestamp(timestamp)),
+            Ok(None) => Ok(QueryResult::NotFound),
+            Err(err) => {
+                log::error!("Error storing timestamp: {}", err);
                 Err(Box::new(rusqlite::Error::InvalidQuery))
             }
-        }
-        Query::Migrations => {
-            if migrations(conn).is_ok() {
-                Ok(QueryResult::Success)
-            } else {
+        },
+        Query::StoreTimestampsBatch(scans) => match store_timestamps_batch(scans, conn) {
+            Ok(results) => Ok(QueryResult::PostTimestamps(results)),
+            Err(err) => {
+                log::error!("Error storing timestamp batch, rolled back: {}", err);
                 Err(Box::new(rusqlite::Error::InvalidQuery))
             }
+        },
+        Query::BackfillTimestamps(facility, scans) => Ok(QueryResult::Backfilled(backfill_timestamps(facility, scans, conn)?)),
+        Query::UpdateTimestamp(id, correction, audit) => match update_timestamp(id, correction, audit, conn)? {
+            CorrectionOutcome::Corrected(corrected) => Ok(QueryResult::Corrected(corrected)),
+            CorrectionOutcome::NoTimestamp => Ok(QueryResult::NotFound),
+            CorrectionOutcome::NoLocation(id) => Ok(QueryResult::Invalid(format!("location: no location with id {}", id))),
+        },
+        Query::DeleteTimestampsBefore(facility, cutoff, limit) => Ok(QueryResult::Count(delete_timestamps_before(facility, cutoff, limit, conn)?)),
+        Query::DeleteTimestamp(id, audit) => match void_timestamp(id, audit, conn)? {
+            Some(voided) => Ok(QueryResult::Voided(voided)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::FindApiKey(hash) => match find_api_key(hash, conn)? {
+            Some(key) => Ok(QueryResult::ApiKey(key)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::StoreApiKey(facility, name, hash) => match store_api_key(facility, name, hash, conn)? {
+            true => Ok(QueryResult::Success),
+            false => Ok(QueryResult::Conflict("name".to_string())),
+        },
+        Query::RevokeApiKey(name) => match revoke_api_key(name, conn)? {
+            0 => Ok(QueryResult::NotFound),
+            _ => Ok(QueryResult::Success),
+        },
+        Query::FindUser(username) => match find_user(username, conn)? {
+            Some(user) => Ok(QueryResult::User(user)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::StoreUser(facility, username, role, hash) => match store_user(facility, username, role, hash, conn)? {
+            true => Ok(QueryResult::Success),
+            false => Ok(QueryResult::Conflict("username".to_string())),
+        },
+        Query::DeleteUser(username) => match conn.execute("DELETE FROM users WHERE username = ?1", params![username])? {
+            0 => Ok(QueryResult::NotFound),
+            _ => Ok(QueryResult::Success),
+        },
+        Query::WriteAuditLog(entry) => {
+            write_audit_log(entry, &conn)?;
+            Ok(QueryResult::Success)
+        }
+        Query::ClaimIdempotencyKey(facility, key, path) => Ok(QueryResult::KeyClaim(
+            claim_idempotency_key(facility, key, path, conn)?,
+        )),
+        Query::StoreIdempotentResponse(facility, key, response) => {
+            store_idempotent_response(facility, key, response, conn)?;
+            Ok(QueryResult::Success)
+        }
+        Query::ReleaseIdempotencyKey(facility, key) => {
+            conn.execute(
+                "DELETE FROM idempotency_keys WHERE facility_id = ?1 AND key = ?2 AND status IS NULL",
+                params![&facility, key],
+            )?;
+            Ok(QueryResult::Success)
+        }
+        Query::IndexAuditLog(facility, limit, offset) => {
+            let (entries, total) = index_audit_log(facility, limit, offset, conn)?;
+            Ok(QueryResult::AuditLog(entries, total))
+        }
+        Query::FacilityOf(record) => match facility_of(record, conn)? {
+            Some(facility) => Ok(QueryResult::Facility(facility)),
+            None => Ok(QueryResult::NotFound),
+        },
+        Query::Migrations => {
+            migrations(conn)?;
+            Ok(QueryResult::Success)
+        }
+        Query::Ping => {
+            conn.query_row("SELECT 1", [], |row| row.get::<_, i64>(0))?;
+            Ok(QueryResult::Success)
         }
         Query::SeedTestData => {
             if seed_test_data(conn).is_ok() {
@@ -153,22 +715,172 @@ pub async fn query(pool: &Pool, query: Query<'_>,) -> Result<QueryResult, Box<dy
     }
 }
 
-pub fn migrations(conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
-    conn.execute_batch("BEGIN TRANSACTION;")?;
+// a constraint violation on a unique column is the client's conflict to resolve, anything
+// else is still an error
+fn conflict_or(err: rusqlite::Error) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    match unique_violation(&err) {
+        Some(column) => Ok(QueryResult::Conflict(column)),
+        None => Err(err.into()),
+    }
+}
+
+/// The column a UNIQUE or PRIMARY KEY constraint failed on, sqlite reports it in the
+/// message as `UNIQUE constraint failed: table.column`
+fn unique_violation(err: &rusqlite::Error) -> Option<String> {
+    match err {
+        rusqlite::Error::SqliteFailure(failure, Some(msg)) if failure.code == rusqlite::ErrorCode::ConstraintViolation => msg
+            .strip_prefix("UNIQUE constraint failed: ")
+            .and_then(|columns| {
+                // the name index is per facility, its conflict is on the name
+                columns
+                    .split(", ")
+                    .filter_map(|column| column.rsplit('.').next())
+                    .find(|column| *column != "facility_id")
+            })
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Item type of the row streams below, the error crosses from the blocking thread
+pub type StreamItem<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Rows buffered between the blocking reader and the response before it waits on the client
+const STREAM_BUFFER: usize = 64;
+
+/// Runs `read` on a blocking thread with a pooled connection, handing it a sender to emit
+/// rows through one at a time. The returned stream ends once `read` returns, and `read`
+/// should stop when a send fails because the client went away. An error `read` returns is
+/// sent as the last item, so a row that fails to read cuts the body off instead of going
+/// missing from it.
+fn stream_rows<T, F>(pool: &Pool, read: F) -> impl Stream<Item = StreamItem<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(Connection, &mpsc::Sender<StreamItem<T>>) -> Result<(), Box<dyn std::error::Error>>
+        + Send
+        + 'static,
+{
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    let pool = pool.clone();
+    actix_web::rt::task::spawn_blocking(move || {
+        let result = pool
+            .get()
+            .map_err(|err| err.to_string())
+            .and_then(|conn| {
+                // a stream lives as long as the client keeps reading, drop any deadline
+                // left on the pooled connection by query()
+                conn.progress_handler(0, None::<fn() -> bool>);
+                read(conn, &tx).map_err(|err| err.to_string())
+            });
+        if let Err(err) = result {
+            log::error!("Error streaming rows: {}", err);
+            let _ = tx.blocking_send(Err(err.into()));
+        }
+    });
+    stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) })
+}
+
+/// GET: (Show) /api/locations/{id}/timestamps/{start}/{end}   Accept: application/x-ndjson
+/// Same rows as Query::ShowLocationTimestampsRange, streamed instead of collected
+pub fn stream_location_timestamps_range(pool: &Pool, id: usize, start: NaiveDate, end: NaiveDate) -> impl Stream<Item = StreamItem<TimeStamp>> {
+    stream_rows(pool, move |conn, tx| {
+        let (from, to) = day_bounds(start, end);
+        let mut stmt = conn.prepare(
+            "SELECT * FROM timestamps WHERE location = ?1 AND ts >= ?2 AND ts < ?3 AND voided_at IS NULL ORDER BY ts, id",
+        )?;
+        let rows = stmt.query_map(params![&id, &from, &to], |row| {
+            Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        for ts in rows {
+            if tx.blocking_send(Ok(ts?)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// GET: /api/timestamps/export.csv
+/// Every non-voided scan of the facility from `start` to `end`, at one location when given,
+/// in time order
+pub fn stream_timestamps_range(pool: &Pool, facility: Facility, start: NaiveDate, end: NaiveDate, location: Option<usize>) -> impl Stream<Item = StreamItem<TimeStamp>> {
+    stream_rows(pool, move |conn, tx| {
+        let (from, to) = day_bounds(start, end);
+        let mut stmt = conn.prepare(
+            "SELECT * FROM timestamps WHERE ts >= ?1 AND ts < ?2 AND voided_at IS NULL
+             AND location IN (SELECT id FROM locations WHERE facility_id = ?3)
+             AND (?4 IS NULL OR location = ?4) ORDER BY ts, id",
+        )?;
+        let rows = stmt.query_map(params![&from, &to, &facility, &location], |row| {
+            Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        for ts in rows {
+            if tx.blocking_send(Ok(ts?)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Brings the schema up to date, safe to run against a database at any earlier state. It all
+/// happens in one transaction, so a failure leaves the schema as it was rather than half built.
+pub fn migrations(mut conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
 
-    let _ = conn.execute(
+    tx.execute(
         "CREATE TABLE IF NOT EXISTS locations (
                 id INTEGER PRIMARY KEY NOT NULL,
                 name TEXT NOT NULL,
                 UNIQUE (id, name)
             )",
         params![],
-    );
+    )?;
+    // databases created before locations had a capacity
+    if !has_column(&tx, "locations", "capacity")? {
+        tx.execute("ALTER TABLE locations ADD COLUMN capacity INTEGER", params![])?;
+    }
+    // and before they had a status, every existing location starts out open
+    if !has_column(&tx, "locations", "status")? {
+        tx.execute("ALTER TABLE locations ADD COLUMN status TEXT NOT NULL DEFAULT 'open'", params![])?;
+    }
+    // and before deletes were soft, a set deleted_at hides the location but keeps its scans resolvable
+    if !has_column(&tx, "locations", "deleted_at")? {
+        tx.execute("ALTER TABLE locations ADD COLUMN deleted_at DATETIME", params![])?;
+    }
+    // and before facilities, everything already there belongs to the default one
+    add_facility_column(&tx, "locations")?;
+    // and before they could be nested, every existing location is a top level one
+    if !has_column(&tx, "locations", "parent_id")? {
+        tx.execute("ALTER TABLE locations ADD COLUMN parent_id INTEGER REFERENCES locations (id)", params![])?;
+    }
+    // and before they had a place on the floor plan, none of them has coordinates
+    for column in ["x", "y"] {
+        if !has_column(&tx, "locations", column)? {
+            tx.execute(&format!("ALTER TABLE locations ADD COLUMN {} INTEGER", column), params![])?;
+        }
+    }
+    // and before edits were versioned, every existing location starts at the first version
+    if !has_column(&tx, "locations", "version")? {
+        tx.execute("ALTER TABLE locations ADD COLUMN version INTEGER NOT NULL DEFAULT 1", params![])?;
+    }
+    // names are unique regardless of case among a facility's locations that aren't deleted, a
+    // database that already has two "Medical" locations has to have one renamed before it can be upgraded
+    let duplicates = duplicate_location_names(&tx)?;
+    if !duplicates.is_empty() {
+        return Err(format!("locations share a name, rename or merge them first: {}", duplicates.join(", ")).into());
+    }
+    tx.execute("DROP INDEX IF EXISTS idx_locations_name", params![])?;
+    tx.execute("DROP INDEX IF EXISTS idx_locations_active_name", params![])?;
+    tx.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_locations_facility_name ON locations (facility_id, name COLLATE NOCASE)
+         WHERE deleted_at IS NULL",
+        params![],
+    )?;
     log::info!("Created locations table");
 
-    let _ = conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS residents (
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS residents (
                 rfid             TEXT PRIMARY KEY NOT NULL,
                 name             TEXT NOT NULL,
                 doc              TEXT NOT NULL,
@@ -179,14 +891,17 @@ pub fn migrations(conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
                 FOREIGN KEY (current_location) REFERENCES locations (id)
                 UNIQUE (rfid, name, doc)
                 );",
-            params![],
-        )
-        .unwrap();
-
+        params![],
+    )?;
+    add_facility_column(&tx, "residents")?;
+    // and before residents could leave, everyone already there is still active
+    if !has_column(&tx, "residents", "status")? {
+        tx.execute("ALTER TABLE residents ADD COLUMN status TEXT NOT NULL DEFAULT 'active'", params![])?;
+    }
     log::info!("Created residents table");
-    let _ = conn
-        .execute(
-            "CREATE TABLE IF NOT EXISTS timestamps (
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS timestamps (
                     id            INTEGER PRIMARY KEY AUTOINCREMENT,
                     rfid          TEXT NOT NULL,
                     location      INTEGER NOT NULL,
@@ -194,25 +909,133 @@ pub fn migrations(conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
                     FOREIGN KEY (rfid) REFERENCES residents (rfid),
                     FOREIGN KEY (location) REFERENCES locations (id)
                 );",
-            params![],
-        )
-        .unwrap();
-
+        params![],
+    )?;
     log::info!("Created timestamps table");
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS idx_timestamps_rfid_ts ON timestamps (rfid, ts)",
+        params![],
+    )?;
+    // voided scans are kept, every read skips them
+    if !has_column(&tx, "timestamps", "voided_at")? {
+        tx.execute("ALTER TABLE timestamps ADD COLUMN voided_at DATETIME", params![])?;
+    }
+    // and before over capacity scans were recorded, none of the earlier ones count as one
+    if !has_column(&tx, "timestamps", "over_capacity")? {
+        tx.execute("ALTER TABLE timestamps ADD COLUMN over_capacity INTEGER NOT NULL DEFAULT 0", params![])?;
+    }
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+                id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                name       TEXT NOT NULL UNIQUE,
+                key_hash   TEXT NOT NULL UNIQUE,
+                revoked    INTEGER NOT NULL DEFAULT 0,
+                created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        params![],
+    )?;
+    add_facility_column(&tx, "api_keys")?;
+    log::info!("Created api_keys table");
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+                id            INTEGER PRIMARY KEY AUTOINCREMENT,
+                username      TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL,
+                role          TEXT NOT NULL DEFAULT 'read_only',
+                created_at    DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        params![],
+    )?;
+    add_facility_column(&tx, "users")?;
+    log::info!("Created users table");
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+                id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor      TEXT NOT NULL,
+                role       TEXT,
+                method     TEXT NOT NULL,
+                endpoint   TEXT NOT NULL,
+                path       TEXT NOT NULL,
+                target     TEXT,
+                status     INTEGER NOT NULL,
+                after      TEXT,
+                ts         DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+        params![],
+    )?;
+    add_facility_column(&tx, "audit_log")?;
+    // and before corrections kept the record as it was
+    if !has_column(&tx, "audit_log", "before")? {
+        tx.execute("ALTER TABLE audit_log ADD COLUMN before TEXT", params![])?;
+    }
+    log::info!("Created audit_log table");
+    // a row per Idempotency-Key seen on a scan upload, status stays NULL until the first
+    // request with the key has its response
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS idempotency_keys (
+                facility_id  INTEGER NOT NULL,
+                key          TEXT NOT NULL,
+                path         TEXT NOT NULL,
+                status       INTEGER,
+                content_type TEXT,
+                location     TEXT,
+                body         BLOB,
+                created_at   DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (facility_id, key)
+            )",
+        params![],
+    )?;
+    log::info!("Created idempotency_keys table");
+    // the primary key is the one lookup a scan makes to check it
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS restrictions (
+                rfid       TEXT NOT NULL,
+                location   INTEGER NOT NULL,
+                created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (rfid, location),
+                FOREIGN KEY (rfid) REFERENCES residents (rfid),
+                FOREIGN KEY (location) REFERENCES locations (id)
+            ) WITHOUT ROWID",
+        params![],
+    )?;
+    log::info!("Created restrictions table");
     let locations = crate::models::locations::Location::read_from_file();
 
     for loc in locations {
-        conn.execute(
+        tx.execute(
             "INSERT OR IGNORE INTO locations (id, name) VALUES (?1, ?2)",
             params![&loc.id, &loc.name],
-        )
-        .unwrap();
+        )?;
     }
     log::info!("Inserted location data");
-    conn.execute_batch("COMMIT;")?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn duplicate_location_names(conn: &rusqlite::Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM locations WHERE deleted_at IS NULL GROUP BY facility_id, name COLLATE NOCASE HAVING COUNT(*) > 1",
+    )?;
+    let names = stmt.query_map(params![], |row| row.get(0))?;
+    names.collect()
+}
+
+fn add_facility_column(conn: &rusqlite::Connection, table: &str) -> Result<()> {
+    if !has_column(conn, table, "facility_id")? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN facility_id INTEGER NOT NULL DEFAULT {}", table, DEFAULT_FACILITY),
+            params![],
+        )?;
+    }
     Ok(())
 }
 
+fn has_column(conn: &rusqlite::Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut names = stmt.query_map(params![], |row| row.get::<_, String>(1))?;
+    names.try_fold(false, |found, name| Ok(found || name? == column))
+}
+
 pub fn seed_test_data(conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
     let residents = Resident::get_test_residents_from_file();
     log::info!("SUCESS RESIDENTS");
@@ -244,6 +1067,16 @@ pub fn seed_test_data(conn: Connection) -> Result<(), Box<dyn std::error::Error>
             ],
         )?;
     }
+    for (name, key, revoked) in [("test-scanner", TEST_SCANNER_KEY, false), ("test-revoked", TEST_REVOKED_KEY, true)] {
+        conn.execute(
+            "INSERT OR IGNORE INTO api_keys (name, key_hash, revoked) VALUES (?1, ?2, ?3)",
+            params![name, ApiKey::hash(key), revoked],
+        )?;
+    }
+    conn.execute(
+        "INSERT OR IGNORE INTO users (username, password_hash, role) VALUES (?1, ?2, ?3)",
+        params![TEST_USERNAME, hash_password(TEST_PASSWORD), Role::Staff.as_str()],
+    )?;
     conn.execute_batch("COMMIT;")?; // Commit the transaction
     Ok(())
 }
@@ -252,97 +1085,180 @@ pub fn seed_test_data(conn: Connection) -> Result<(), Box<dyn std::error::Error>
 //-------------------------- RESIDENTS ---------------------------------//
 //+++++=======================++++++===================================+++++
 
-/// GET: (Index) /api/residents
-fn index_residents(conn: Connection) -> Result<Vec<Resident>, Box<dyn std::error::Error>> {
-    let mut stmt = conn.prepare("SELECT * FROM residents")?;
-    let residents_iter = stmt.query_map([], |row| {
-        Ok(Resident::new(
-            row.get(0)?,
-            row.get(1)?,
-            row.get(2)?,
-            row.get(3)?,
-            row.get(4)?,
-            row.get(5)?,
-        ))
-    })?;
+fn resident_from_row(row: &rusqlite::Row) -> rusqlite::Result<Resident> {
+    let mut resident = Resident::new(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?);
+    resident.facility_id = row.get(6)?;
+    resident.status = row.get(7)?;
+    Ok(resident)
+}
+
+/// Most residents a search returns
+pub const RESIDENT_SEARCH_LIMIT: usize = 50;
+
+/// GET: (Index) /api/residents?q=&fuzzy=
+/// Case-insensitive substring of the name or a prefix of the rfid. The best match comes first:
+/// the exact rfid, then rfid prefixes, then names starting with the term, then by name.
+/// With `fuzzy` and nothing found that way, see fuzzy_search_residents.
+#[rustfmt::skip]
+fn search_residents(facility: Facility, term: &str, fuzzy: bool, conn: Connection) -> Result<Vec<Resident>, Box<dyn std::error::Error>> {
+    let escaped = term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    let mut stmt = conn.prepare(
+        "SELECT * FROM residents
+         WHERE (name LIKE '%' || ?1 || '%' ESCAPE '\\' OR substr(rfid, 1, length(?2)) = ?2) AND facility_id = ?4
+         ORDER BY CASE
+             WHEN rfid = ?2 THEN 0
+             WHEN substr(rfid, 1, length(?2)) = ?2 THEN 1
+             WHEN name LIKE ?1 || '%' ESCAPE '\\' THEN 2
+             ELSE 3
+         END, name, rfid
+         LIMIT ?3",
+    )?;
+    let residents = stmt
+        .query_map(params![&escaped, &term, RESIDENT_SEARCH_LIMIT, &facility], resident_from_row)?
+        .collect::<Result<Vec<Resident>>>()?;
+    if residents.is_empty() && fuzzy {
+        return fuzzy_search_residents(facility, term, &conn);
+    }
+    Ok(residents)
+}
 
-    Ok(residents_iter
-        .filter_map(|res| res.is_ok().then(|| res.unwrap()))
-        .collect::<Vec<Resident>>())
+/// The facility's residents whose name is at least FUZZY_MATCH_THRESHOLD like the term, most
+/// alike first and then by name. sqlite has nothing like pg_trgm, so every name is scored here,
+/// which is why it only runs once the indexed search has come up empty.
+#[rustfmt::skip]
+fn fuzzy_search_residents(facility: Facility, term: &str, conn: &Connection) -> Result<Vec<Resident>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare("SELECT * FROM residents WHERE facility_id = ?1")?;
+    let mut scored = stmt
+        .query_map(params![&facility], resident_from_row)?
+        .map(|res| res.map(|resident| (name_similarity(term, &resident.name), resident)))
+        .filter(|res| res.as_ref().map_or(true, |(score, _)| *score >= FUZZY_MATCH_THRESHOLD))
+        .collect::<Result<Vec<(f64, Resident)>>>()?;
+    scored.sort_by(|(a_score, a), (b_score, b)| {
+        b_score.total_cmp(a_score).then_with(|| (&a.name, &a.rfid).cmp(&(&b.name, &b.rfid)))
+    });
+    Ok(scored.into_iter().take(RESIDENT_SEARCH_LIMIT).map(|(_, resident)| resident).collect())
 }
 
 /// GET: (Show) /api/residents/{id}
-fn show_resident(id: &str, conn: Connection) -> Result<Resident, Box<dyn std::error::Error>> {
+fn show_resident(id: &str, conn: Connection) -> Result<Option<Resident>, Box<dyn std::error::Error>> {
     let mut stmt = conn.prepare("SELECT * FROM residents WHERE rfid = ?1")?;
-    if let Ok(resident) = stmt.query_row(params![id], |row| {
-        Ok(Resident::new(
-            row.get(0)?,
-            row.get(1)?,
-            row.get(2)?,
-            row.get(3)?,
-            row.get(4)?,
-            row.get(5)?,
-        ))
-    }) {
-        Ok(resident)
-    } else {
-        Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
-    }
+    Ok(stmt.query_row(params![id], resident_from_row).optional()?)
 }
 
 /// POST: (Store) /api/residents/{resident}
 #[rustfmt::skip]
-fn store_resident(resident: &Resident, conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
+fn store_resident(resident: &Resident, mut conn: Connection) -> Result<Option<String>, Box<dyn std::error::Error>> {
     log::info!("Storing resident: {:?}", resident);
-    let query = "INSERT OR IGNORE INTO residents (rfid, name, doc, room, unit, current_location) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
-    let mut stmt = conn.prepare(query)?;
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    if let Some(column) = taken_resident_field(resident, true, &tx)? {
+        return Ok(Some(column));
+    }
+    tx.execute(
+        "INSERT INTO residents (rfid, name, doc, room, unit, current_location, facility_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            &resident.rfid,
+            &resident.name,
+            &resident.doc,
+            &resident.room,
+            &resident.unit,
+            &resident.current_location,
+            &resident.facility_id,
+        ],
+    )?;
+    tx.commit()?;
+    Ok(None)
+}
+
+/// `doc` when another resident of the same facility has the doc number, or for a `new`
+/// resident `rfid` when one already has the rfid, the two a resident can't share. Every path
+/// that stores or changes a resident checks here.
+fn taken_resident_field(resident: &Resident, new: bool, tx: &rusqlite::Transaction) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let taken = tx
+        .prepare_cached(
+            "SELECT 'rfid' FROM residents WHERE ?4 AND rfid = ?1
+             UNION ALL SELECT 'doc' FROM residents WHERE doc = ?2 AND facility_id = ?3 AND rfid <> ?1 LIMIT 1",
+        )?
+        .query_row(params![&resident.rfid, &resident.doc, &resident.facility_id, &new], |row| row.get(0))
+        .optional()?;
+    Ok(taken)
+}
 
-    match stmt.execute(params![
-        &resident.rfid,
-        &resident.name,
-        &resident.doc,
-        &resident.room,
-        &resident.unit,
-        &resident.current_location,
-    ]) {
-        Ok(_) => Ok(()),
-        Err(err) => {
-            log::error!("Error executing SQL query: {}", err);
-            Err(err.into())
+/// POST: (Store) /api/residents/import
+/// One transaction for the whole set. A resident whose rfid is already stored, or whose doc
+/// number is already a resident's of the same facility, is skipped as a duplicate, which
+/// includes an earlier row of the same import. Any failure rolls everything back.
+#[rustfmt::skip]
+fn store_residents_batch(residents: &[Resident], mut conn: Connection) -> Result<Vec<std::result::Result<(), String>>, Box<dyn std::error::Error>> {
+    log::info!("Storing {} residents", residents.len());
+    let tx = conn.transaction()?;
+    let mut results = Vec::with_capacity(residents.len());
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO residents (rfid, name, doc, room, unit, current_location, facility_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for resident in residents {
+            if let Some(column) = taken_resident_field(resident, true, &tx)? {
+                results.push(Err(column));
+                continue;
+            }
+            insert.execute(params![
+                &resident.rfid,
+                &resident.name,
+                &resident.doc,
+                &resident.room,
+                &resident.unit,
+                &resident.current_location,
+                &resident.facility_id,
+            ])?;
+            results.push(Ok(()));
         }
     }
+    tx.commit()?;
+    Ok(results)
 }
 
 /// PUT: (Update) /api/residents/{id}
+/// Some with the column when the change would give the resident another's doc number
 #[rustfmt::skip]
-  fn update_resident(resident: &Resident, conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let mut stmt =
-            conn.prepare("UPDATE residents SET name = ?2, doc = ?3, room = ?4, unit = ?5, current_location = ?6 WHERE rfid = ?1")?;
-        stmt.execute(params![
-            &resident.rfid,
-            &resident.name,
-            &resident.doc,
-            &resident.room,
-            &resident.unit,
-            &resident.current_location
-        ])?;
-        Ok(())
+  fn update_resident(resident: &Resident, mut conn: Connection) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+        if let Some(column) = taken_resident_field(resident, false, &tx)? {
+            return Ok(Some(column));
+        }
+        tx.execute(
+            "UPDATE residents SET name = ?2, doc = ?3, room = ?4, unit = ?5, current_location = ?6 WHERE rfid = ?1",
+            params![
+                &resident.rfid,
+                &resident.name,
+                &resident.doc,
+                &resident.room,
+                &resident.unit,
+                &resident.current_location
+            ],
+        )?;
+        tx.commit()?;
+        Ok(None)
     }
 
 #[rustfmt::skip]
     /// DELETE: (Destroy) /api/residents/{id}
- fn delete_resident(id: &str, conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
-        let mut stmt = conn.prepare("DELETE FROM residents WHERE rfid = ?1")?;
-        stmt.execute(params![id])?;
+    /// The resident's restrictions go with them in one transaction, so none are left behind to
+    /// flag the scans of whoever gets the rfid next
+ fn delete_resident(id: &str, mut conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM restrictions WHERE rfid = ?1", params![id])?;
+        tx.execute("DELETE FROM residents WHERE rfid = ?1", params![id])?;
+        tx.commit()?;
         Ok(())
     }
 
 /// GET: (Update) /api/residents/{id}/timestamps/       DEFAULT: TODAY
     #[rustfmt::skip]
  fn show_resident_timestamps(rfid: String, conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
+        let (from, to) = day_bounds(facility_today(), facility_today());
         let mut stmt = conn
-            .prepare("SELECT * FROM timestamps WHERE rfid = ?1 AND DATE(ts) = DATE('now')")?;
-        let last_iter = stmt.query_map(params![&rfid.clone()], |row| {
+            .prepare("SELECT * FROM timestamps WHERE rfid = ?1 AND ts >= ?2 AND ts < ?3 AND voided_at IS NULL")?;
+        let last_iter = stmt.query_map(params![&rfid, &from, &to], |row| {
             Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
         })?;
         Ok(last_iter.map(|ts| ts.unwrap()).collect::<Vec<TimeStamp>>())
@@ -350,168 +1266,1520 @@ fn store_resident(resident: &Resident, conn: Connection) -> Result<(), Box<dyn s
 
 #[rustfmt::skip]
 fn show_resident_timestamps_range(rfid: &str, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
-    let start_date = start.format("%Y-%m-%d").to_string();
-    let end_date = end.format("%Y-%m-%d").to_string();
+    let (from, to) = day_bounds(*start, *end);
     let mut stmt = conn
-        .prepare("SELECT * FROM timestamps WHERE rfid = ?1 AND DATE(ts) BETWEEN DATE(?2) AND DATE(?3)")?;
-    let last_iter = stmt.query_map(params![&rfid, &start_date, &end_date], |row| {
+        .prepare("SELECT * FROM timestamps WHERE rfid = ?1 AND ts >= ?2 AND ts < ?3 AND voided_at IS NULL")?;
+    let last_iter = stmt.query_map(params![&rfid, &from, &to], |row| {
         Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
     })?;
     Ok(last_iter.map(|ts| ts.unwrap()).collect::<Vec<TimeStamp>>())
 }
 
-// +++++========================+++++==========================================++++++
-/// ----------------------------- TIMESTAMPS ---------------------------------///
+/// GET: (Show) /api/residents/{id}/movements/{start}/{end}
+#[rustfmt::skip]
+fn show_resident_movements(rfid: &str, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<Movement>, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*start, *end);
+    let mut stmt = conn.prepare(
+        "SELECT t.rfid, t.location, l.name, t.ts FROM timestamps t
+         LEFT JOIN locations l ON l.id = t.location
+         WHERE t.rfid = ?1 AND t.ts >= ?2 AND t.ts < ?3 AND t.voided_at IS NULL
+         ORDER BY t.ts, t.id",
+    )?;
+    let movements_iter = stmt.query_map(params![&rfid, &from, &to], |row| {
+        Ok(Movement {
+            rfid: row.get(0)?,
+            location: row.get(1)?,
+            location_name: row.get(2)?,
+            time: row.get(3)?,
+        })
+    })?;
+    Ok(movements_iter.collect::<Result<Vec<Movement>>>()?)
+}
 
-/// GET: (Index) /api/timestamps/
+/// GET: (Show) /api/residents/{id}/locations/{start}/{end}
+/// One row per location the resident scanned at, in the order they first got there
 #[rustfmt::skip]
-fn index_timestamps(conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
-    let mut stmt = conn.prepare("SELECT * FROM timestamps WHERE DATE(ts) = DATE('now')")?;
-    let timestamps = stmt.query_map([], |row| {
-        Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+fn resident_distinct_locations(rfid: &str, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<VisitedLocation>, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*start, *end);
+    let mut stmt = conn.prepare(
+        "SELECT t.location, l.name, MIN(t.ts), MAX(t.ts), COUNT(*) FROM timestamps t
+         LEFT JOIN locations l ON l.id = t.location
+         WHERE t.rfid = ?1 AND t.ts >= ?2 AND t.ts < ?3 AND t.voided_at IS NULL
+         GROUP BY t.location
+         ORDER BY MIN(t.ts), t.location",
+    )?;
+    let rows = stmt.query_map(params![&rfid, &from, &to], |row| {
+        Ok(VisitedLocation {
+            location: row.get(0)?,
+            location_name: row.get(1)?,
+            first_seen: row.get(2)?,
+            last_seen: row.get(3)?,
+            scans: row.get(4)?,
+        })
     })?;
-    Ok(timestamps
-        .filter(|ts| ts.as_ref().is_ok())
-        .map(|x| x.unwrap())
-        .collect::<Vec<TimeStamp>>())
+    Ok(rows.collect::<Result<Vec<VisitedLocation>>>()?)
 }
 
-/// GET: (Show) /api/timestamps/{range}
-    #[rustfmt::skip]
- fn show_timestamps_range(start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
-    log::info!("Fetching timestamps between {} and {}", start, end);
-        let start = start.format("%Y-%m-%d").to_string();
-        let end = end.format("%Y-%m-%d").to_string();
-        let mut stmt = conn.prepare(
-            "SELECT * FROM timestamps WHERE DATE(ts) BETWEEN DATE(?1) AND DATE(?2)")?;
-        let timestamps_iter = stmt.query_map(params![&start, &end], |row| {
-            Ok(TimeStamp::new(
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-            ))
-        })?;
-    Ok(timestamps_iter
-        .filter_map(|ts| ts.is_ok().then(|| ts.unwrap()))
-        .collect::<Vec<TimeStamp>>())
-    }
+/// GET: (Index) /api/residents/last-seen
+/// The latest scan comes from the (rfid, ts) index one resident at a time, the LEFT JOINs
+/// keep residents who have never scanned with null location and time
+#[rustfmt::skip]
+fn last_seen_per_resident(facility: Facility, conn: Connection) -> Result<Vec<LastSeen>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.rfid, r.name, t.location, l.name, t.ts FROM residents r
+         LEFT JOIN timestamps t ON t.id = (
+             SELECT id FROM timestamps WHERE rfid = r.rfid AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1
+         )
+         LEFT JOIN locations l ON l.id = t.location
+         WHERE r.facility_id = ?1
+         ORDER BY r.name, r.rfid",
+    )?;
+    let rows = stmt.query_map(params![&facility], |row| {
+        Ok(LastSeen {
+            rfid: row.get(0)?,
+            name: row.get(1)?,
+            location: row.get(2)?,
+            location_name: row.get(3)?,
+            time: row.get(4)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<LastSeen>>>()?)
+}
 
-/// GET: (Show) /api/locations/{id}/timestamps
+/// GET: (Index) /api/residents/most-active/{start}/{end}?limit=
+/// Counted and ranked by sqlite, only the top `limit` rows come back. Most scans first, a
+/// tie goes to the lower rfid so the board doesn't reshuffle between calls.
 #[rustfmt::skip]
- fn show_timestamps_location(id: usize, conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
-        let mut stmt = conn.prepare(
-                "SELECT * FROM timestamps WHERE dest = ?1 AND DATE(ts) = DATE('now')",
-            )?;
-        let timestamps_iter = stmt.query_map(params![&id], |row| {
-            Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
-        })?;
-        Ok(timestamps_iter
-            .filter_map(|ts| ts.is_ok().then(|| ts.unwrap()))
-            .collect::<Vec<TimeStamp>>())
+fn most_active_residents(facility: Facility, start: &NaiveDate, end: &NaiveDate, limit: usize, conn: Connection) -> Result<Vec<ActiveResident>, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*start, *end);
+    let mut stmt = conn.prepare(
+        "SELECT r.rfid, r.name, COUNT(*) AS scans FROM timestamps t
+         JOIN residents r ON r.rfid = t.rfid
+         WHERE t.ts >= ?1 AND t.ts < ?2 AND t.voided_at IS NULL AND r.facility_id = ?3
+         GROUP BY r.rfid, r.name
+         ORDER BY scans DESC, r.rfid LIMIT ?4",
+    )?;
+    let rows = stmt.query_map(params![&from, &to, &facility, &limit], |row| {
+        Ok(ActiveResident { rfid: row.get(0)?, name: row.get(1)?, scans: row.get(2)? })
+    })?;
+    Ok(rows.collect::<Result<Vec<ActiveResident>>>()?)
+}
+
+/// GET: (Index) /api/residents/missing?hours=
+/// Active residents whose latest scan is older than `window`, or who have never scanned. The
+/// latest scan is picked the same way as show_current_occupancy, the later id wins a tie on
+/// the time, so a transfer's in-scan is where they were last seen rather than its out-scan.
+#[rustfmt::skip]
+fn residents_not_seen_since(facility: Facility, window: Duration, conn: Connection) -> Result<Vec<LastSeen>, Box<dyn std::error::Error>> {
+    let cutoff = (Utc::now().naive_utc() - window).format(TIMESTAMP_FORMAT).to_string();
+    let mut stmt = conn.prepare(
+        "SELECT r.rfid, r.name, last.location, l.name, last.ts FROM residents r
+         LEFT JOIN timestamps last ON last.id = (
+             SELECT id FROM timestamps WHERE rfid = r.rfid AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1
+         )
+         LEFT JOIN locations l ON l.id = last.location
+         WHERE (last.ts IS NULL OR last.ts < ?1) AND r.facility_id = ?2 AND r.status = 'active'
+         ORDER BY last.ts IS NOT NULL, last.ts, r.name",
+    )?;
+    let rows = stmt.query_map(params![&cutoff, &facility], |row| {
+        Ok(LastSeen {
+            rfid: row.get(0)?,
+            name: row.get(1)?,
+            location: row.get(2)?,
+            location_name: row.get(3)?,
+            time: row.get(4)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<Vec<LastSeen>>>()?)
+}
+
+/// GET: (Show) /api/residents/{rfid}/restrictions
+/// The locations the resident is barred from by id, None when there's no such resident
+#[rustfmt::skip]
+fn show_restrictions(rfid: &str, conn: &rusqlite::Connection) -> Result<Option<Vec<Restriction>>, Box<dyn std::error::Error>> {
+    let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM residents WHERE rfid = ?1)", params![rfid], |row| row.get(0))?;
+    if !exists {
+        return Ok(None);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT r.rfid, r.location, l.name, r.created_at FROM restrictions r
+         LEFT JOIN locations l ON l.id = r.location
+         WHERE r.rfid = ?1 ORDER BY r.location",
+    )?;
+    let restrictions = stmt.query_map(params![rfid], |row| {
+        Ok(Restriction { rfid: row.get(0)?, location_id: row.get(1)?, location_name: row.get(2)?, created_at: row.get(3)? })
+    })?;
+    Ok(Some(restrictions.collect::<Result<Vec<Restriction>>>()?))
+}
+
+/// POST: (Store) /api/residents/{rfid}/restrictions
+/// SIGNED_OUT can't be restricted, anyone may sign out
+#[rustfmt::skip]
+fn store_restriction(rfid: &str, location: usize, mut conn: Connection) -> Result<RestrictionOutcome, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let resident: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM residents WHERE rfid = ?1)", params![rfid], |row| row.get(0))?;
+    if !resident {
+        return Ok(RestrictionOutcome::NoResident);
+    }
+    let target: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND id != 0 AND deleted_at IS NULL)", params![location], |row| row.get(0),
+    )?;
+    if !target {
+        return Ok(RestrictionOutcome::NoLocation);
+    }
+    if tx.execute("INSERT OR IGNORE INTO restrictions (rfid, location) VALUES (?1, ?2)", params![rfid, location])? == 0 {
+        return Ok(RestrictionOutcome::AlreadyRestricted);
+    }
+    let restrictions = show_restrictions(rfid, &tx)?.unwrap_or_default();
+    tx.commit()?;
+    log::info!("Restricted {} from location {}", rfid, location);
+    Ok(RestrictionOutcome::Added(restrictions))
+}
+
+/// DELETE: (Destroy) /api/residents/{rfid}/restrictions
+/// The restrictions left, None when the resident had none at the location
+#[rustfmt::skip]
+fn delete_restriction(rfid: &str, location: usize, mut conn: Connection) -> Result<Option<Vec<Restriction>>, Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+    if tx.execute("DELETE FROM restrictions WHERE rfid = ?1 AND location = ?2", params![rfid, location])? == 0 {
+        return Ok(None);
+    }
+    let restrictions = show_restrictions(rfid, &tx)?;
+    tx.commit()?;
+    log::info!("Lifted the restriction of {} from location {}", rfid, location);
+    Ok(restrictions)
+}
+
+/// POST: (Store) /api/residents/{rfid}/status
+/// None when there's no such resident, Some(Err) naming why the change isn't allowed from
+/// the status they have, see ResidentStatus::transition. Read and written in one IMMEDIATE
+/// transaction so two changes at once can't both pass the check.
+#[rustfmt::skip]
+fn set_resident_status(rfid: &str, change: &StatusChange, mut conn: Connection) -> Result<Option<std::result::Result<Resident, String>>, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let Some(mut resident) = tx.query_row("SELECT * FROM residents WHERE rfid = ?1", params![rfid], resident_from_row).optional()? else {
+        return Ok(None);
+    };
+    if let Err(msg) = resident.status.transition(change.status, change.reactivate) {
+        return Ok(Some(Err(msg)));
+    }
+    tx.execute("UPDATE residents SET status = ?2 WHERE rfid = ?1", params![rfid, &change.status])?;
+    tx.commit()?;
+    resident.status = change.status;
+    Ok(Some(Ok(resident)))
+}
+
+/// POST: (Store) /api/residents/{rfid}/transfer
+/// An out-scan at the resident's current location and an in-scan at `to`, so replaying the
+/// timestamps moves them the same way. The IMMEDIATE transaction takes the write lock before
+/// reading where they are, a scan arriving mid-transfer waits rather than being overwritten.
+#[rustfmt::skip]
+fn transfer_resident(rfid: &str, to: usize, mut conn: Connection) -> Result<TransferOutcome, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let from = tx
+        .query_row("SELECT current_location FROM residents WHERE rfid = ?1", params![rfid], |row| row.get::<_, usize>(0))
+        .optional()?;
+    let Some(from) = from else {
+        return Ok(TransferOutcome::NoResident);
+    };
+    let target_exists: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND deleted_at IS NULL)", params![to], |row| row.get(0),
+    )?;
+    if !target_exists {
+        return Ok(TransferOutcome::NoLocation);
     }
+    if from == 0 {
+        return Ok(TransferOutcome::LocationUnknown);
+    }
+    if from == to {
+        return Ok(TransferOutcome::AlreadyThere);
+    }
+    let time = Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string();
+    let mut insert = tx.prepare_cached("INSERT INTO timestamps (rfid, location, ts) VALUES (?1, ?2, ?3)")?;
+    insert.execute(params![rfid, from, &time])?;
+    insert.execute(params![rfid, to, &time])?;
+    drop(insert);
+    tx.execute("UPDATE residents SET current_location = ?2 WHERE rfid = ?1", params![rfid, to])?;
+    tx.commit()?;
+    log::info!("Transferred {} from location {} to {}", rfid, from, to);
+    Ok(TransferOutcome::Moved(Transfer { rfid: rfid.to_string(), from_location: from, to_location: to, time }))
+}
+
+// +++++========================+++++==========================================++++++
+// ----------------------------- TIMESTAMPS ---------------------------------//
 
+/// GET: (Index) /api/timestamps
+/// GET: (Show) /api/timestamps/{start}/{end}
+/// The facility's scans from ?2 until ?3
+const FACILITY_SCANS: &str = "ts >= ?2 AND ts < ?3 AND location IN (SELECT id FROM locations WHERE facility_id = ?1)";
+
+/// GET: (Show) /api/locations/{id}/timestamps
 /// GET: (Show) /api/locations/{id}/timestamps/{start}/{end}
+/// The scans `filter` matches and `scans` narrows further, a page from timestamps_page or all
+/// of them. Unpaged they're in the order stored unless `order` is given.
 #[rustfmt::skip]
- fn show_timestamps_location_range(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<TimeStamp>, Box<dyn std::error::Error>> {
-    log::info!("Fetching timestamps between {} and {}", start, end);
-        let start = start.format("%Y-%m-%d").to_string();
-        let end = end.format("%Y-%m-%d").to_string();
-        let mut stmt = conn.prepare(
-                "SELECT * FROM timestamps WHERE dest = ?1 AND DATE(ts) BETWEEN DATE(?2) AND DATE(?3)",
-            )?;
-        let timestamps_iter = stmt.query_map(params![&id, &start, &end], |row| {
-            Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
-        })?;
-        Ok(timestamps_iter
-            .filter_map(|ts| ts.is_ok().then(|| ts.unwrap()))
-            .collect::<Vec<TimeStamp>>())
+fn list_timestamps(conn: &Connection, filter: &str, args: &[&dyn ToSql], scans: &ScanFilter, order: Option<SortOrder>, page: Option<(usize, usize)>) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    let narrowing = [
+        ("rfid = ?", scans.rfid()),
+        ("rfid IN (SELECT rfid FROM residents WHERE doc = ?)", scans.doc()),
+    ];
+    let extra = narrowing
+        .into_iter()
+        .filter_map(|(clause, value)| value.map(|value| (clause, Value::Text(value.to_string()))))
+        .collect::<Vec<_>>();
+    let filter = narrowed(filter, args.len(), &extra);
+    let mut args = args.to_vec();
+    args.extend(extra.iter().map(|(_, value)| value as &dyn ToSql));
+    if let Some((limit, offset)) = page {
+        let (timestamps, total) = timestamps_page(conn, &filter, &args, order.unwrap_or(SortOrder::Desc), limit, offset)?;
+        return Ok(QueryResult::TimeStampsPage(timestamps, total));
     }
+    let order = order.map_or(String::new(), |order| format!(" ORDER BY ts {0}, id {0}", order.as_sql()));
+    let mut stmt = conn.prepare_cached(&format!("SELECT * FROM timestamps WHERE {} AND voided_at IS NULL{}", filter, order))?;
+    let timestamps = stmt.query_map(args.as_slice(), |row| {
+        Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    Ok(QueryResult::TimeStamps(timestamps.collect::<Result<Vec<TimeStamp>>>()?))
+}
 
-/// POST: (Store) /api/timestamps/{timestamp}
-fn store_timestamp(
-    ts: &PostTimestamp,
-    conn: Connection,
-) -> Result<PostTimestamp, Box<dyn std::error::Error>> {
-    conn.execute_batch("BEGIN TRANSACTION;")?;
-    let mut stmt = conn.prepare("SELECT * FROM residents WHERE rfid = ?1")?;
-    let mut resident = stmt.query_row(params![&ts.rfid], |row| {
-        Ok(Resident::new(
-            row.get(0)?,
-            row.get(1)?,
-            row.get(2)?,
-            row.get(3)?,
-            row.get(4)?,
-            row.get(5)?,
+/// GET: (Index) /api/residents
+/// GET: (Show) /api/locations/{id}/residents
+/// The residents `filter` matches and `residents` narrows further. An order or a page sorts
+/// them by name, rfid breaking ties so pages don't overlap; a page is ascending unless asked.
+#[rustfmt::skip]
+fn list_residents(conn: &Connection, filter: &str, args: &[&dyn ToSql], residents: &ResidentFilter, order: Option<SortOrder>, page: Option<(usize, usize)>) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    let narrowing = [
+        ("doc = ?", residents.doc().map(|doc| Value::Text(doc.to_string()))),
+        ("unit = ?", residents.unit.map(|unit| Value::Integer(unit as i64))),
+    ];
+    let extra = narrowing
+        .into_iter()
+        .filter_map(|(clause, value)| value.map(|value| (clause, value)))
+        .collect::<Vec<_>>();
+    let filter = narrowed(filter, args.len(), &extra);
+    let mut args = args.to_vec();
+    args.extend(extra.iter().map(|(_, value)| value as &dyn ToSql));
+    let order = order.or(page.map(|_| SortOrder::Asc)).map_or(String::new(), |order| {
+        format!(" ORDER BY name COLLATE NOCASE {0}, rfid {0}", order.as_sql())
+    });
+    let Some((limit, offset)) = page else {
+        let mut stmt = conn.prepare_cached(&format!("SELECT * FROM residents WHERE {}{}", filter, order))?;
+        let residents = stmt.query_map(args.as_slice(), resident_from_row)?;
+        return Ok(QueryResult::Residents(residents.collect::<Result<Vec<Resident>>>()?));
+    };
+    let total = conn
+        .prepare_cached(&format!("SELECT COUNT(*) FROM residents WHERE {}", filter))?
+        .query_row(args.as_slice(), |row| row.get(0))?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT * FROM residents WHERE {}{} LIMIT ?{} OFFSET ?{}", filter, order, args.len() + 1, args.len() + 2,
+    ))?;
+    args.extend([&limit as &dyn ToSql, &offset]);
+    let residents = stmt.query_map(args.as_slice(), resident_from_row)?;
+    Ok(QueryResult::ResidentsPage(residents.collect::<Result<Vec<Resident>>>()?, total))
+}
+
+// `filter` with each clause of `extra` ANDed on, its `?` numbered after the `args` parameters
+// `filter` already has
+fn narrowed(filter: &str, args: usize, extra: &[(&str, Value)]) -> String {
+    extra.iter().enumerate().fold(filter.to_string(), |sql, (i, (clause, _))| {
+        format!("{} AND {}", sql, clause.replace('?', &format!("?{}", args + i + 1)))
+    })
+}
+
+/// GET: (Show) /api/locations/{id}/residents/{rfid}/timestamps/{start}/{end}
+/// Oldest first. A resident who never scanned at the location has no scans rather than being
+/// missing, NoLocation and NoResident are for ids that don't exist.
+#[rustfmt::skip]
+fn show_location_resident_timestamps(id: usize, rfid: &str, range: Option<(&NaiveDate, &NaiveDate)>, conn: Connection) -> Result<ResidentScansOutcome, Box<dyn std::error::Error>> {
+    let location_exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND deleted_at IS NULL)", params![&id], |row| row.get(0),
+    )?;
+    if !location_exists {
+        return Ok(ResidentScansOutcome::NoLocation);
+    }
+    let resident_exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM residents WHERE rfid = ?1)", params![rfid], |row| row.get(0),
+    )?;
+    if !resident_exists {
+        return Ok(ResidentScansOutcome::NoResident);
+    }
+    let (from, to) = range.map(|(start, end)| day_bounds(*start, *end)).unzip();
+    let mut stmt = conn.prepare(
+        "SELECT * FROM timestamps WHERE location = ?1 AND rfid = ?2 AND voided_at IS NULL
+         AND (?3 IS NULL OR ts >= ?3) AND (?4 IS NULL OR ts < ?4)
+         ORDER BY ts, id",
+    )?;
+    let scans = stmt.query_map(params![&id, rfid, &from, &to], |row| {
+        Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    Ok(ResidentScansOutcome::Scans(scans.collect::<Result<Vec<TimeStamp>>>()?))
+}
+
+/// The page of `limit` scans from `offset` that `filter` matches, by time in `order`, and how
+/// many it matches in all. `filter` is a WHERE clause numbering its parameters from ?1, `args`
+/// fill them and the LIMIT and OFFSET are bound after. Voided scans are always left out.
+/// Callers build the filter from a few fixed clauses, so both statements are cached per filter.
+#[rustfmt::skip]
+fn timestamps_page(conn: &Connection, filter: &str, args: &[&dyn ToSql], order: SortOrder, limit: usize, offset: usize) -> Result<(Vec<TimeStamp>, usize)> {
+    let total = conn
+        .prepare_cached(&format!("SELECT COUNT(*) FROM timestamps WHERE {} AND voided_at IS NULL", filter))?
+        .query_row(args, |row| row.get(0))?;
+    let mut page_args = args.to_vec();
+    page_args.extend([&limit as &dyn ToSql, &offset]);
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT * FROM timestamps WHERE {0} AND voided_at IS NULL ORDER BY ts {3}, id {3} LIMIT ?{1} OFFSET ?{2}",
+        filter, args.len() + 1, args.len() + 2, order.as_sql(),
+    ))?;
+    let timestamps = stmt.query_map(page_args.as_slice(), |row| {
+        Ok(TimeStamp::new(row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    Ok((timestamps.collect::<Result<Vec<TimeStamp>>>()?, total))
+}
+
+/// A resident's location going into a window, and their (location, time) scans within it
+type ScanHistory = (Option<usize>, Vec<(usize, NaiveDateTime)>);
+
+// the facility's local days from start to end inclusive, in UTC like the scans, but an
+// interval can't be open past the present. Scan times are whole seconds and the end is
+// exclusive, so the present rounds up to take in a scan made this second
+fn report_window(start: &NaiveDate, end: &NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+    let window_start = local_midnight(*start).naive_utc();
+    let window_end = local_midnight(*end + Duration::days(1))
+        .naive_utc()
+        .min(Utc::now().naive_utc().with_nanosecond(0).unwrap_or_default() + Duration::seconds(1));
+    (window_start, window_end)
+}
+
+/// Each resident's location going into the window and their scans during it, for everyone
+/// who was at the location at the start or scanned there during it
+#[rustfmt::skip]
+fn location_history(id: usize, window_start: NaiveDateTime, window_end: NaiveDateTime, conn: &Connection) -> Result<BTreeMap<String, ScanHistory>, Box<dyn std::error::Error>> {
+    let (from, to) = (
+        window_start.format(TIMESTAMP_FORMAT).to_string(),
+        window_end.format(TIMESTAMP_FORMAT).to_string(),
+    );
+    // every resident who was here going into the window or scanned here during it,
+    // along with all of their scans in the window so moves elsewhere close the interval
+    let mut stmt = conn.prepare(
+        "WITH prior AS (
+            SELECT r.rfid, (
+                SELECT p.location FROM timestamps p WHERE p.rfid = r.rfid AND p.ts < ?1 AND p.voided_at IS NULL
+                ORDER BY p.ts DESC, p.id DESC LIMIT 1
+            ) AS location
+            FROM (SELECT DISTINCT rfid FROM timestamps WHERE voided_at IS NULL) r
+        ),
+        candidates AS (
+            SELECT rfid FROM prior WHERE location = ?3
+            UNION
+            SELECT rfid FROM timestamps WHERE location = ?3 AND ts >= ?1 AND ts < ?2 AND voided_at IS NULL
+        )
+        SELECT c.rfid, p.location, t.location, t.ts FROM candidates c
+        LEFT JOIN prior p ON p.rfid = c.rfid
+        LEFT JOIN timestamps t ON t.rfid = c.rfid AND t.ts >= ?1 AND t.ts < ?2 AND t.voided_at IS NULL
+        ORDER BY c.rfid, t.ts, t.id",
+    )?;
+    let rows = stmt.query_map(params![&from, &to, &id], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, Option<usize>>(1)?,
+            row.get::<_, Option<usize>>(2)?,
+            row.get::<_, Option<String>>(3)?,
         ))
     })?;
-    resident.update_location(ts.location);
-    let mut update_stmt =
-        conn.prepare("UPDATE residents SET name = ?2, doc = ?3, room = ?4, unit = ?5, current_location = ?6 WHERE rfid = ?1")?;
-    update_stmt.execute(params![
-        &resident.rfid,
-        &resident.name,
-        &resident.doc,
-        &resident.room,
-        &resident.unit,
-        &resident.current_location
-    ])?;
+    let mut history: BTreeMap<String, ScanHistory> = BTreeMap::new();
+    for row in rows {
+        let (rfid, prior, location, time) = row?;
+        let entry = history.entry(rfid).or_insert((prior, Vec::new()));
+        if let (Some(location), Some(time)) = (location, time) {
+            if let Ok(time) = NaiveDateTime::parse_from_str(&time, TIMESTAMP_FORMAT) {
+                entry.1.push((location, time));
+            }
+        }
+    }
+    Ok(history)
+}
+
+/// GET: (Show) /api/locations/{id}/dwell/{start}/{end}
+/// Total seconds each resident spent at the location, see models::timestamps::dwell_seconds
+#[rustfmt::skip]
+fn show_location_dwell(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<BTreeMap<String, i64>, Box<dyn std::error::Error>> {
+    let (window_start, window_end) = report_window(start, end);
+    Ok(location_history(id, window_start, window_end, &conn)?
+        .into_iter()
+        .map(|(rfid, (prior, scans))| {
+            (rfid, dwell_seconds(id, prior, &scans, window_start, window_end))
+        })
+        .collect())
+}
+
+/// GET: (Show) /api/locations/{id}/visits/{start}/{end}
+/// Every resident's stays at the location, in the order they arrived, see models::timestamps::visit_intervals
+#[rustfmt::skip]
+fn location_visits(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<Visit>, Box<dyn std::error::Error>> {
+    let window = report_window(start, end);
+    let now = Utc::now().naive_utc();
+    let mut visits: Vec<Visit> = location_history(id, window.0, window.1, &conn)?
+        .into_iter()
+        .flat_map(|(rfid, (prior, scans))| visit_intervals(id, &rfid, prior, &scans, window, now))
+        .collect();
+    visits.sort_by(|a, b| (&a.arrived, &a.rfid).cmp(&(&b.arrived, &b.rfid)));
+    Ok(visits)
+}
+
+/// GET: (Show) /api/locations/{id}/peak/{start}/{end}
+/// The most residents at the location at once, see models::timestamps::peak_occupancy
+#[rustfmt::skip]
+fn show_peak_occupancy(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<PeakOccupancy, Box<dyn std::error::Error>> {
+    let (window_start, window_end) = report_window(start, end);
+    let mut present = 0;
+    let mut changes = Vec::new();
+    for (prior, scans) in location_history(id, window_start, window_end, &conn)?.into_values() {
+        if prior == Some(id) {
+            present += 1;
+        }
+        changes.extend(presence_changes(id, prior, &scans));
+    }
+    Ok(peak_occupancy(present, changes, window_start))
+}
+
+/// GET: (Show) /api/locations/{id}/occupancy/hourly/{start}/{end}
+/// Scans are stored in UTC, so the range is the facility's local days converted to UTC and
+/// each scan is counted in whole hours since the local start of the range
+#[rustfmt::skip]
+fn occupancy_by_hour(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<HourlyCount>, Box<dyn std::error::Error>> {
+    let from = local_midnight(*start);
+    let to = local_midnight(*end + Duration::days(1));
     let mut stmt = conn.prepare(
-        "INSERT INTO timestamps (rfid, location)
-                  VALUES (?1, ?2)",
+        "SELECT (strftime('%s', ts) - ?2) / 3600, COUNT(*) FROM timestamps
+         WHERE location = ?1 AND ts >= ?3 AND ts < ?4 AND voided_at IS NULL
+         GROUP BY 1",
+    )?;
+    let rows = stmt.query_map(
+        params![&id, from.timestamp(), from.format(TIMESTAMP_FORMAT).to_string(), to.format(TIMESTAMP_FORMAT).to_string()],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, usize>(1)?)),
     )?;
-    let _ = stmt.insert(params![&ts.rfid, &ts.location])?;
-    conn.execute_batch("COMMIT;")?;
-    let timestamp = PostTimestamp::new(ts.rfid.clone(), resident.current_location);
+    let counts = rows.collect::<Result<BTreeMap<i64, usize>>>()?;
+    Ok(hourly_counts(*start, *end, &counts))
+}
+
+/// GET: (Show) /api/locations/{id}/scans/daily/{start}/{end}
+/// sqlite only knows UTC, so scans are counted per UTC minute there and each minute is put
+/// in its local day here. Every offset is whole minutes, so no minute straddles two days.
+#[rustfmt::skip]
+fn daily_scan_counts(id: usize, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<DailyCount>, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*start, *end);
+    let mut stmt = conn.prepare(
+        "SELECT strftime('%Y-%m-%d %H:%M:00', ts), COUNT(*) FROM timestamps
+         WHERE location = ?1 AND ts >= ?2 AND ts < ?3 AND voided_at IS NULL
+         GROUP BY 1",
+    )?;
+    let rows = stmt.query_map(params![&id, &from, &to], |row| Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?)))?;
+    let mut counts = BTreeMap::new();
+    for row in rows {
+        let (minute, count) = row?;
+        let day = Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(&minute, TIMESTAMP_FORMAT)?)
+            .with_timezone(&config().facility_tz)
+            .format("%Y-%m-%d")
+            .to_string();
+        *counts.entry(day).or_insert(0) += count;
+    }
+    Ok(daily_counts(*start, *end, &counts))
+}
+
+/// GET: /api/reports/counts/export
+/// Counted per UTC minute and location like daily_scan_counts, then put in local days
+#[rustfmt::skip]
+fn daily_counts_by_location(facility: Facility, start: &NaiveDate, end: &NaiveDate, conn: Connection) -> Result<Vec<LocationDailyCount>, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*start, *end);
+    let mut stmt = conn.prepare(
+        "SELECT strftime('%Y-%m-%d %H:%M:00', t.ts), l.id, l.name, COUNT(*) FROM timestamps t
+         JOIN locations l ON l.id = t.location
+         WHERE l.facility_id = ?1 AND t.ts >= ?2 AND t.ts < ?3 AND t.voided_at IS NULL
+         GROUP BY 1, 2",
+    )?;
+    let rows = stmt.query_map(params![&facility, &from, &to], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?, row.get::<_, String>(2)?, row.get::<_, usize>(3)?))
+    })?;
+    let mut counts: BTreeMap<(String, usize), (String, usize)> = BTreeMap::new();
+    for row in rows {
+        let (minute, id, name, count) = row?;
+        let day = Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(&minute, TIMESTAMP_FORMAT)?)
+            .with_timezone(&config().facility_tz)
+            .format("%Y-%m-%d")
+            .to_string();
+        counts.entry((day, id)).or_insert((name, 0)).1 += count;
+    }
+    Ok(counts
+        .into_iter()
+        .map(|((day, location_id), (location_name, scans))| LocationDailyCount { day, location_id, location_name, scans })
+        .collect())
+}
+
+/// GET: /api/summary/today
+/// The scans counted are those of the facility's locations from the local midnight starting
+/// `date` to the next, whichever part is asked for
+#[rustfmt::skip]
+fn today_summary(facility: Facility, date: &NaiveDate, part: SummaryPart, conn: Connection) -> Result<QueryResult, Box<dyn std::error::Error>> {
+    let (from, to) = day_bounds(*date, *date);
+    let scans = "FROM timestamps t JOIN locations l ON l.id = t.location
+                 WHERE l.facility_id = ?1 AND t.ts >= ?2 AND t.ts < ?3 AND t.voided_at IS NULL";
+    let count = |select: &str| -> Result<QueryResult, Box<dyn std::error::Error>> {
+        let sql = format!("SELECT {} {}", select, scans);
+        Ok(QueryResult::Count(conn.query_row(&sql, params![&facility, &from, &to], |row| row.get(0))?))
+    };
+    match part {
+        SummaryPart::TotalScans => count("COUNT(*)"),
+        SummaryPart::ResidentsMoved => count("COUNT(DISTINCT t.rfid)"),
+        SummaryPart::OverCapacityEvents => count("COALESCE(SUM(t.over_capacity), 0)"),
+        SummaryPart::BusiestLocation => {
+            let sql = format!("SELECT l.id, l.name, COUNT(*) {} GROUP BY l.id ORDER BY COUNT(*) DESC, l.id LIMIT 1", scans);
+            let busiest = conn
+                .query_row(&sql, params![&facility, &from, &to], |row| {
+                    Ok(BusiestLocation { id: row.get(0)?, name: row.get(1)?, scans: row.get(2)? })
+                })
+                .optional()?;
+            Ok(QueryResult::Busiest(busiest))
+        }
+    }
+}
+
+/// POST: (Store) /api/timestamps/{timestamp}
+/// POST: (Store) /api/locations/{id}/timestamps
+/// Returns None when the rfid doesn't belong to a resident. The IMMEDIATE transaction takes
+/// the write lock before reading where the resident is, so two scanners recording them at once
+/// are applied one after the other instead of both moving them from the same place.
+#[rustfmt::skip]
+fn store_timestamp(ts: &PostTimestamp, mut conn: Connection) -> Result<Option<PostTimestamp>, Box<dyn std::error::Error>> {
+    // dropping the transaction without committing rolls it back, so an early return is safe
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let timestamp = record_scan(ts, &tx)?;
+    tx.commit()?;
     Ok(timestamp)
 }
 
-fn index_locations(conn: Connection) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
-    let mut stmt = conn.prepare("SELECT * FROM locations")?;
-    let locations_iter = stmt.query_map([], |row| Ok(Location::new(row.get(0)?, row.get(1)?)))?;
+/// POST: (Store) /api/locations/{id}/timestamps/batch
+/// Every scan goes through one transaction, an unknown rfid only skips that scan
+/// while a hard database error rolls back the whole batch. IMMEDIATE for the same reason
+/// as store_timestamp.
+#[rustfmt::skip]
+fn store_timestamps_batch(scans: &[PostTimestamp], mut conn: Connection) -> Result<Vec<Option<PostTimestamp>>, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let results = scans
+        .iter()
+        .map(|ts| record_scan(ts, &tx))
+        .collect::<Result<Vec<Option<PostTimestamp>>, Box<dyn std::error::Error>>>()?;
+    tx.commit()?;
+    Ok(results)
+}
+
+/// Moves the resident and inserts the timestamp, the caller owns the transaction
+#[rustfmt::skip]
+fn record_scan(ts: &PostTimestamp, tx: &rusqlite::Transaction) -> Result<Option<PostTimestamp>, Box<dyn std::error::Error>> {
+    // only the location's facility's residents scan there, anyone can sign out at SIGNED_OUT
+    let resident = tx
+        .prepare_cached(
+            "SELECT * FROM residents WHERE rfid = ?1
+             AND facility_id = COALESCE((SELECT facility_id FROM locations WHERE id = ?2 AND id != 0), facility_id)",
+        )?
+        .query_row(params![&ts.rfid, &ts.location], resident_from_row)
+        .optional()?;
+    let Some(mut resident) = resident else {
+        return Ok(None);
+    };
+    if config().scan_dedup_seconds > 0 && is_duplicate_scan(ts, tx)? {
+        log::info!("Ignoring duplicate scan: {:?}", ts);
+        return Ok(Some(PostTimestamp::duplicate_of(ts.rfid.clone(), resident.current_location)));
+    }
+    resident.update_location(ts.location);
+    tx.prepare_cached("UPDATE residents SET current_location = ?2 WHERE rfid = ?1")?
+        .execute(params![&resident.rfid, &resident.current_location])?;
+    // kept with the scan so the day's summary can count them
+    let over_capacity = resident.current_location == ts.location && is_over_capacity(ts.location, tx)?;
+    let restricted: bool = tx
+        .prepare_cached("SELECT EXISTS (SELECT 1 FROM restrictions WHERE rfid = ?1 AND location = ?2)")?
+        .query_row(params![&ts.rfid, &ts.location], |row| row.get(0))?;
+    if restricted {
+        log::warn!("Restricted scan: {} is barred from location {}", ts.rfid, ts.location);
+    }
+    tx.prepare_cached(
+        "INSERT INTO timestamps (rfid, location, ts, over_capacity)
+                  VALUES (?1, ?2, COALESCE(?3, CURRENT_TIMESTAMP), ?4)",
+    )?
+    .execute(params![&ts.rfid, &ts.location, &ts.time, &over_capacity])?;
+    let mut timestamp = PostTimestamp::new(ts.rfid.clone(), resident.current_location);
+    timestamp.id = Some(tx.last_insert_rowid() as usize);
+    timestamp.over_capacity = over_capacity;
+    timestamp.restricted = restricted;
+    Ok(Some(timestamp))
+}
+
+/// POST: (Store) /api/timestamps/backfill
+/// Each scan is stored at its own time as it is, with none of record_scan's dedup or moving
+/// of the resident: old scans arriving out of order would leave current_location wherever
+/// the last one sent happened to be. A scan whose resident or location isn't the facility's
+/// is rejected on its own, only a database failure rolls back the rest.
+#[rustfmt::skip]
+fn backfill_timestamps(facility: Facility, scans: &[PostTimestamp], mut conn: Connection) -> Result<Vec<std::result::Result<PostTimestamp, String>>, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let mut results = Vec::with_capacity(scans.len());
+    {
+        let mut resident = tx.prepare_cached("SELECT EXISTS (SELECT 1 FROM residents WHERE rfid = ?1 AND facility_id = ?2)")?;
+        // SIGNED_OUT is every facility's
+        let mut location = tx.prepare_cached("SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND (id = 0 OR facility_id = ?2))")?;
+        let mut insert = tx.prepare_cached("INSERT INTO timestamps (rfid, location, ts) VALUES (?1, ?2, ?3)")?;
+        for ts in scans {
+            if !resident.query_row(params![&ts.rfid, &facility], |row| row.get::<_, bool>(0))? {
+                results.push(Err("no resident with this rfid".to_string()));
+                continue;
+            }
+            if !location.query_row(params![&ts.location, &facility], |row| row.get::<_, bool>(0))? {
+                results.push(Err(format!("no location with id {}", ts.location)));
+                continue;
+            }
+            insert.execute(params![&ts.rfid, &ts.location, &ts.time])?;
+            let mut stored = ts.clone();
+            stored.id = Some(tx.last_insert_rowid() as usize);
+            results.push(Ok(stored));
+        }
+    }
+    tx.commit()?;
+    Ok(results)
+}
+
+/// What a correction to a recorded scan came to
+pub enum CorrectionOutcome {
+    Corrected(CorrectedTimestamp),
+    NoTimestamp,
+    // the new location doesn't exist or is deleted
+    NoLocation(usize),
+}
+
+/// GET: (Show) /api/timestamps/{id}
+fn show_timestamp(id: usize, conn: Connection) -> Result<Option<TimeStamp>> {
+    conn.query_row("SELECT rfid, location, ts FROM timestamps WHERE id = ?1 AND voided_at IS NULL", params![&id], |row| {
+        Ok(TimeStamp::new(row.get(0)?, row.get(1)?, row.get(2)?))
+    })
+    .optional()
+}
+
+/// PATCH: (Update) /api/timestamps/{id}
+/// Reads the scan, rewrites it and writes `audit` in one transaction, so the `before` handed
+/// back and recorded is exactly what was replaced. The resident's current_location is left
+/// as it is.
+#[rustfmt::skip]
+fn update_timestamp(id: usize, correction: &TimestampCorrection, audit: &AuditEntry, mut conn: Connection) -> Result<CorrectionOutcome, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let before = tx
+        .query_row("SELECT rfid, location, ts FROM timestamps WHERE id = ?1 AND voided_at IS NULL", params![&id], |row| {
+            Ok(TimeStamp::new(row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()?;
+    let Some(before) = before else {
+        return Ok(CorrectionOutcome::NoTimestamp);
+    };
+    if let Some(location) = correction.location {
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND deleted_at IS NULL)", params![&location], |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(CorrectionOutcome::NoLocation(location));
+        }
+    }
+    let after = TimeStamp::new(
+        before.rfid.clone(),
+        correction.location.unwrap_or(before.location),
+        correction.time.clone().or_else(|| before.time.clone()),
+    );
+    tx.execute("UPDATE timestamps SET location = ?2, ts = ?3 WHERE id = ?1", params![&id, &after.location, &after.time])?;
+    // the correction and its record go in together or not at all
+    let entry = AuditEntry { before: serde_json::to_value(&before).ok(), ..audit.clone() };
+    write_audit_log(&entry, &tx)?;
+    tx.commit()?;
+    log::info!("Corrected timestamp {} from {:?} to {:?}: {}", id, before, after, correction.reason);
+    Ok(CorrectionOutcome::Corrected(CorrectedTimestamp { id, before, after }))
+}
+
+/// DELETE: (Destroy) /api/timestamps/{id}
+/// Sets voided_at rather than removing the row, so the scan is still there to rebuild occupancy
+/// history from. Hands back the scan as it was, None when there's no such scan or it's already
+/// voided. `audit` is written in the same transaction. The resident's current_location is
+/// left as it is.
+#[rustfmt::skip]
+fn void_timestamp(id: usize, audit: &AuditEntry, mut conn: Connection) -> Result<Option<TimeStamp>, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let voided = tx
+        .query_row("SELECT rfid, location, ts FROM timestamps WHERE id = ?1 AND voided_at IS NULL", params![&id], |row| {
+            Ok(TimeStamp::new(row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()?;
+    if let Some(scan) = &voided {
+        let now = Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string();
+        tx.execute("UPDATE timestamps SET voided_at = ?2 WHERE id = ?1", params![&id, &now])?;
+        let entry = AuditEntry { before: serde_json::to_value(scan).ok(), ..audit.clone() };
+        write_audit_log(&entry, &tx)?;
+        tx.commit()?;
+        log::info!("Voided timestamp {}: {:?}", id, voided);
+    }
+    Ok(voided)
+}
+
+/// DELETE: /api/timestamps?before=
+/// One batch of a retention purge. The statement is its own transaction, so the write lock is
+/// let go between batches and scans keep being recorded while the purge works through the
+/// table. A scan is the facility's by its location, or by its resident at SIGNED_OUT.
+#[rustfmt::skip]
+fn delete_timestamps_before(facility: Facility, cutoff: &str, limit: usize, conn: Connection) -> Result<usize, Box<dyn std::error::Error>> {
+    Ok(conn.execute(
+        "DELETE FROM timestamps WHERE id IN (
+            SELECT id FROM timestamps WHERE ts < ?2
+            AND (location IN (SELECT id FROM locations WHERE facility_id = ?1 AND id != 0)
+                 OR (location = 0 AND rfid IN (SELECT rfid FROM residents WHERE facility_id = ?1)))
+            ORDER BY ts, id LIMIT ?3
+        )",
+        params![&facility, cutoff, &limit],
+    )?)
+}
+
+/// True when more active residents are at the location than its capacity allows. A released
+/// or transferred resident keeps their last current_location but no longer takes up a place.
+#[rustfmt::skip]
+fn is_over_capacity(location: usize, tx: &rusqlite::Transaction) -> Result<bool, Box<dyn std::error::Error>> {
+    let over = tx
+        .prepare_cached(
+            "SELECT COALESCE(
+                (SELECT COUNT(*) FROM residents WHERE current_location = ?1 AND status = 'active') > l.capacity, 0
+            ) FROM locations l WHERE l.id = ?1",
+        )?
+        .query_row(params![&location], |row| row.get(0))
+        .optional()?;
+    Ok(over.unwrap_or(false))
+}
+
+/// A scan is a duplicate when the resident's most recent scan was at the same location
+/// within SCAN_DEDUP_SECONDS of this one
+#[rustfmt::skip]
+fn is_duplicate_scan(ts: &PostTimestamp, tx: &rusqlite::Transaction) -> Result<bool, Box<dyn std::error::Error>> {
+    let duplicate = tx
+        .prepare_cached(
+            "SELECT EXISTS (
+                SELECT 1 FROM timestamps
+                WHERE id = (SELECT id FROM timestamps WHERE rfid = ?1 AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1)
+                AND location = ?2
+                AND ABS(strftime('%s', COALESCE(?3, CURRENT_TIMESTAMP)) - strftime('%s', ts)) < ?4
+            )",
+        )?
+        .query_row(params![&ts.rfid, &ts.location, &ts.time, config().scan_dedup_seconds], |row| row.get(0))?;
+    Ok(duplicate)
+}
+
+fn location_from_row(row: &rusqlite::Row) -> rusqlite::Result<Location> {
+    let mut location = Location::with_capacity(row.get(0)?, row.get(1)?, row.get(2)?).with_status(row.get(3)?);
+    location.deleted_at = row.get(4)?;
+    location.facility_id = row.get(5)?;
+    location.parent_id = row.get(6)?;
+    location.x = row.get(7)?;
+    location.y = row.get(8)?;
+    location.version = row.get(9)?;
+    Ok(location)
+}
+
+/// GET: (Index) /api/locations
+/// Each location once, by name ignoring case and then by id, so two calls over the same rows
+/// list them the same way. A row that fails to read fails the index rather than going missing.
+fn index_locations(facility: Facility, conn: Connection) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE deleted_at IS NULL AND facility_id = ?1 ORDER BY name COLLATE NOCASE, id",
+    )?;
+    let locations_iter = stmt.query_map(params![&facility], location_from_row)?;
     log::info!("Locations fetched!");
-    Ok(locations_iter
-        .filter(|loc| loc.as_ref().is_ok())
-        .map(|loc| loc.unwrap())
-        .collect::<Vec<Location>>())
+    Ok(locations_iter.collect::<Result<Vec<Location>>>()?)
+}
+
+/// GET: (Index) /api/locations?include_deleted=true
+/// In the same order as index_locations
+fn index_all_locations(facility: Facility, conn: Connection) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE facility_id = ?1 ORDER BY name COLLATE NOCASE, id",
+    )?;
+    let locations_iter = stmt.query_map(params![&facility], location_from_row)?;
+    Ok(locations_iter.collect::<Result<Vec<Location>>>()?)
+}
+
+/// GET: (Index) /api/locations?limit=&offset=
+/// Pages in the same order as index_locations
+#[rustfmt::skip]
+fn index_locations_paged(facility: Facility, limit: usize, offset: usize, include_deleted: bool, conn: Connection) -> Result<(Vec<Location>, usize), Box<dyn std::error::Error>> {
+    let total: usize = conn.query_row(
+        "SELECT COUNT(*) FROM locations WHERE (?1 OR deleted_at IS NULL) AND facility_id = ?2", params![&include_deleted, &facility], |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations WHERE (?3 OR deleted_at IS NULL) AND facility_id = ?4 ORDER BY name COLLATE NOCASE, id LIMIT ?1 OFFSET ?2",
+    )?;
+    let locations_iter = stmt.query_map(params![&limit, &offset, &include_deleted, &facility], location_from_row)?;
+    Ok((locations_iter.collect::<Result<Vec<Location>>>()?, total))
 }
 
-fn store_location(loc: &Location, conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
+/// Active residents whose latest scan puts them where their current_location says, one row each
+const PRESENT_RESIDENTS: &str = "present AS (
+    SELECT r.current_location AS location FROM residents r
+    JOIN timestamps t ON t.id = (
+        SELECT id FROM timestamps WHERE rfid = r.rfid AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1
+    )
+    WHERE t.location = r.current_location AND r.status = 'active'
+)";
+
+/// GET: (Index) /api/locations?sort=&order=
+/// The ORDER BY is built from the enums, never the request's text. Only the occupancy sort
+/// joins the present residents; ties fall back to the id so pages don't overlap.
+#[rustfmt::skip]
+fn index_locations_sorted(facility: Facility, sort: LocationSort, order: SortOrder, include_deleted: bool, page: Option<(usize, usize)>, conn: Connection) -> Result<(Vec<Location>, usize), Box<dyn std::error::Error>> {
+    let total: usize = conn.query_row(
+        "SELECT COUNT(*) FROM locations WHERE (?1 OR deleted_at IS NULL) AND facility_id = ?2", params![&include_deleted, &facility], |row| row.get(0),
+    )?;
+    let sql = match sort {
+        LocationSort::Name => format!(
+            "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+             WHERE (?1 OR deleted_at IS NULL) AND facility_id = ?2
+             ORDER BY name COLLATE NOCASE {}, id LIMIT ?3 OFFSET ?4",
+            order.as_sql(),
+        ),
+        LocationSort::Occupancy => format!(
+            "WITH {}
+            SELECT l.id, l.name, l.capacity, l.status, l.deleted_at, l.facility_id, l.parent_id, l.x, l.y, l.version FROM locations l
+            LEFT JOIN (SELECT location, COUNT(*) AS present FROM present GROUP BY location) o ON o.location = l.id
+            WHERE (?1 OR l.deleted_at IS NULL) AND l.facility_id = ?2
+            ORDER BY COALESCE(o.present, 0) {}, l.id LIMIT ?3 OFFSET ?4",
+            PRESENT_RESIDENTS, order.as_sql(),
+        ),
+    };
+    // a negative LIMIT is no limit in sqlite
+    let (limit, offset) = page.map_or((-1, 0), |(limit, offset)| (limit as i64, offset as i64));
+    let mut stmt = conn.prepare(&sql)?;
+    let locations = stmt.query_map(params![&include_deleted, &facility, &limit, &offset], location_from_row)?;
+    Ok((locations.collect::<Result<Vec<Location>>>()?, total))
+}
+
+/// GET: (Index) /api/locations?q=
+/// Case-insensitive substring match on the name, LIKE wildcards in the term are matched literally
+#[rustfmt::skip]
+fn search_locations(facility: Facility, term: &str, include_deleted: bool, conn: Connection) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
+    let escaped = term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    let mut stmt = conn.prepare(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE name LIKE '%' || ?1 || '%' ESCAPE '\\' AND (?2 OR deleted_at IS NULL) AND facility_id = ?3 ORDER BY name COLLATE NOCASE, id",
+    )?;
+    let locations_iter = stmt.query_map(params![&escaped, &include_deleted, &facility], location_from_row)?;
+    Ok(locations_iter.collect::<Result<Vec<Location>>>()?)
+}
+
+/// POST: (Store) /api/locations
+/// An id of 0 lets the database assign one. Hands back the stored location, a taken id or
+/// name is the constraint error that conflict_or turns into a Conflict
+#[rustfmt::skip]
+fn store_location(loc: &Location, conn: Connection) -> Result<Location> {
     log::info!("Storing location: {:?}", loc);
-    let mut stmt = conn.prepare("INSERT OR IGNORE INTO locations (id, name) VALUES (?1, ?2)")?;
-    if stmt.execute(params![&loc.id, &loc.name]).is_ok() {
-        Ok(())
-    } else {
-        Err(Box::new(rusqlite::Error::InvalidQuery))
+    insert_location(loc, &conn)
+}
+
+/// POST: (Store) /api/locations/import
+/// One transaction for the whole set, a location that hits a unique constraint is skipped
+/// and the rest still go in. Any other failure rolls everything back.
+#[rustfmt::skip]
+fn store_locations_batch(locations: &[Location], mut conn: Connection) -> Result<Vec<std::result::Result<Location, String>>, Box<dyn std::error::Error>> {
+    log::info!("Storing {} locations", locations.len());
+    let tx = conn.transaction()?;
+    let mut results = Vec::with_capacity(locations.len());
+    for loc in locations {
+        match insert_location(loc, &tx) {
+            Ok(location) => results.push(Ok(location)),
+            Err(err) => match unique_violation(&err) {
+                Some(column) => results.push(Err(column)),
+                None => return Err(err.into()),
+            },
+        }
+    }
+    tx.commit()?;
+    Ok(results)
+}
+
+// a 0 id has sqlite assign the next one
+fn insert_location(loc: &Location, conn: &rusqlite::Connection) -> Result<Location> {
+    let id = (loc.id != 0).then_some(loc.id);
+    let mut stmt = conn.prepare_cached("INSERT INTO locations (id, name, capacity, status, facility_id, parent_id, x, y) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?;
+    stmt.execute(params![&id, &loc.name, &loc.capacity, &loc.status, &loc.facility_id, &loc.parent_id, &loc.x, &loc.y])?;
+    let id = conn.last_insert_rowid() as usize;
+    Ok(Location::with_capacity(id, loc.name.clone(), loc.capacity)
+        .with_status(loc.status)
+        .with_facility(loc.facility_id)
+        .with_parent(loc.parent_id)
+        .with_coordinates(loc.x, loc.y))
+}
+
+/// PUT: (Update) /api/locations/{id}
+/// The status is left alone so a stale edit can't lift a lockdown, the stored row is returned.
+/// Only applies when the location is still at the version the edit was made from.
+#[rustfmt::skip]
+fn update_location(id: usize, loc: &Location, mut conn: Connection) -> Result<UpdateOutcome> {
+    log::info!("Updating location {} from version {}: {:?}", id, loc.version, loc);
+    // the version check and the write are one transaction, two PUTs of the same version can't
+    // both apply, and the row read first is the one the write replaced
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let select = "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations WHERE id = ?1";
+    let Some(before) = tx.query_row(&format!("{} AND deleted_at IS NULL", select), params![&id], location_from_row).optional()? else {
+        return Ok(UpdateOutcome::NoLocation);
+    };
+    if before.version != loc.version {
+        return Ok(UpdateOutcome::Stale(before.version));
     }
+    tx.execute(
+        "UPDATE locations SET name = ?2, capacity = ?3, parent_id = ?4, x = ?5, y = ?6, version = version + 1 WHERE id = ?1",
+        params![&id, &loc.name, &loc.capacity, &loc.parent_id, &loc.x, &loc.y],
+    )?;
+    let after = tx.query_row(select, params![&id], location_from_row)?;
+    tx.commit()?;
+    Ok(UpdateOutcome::Updated(Box::new(before), after))
+}
+
+/// Why the location can't be put inside its parent_id, None when it can or has none. The
+/// parent has to be a location of the same facility that isn't deleted, and when `id` is an
+/// existing location, neither it nor anything inside it, or the tree would loop.
+#[rustfmt::skip]
+fn parent_problem(id: Option<usize>, loc: &Location, conn: &rusqlite::Connection) -> Result<Option<String>> {
+    let Some(parent) = loc.parent_id else {
+        return Ok(None);
+    };
+    let parent_facility: Option<Facility> = conn
+        .query_row("SELECT facility_id FROM locations WHERE id = ?1 AND deleted_at IS NULL", params![&parent], |row| row.get(0))
+        .optional()?;
+    if parent_facility != Some(loc.facility_id) {
+        return Ok(Some(format!("parent_id: no location with id {}", parent)));
+    }
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    // UNION rather than UNION ALL stops at rows already seen, should the data hold a loop
+    let is_descendant: bool = conn.query_row(
+        "WITH RECURSIVE subtree(id) AS (
+            SELECT ?1
+            UNION
+            SELECT l.id FROM locations l JOIN subtree s ON l.parent_id = s.id
+        )
+        SELECT EXISTS (SELECT 1 FROM subtree WHERE id = ?2)",
+        params![&id, &parent],
+        |row| row.get(0),
+    )?;
+    Ok(is_descendant.then(|| format!("parent_id: location {} is inside location {}, it can't also contain it", parent, id)))
+}
+
+/// PUT: (Update) /api/locations/{id}/status
+#[rustfmt::skip]
+fn set_location_status(id: usize, status: LocationStatus, conn: Connection) -> Result<Option<Location>, Box<dyn std::error::Error>> {
+    log::info!("Setting location {} to {}", id, status.as_str());
+    if conn.execute("UPDATE locations SET status = ?2 WHERE id = ?1 AND deleted_at IS NULL", params![&id, &status])? == 0 {
+        return Ok(None);
+    }
+    show_location(id, &conn)
+}
+
+/// POST: /api/locations/status/batch
+/// One transaction for every id, so during a lockdown either all of the facility's named
+/// locations change or, on a database failure, none do. SIGNED_OUT is shared and never changes.
+#[rustfmt::skip]
+fn set_location_status_batch(facility: Facility, ids: &[usize], status: LocationStatus, mut conn: Connection) -> Result<StatusBatchResult, Box<dyn std::error::Error>> {
+    log::info!("Setting {} locations to {}", ids.len(), status.as_str());
+    let tx = conn.transaction()?;
+    let mut result = StatusBatchResult { status, updated: Vec::new(), not_found: Vec::new() };
+    {
+        let mut stmt = tx.prepare_cached(
+            "UPDATE locations SET status = ?2 WHERE id = ?1 AND id != 0 AND deleted_at IS NULL AND facility_id = ?3",
+        )?;
+        for &id in ids {
+            if result.updated.contains(&id) || result.not_found.contains(&id) {
+                continue;
+            }
+            match stmt.execute(params![&id, &status, &facility])? {
+                0 => result.not_found.push(id),
+                _ => result.updated.push(id),
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(result)
+}
+
+/// DELETE: (Destroy) /api/locations/{id}
+/// Only marks the location deleted, the row stays so its scans still resolve to a name
+/// None when there's no such location, or the location as it was before it was deleted
+fn delete_location(id: usize, mut conn: Connection) -> Result<Option<Location>, Box<dyn std::error::Error>> {
+    let now = Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string();
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let before = tx
+        .query_row(
+            "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+             WHERE id = ?1 AND deleted_at IS NULL",
+            params![&id],
+            location_from_row,
+        )
+        .optional()?;
+    if before.is_some() {
+        tx.execute("UPDATE locations SET deleted_at = ?2 WHERE id = ?1", params![&id, &now])?;
+        tx.commit()?;
+    }
+    Ok(before)
+}
+
+/// POST: /api/locations/{src_id}/merge/{dst_id}
+/// Re-points the source's scans, residents and child locations to the destination and
+/// soft-deletes the source, all in one IMMEDIATE transaction so a scan arriving mid-merge
+/// waits rather than landing on the half-merged source, and a failure leaves both untouched
+#[rustfmt::skip]
+fn merge_locations(source: usize, destination: usize, mut conn: Connection) -> Result<MergeOutcome, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let exists = |id: usize| -> Result<bool> {
+        tx.query_row("SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND deleted_at IS NULL)", params![&id], |row| row.get(0))
+    };
+    if !exists(source)? {
+        return Ok(MergeOutcome::NoSource);
+    }
+    if !exists(destination)? {
+        return Ok(MergeOutcome::NoDestination);
+    }
+    let nested: bool = tx.query_row(
+        "WITH RECURSIVE subtree(id) AS (
+            SELECT ?1
+            UNION
+            SELECT l.id FROM locations l JOIN subtree s ON l.parent_id = s.id
+        )
+        SELECT EXISTS (SELECT 1 FROM subtree WHERE id = ?2)",
+        params![&source, &destination],
+        |row| row.get(0),
+    )?;
+    if nested {
+        return Ok(MergeOutcome::Nested);
+    }
+    let timestamps = tx.execute("UPDATE timestamps SET location = ?2 WHERE location = ?1", params![&source, &destination])?;
+    let residents = tx.execute(
+        "UPDATE residents SET
+            unit = CASE WHEN unit = ?1 THEN ?2 ELSE unit END,
+            current_location = CASE WHEN current_location = ?1 THEN ?2 ELSE current_location END
+         WHERE unit = ?1 OR current_location = ?1",
+        params![&source, &destination],
+    )?;
+    let children = tx.execute("UPDATE locations SET parent_id = ?2 WHERE parent_id = ?1 AND deleted_at IS NULL", params![&source, &destination])?;
+    // a resident barred from the source is barred from where it went
+    tx.execute("INSERT OR IGNORE INTO restrictions (rfid, location, created_at) SELECT rfid, ?2, created_at FROM restrictions WHERE location = ?1", params![&source, &destination])?;
+    tx.execute("DELETE FROM restrictions WHERE location = ?1", params![&source])?;
+    let now = Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string();
+    tx.execute("UPDATE locations SET deleted_at = ?2 WHERE id = ?1", params![&source, &now])?;
+    tx.commit()?;
+    log::info!("Merged location {} into {}: {} scans, {} residents, {} children", source, destination, timestamps, residents, children);
+    Ok(MergeOutcome::Merged(LocationMerge { source, destination, timestamps, residents, children }))
+}
+
+/// GET: (Show) /api/locations/batch?ids=
+/// The facility's locations that aren't deleted among `ids`, by id. Any other id is left out.
+#[rustfmt::skip]
+fn show_locations_by_ids(facility: Facility, ids: &[usize], conn: &Connection) -> Result<Vec<Location>, Box<dyn std::error::Error>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let placeholders = (2..ids.len() + 2).map(|n| format!("?{}", n)).collect::<Vec<_>>().join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE facility_id = ?1 AND deleted_at IS NULL AND id IN ({}) ORDER BY id",
+        placeholders,
+    ))?;
+    let mut values: Vec<&dyn ToSql> = vec![&facility];
+    values.extend(ids.iter().map(|id| id as &dyn ToSql));
+    let locations = stmt.query_map(values.as_slice(), location_from_row)?;
+    Ok(locations.collect::<Result<Vec<Location>>>()?)
 }
 
 /// GET: (Index) /api/locations/{id}
-fn show_location(id: usize, conn: Connection) -> Result<Location, Box<dyn std::error::Error>> {
-    let mut stmt = conn.prepare("SELECT * FROM locations WHERE id = ?1 LIMIT 1")?;
-    if let Ok(res) = stmt.query_row(params![&id], |row| {
-        Ok(Location::new(row.get_unwrap(0), row.get_unwrap(1)))
-    }) {
-        Ok(res)
-    } else {
-        Err(Box::new(rusqlite::Error::QueryReturnedNoRows))
+#[rustfmt::skip]
+fn show_location(id: usize, conn: &Connection) -> Result<Option<Location>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare("SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations WHERE id = ?1 AND deleted_at IS NULL LIMIT 1")?;
+    Ok(stmt
+        .query_row(params![&id], location_from_row)
+        .optional()?)
+}
+
+/// GET: (Show) /api/locations/{id}/children
+/// The locations directly inside it, None when the location itself doesn't exist
+#[rustfmt::skip]
+fn show_location_children(id: usize, conn: Connection) -> Result<Option<Vec<Location>>, Box<dyn std::error::Error>> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM locations WHERE id = ?1 AND deleted_at IS NULL)", params![&id], |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(None);
     }
+    let mut stmt = conn.prepare(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE parent_id = ?1 AND deleted_at IS NULL ORDER BY id",
+    )?;
+    let children = stmt.query_map(params![&id], location_from_row)?;
+    Ok(Some(children.collect::<Result<Vec<Location>>>()?))
 }
 
-/// GET: (Show) /api/locations/{id}/residents
+/// Most levels an ancestor chain is followed up, far deeper than any real facility is nested
+const MAX_TREE_DEPTH: usize = 64;
+
+/// GET: (Show) /api/locations/{from_id}/path/{to_id}
+#[rustfmt::skip]
+fn location_path(from: usize, to: usize, conn: Connection) -> Result<PathOutcome, Box<dyn std::error::Error>> {
+    let from_chain = ancestor_chain(from, &conn)?;
+    if from_chain.is_empty() {
+        return Ok(PathOutcome::NoFrom);
+    }
+    let to_chain = ancestor_chain(to, &conn)?;
+    if to_chain.is_empty() {
+        return Ok(PathOutcome::NoTo);
+    }
+    Ok(route_between(from_chain, to_chain).map_or(PathOutcome::NoCommonAncestor, PathOutcome::Route))
+}
+
+/// The location and each of its ancestors in turn up to the top of its tree, empty when it
+/// doesn't exist. A deleted parent ends the chain as though the location were top level.
 #[rustfmt::skip]
-fn show_location_residents(id: usize, conn: Connection) -> Result<Vec<Resident>, Box<dyn std::error::Error>> {
-    let mut stmt = conn.prepare("SELECT * FROM residents WHERE unit = ?1")?;
-    let residents_iter = stmt.query_map(params![&id], |row| {
-        Ok(Resident::new(
-            row.get(0)?,
-            row.get(1)?,
-            row.get(2)?,
-            row.get(3)?,
-            row.get(4)?,
-            row.get(5)?,
+fn ancestor_chain(id: usize, conn: &Connection) -> Result<Vec<Location>> {
+    let mut stmt = conn.prepare_cached(
+        "WITH RECURSIVE chain(id, depth) AS (
+            SELECT id, 0 FROM locations WHERE id = ?1 AND deleted_at IS NULL
+            UNION ALL
+            SELECT parent.id, c.depth + 1 FROM chain c
+            JOIN locations l ON l.id = c.id
+            JOIN locations parent ON parent.id = l.parent_id AND parent.deleted_at IS NULL
+            WHERE c.depth < ?2
+        )
+        SELECT l.id, l.name, l.capacity, l.status, l.deleted_at, l.facility_id, l.parent_id, l.x, l.y, l.version FROM chain c
+        JOIN locations l ON l.id = c.id ORDER BY c.depth",
+    )?;
+    let chain = stmt.query_map(params![&id, &MAX_TREE_DEPTH], location_from_row)?;
+    chain.collect()
+}
+
+/// GET: (Show) /api/locations/{id}/nearby?count=
+/// The `count` locations of the origin's facility closest to it, nearest first by straight line
+/// distance on the grid and by id between equals. Locations without coordinates aren't
+/// candidates, and neither is SIGNED_OUT.
+#[rustfmt::skip]
+fn nearby_locations(origin: &Location, (x, y): (i64, i64), count: usize, conn: &Connection) -> Result<Vec<NearbyLocation>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, capacity, status, deleted_at, facility_id, parent_id, x, y, version FROM locations
+         WHERE facility_id = ?1 AND id != ?2 AND id != 0 AND deleted_at IS NULL AND x IS NOT NULL AND y IS NOT NULL
+         ORDER BY (x - ?3) * (x - ?3) + (y - ?4) * (y - ?4), id LIMIT ?5",
+    )?;
+    let nearby = stmt.query_map(params![&origin.facility_id, &origin.id, &x, &y, &count], location_from_row)?;
+    Ok(nearby
+        .map(|location| {
+            let location = location?;
+            let (dx, dy) = location.coordinates().map_or((0, 0), |(lx, ly)| (lx - x, ly - y));
+            let distance = ((dx * dx + dy * dy) as f64).sqrt();
+            Ok(NearbyLocation { location, distance })
+        })
+        .collect::<Result<Vec<NearbyLocation>>>()?)
+}
+
+/// GET: (Index) /api/locations/residents/counts
+/// Residents assigned to every location in one pass, the LEFT JOIN keeps locations with none
+#[rustfmt::skip]
+fn count_residents_per_location(facility: Facility, conn: Connection) -> Result<Vec<ResidentCount>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT l.id, l.name, COUNT(r.rfid) FROM locations l
+         LEFT JOIN residents r ON r.unit = l.id
+         WHERE l.deleted_at IS NULL AND l.facility_id = ?1
+         GROUP BY l.id, l.name ORDER BY l.id",
+    )?;
+    let counts = stmt.query_map(params![&facility], |row| {
+        Ok(ResidentCount { location_id: row.get(0)?, name: row.get(1)?, count: row.get(2)? })
+    })?;
+    Ok(counts.collect::<Result<Vec<ResidentCount>>>()?)
+}
+
+/// GET: (Index) /api/locations/reconcile
+/// Who is present counts the same as show_current_occupancy. Biggest discrepancy first, either
+/// way, then by id so locations that agree keep a stable order.
+#[rustfmt::skip]
+fn reconcile_headcount(facility: Facility, conn: Connection) -> Result<Vec<Headcount>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(&format!(
+        "WITH {}
+        SELECT l.id, l.name,
+            (SELECT COUNT(*) FROM residents r WHERE r.unit = l.id) AS expected,
+            (SELECT COUNT(*) FROM present p WHERE p.location = l.id) AS actual
+        FROM locations l
+        WHERE l.deleted_at IS NULL AND l.facility_id = ?1 AND l.id != 0
+        ORDER BY ABS(actual - expected) DESC, l.id",
+        PRESENT_RESIDENTS,
+    ))?;
+    let headcounts = stmt.query_map(params![&facility], |row| {
+        Ok(Headcount::new(row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?;
+    Ok(headcounts.collect::<Result<Vec<Headcount>>>()?)
+}
+
+/// GET: (Show) /api/locations/{id}/occupancy
+/// Active residents whose most recent scan is at this location and who weren't scanned back out of it
+#[rustfmt::skip]
+fn show_current_occupancy(id: usize, conn: Connection) -> Result<Vec<Resident>, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.* FROM residents r
+         JOIN timestamps t ON t.id = (
+             SELECT id FROM timestamps WHERE rfid = r.rfid AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1
+         )
+         WHERE t.location = ?1 AND r.current_location = ?1 AND r.status = 'active'",
+    )?;
+    let residents_iter = stmt.query_map(params![&id], resident_from_row)?;
+    Ok(residents_iter.collect::<Result<Vec<Resident>>>()?)
+}
+
+/// GET: (Show) /api/locations/{id}/occupancy/rollup
+/// Who is present counts the same as show_current_occupancy. Every location under the root's
+/// direct children is walked with the child it descends from as its branch, so one pass
+/// totals each child's subtree. None when the location doesn't exist.
+#[rustfmt::skip]
+fn occupancy_rollup(id: usize, conn: Connection) -> Result<Option<OccupancyRollup>, Box<dyn std::error::Error>> {
+    let name: Option<String> = conn
+        .query_row("SELECT name FROM locations WHERE id = ?1 AND deleted_at IS NULL", params![&id], |row| row.get(0))
+        .optional()?;
+    let Some(name) = name else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare(&format!(
+        "WITH RECURSIVE subtree(id, branch) AS (
+            SELECT id, id FROM locations WHERE parent_id = ?1 AND deleted_at IS NULL
+            UNION
+            SELECT l.id, s.branch FROM locations l JOIN subtree s ON l.parent_id = s.id
+            WHERE l.deleted_at IS NULL AND l.id != ?1
+        ),
+        {}
+        SELECT ?1, (SELECT COUNT(*) FROM present WHERE location = ?1)
+        UNION ALL
+        SELECT s.branch, COUNT(p.location) FROM subtree s
+        LEFT JOIN present p ON p.location = s.id
+        GROUP BY s.branch",
+        PRESENT_RESIDENTS,
+    ))?;
+    let counts = stmt
+        .query_map(params![&id], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, usize>(1)?)))?
+        .collect::<Result<BTreeMap<usize, usize>>>()?;
+    let present = counts.get(&id).copied().unwrap_or(0);
+    let mut stmt = conn.prepare("SELECT id, name FROM locations WHERE parent_id = ?1 AND deleted_at IS NULL ORDER BY id")?;
+    let children = stmt
+        .query_map(params![&id], |row| Ok((row.get::<_, usize>(0)?, row.get::<_, String>(1)?)))?
+        .map(|child| {
+            child.map(|(location_id, name)| ChildOccupancy { location_id, name, total: counts.get(&location_id).copied().unwrap_or(0) })
+        })
+        .collect::<Result<Vec<ChildOccupancy>>>()?;
+    let total = present + children.iter().map(|child| child.total).sum::<usize>();
+    Ok(Some(OccupancyRollup { location_id: id, name, present, total, children }))
+}
+
+/// GET: /api/snapshot?at=
+/// Each active resident is where their latest scan at or before `at` puts them, the same
+/// way location_history takes a resident's location going into a window. Residents with no
+/// scan by then and those signed out are left out, and only occupied locations are listed.
+#[rustfmt::skip]
+fn occupancy_snapshot(facility: Facility, at: &str, conn: Connection) -> Result<OccupancySnapshot, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.location, l.name, r.rfid, r.name, t.ts FROM residents r
+         JOIN timestamps t ON t.id = (
+             SELECT id FROM timestamps WHERE rfid = r.rfid AND ts <= ?2 AND voided_at IS NULL ORDER BY ts DESC, id DESC LIMIT 1
+         )
+         JOIN locations l ON l.id = t.location
+         WHERE r.facility_id = ?1 AND r.status = 'active' AND t.location != 0
+         ORDER BY t.location, r.name, r.rfid",
+    )?;
+    let rows = stmt.query_map(params![&facility, at], |row| {
+        Ok((
+            row.get::<_, usize>(0)?,
+            row.get::<_, String>(1)?,
+            SnapshotResident { rfid: row.get(2)?, name: row.get(3)?, since: row.get(4)? },
         ))
     })?;
-    Ok(residents_iter
-        .filter_map(|res| res.is_ok().then(|| res.unwrap()))
-        .collect::<Vec<Resident>>())
+    let mut locations: Vec<SnapshotLocation> = Vec::new();
+    for row in rows {
+        let (location_id, name, resident) = row?;
+        match locations.last_mut() {
+            Some(last) if last.location_id == location_id => last.residents.push(resident),
+            _ => locations.push(SnapshotLocation { location_id, name, residents: vec![resident] }),
+        }
+    }
+    Ok(OccupancySnapshot { at: at.to_string(), locations })
+}
+
+//
+//-------------------------- API KEYS ----------------------------------//
+//+++++=======================++++++===================================+++++
+
+/// X-Api-Key lookup, by the hash of the presented key
+#[rustfmt::skip]
+fn find_api_key(hash: &str, conn: Connection) -> Result<Option<ApiKey>, Box<dyn std::error::Error>> {
+    Ok(conn.query_row(
+        "SELECT name, revoked, facility_id FROM api_keys WHERE key_hash = ?1",
+        params![hash],
+        |row| Ok(ApiKey { name: row.get(0)?, revoked: row.get(1)?, facility_id: row.get(2)? }),
+    ).optional()?)
+}
+
+/// false when a key with that name already exists
+#[rustfmt::skip]
+fn store_api_key(facility: Facility, name: &str, hash: &str, conn: Connection) -> Result<bool, Box<dyn std::error::Error>> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO api_keys (name, key_hash, facility_id) VALUES (?1, ?2, ?3)",
+        params![name, hash, &facility],
+    )?;
+    Ok(inserted == 1)
+}
+
+#[rustfmt::skip]
+fn revoke_api_key(name: &str, conn: Connection) -> Result<usize, Box<dyn std::error::Error>> {
+    Ok(conn.execute("UPDATE api_keys SET revoked = 1 WHERE name = ?1", params![name])?)
+}
+
+//
+//-------------------------- USERS -------------------------------------//
+//+++++=======================++++++===================================+++++
+
+/// A role that isn't one any more is read-only, like a token without one
+#[rustfmt::skip]
+fn find_user(username: &str, conn: Connection) -> Result<Option<User>, Box<dyn std::error::Error>> {
+    Ok(conn.query_row(
+        "SELECT username, password_hash, role, facility_id FROM users WHERE username = ?1",
+        params![username],
+        |row| Ok(User {
+            username: row.get(0)?,
+            password_hash: row.get(1)?,
+            role: Role::parse(&row.get::<_, String>(2)?).unwrap_or_default(),
+            facility_id: row.get(3)?,
+        }),
+    ).optional()?)
+}
+
+/// false when a user with that name already exists
+#[rustfmt::skip]
+fn store_user(facility: Facility, username: &str, role: Role, hash: &str, conn: Connection) -> Result<bool, Box<dyn std::error::Error>> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO users (username, password_hash, role, facility_id) VALUES (?1, ?2, ?3, ?4)",
+        params![username, hash, role.as_str(), &facility],
+    )?;
+    Ok(inserted == 1)
+}
+
+//
+//-------------------------- IDEMPOTENCY KEYS --------------------------//
+//+++++=======================++++++===================================+++++
+
+/// Holds the key for a request about to upload scans, unless an earlier one already has it.
+/// Keys older than IDEMPOTENCY_TTL_SECONDS are cleared out first, so an expired key is new
+/// again, and so is one left held by a request the server never finished.
+#[rustfmt::skip]
+fn claim_idempotency_key(facility: Facility, key: &str, path: &str, mut conn: Connection) -> Result<KeyClaim, Box<dyn std::error::Error>> {
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    tx.execute(
+        "DELETE FROM idempotency_keys WHERE created_at <= datetime('now', ?1)",
+        params![format!("-{} seconds", config().idempotency_ttl_seconds)],
+    )?;
+    let existing = tx.query_row(
+        "SELECT path, status, content_type, location, body FROM idempotency_keys WHERE facility_id = ?1 AND key = ?2",
+        params![&facility, key],
+        |row| {
+            let status: Option<u16> = row.get(1)?;
+            let response = status.map(|status| -> Result<StoredResponse> {
+                Ok(StoredResponse { status, content_type: row.get(2)?, location: row.get(3)?, body: row.get(4)? })
+            });
+            Ok((row.get::<_, String>(0)?, response.transpose()?))
+        },
+    ).optional()?;
+    let claim = match existing {
+        Some((used_for, _)) if used_for != path => KeyClaim::Reused(used_for),
+        Some((_, Some(response))) => KeyClaim::Replay(response),
+        Some((_, None)) => KeyClaim::InFlight,
+        None => {
+            tx.execute(
+                "INSERT INTO idempotency_keys (facility_id, key, path) VALUES (?1, ?2, ?3)",
+                params![&facility, key, path],
+            )?;
+            KeyClaim::Claimed
+        }
+    };
+    tx.commit()?;
+    Ok(claim)
+}
+
+/// Keeps the response the request holding the key got, for its retries to be given
+#[rustfmt::skip]
+fn store_idempotent_response(facility: Facility, key: &str, response: &StoredResponse, conn: Connection) -> Result<(), Box<dyn std::error::Error>> {
+    conn.execute(
+        "UPDATE idempotency_keys SET status = ?3, content_type = ?4, location = ?5, body = ?6
+         WHERE facility_id = ?1 AND key = ?2",
+        params![&facility, key, &response.status, &response.content_type, &response.location, &response.body],
+    )?;
+    Ok(())
+}
+
+//
+//-------------------------- AUDIT LOG ---------------------------------//
+//+++++=======================++++++===================================+++++
+
+/// Written by middleware::audit after every recorded mutation, or in the transaction of a change
+/// whose handler writes its own entry
+#[rustfmt::skip]
+fn write_audit_log(entry: &AuditEntry, conn: &rusqlite::Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let before = entry.before.as_ref().map(|before| before.to_string());
+    let after = entry.after.as_ref().map(|after| after.to_string());
+    conn.execute(
+        "INSERT INTO audit_log (actor, role, method, endpoint, path, target, status, before, after, facility_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![&entry.actor, &entry.role, &entry.method, &entry.endpoint, &entry.path, &entry.target, &entry.status, &before, &after, &entry.facility_id],
+    )?;
+    Ok(())
+}
+
+/// GET: (Index) /api/audit?limit=&offset=
+/// Newest first
+#[rustfmt::skip]
+fn index_audit_log(facility: Facility, limit: usize, offset: usize, conn: Connection) -> Result<(Vec<AuditEntry>, usize), Box<dyn std::error::Error>> {
+    let total: usize = conn.query_row("SELECT COUNT(*) FROM audit_log WHERE facility_id = ?1", params![&facility], |row| row.get(0))?;
+    let mut stmt = conn.prepare(
+        "SELECT id, actor, role, method, endpoint, path, target, status, after, ts, facility_id, before FROM audit_log
+         WHERE facility_id = ?3 ORDER BY id DESC LIMIT ?1 OFFSET ?2",
+    )?;
+    let entries = stmt.query_map(params![&limit, &offset, &facility], |row| {
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            actor: row.get(1)?,
+            role: row.get(2)?,
+            method: row.get(3)?,
+            endpoint: row.get(4)?,
+            path: row.get(5)?,
+            target: row.get(6)?,
+            status: row.get(7)?,
+            after: row.get::<_, Option<String>>(8)?.and_then(|after| serde_json::from_str(&after).ok()),
+            time: row.get(9)?,
+            facility_id: row.get(10)?,
+            before: row.get::<_, Option<String>>(11)?.and_then(|before| serde_json::from_str(&before).ok()),
+        })
+    })?;
+    Ok((entries.collect::<Result<Vec<AuditEntry>>>()?, total))
+}
+
+//
+//-------------------------- FACILITIES --------------------------------//
+//+++++=======================++++++===================================+++++
+
+/// The facility a location or resident belongs to, None when there's no such record. A
+/// deleted location still has one, it isn't any other facility's to see.
+#[rustfmt::skip]
+fn facility_of(record: Record<'_>, conn: Connection) -> Result<Option<Facility>, Box<dyn std::error::Error>> {
+    let facility = match record {
+        Record::Location(id) => conn.query_row("SELECT facility_id FROM locations WHERE id = ?1", params![&id], |row| row.get(0)),
+        Record::Resident(rfid) => conn.query_row("SELECT facility_id FROM residents WHERE rfid = ?1", params![rfid], |row| row.get(0)),
+        Record::Timestamp(id) => conn.query_row(
+            "SELECT r.facility_id FROM timestamps t JOIN residents r ON r.rfid = t.rfid WHERE t.id = ?1", params![&id], |row| row.get(0),
+        ),
+    };
+    Ok(facility.optional()?)
 }
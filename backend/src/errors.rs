@@ -0,0 +1,248 @@
+use crate::database::db::{DbError, QueryResult};
+use crate::models::envelope::{Envelope, EnvelopeError, ENVELOPE_MIME};
+use actix_web::error::{JsonPayloadError, PathError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Seconds a client is told to wait before retrying a 503
+pub const RETRY_AFTER_SECONDS: u32 = 1;
+
+/// Error returned by the API handlers, rendered as `{ "error": "...", "kind": "..." }`, or
+/// inside the v2 envelope for a client that asks for it, see middleware::envelope
+#[derive(Debug)]
+pub enum ApiError {
+    NotFound(String),
+    Validation(String),
+    Db(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Unavailable(String),
+    Timeout(String),
+    MalformedJson(JsonProblem),
+    NotAcceptable(String),
+    UnsupportedMediaType(String),
+    Locked(String),
+    TooLarge(String),
+    /// The message and the seconds until the caller may try again
+    RateLimited(String, u64),
+}
+
+/// What was wrong with a JSON body, returned as the error's `details`
+#[derive(Debug, Serialize)]
+pub struct JsonProblem {
+    /// `syntax`, `eof`, `missing_field`, `unknown_field`, `wrong_type` or `invalid_value`
+    pub problem: &'static str,
+    // only known for missing and unknown fields, serde doesn't report the field of a bad value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl JsonProblem {
+    pub fn from_serde(err: &serde_json::Error) -> Self {
+        use serde_json::error::Category;
+        // serde_json appends " at line 1 column 15" to the message, it's reported separately
+        let message = err.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(at) => message[..at].to_string(),
+            None => message,
+        };
+        let problem = match err.classify() {
+            Category::Syntax | Category::Io => "syntax",
+            Category::Eof => "eof",
+            Category::Data if message.starts_with("missing field") => "missing_field",
+            Category::Data if message.starts_with("unknown field") => "unknown_field",
+            Category::Data if message.starts_with("invalid type") => "wrong_type",
+            Category::Data => "invalid_value",
+        };
+        let field = match problem {
+            "missing_field" | "unknown_field" => message.split('`').nth(1).map(str::to_string),
+            _ => None,
+        };
+        JsonProblem {
+            problem,
+            field,
+            message,
+            line: err.line(),
+            column: err.column(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: String,
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<&'a JsonProblem>,
+}
+
+/// `JsonConfig::error_handler` for every `web::Json` extractor, a body that doesn't
+/// deserialize is a 400 saying where and why and one that isn't JSON at all is a 415. Other
+/// payload errors keep actix's response.
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            ApiError::too_large(limit).into()
+        }
+        JsonPayloadError::ContentType => {
+            log::info!("Non-JSON body for {}", req.path());
+            ApiError::UnsupportedMediaType("the body must be sent as application/json".to_string()).into()
+        }
+        JsonPayloadError::Deserialize(err) => {
+            let problem = JsonProblem::from_serde(&err);
+            log::info!("Malformed JSON body for {}: {}", req.path(), err);
+            ApiError::MalformedJson(problem).into()
+        }
+        err => err.into(),
+    }
+}
+
+/// `PathConfig::error_handler` for every `web::Path` extractor, a segment that doesn't parse
+/// is a 400 in the same shape as every other error rather than actix's plain text one
+pub fn path_error_handler(err: PathError, req: &HttpRequest) -> actix_web::Error {
+    let msg = match &err {
+        PathError::Deserialize(err) => err.to_string(),
+        err => err.to_string(),
+    };
+    log::info!("Invalid path {}: {}", req.path(), msg);
+    ApiError::Validation(msg).into()
+}
+
+impl ApiError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Validation(_) => "validation",
+            ApiError::Db(_) => "database",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::MalformedJson(_) => "malformed_json",
+            ApiError::NotAcceptable(_) => "not_acceptable",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Locked(_) => "locked",
+            ApiError::TooLarge(_) => "payload_too_large",
+            ApiError::RateLimited(..) => "rate_limited",
+        }
+    }
+
+    /// A 413 for a body over `limit` bytes, see middleware::body_limit
+    pub fn too_large(limit: usize) -> Self {
+        ApiError::TooLarge(format!("the body may be at most {} bytes", limit))
+    }
+
+    /// Maps a failed or unexpected query() result, an exhausted pool becomes a retryable 503
+    pub fn from_query(res: Result<QueryResult, Box<dyn Error>>, msg: &str) -> Self {
+        Self::unavailable(&res).unwrap_or_else(|| ApiError::Db(msg.to_string()))
+    }
+
+    /// Some when query() gave up on an exhausted pool or its deadline rather than the SQL erroring
+    pub fn unavailable(res: &Result<QueryResult, Box<dyn Error>>) -> Option<Self> {
+        match res.as_ref().err()?.downcast_ref::<DbError>()? {
+            DbError::PoolExhausted(msg) => Some(ApiError::Unavailable(msg.clone())),
+            err @ DbError::Timeout(_) => Some(ApiError::Timeout(err.to_string())),
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::NotFound(msg) => write!(f, "Not found: {}", msg),
+            ApiError::Validation(msg) => {
+                write!(f, "A validation error occured on the input: {}", msg)
+            }
+            ApiError::Db(msg) => write!(f, "A database error occurred: {}", msg),
+            ApiError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            ApiError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            ApiError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            ApiError::Unavailable(msg) => write!(f, "Service unavailable: {}", msg),
+            ApiError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            ApiError::MalformedJson(problem) => {
+                write!(f, "Malformed JSON body: {}", problem.message)
+            }
+            ApiError::NotAcceptable(msg) => write!(f, "Not acceptable: {}", msg),
+            ApiError::UnsupportedMediaType(msg) => write!(f, "Unsupported media type: {}", msg),
+            ApiError::Locked(msg) => write!(f, "Locked: {}", msg),
+            ApiError::TooLarge(msg) => write!(f, "Payload too large: {}", msg),
+            ApiError::RateLimited(msg, _) => write!(f, "Too many requests: {}", msg),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::MalformedJson(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Locked(_) => StatusCode::LOCKED,
+            ApiError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.response_builder()
+            .insert_header(header::ContentType::json())
+            .json(ErrorBody {
+                error: self.to_string(),
+                kind: self.kind(),
+                details: match self {
+                    ApiError::MalformedJson(problem) => Some(problem),
+                    _ => None,
+                },
+            })
+    }
+}
+
+impl ApiError {
+    // the status and any headers that go with it, for either shape of body
+    fn response_builder(&self) -> HttpResponseBuilder {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Unavailable(_) => {
+                response.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECONDS));
+            }
+            ApiError::RateLimited(_, retry_after) => {
+                response.insert_header((header::RETRY_AFTER, *retry_after));
+            }
+            ApiError::Unauthorized(_) => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+            }
+            _ => {}
+        }
+        response
+    }
+
+    /// The error inside a v2 envelope, for a client that asked for one
+    pub fn envelope_response(&self) -> HttpResponse {
+        self.response_builder()
+            .content_type(ENVELOPE_MIME)
+            .json(Envelope::from_error(EnvelopeError {
+                message: self.to_string(),
+                kind: self.kind(),
+                details: match self {
+                    ApiError::MalformedJson(problem) => serde_json::to_value(problem).ok(),
+                    _ => None,
+                },
+            }))
+    }
+}
@@ -5,3 +5,19 @@ pub mod models;
 pub mod controllers;
 
 pub mod app_config;
+
+pub mod errors;
+
+pub mod middleware;
+
+pub mod metrics;
+
+pub mod logging;
+
+pub mod webhooks;
+
+pub mod live;
+
+pub mod reports;
+
+pub mod shutdown;
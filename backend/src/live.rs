@@ -0,0 +1,201 @@
+use crate::models::timestamps::{PostTimestamp, ScanEvent};
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::web::{self, Bytes, BytesMut};
+use futures_util::{future, stream, Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+/// Scans a socket may fall behind by before it starts missing the oldest of them. Recording
+/// a scan never waits on a socket, one that can't keep up skips ahead and carries on.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Frames queued for a socket before it stops taking scans off the channel
+const OUTBOX_CAPACITY: usize = 16;
+
+/// How often an idle socket is pinged, a client silent for two of these is dropped
+const HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// Milliseconds an event stream's client is told to wait before reconnecting once it drops
+const SSE_RETRY_MS: u64 = 5000;
+
+/// The channel of each location someone is watching, kept in the app data and shared by
+/// every worker. A location gets its channel when its first socket or event stream opens,
+/// and a scan at a location nobody watches is dropped without being encoded.
+#[derive(Debug, Default)]
+pub struct LiveScans {
+    state: Mutex<Channels>,
+}
+
+#[derive(Debug, Default)]
+struct Channels {
+    locations: HashMap<usize, broadcast::Sender<ScanEvent>>,
+    // set once the server is stopping, no feed opens after that
+    closed: bool,
+}
+
+impl LiveScans {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands a stored scan to every open live socket and event stream of its location
+    pub fn publish(&self, scan: &PostTimestamp) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sender) = state.locations.get(&scan.location) {
+            // an error only means the last socket closed in the meantime
+            if sender.send(ScanEvent::from_scan(scan)).is_err() {
+                state.locations.remove(&scan.location);
+            }
+        }
+    }
+
+    /// Ends every open socket and event stream, for the server shutting down. They'd otherwise
+    /// stay open until the shutdown timeout and hold it up for all of it.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.closed = true;
+        // dropping the senders is what ends them, each receiver sees the channel closed
+        state.locations.clear();
+    }
+
+    fn subscribe(&self, location: usize) -> broadcast::Receiver<ScanEvent> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.closed {
+            // its sender is gone as soon as it's made, so the feed ends straight away
+            return broadcast::channel(1).1;
+        }
+        // the channels of locations whose last feed has closed
+        state.locations.retain(|_, sender| sender.receiver_count() > 0);
+        state
+            .locations
+            .entry(location)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+}
+
+/// The body of an accepted WebSocket for `location`: each scan recorded there as a JSON text
+/// frame, pings to keep it open, and pongs and the closing frame in answer to the client's.
+/// The socket is served by a spawned task that ends when the client closes it or goes away,
+/// or with a going away frame when the server shuts down.
+pub fn socket(live: &LiveScans, location: usize, mut payload: web::Payload) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let (outbox, mut frames) = mpsc::channel::<Message>(OUTBOX_CAPACITY);
+    let mut scans = live.subscribe(location);
+    actix_web::rt::spawn(async move {
+        let mut codec = Codec::new();
+        let mut buf = BytesMut::new();
+        let mut heartbeat = time::interval_at(Instant::now() + HEARTBEAT, HEARTBEAT);
+        let mut last_heard = Instant::now();
+        loop {
+            let message = tokio::select! {
+                chunk = payload.next() => match chunk {
+                    Some(Ok(chunk)) => {
+                        last_heard = Instant::now();
+                        buf.extend_from_slice(&chunk);
+                        match reply(&mut codec, &mut buf) {
+                            Ok(Some(message)) => message,
+                            Ok(None) => continue,
+                            Err(close) => {
+                                let _ = outbox.send(Message::Close(Some(close))).await;
+                                break;
+                            }
+                        }
+                    }
+                    // the client hung up without a closing frame
+                    _ => break,
+                },
+                scan = scans.recv() => match scan {
+                    Ok(scan) => match serde_json::to_string(&scan) {
+                        Ok(json) => Message::Text(json.into()),
+                        Err(err) => {
+                            log::error!("Unable to encode live scan {:?}: {}", scan, err);
+                            continue;
+                        }
+                    },
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!("Live socket for location {} fell behind and missed {} scans", location, missed);
+                        continue;
+                    }
+                    // LiveScans::close, the server is stopping
+                    Err(RecvError::Closed) => Message::Close(Some(CloseCode::Away.into())),
+                },
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > HEARTBEAT * 2 {
+                        log::info!("Closing live socket for location {}, the client stopped answering", location);
+                        break;
+                    }
+                    Message::Ping(Bytes::new())
+                }
+            };
+            let closing = matches!(message, Message::Close(_));
+            // the response was dropped, the connection is gone
+            if outbox.send(message).await.is_err() || closing {
+                break;
+            }
+        }
+    });
+    let mut codec = Codec::new();
+    stream::poll_fn(move |cx| frames.poll_recv(cx)).map(move |message| {
+        let mut out = BytesMut::new();
+        codec.encode(message, &mut out)?;
+        Ok(out.freeze())
+    })
+}
+
+/// The body of a `text/event-stream` for `location`: each scan recorded there as a `scan`
+/// event with the same JSON the socket sends, and a comment every HEARTBEAT so proxies don't
+/// time out an idle stream. It ends when the client goes away and actix drops the body, or
+/// when the server shuts down, and the client reconnects to another once it's back.
+pub fn events(live: &LiveScans, location: usize) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let scans = live.subscribe(location);
+    let heartbeat = time::interval_at(Instant::now() + HEARTBEAT, HEARTBEAT);
+    let retry = stream::once(future::ready(Ok(Bytes::from(format!("retry: {}\n\n", SSE_RETRY_MS)))));
+    let events = stream::unfold((scans, heartbeat), move |(mut scans, mut heartbeat)| async move {
+        loop {
+            let event = tokio::select! {
+                scan = scans.recv() => match scan {
+                    // compact JSON has no newlines, so it's a single data line
+                    Ok(scan) => match serde_json::to_string(&scan) {
+                        Ok(json) => format!("event: scan\ndata: {}\n\n", json),
+                        Err(err) => {
+                            log::error!("Unable to encode live scan {:?}: {}", scan, err);
+                            continue;
+                        }
+                    },
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!("Event stream for location {} fell behind and missed {} scans", location, missed);
+                        continue;
+                    }
+                    // LiveScans::close, the server is stopping
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = heartbeat.tick() => ": keep-alive\n\n".to_string(),
+            };
+            return Some((Ok(Bytes::from(event)), (scans, heartbeat)));
+        }
+    });
+    retry.chain(events)
+}
+
+// the answer to the frames buffered so far, Err with the reason once the socket has to close
+fn reply(codec: &mut Codec, buf: &mut BytesMut) -> Result<Option<Message>, CloseReason> {
+    let mut answer = None;
+    loop {
+        match codec.decode(buf) {
+            Ok(Some(Frame::Ping(ping))) => answer = Some(Message::Pong(ping)),
+            Ok(Some(Frame::Close(reason))) => return Err(reason.unwrap_or_else(|| CloseCode::Normal.into())),
+            // the feed is one way, anything else the client sends is ignored
+            Ok(Some(_)) => continue,
+            Ok(None) => return Ok(answer),
+            Err(err) => {
+                log::info!("Closing live socket after a protocol error: {}", err);
+                return Err(CloseCode::Protocol.into());
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::middleware::request_id;
+use chrono::Utc;
+use std::io::Write;
+
+/// Log lines as `key=value` pairs, or one JSON object per line with LOG_FORMAT=json, so the
+/// aggregator can index them. Lines written while serving a request carry its request_id.
+pub fn init(json: bool) {
+    env_logger::Builder::from_default_env()
+        .format(move |buf, record| {
+            let ts = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let request_id = request_id::current();
+            let msg = record.args().to_string();
+            if json {
+                let line = serde_json::json!({
+                    "ts": ts,
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "request_id": request_id,
+                    "msg": msg,
+                });
+                writeln!(buf, "{}", line)
+            } else {
+                write!(
+                    buf,
+                    "ts={} level={} target={}",
+                    ts,
+                    record.level(),
+                    record.target()
+                )?;
+                if let Some(id) = request_id {
+                    write!(buf, " request_id={}", id)?;
+                }
+                writeln!(buf, " msg={:?}", msg)
+            }
+        })
+        .init();
+}
@@ -1,85 +1,257 @@
-use actix_cors::Cors;
 use actix_web::{
-    middleware,
-    web::{Data, JsonConfig},
+    middleware::{self, from_fn, Condition},
+    web::{Data, JsonConfig, PathConfig, PayloadConfig},
     App, HttpServer,
 };
-use r2d2_sqlite::SqliteConnectionManager;
-use reqwest::header;
 use scan_mvcf::{
-    controllers::{locations_controller, residents_controller, timestamps_controller},
-    database::db::{query, Query},
+    app_config,
+    controllers::{
+        audit_controller, auth_controller, health_controller, locations_controller, metrics_controller,
+        reports_controller, residents_controller, summary_controller, timestamps_controller,
+    },
+    database::db::{connection_manager, query, Query, QueryResult},
+    errors,
+    live::LiveScans,
+    logging,
+    middleware::{
+        api_key, audit, auth, body_limit, cache_control, compression, cors, envelope, facility, headers, idempotency, metrics,
+        rate_limit, request_id, roles::Role,
+    },
+    models::{api_keys::ApiKey, facilities::Facility, users},
+    shutdown,
 };
-use std::io;
+use std::{io, time::Duration};
+
+// actix's default access log line with the request id first, the line is written after
+// the request's task has finished so the formatter can't add it
+const ACCESS_LOG_FORMAT: &str =
+    r#"request_id=%{x-request-id}o %a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
-    let dbpath = dirs::data_local_dir().unwrap().join("mvcf_scan.db");
-    let manager = SqliteConnectionManager::file(dbpath);
+    let config = app_config::init();
+    // the format is the one setting needed before the rest can be reported on
+    logging::init(config.as_ref().is_ok_and(|config| config.log_json));
+    let config = config.map_err(|msg| {
+        log::error!("invalid configuration: {}", msg);
+        io::Error::other(msg)
+    })?;
+    if !config.auth_enabled {
+        log::warn!("AUTH=off, the API is open to anyone who can reach it");
+    }
+    log::info!("using sqlite database {}", config.database_path.display());
+    let manager = connection_manager(&config.database_path, config.statement_cache_capacity);
+    log::info!(
+        "database pool: max_size={} min_idle={} connection_timeout={}ms idle_timeout={}",
+        config.pool_max_size,
+        config.pool_min_idle.unwrap_or(config.pool_max_size),
+        config.pool_timeout_ms,
+        config.pool_idle_timeout_seconds.map_or("none".to_string(), |secs| format!("{}s", secs)),
+    );
     let pool = r2d2::Pool::builder()
+        .max_size(config.pool_max_size)
+        .min_idle(config.pool_min_idle)
+        .idle_timeout(config.pool_idle_timeout_seconds.map(Duration::from_secs))
+        .connection_timeout(Duration::from_millis(config.pool_timeout_ms))
         .build(manager)
         .expect("Not pointing to proper file");
+    // before anything else touches the database, a server on a half built schema would
+    // fail every request instead of failing here
+    if let Err(err) = query(&pool, Query::Migrations).await {
+        log::error!("database migrations failed: {}", err);
+        return Err(io::Error::other(format!("database migrations failed: {}", err)));
+    }
+    log::info!("database migrations complete");
     if let Some(args) = std::env::args().nth(1) {
         match args.as_str() {
             "--test-seed" => {
-                if query(&pool, Query::Migrations).await.is_ok() {
-                    log::info!("database migrations complete");
-                    if query(&pool, Query::SeedTestData).await.is_ok() {
-                        log::info!("database seeded with test data");
-                    } else {
-                        log::info!("database seed failed");
-                    }
+                if query(&pool, Query::SeedTestData).await.is_ok() {
+                    log::info!("database seeded with test data");
                 } else {
-                    log::info!("database migrations failed");
+                    log::info!("database seed failed");
                 }
             }
-            "--migrate" => {
-                if query(&pool, Query::Migrations).await.is_ok() {
-                    log::info!("database migrations complete");
-                } else {
-                    log::info!("database migrations failed");
+            // the migrations above are all it does
+            "--migrate" => return Ok(()),
+            // prints a new scanner key once, only its hash is stored
+            "--issue-scanner-key" => {
+                let usage = "usage: --issue-scanner-key <name> [facility_id]";
+                let name = std::env::args().nth(2).expect(usage);
+                let facility = std::env::args()
+                    .nth(3)
+                    .map_or(Ok(Facility::default()), |id| id.parse().map(Facility))
+                    .expect(usage);
+                let key = ApiKey::generate();
+                match query(&pool, Query::StoreApiKey(facility, &name, &ApiKey::hash(&key))).await {
+                    Ok(QueryResult::Success) => println!("{}", key),
+                    Ok(QueryResult::Conflict(_)) => {
+                        log::error!("a scanner key named {} already exists", name)
+                    }
+                    _ => log::error!("unable to issue scanner key"),
                 }
+                return Ok(());
+            }
+            "--revoke-scanner-key" => {
+                let name = std::env::args()
+                    .nth(2)
+                    .expect("usage: --revoke-scanner-key <name>");
+                match query(&pool, Query::RevokeApiKey(&name)).await {
+                    Ok(QueryResult::Success) => log::info!("revoked scanner key {}", name),
+                    Ok(QueryResult::NotFound) => log::error!("no scanner key named {}", name),
+                    _ => log::error!("unable to revoke scanner key"),
+                }
+                return Ok(());
+            }
+            // prints the new user's password once, only its hash is stored
+            "--create-user" => {
+                let usage = "usage: --create-user <username> <read_only|staff|supervisor|admin> [facility_id]";
+                let username = std::env::args().nth(2).expect(usage);
+                let role = std::env::args().nth(3).and_then(|role| Role::parse(&role)).expect(usage);
+                let facility = std::env::args()
+                    .nth(4)
+                    .map_or(Ok(Facility::default()), |id| id.parse().map(Facility))
+                    .expect(usage);
+                let password = users::generate_password();
+                match query(&pool, Query::StoreUser(facility, &username, role, &users::hash_password(&password))).await {
+                    Ok(QueryResult::Success) => println!("{}", password),
+                    Ok(QueryResult::Conflict(_)) => log::error!("a user named {} already exists", username),
+                    _ => log::error!("unable to create user"),
+                }
+                return Ok(());
+            }
+            "--delete-user" => {
+                let username = std::env::args().nth(2).expect("usage: --delete-user <username>");
+                match query(&pool, Query::DeleteUser(&username)).await {
+                    Ok(QueryResult::Success) => log::info!("deleted user {}", username),
+                    Ok(QueryResult::NotFound) => log::error!("no user named {}", username),
+                    _ => log::error!("unable to delete user"),
+                }
+                return Ok(());
             }
             &_ => {
                 log::info!("invalid argument: {}", args);
             }
         }
     }
-    log::info!("starting Actix-Web HTTP server at http://localhost:8080");
-    let json_config = JsonConfig::default().limit(4096);
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH"])
-            .allowed_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT])
-            .max_age(3600);
-
+    log::info!("starting Actix-Web HTTP server at http://{}:{}", config.host, config.port);
+    // the largest any route takes, middleware::body_limit holds each route to its own
+    let json_config = JsonConfig::default()
+        .limit(config.max_bulk_body_bytes)
+        .error_handler(errors::json_error_handler);
+    let payload_config = PayloadConfig::new(config.max_bulk_body_bytes);
+    let app_pool = pool.clone();
+    // one set of channels for every worker, so a scan reaches a feed on any of them
+    let live = Data::new(LiveScans::new());
+    let app_live = live.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(pool.clone()))
+            .app_data(Data::new(app_pool.clone()))
+            .app_data(app_live.clone())
             .app_data(json_config.clone())
+            .app_data(payload_config.clone())
+            .app_data(PathConfig::default().error_handler(errors::path_error_handler))
             .service(locations_controller::index)
+            .service(locations_controller::show_resident_counts)
+            .service(locations_controller::show_reconciliation)
+            .service(locations_controller::import)
+            .service(locations_controller::update_status_batch)
+            .service(locations_controller::show_batch)
             .service(locations_controller::show)
             .service(locations_controller::show_location_timestamps)
             .service(locations_controller::show_location_timestamps_range)
+            .service(locations_controller::export_location_timestamps)
+            .service(locations_controller::export_location_timestamps_as)
+            .service(locations_controller::show_location_dwell)
+            .service(locations_controller::show_location_visits)
+            .service(locations_controller::store_timestamp)
+            .service(locations_controller::store_timestamps_batch)
+            .service(locations_controller::live_timestamps)
+            .service(locations_controller::live_events)
+            .service(locations_controller::show_location_children)
+            .service(locations_controller::show_nearby_locations)
+            .service(locations_controller::show_location_path)
+            .service(locations_controller::show_location_resident_timestamps)
+            .service(locations_controller::show_location_resident_timestamps_range)
             .service(locations_controller::show_location_residents)
+            .service(locations_controller::show_current_occupancy)
+            .service(locations_controller::show_occupancy_rollup)
+            .service(locations_controller::show_occupancy_by_hour)
+            .service(locations_controller::show_daily_scan_counts)
+            .service(locations_controller::show_peak_occupancy)
             .service(locations_controller::store)
+            .service(locations_controller::update)
+            .service(locations_controller::update_status)
+            .service(locations_controller::destroy)
+            .service(locations_controller::merge)
             .service(residents_controller::index)
+            .service(residents_controller::last_seen)
+            .service(residents_controller::missing)
+            .service(residents_controller::most_active)
+            .service(residents_controller::import)
             .service(residents_controller::show)
             .service(residents_controller::show_resident_timestamps)
             .service(residents_controller::show_resident_timestamps_range)
+            .service(residents_controller::show_resident_movements)
+            .service(residents_controller::show_resident_locations)
             .service(residents_controller::store)
             .service(residents_controller::destroy)
             .service(residents_controller::update)
+            .service(residents_controller::transfer)
+            .service(residents_controller::show_restrictions)
+            .service(residents_controller::store_restriction)
+            .service(residents_controller::destroy_restriction)
+            .service(residents_controller::update_status)
             .service(timestamps_controller::index_timestamps)
             .service(timestamps_controller::show_range)
+            .service(timestamps_controller::backfill_timestamps)
+            .service(timestamps_controller::export_timestamps)
             .service(timestamps_controller::store_timestamp)
-            .wrap(middleware::Logger::default())
-            .wrap(cors)
+            .service(timestamps_controller::show_timestamp)
+            .service(timestamps_controller::update_timestamp)
+            .service(timestamps_controller::destroy_timestamp)
+            .service(timestamps_controller::purge_timestamps)
+            .service(reports_controller::export_timestamps)
+            .service(reports_controller::export_counts)
+            .service(auth_controller::login)
+            .service(health_controller::healthz)
+            .service(metrics_controller::metrics)
+            .service(audit_controller::index)
+            .service(summary_controller::today)
+            .service(summary_controller::snapshot)
+            // inside the auth checks, so these only see callers they let through
+            .wrap(from_fn(facility::require_own_facility))
+            .wrap(from_fn(audit::record_mutations))
+            // outside the audit, a replayed upload wasn't made twice
+            .wrap(from_fn(idempotency::replay_scans))
+            .wrap(from_fn(body_limit::limit_body_size))
+            .wrap(from_fn(auth::require_jwt))
+            .wrap(from_fn(api_key::require_scanner_key))
+            .wrap(from_fn(headers::check_headers))
+            // before the key is looked up, so a flood doesn't reach the pool
+            .wrap(from_fn(rate_limit::limit_scan_ingest))
+            .wrap(from_fn(envelope::wrap_errors))
+            .wrap(from_fn(cache_control::set_cache_control))
+            .wrap(from_fn(compression::skip_small_responses))
+            .wrap(Condition::new(
+                config.compression_enabled,
+                middleware::Compress::default(),
+            ))
+            .wrap(from_fn(metrics::record_metrics))
+            .wrap(from_fn(request_id::assign_request_id))
+            .wrap(middleware::Logger::new(ACCESS_LOG_FORMAT))
+            .wrap(cors::from_config())
     })
-    .bind(("127.0.0.1", 8080))?
+    .bind((config.host.as_str(), config.port))?
     .workers(2)
-    .run()
-    .await
+    .shutdown_timeout(config.shutdown_timeout_seconds)
+    .disable_signals()
+    .run();
+    actix_web::rt::spawn(shutdown::stop_on_signal(server.handle(), live.into_inner()));
+    server.await?;
+    // the workers have dropped their clones, so this closes every pooled connection
+    log::info!("server stopped, closing {} database connections", pool.state().connections);
+    drop(pool);
+    log::info!("shutdown complete");
+    Ok(())
 }
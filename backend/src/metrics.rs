@@ -0,0 +1,93 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Everything /metrics reports, kept off prometheus' global registry
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+/// Labeled by route pattern, so `/api/locations/{location_id}/timestamps` and
+/// `/api/locations/{location_id}/timestamps/batch` count separately
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("mvscanner_http_requests_total", "HTTP requests handled"),
+        &["method", "route", "status"],
+    ))
+});
+
+pub static HTTP_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "mvscanner_http_request_duration_seconds",
+            "Time from receiving a request to its response being ready",
+        )
+        .buckets(vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ]),
+        &["method", "route", "status"],
+    ))
+});
+
+/// Labeled by the `Query` variant's name, so the range reads and the residents joins can be
+/// told apart. Includes any wait for a connection and any retries, as the caller saw it.
+pub static DB_QUERY_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "mvscanner_db_query_duration_seconds",
+            "Time a query() call took, from its start to its result",
+        )
+        .buckets(vec![
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ]),
+        &["query"],
+    ))
+});
+
+pub static DB_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "mvscanner_db_errors_total",
+            "query() calls that returned an error",
+        ),
+        &["query"],
+    ))
+});
+
+/// Calls that ran past SLOW_QUERY_MS, whether or not they succeeded
+pub static SLOW_QUERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "mvscanner_slow_queries_total",
+            "query() calls slower than SLOW_QUERY_MS",
+        ),
+        &["query"],
+    ))
+});
+
+/// Individual scans stored, a batch upload adds one per accepted scan
+pub static SCANS_STORED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "mvscanner_scans_stored_total",
+            "Scans written to the timestamps table",
+        ),
+        &["path"],
+    ))
+});
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    collector: prometheus::Result<T>,
+) -> T {
+    let collector = collector.expect("metric definitions are valid");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric names are unique");
+    collector
+}
+
+/// The registry in the Prometheus text exposition format
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
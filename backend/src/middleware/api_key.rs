@@ -0,0 +1,77 @@
+use crate::app_config::config;
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::middleware::rate_limit::limit_scanner_key;
+use crate::middleware::roles::is_scan_ingest;
+use crate::models::api_keys::ApiKey;
+use crate::models::facilities::Facility;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// The scanner a request was authenticated as, stored in the request extensions in place
+/// of JWT claims
+#[derive(Debug, Clone)]
+pub struct ScannerKey {
+    pub name: String,
+    pub facility_id: Facility,
+}
+
+/// Authenticates badge scanners by `X-Api-Key` against the api_keys table, separately from
+/// the JWTs staff use. A key only opens the scan uploads in `roles::SCAN_INGEST_ROUTES`,
+/// anywhere else, or unknown or revoked, it's a 401. A key that's found is then held to the
+/// scan rate on its own. Requests without the header fall through to `auth::require_jwt`.
+pub async fn require_scanner_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if config().auth_enabled {
+        if let Some(key) = req.headers().get(API_KEY_HEADER) {
+            let key = key.to_str().unwrap_or_default().trim().to_string();
+            let scanner = validate_key(&req, &key).await?;
+            limit_scanner_key(&req, &key)?;
+            req.extensions_mut().insert(scanner);
+        }
+    }
+    next.call(req).await
+}
+
+async fn validate_key(req: &ServiceRequest, key: &str) -> Result<ScannerKey, ApiError> {
+    let source = req
+        .peer_addr()
+        .map_or("unknown".to_string(), |addr| addr.ip().to_string());
+    if !is_scan_ingest(req.method(), req.match_pattern().as_deref()) {
+        log::warn!(
+            "API key used on {} {} from {}",
+            req.method(),
+            req.path(),
+            source
+        );
+        return Err(ApiError::Unauthorized(
+            "API keys may only upload scans".to_string(),
+        ));
+    }
+    let pool = req
+        .app_data::<web::Data<Pool>>()
+        .ok_or_else(|| ApiError::Db("no database pool configured".to_string()))?;
+    match query(pool, Query::FindApiKey(&ApiKey::hash(key))).await {
+        Ok(QueryResult::ApiKey(found)) if !found.revoked => Ok(ScannerKey {
+            name: found.name,
+            facility_id: found.facility_id,
+        }),
+        Ok(QueryResult::ApiKey(found)) => {
+            log::warn!("Revoked API key for {} used from {}", found.name, source);
+            Err(ApiError::Unauthorized(
+                "API key has been revoked".to_string(),
+            ))
+        }
+        Ok(QueryResult::NotFound) => {
+            log::warn!("Unknown API key used from {}", source);
+            Err(ApiError::Unauthorized("invalid API key".to_string()))
+        }
+        res => Err(ApiError::from_query(res, "Unable to check API key")),
+    }
+}
@@ -0,0 +1,150 @@
+use crate::database::db::{query, Pool, Query};
+use crate::middleware::api_key::ScannerKey;
+use crate::middleware::auth::{is_open, Claims};
+use crate::middleware::facility::caller_facility;
+use crate::middleware::roles::{is_scan_ingest, Role};
+use crate::models::audit::AuditEntry;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use serde::Serialize;
+
+/// Largest request body kept as an entry's `after`, bigger ones are recorded without it
+const MAX_AUDIT_BODY: usize = 4096;
+
+/// Put in the request extensions by a handler that changes a record in place, the record as
+/// it was beforehand becomes the entry's `before`
+#[derive(Debug, Clone)]
+pub struct AuditBefore(pub serde_json::Value);
+
+/// Records `record`, as the handler read it before changing it, as the entry's `before`
+pub fn audit_before(req: &HttpRequest, record: &impl Serialize) {
+    match serde_json::to_value(record) {
+        Ok(before) => {
+            req.extensions_mut().insert(AuditBefore(before));
+        }
+        Err(err) => log::error!("Unable to record an audit entry's before: {}", err),
+    }
+}
+
+/// Put in the request extensions by a handler whose request has no body to record, what it
+/// did becomes the entry's `after`
+#[derive(Debug, Clone)]
+pub struct AuditAfter(pub serde_json::Value);
+
+/// Put in the request extensions by a handler that wrote its own entry from `audit_entry`, in
+/// the transaction that made the change, so the change isn't kept without it. The middleware
+/// doesn't write a second.
+#[derive(Debug, Clone, Copy)]
+pub struct AuditWritten;
+
+/// The entry the middleware would write for `req` answered with `status`, for a handler that
+/// writes it itself. `after` is what the caller asked for, and `before` is left to the handler.
+pub fn audit_entry(req: &HttpRequest, status: StatusCode, after: Option<serde_json::Value>) -> AuditEntry {
+    let claims = req.extensions().get::<Claims>().cloned();
+    AuditEntry {
+        id: 0,
+        actor: claims.as_ref().map_or("anonymous".to_string(), |claims| claims.sub.clone()),
+        role: claims.as_ref().map(|claims| claims.role.as_str().to_string()),
+        method: req.method().to_string(),
+        endpoint: req.match_pattern().unwrap_or_else(|| req.path().to_string()),
+        path: req.path().to_string(),
+        target: path_target(req),
+        status: status.as_u16(),
+        before: None,
+        after,
+        time: None,
+        facility_id: caller_facility(&req.extensions()),
+    }
+}
+
+/// Writes an audit_log row for every successful POST, PUT, PATCH or DELETE under `/api`,
+/// naming the caller from their token claims. Reads aren't recorded, and neither are scans
+/// uploaded by the scanners themselves, an API key or a token below Staff, or logins. A scan recorded
+/// by a member of staff by hand is. The row is written after the response is built, on a
+/// spawned task so it doesn't hold the response up, and a failure to write it is logged. A
+/// change that mustn't go unrecorded has its handler write the entry instead, see AuditWritten.
+pub async fn record_mutations(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if !is_audited(&req) {
+        return next.call(req).await;
+    }
+    let after = json_body(&mut req).await?;
+    let res = next.call(req).await?;
+    let request = res.request();
+    if res.status().is_success() && !request.extensions().contains::<AuditWritten>() {
+        let after = after.or_else(|| request.extensions().get::<AuditAfter>().map(|after| after.0.clone()));
+        let mut entry = audit_entry(request, res.status(), after);
+        entry.target = entry.target.or_else(|| created_target(&res));
+        entry.before = request.extensions().get::<AuditBefore>().map(|before| before.0.clone());
+        if let Some(pool) = res.request().app_data::<web::Data<Pool>>().cloned() {
+            actix_web::rt::spawn(async move {
+                if let Err(err) = query(&pool, Query::WriteAuditLog(&entry)).await {
+                    log::error!("Unable to write audit entry {:?}: {}", entry, err);
+                }
+            });
+        }
+    }
+    Ok(res)
+}
+
+fn is_audited(req: &ServiceRequest) -> bool {
+    if !req.path().starts_with("/api/")
+        || matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+    {
+        return false;
+    }
+    // a login changes nothing, and its body is a password
+    if is_open(req.method(), req.match_pattern().as_deref()) {
+        return false;
+    }
+    if is_scan_ingest(req.method(), req.match_pattern().as_deref()) {
+        let extensions = req.extensions();
+        let by_staff = extensions
+            .get::<Claims>()
+            .is_some_and(|claims| claims.role >= Role::Staff);
+        return by_staff && !extensions.contains::<ScannerKey>();
+    }
+    true
+}
+
+// reads a JSON body and puts it back for the handler, anything else is left alone
+async fn json_body(req: &mut ServiceRequest) -> Result<Option<serde_json::Value>, Error> {
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return Ok(None);
+    }
+    let body = req.extract::<web::Bytes>().await?;
+    let after = (body.len() <= MAX_AUDIT_BODY)
+        .then(|| serde_json::from_slice(&body).ok())
+        .flatten();
+    req.set_payload(Payload::from(body));
+    Ok(after)
+}
+
+// the record's id from the path
+fn path_target(req: &HttpRequest) -> Option<String> {
+    let info = req.match_info();
+    info.get("location_id")
+        .or_else(|| info.get("rfid"))
+        .or_else(|| info.get("timestamp_id"))
+        .or_else(|| info.get("src_id"))
+        .map(str::to_string)
+}
+
+// where a create said the new record is, for a route with no id in its path
+fn created_target<B>(res: &ServiceResponse<B>) -> Option<String> {
+    res.headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| location.rsplit('/').next())
+        .map(str::to_string)
+}
@@ -0,0 +1,124 @@
+use crate::app_config::config;
+use crate::errors::ApiError;
+use crate::middleware::api_key::ScannerKey;
+use crate::middleware::roles::{required_role, Role};
+use crate::models::facilities::Facility;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Decoded from a valid bearer token and stored in the request extensions, handlers read
+/// the caller with `web::ReqData<Claims>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    // who the token was issued to, a staff username or a scanner name
+    pub sub: String,
+    // seconds since the epoch, checked on every request
+    pub exp: usize,
+    #[serde(default)]
+    pub role: Role,
+    // the facility whose records the caller sees, DEFAULT_FACILITY when the token has none
+    #[serde(default)]
+    pub facility_id: Facility,
+}
+
+static DECODING_KEY: Lazy<Option<DecodingKey>> = Lazy::new(|| {
+    config()
+        .jwt_secret
+        .as_deref()
+        .map(|secret| DecodingKey::from_secret(secret.as_bytes()))
+});
+
+/// Routes anyone may call without a token, only the login that hands one out
+pub const OPEN_ROUTES: &[(Method, &str)] = &[(Method::POST, "/api/auth/login")];
+
+/// Whether the route is one of OPEN_ROUTES
+pub fn is_open(method: &Method, pattern: Option<&str>) -> bool {
+    OPEN_ROUTES
+        .iter()
+        .any(|(route_method, route)| route_method == method && Some(*route) == pattern)
+}
+
+/// Rejects `/api` requests without a valid HS256 `Authorization: Bearer` token with a 401,
+/// and a token whose role is below the one `roles::ROUTE_ROLES` gives the route with a 403.
+/// GETs are gated by AUTH_READS and everything else by AUTH_WRITES, AUTH=off skips both.
+pub async fn require_jwt(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // already authenticated by a scanner's API key, or asking for a token
+    if req.extensions().contains::<ScannerKey>() || is_open(req.method(), req.match_pattern().as_deref()) {
+        return next.call(req).await;
+    }
+    if requires_token(&req) {
+        let claims = validate_token(&req)?;
+        authorize(&req, &claims)?;
+        req.extensions_mut().insert(claims);
+    } else if offers_token(&req) {
+        // an open read is still scoped to the facility of a caller who sends a token
+        let claims = validate_token(&req)?;
+        req.extensions_mut().insert(claims);
+    }
+    next.call(req).await
+}
+
+fn authorize(req: &ServiceRequest, claims: &Claims) -> Result<(), ApiError> {
+    let required = required_role(req.method(), req.match_pattern().as_deref());
+    if claims.role < required {
+        log::info!(
+            "Denied {} {} to {} with role {:?}, needs {:?}",
+            req.method(),
+            req.path(),
+            claims.sub,
+            claims.role,
+            required
+        );
+        return Err(ApiError::Forbidden(format!(
+            "this action needs the {:?} role",
+            required
+        )));
+    }
+    Ok(())
+}
+
+fn requires_token(req: &ServiceRequest) -> bool {
+    if !config().auth_enabled || !req.path().starts_with("/api/") {
+        return false;
+    }
+    match *req.method() {
+        // preflights carry no credentials, CORS answers them before they get here
+        Method::OPTIONS => false,
+        // AUTH_READS=off doesn't open a read that needs more than ReadOnly
+        Method::GET | Method::HEAD => {
+            config().auth_reads || required_role(req.method(), req.match_pattern().as_deref()) > Role::ReadOnly
+        }
+        _ => config().auth_writes,
+    }
+}
+
+fn offers_token(req: &ServiceRequest) -> bool {
+    config().auth_enabled && req.path().starts_with("/api/") && req.headers().contains_key(header::AUTHORIZATION)
+}
+
+fn validate_token(req: &ServiceRequest) -> Result<Claims, ApiError> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::Unauthorized("missing bearer token".to_string()))?;
+    let key = DECODING_KEY
+        .as_ref()
+        .ok_or_else(|| ApiError::Unauthorized("no signing key is configured".to_string()))?;
+    decode::<Claims>(token.trim(), key, &Validation::new(Algorithm::HS256))
+        .map(|data| data.claims)
+        .map_err(|err| {
+            log::info!("Rejected bearer token for {}: {}", req.path(), err);
+            ApiError::Unauthorized(format!("invalid token: {}", err))
+        })
+}
@@ -0,0 +1,75 @@
+use crate::app_config::config;
+use crate::errors::ApiError;
+use actix_http::BoxedPayloadStream;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use futures_util::StreamExt;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Writes whose body is a whole batch of scans or an uploaded file, held to
+/// MAX_BULK_BODY_BYTES instead of the MAX_BODY_BYTES every other request gets
+pub const BULK_ROUTES: &[(Method, &str)] = &[
+    (
+        Method::POST,
+        "/api/locations/{location_id}/timestamps/batch",
+    ),
+    (Method::POST, "/api/locations/import"),
+    (Method::POST, "/api/residents/import"),
+    (Method::POST, "/api/timestamps/backfill"),
+];
+
+/// Most bytes a request to the route may send in its body
+pub fn body_limit(method: &Method, pattern: Option<&str>) -> usize {
+    let bulk = BULK_ROUTES
+        .iter()
+        .any(|(route_method, route)| route_method == method && Some(*route) == pattern);
+    if bulk {
+        config().max_bulk_body_bytes
+    } else {
+        config().max_body_bytes
+    }
+}
+
+/// Refuses a body over the route's limit with a 413 before it is buffered. A Content-Length
+/// over the limit is refused without reading anything, and a chunked body is cut off as soon
+/// as it passes the limit, so a worker never holds more than that of any one request.
+pub async fn limit_body_size(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limit = body_limit(req.method(), req.match_pattern().as_deref());
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        log::info!("Refused a {} byte body for {}", declared.unwrap_or_default(), req.path());
+        return Err(ApiError::too_large(limit).into());
+    }
+    let overflowed = Rc::new(Cell::new(false));
+    let seen_overflow = overflowed.clone();
+    let mut read = 0;
+    let payload = std::mem::replace(req.parts_mut().1, Payload::None).map(move |chunk| {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > limit {
+            seen_overflow.set(true);
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    });
+    req.set_payload(Payload::from(Box::pin(payload) as BoxedPayloadStream));
+    let res = next.call(req).await;
+    // whichever extractor read the body failed on the overflow, answer with the limit
+    // rather than its own error
+    if overflowed.get() {
+        return Err(ApiError::too_large(limit).into());
+    }
+    res
+}
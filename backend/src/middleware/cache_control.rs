@@ -0,0 +1,51 @@
+use crate::app_config::config;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+/// Reads an edge cache may hold for LOCATIONS_MAX_AGE_SECONDS. Locations change when staff
+/// edit one, not with every scan, and these are what the boards poll.
+pub const CACHEABLE_ROUTES: &[&str] = &["/api/locations", "/api/locations/batch", "/api/locations/{location_id}"];
+
+/// The Cache-Control a response to the route gets. Everything but the cacheable reads is
+/// `no-store`, scans and occupancy are out of date as soon as they're sent.
+pub fn cache_control(method: &Method, pattern: Option<&str>, status: StatusCode) -> String {
+    let max_age = config().locations_max_age_seconds;
+    let cacheable = matches!(*method, Method::GET | Method::HEAD)
+        && (status.is_success() || status == StatusCode::NOT_MODIFIED)
+        && pattern.is_some_and(|pattern| CACHEABLE_ROUTES.contains(&pattern));
+    if cacheable && max_age > 0 {
+        format!("public, max-age={}", max_age)
+    } else {
+        "no-store".to_string()
+    }
+}
+
+/// Sets each response's Cache-Control from cache_control, unless its handler set its own. A
+/// cacheable one varies on the caller's credentials too, each facility sees its own locations.
+pub async fn set_cache_control(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let method = req.method().clone();
+    let pattern = req.match_pattern();
+    let mut res = next.call(req).await?;
+    if res.headers().contains_key(header::CACHE_CONTROL) {
+        return Ok(res);
+    }
+    let directive = cache_control(&method, pattern.as_deref(), res.status());
+    let cacheable = directive != "no-store";
+    if let Ok(value) = HeaderValue::from_str(&directive) {
+        res.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+    if cacheable {
+        res.headers_mut().append(
+            header::VARY,
+            HeaderValue::from_static("Accept, Authorization, X-Api-Key"),
+        );
+    }
+    Ok(res)
+}
@@ -0,0 +1,25 @@
+use crate::app_config::config;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+/// Runs inside `Compress` and marks responses smaller than COMPRESS_MIN_BYTES as
+/// `Content-Encoding: identity`, which `Compress` leaves alone. Streamed bodies have
+/// no known size and are always eligible.
+pub async fn skip_small_responses(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+    if let BodySize::Sized(len) = res.response().body().size() {
+        if len < config().compress_min_bytes {
+            res.headers_mut().insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static("identity"),
+            );
+        }
+    }
+    Ok(res)
+}
@@ -0,0 +1,22 @@
+use crate::app_config::config;
+use actix_cors::Cors;
+use actix_web::http::header;
+
+/// Built from the CORS_* settings for each worker. `*` among the origins allows any of them,
+/// which the configuration only permits outside production.
+pub fn from_config() -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(config().cors_allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config().cors_allowed_headers.iter().map(String::as_str))
+        // the frontend reads these from paged, created and throttled responses
+        .expose_headers([header::LOCATION, header::RETRY_AFTER, header::LINK])
+        .expose_headers(["X-Total-Count"])
+        .max_age(3600);
+    config()
+        .cors_allowed_origins
+        .iter()
+        .fold(cors, |cors, origin| match origin.as_str() {
+            "*" => cors.allow_any_origin(),
+            origin => cors.allowed_origin(origin),
+        })
+}
@@ -0,0 +1,42 @@
+use crate::controllers::negotiation::accepts_envelope;
+use crate::errors::ApiError;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::middleware::Next;
+use actix_web::Error;
+
+/// Re-renders an ApiError inside the v2 envelope when the client asked for it, whichever
+/// handler, extractor or middleware it came from. Errors that aren't an ApiError, like
+/// actix's own 404 for an unknown route, keep their response.
+pub async fn wrap_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let wants_envelope = accepts_envelope(req.request());
+    // a middleware further in refuses a request with an Err rather than a response, which is
+    // swapped for one that renders as the envelope
+    let res = match next.call(req).await {
+        Ok(res) => res,
+        Err(err) => {
+            return match err.as_error::<ApiError>() {
+                Some(api_err) if wants_envelope => {
+                    Err(InternalError::from_response(api_err.to_string(), api_err.envelope_response()).into())
+                }
+                _ => Err(err),
+            };
+        }
+    };
+    if !wants_envelope {
+        return Ok(res.map_into_left_body());
+    }
+    let enveloped = res
+        .response()
+        .error()
+        .and_then(|err| err.as_error::<ApiError>())
+        .map(ApiError::envelope_response);
+    match enveloped {
+        Some(response) => Ok(res.into_response(response).map_into_right_body()),
+        None => Ok(res.map_into_left_body()),
+    }
+}
@@ -0,0 +1,81 @@
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::middleware::api_key::ScannerKey;
+use crate::middleware::auth::Claims;
+use crate::models::facilities::{Facility, Record};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Extensions, Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+
+/// The caller's facility: their token's, their scanner key's, or DEFAULT_FACILITY for a
+/// request that carries neither (AUTH=off, or a read left open by AUTH_READS=off)
+pub fn caller_facility(extensions: &Extensions) -> Facility {
+    extensions
+        .get::<Claims>()
+        .map(|claims| claims.facility_id)
+        .or_else(|| extensions.get::<ScannerKey>().map(|key| key.facility_id))
+        .unwrap_or_default()
+}
+
+/// Handlers take the facility to scope their queries to as an argument
+impl FromRequest for Facility {
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(caller_facility(&req.extensions())))
+    }
+}
+
+/// Rejects with a 403 a request whose path names a location or resident of another facility,
+/// before it reaches the handler. One that doesn't exist at all is left to the handler's 404.
+pub async fn require_own_facility(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let facility = caller_facility(&req.extensions());
+    if let (Some(pattern), Some(pool)) = (req.match_pattern(), req.app_data::<web::Data<Pool>>()) {
+        if let Some(record) = path_record(&pattern, req.path()) {
+            ensure_own(pool, facility, record).await?;
+        }
+    }
+    next.call(req).await
+}
+
+// the routing that fills match_info runs after the middleware, so the id is read from the
+// path segment in the place of the pattern's {location_id}, {rfid} or {timestamp_id}. An id
+// sqlite couldn't hold is left for the handler's extractor to refuse.
+fn path_record<'a>(pattern: &str, path: &'a str) -> Option<Record<'a>> {
+    pattern
+        .split('/')
+        .zip(path.split('/'))
+        .find_map(|(segment, value)| match segment {
+            "{location_id}" => row_id(value).map(Record::Location),
+            "{rfid}" => Some(Record::Resident(value)),
+            "{timestamp_id}" => row_id(value).map(Record::Timestamp),
+            _ => None,
+        })
+}
+
+fn row_id(value: &str) -> Option<usize> {
+    value.parse::<i64>().ok().and_then(|id| usize::try_from(id).ok())
+}
+
+/// Err with a 403 when the record exists and belongs to a facility other than `facility`
+pub async fn ensure_own(db: &Pool, facility: Facility, record: Record<'_>) -> Result<(), ApiError> {
+    // SIGNED_OUT is where every facility's residents go when they're away
+    if record == Record::Location(0) {
+        return Ok(());
+    }
+    match query(db, Query::FacilityOf(record)).await {
+        Ok(QueryResult::Facility(owner)) if owner != facility => {
+            log::warn!("Refused {} to a caller from {}, it belongs to {}", record, facility, owner);
+            Err(ApiError::Forbidden(format!("{} belongs to another facility", record)))
+        }
+        Ok(QueryResult::Facility(_)) | Ok(QueryResult::NotFound) => Ok(()),
+        res => Err(ApiError::from_query(res, "Unable to check the facility")),
+    }
+}
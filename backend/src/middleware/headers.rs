@@ -0,0 +1,65 @@
+use crate::controllers::negotiation::{check_accept, negotiate, Format};
+use crate::errors::ApiError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{mime, Error};
+
+/// API routes that pick their own response format from the Accept header, or don't answer
+/// with JSON at all. Every other one only has JSON to give.
+pub const NEGOTIATED_ROUTES: &[(Method, &str)] = &[
+    (Method::GET, "/api/locations"),
+    (Method::GET, "/api/locations/{location_id}"),
+    (Method::GET, "/api/locations/{location_id}/live"),
+    (Method::GET, "/api/locations/{location_id}/events"),
+    (Method::GET, "/api/locations/{location_id}/residents"),
+    (Method::GET, "/api/locations/{location_id}/timestamps"),
+    (
+        Method::GET,
+        "/api/locations/{location_id}/timestamps/{start_date}/{end_date}",
+    ),
+    (
+        Method::GET,
+        "/api/locations/{location_id}/timestamps/{start_date}/{end_date}/export.xlsx",
+    ),
+    (
+        Method::GET,
+        "/api/locations/{location_id}/timestamps/{start_date}/{end_date}/export",
+    ),
+    (Method::GET, "/api/residents"),
+    (Method::GET, "/api/timestamps"),
+    (Method::GET, "/api/timestamps/export.csv"),
+    (Method::GET, "/api/reports/timestamps/export"),
+    (Method::GET, "/api/reports/counts/export"),
+    (Method::GET, "/api/timestamps/{start_date}/{end_date}"),
+];
+
+/// Turns away a request whose headers can't be acted on before anything else reads them. An
+/// Accept or Content-Type that doesn't parse is a 400 rather than being taken as absent, and
+/// a JSON-only route asked for something else is a 406. Routes in NEGOTIATED_ROUTES check
+/// Accept against their own formats, and whether a body's type fits is up to its extractor.
+pub async fn check_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    check_accept(req.request())?;
+    if let Some(value) = req.headers().get(header::CONTENT_TYPE) {
+        let parsed = value.to_str().ok().and_then(|value| value.parse::<mime::Mime>().ok());
+        if parsed.is_none() {
+            let msg = format!("Content-Type: {:?} is not a media type", value);
+            return Err(ApiError::Validation(msg).into());
+        }
+    }
+    let pattern = req.match_pattern();
+    let json_only = pattern.as_deref().is_some_and(|pattern| {
+        pattern.starts_with("/api/")
+            && !NEGOTIATED_ROUTES
+                .iter()
+                .any(|(method, route)| method == req.method() && *route == pattern)
+    });
+    if json_only {
+        negotiate(req.request(), &[Format::Json])?;
+    }
+    next.call(req).await
+}
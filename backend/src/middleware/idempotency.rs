@@ -0,0 +1,107 @@
+use crate::database::db::{query, Pool, Query, QueryResult};
+use crate::errors::ApiError;
+use crate::middleware::facility::caller_facility;
+use crate::middleware::roles::is_scan_ingest;
+use crate::models::facilities::Facility;
+use crate::models::idempotency::{parse_key, KeyClaim, StoredResponse, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED};
+use actix_web::body::{to_bytes, BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+
+/// Lets a scanner retry a scan upload that timed out without it being stored twice. The first
+/// upload with a given Idempotency-Key runs and its successful response is kept, and any later
+/// one with the same key, within IDEMPOTENCY_TTL_SECONDS and from the same facility, gets that
+/// response back with `Idempotent-Replayed: true` instead of running again. The key is held
+/// while the first is in flight, so a retry that overtakes it is a 409, and a failed upload
+/// lets its key go for the retry to try again. Uploads without the header aren't affected.
+pub async fn replay_scans(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody, BoxBody>>, Error> {
+    let sent = req.headers().get(IDEMPOTENCY_KEY);
+    let pool = req.app_data::<web::Data<Pool>>().cloned();
+    let (Some(sent), Some(pool)) = (sent, pool) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if !is_scan_ingest(req.method(), req.match_pattern().as_deref()) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    let key = sent
+        .to_str()
+        .map_err(|_| format!("{}: must be a UUID", IDEMPOTENCY_KEY))
+        .and_then(parse_key)
+        .map_err(ApiError::Validation)?;
+    let facility = caller_facility(&req.extensions());
+    let path = req.path().to_string();
+    match query(&pool, Query::ClaimIdempotencyKey(facility, &key, &path)).await {
+        Ok(QueryResult::KeyClaim(KeyClaim::Claimed)) => {}
+        Ok(QueryResult::KeyClaim(KeyClaim::Replay(stored))) => {
+            log::info!("replaying the response to {} {}", IDEMPOTENCY_KEY, key);
+            return Ok(req.into_response(replay(&stored)).map_into_right_body());
+        }
+        Ok(QueryResult::KeyClaim(KeyClaim::InFlight)) => {
+            let msg = format!("an upload with this {} is still in progress", IDEMPOTENCY_KEY);
+            return Err(ApiError::Conflict(msg).into());
+        }
+        Ok(QueryResult::KeyClaim(KeyClaim::Reused(used_for))) => {
+            let msg = format!("{} was already used for {}", IDEMPOTENCY_KEY, used_for);
+            return Err(ApiError::Conflict(msg).into());
+        }
+        res => return Err(ApiError::from_query(res, "Unable to check the idempotency key").into()),
+    }
+    let res = match next.call(req).await {
+        Ok(res) if res.status().is_success() => res,
+        res => {
+            release(&pool, facility, &key).await;
+            return res.map(ServiceResponse::map_into_left_body);
+        }
+    };
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            release(&pool, facility, &key).await;
+            let err: Box<dyn std::error::Error> = err.into();
+            return Err(actix_web::error::ErrorInternalServerError(err.to_string()));
+        }
+    };
+    let kept = |name| res.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let stored = StoredResponse {
+        status: res.status().as_u16(),
+        content_type: kept(header::CONTENT_TYPE),
+        location: kept(header::LOCATION),
+        body: body.to_vec(),
+    };
+    // the scans are stored either way, a response that couldn't be kept only means a retry
+    // would run again
+    if let Err(err) = query(&pool, Query::StoreIdempotentResponse(facility, &key, &stored)).await {
+        log::error!("Unable to keep the response to {} {}: {}", IDEMPOTENCY_KEY, key, err);
+        release(&pool, facility, &key).await;
+    }
+    let res = res.set_body(body).map_into_boxed_body();
+    Ok(ServiceResponse::new(req, res).map_into_right_body())
+}
+
+fn replay(stored: &StoredResponse) -> HttpResponse {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    response.insert_header((IDEMPOTENT_REPLAYED, "true"));
+    if let Some(content_type) = &stored.content_type {
+        response.insert_header((header::CONTENT_TYPE, content_type.as_str()));
+    }
+    if let Some(location) = &stored.location {
+        response.insert_header((header::LOCATION, location.as_str()));
+    }
+    response.body(stored.body.clone())
+}
+
+// frees a key whose request failed so the retry runs, a failure here only holds the key
+// until it expires
+async fn release(pool: &Pool, facility: Facility, key: &str) {
+    if let Err(err) = query(pool, Query::ReleaseIdempotencyKey(facility, key)).await {
+        log::error!("Unable to release {} {}: {}", IDEMPOTENCY_KEY, key, err);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::metrics::{HTTP_LATENCY, HTTP_REQUESTS};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use std::time::Instant;
+
+/// Counts and times every request by method, route pattern and status, including the ones
+/// an inner middleware turned away. Paths that match no route share one label so stray
+/// URLs can't grow the series without bound.
+pub async fn record_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or("unmatched".to_string());
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_LATENCY
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    res
+}
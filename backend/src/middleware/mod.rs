@@ -0,0 +1,15 @@
+pub mod api_key;
+pub mod audit;
+pub mod body_limit;
+pub mod auth;
+pub mod cache_control;
+pub mod compression;
+pub mod cors;
+pub mod envelope;
+pub mod facility;
+pub mod headers;
+pub mod idempotency;
+pub mod metrics;
+pub mod rate_limit;
+pub mod request_id;
+pub mod roles;
@@ -0,0 +1,83 @@
+use crate::app_config::config;
+use crate::errors::ApiError;
+use crate::middleware::roles::is_scan_ingest;
+use crate::models::api_keys::ApiKey;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+/// Buckets kept before the full ones, callers that have gone quiet, are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// shared by every worker, a scanner's requests can land on any of them
+static BUCKETS: Lazy<Mutex<HashMap<String, Bucket>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Holds each address calling the scan uploads to SCAN_RATE_PER_SECOND, with bursts of up to
+/// SCAN_RATE_BURST, and answers the rest with a 429 and `Retry-After`. Every request counts
+/// against the address the connection came from, whatever X-Api-Key it sends, and runs before
+/// the key is looked up, so a flood is turned away without taking a database connection per
+/// request. A key is counted on its own as well once it's found, see `limit_scanner_key`.
+/// Every other route is left alone.
+pub async fn limit_scan_ingest(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if config().scan_rate_per_second == 0 || !is_scan_ingest(req.method(), req.match_pattern().as_deref()) {
+        return next.call(req).await;
+    }
+    let caller = format!("ip:{}", req.peer_addr().map_or("unknown".to_string(), |addr| addr.ip().to_string()));
+    limit(&req, &caller)?;
+    next.call(req).await
+}
+
+/// The same limit for a scanner's X-Api-Key, so scanners sharing an address are also held to
+/// it one by one. Called by `api_key::require_scanner_key` only after the key is found, an
+/// unknown key never gets a bucket of its own.
+pub fn limit_scanner_key(req: &ServiceRequest, key: &str) -> Result<(), ApiError> {
+    if config().scan_rate_per_second == 0 {
+        return Ok(());
+    }
+    limit(req, &format!("key:{}", ApiKey::hash(key)))
+}
+
+fn limit(req: &ServiceRequest, caller: &str) -> Result<(), ApiError> {
+    take(caller, Instant::now()).map_err(|retry_after| {
+        log::warn!("Rate limited {} {} from {}", req.method(), req.path(), caller);
+        let msg = format!(
+            "more than {} scan uploads a second, retry in {} seconds", config().scan_rate_per_second, retry_after
+        );
+        ApiError::RateLimited(msg, retry_after)
+    })
+}
+
+// takes a token from the caller's bucket, which refills at the rate up to the burst. Err has
+// the whole seconds until the next token is back.
+fn take(caller: &str, now: Instant) -> Result<(), u64> {
+    let rate = config().scan_rate_per_second as f64;
+    let burst = config().scan_rate_burst as f64;
+    let refilled = |bucket: &Bucket| (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+    let mut buckets = BUCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+    if buckets.len() >= MAX_BUCKETS {
+        buckets.retain(|_, bucket| refilled(bucket) < burst);
+    }
+    let bucket = buckets
+        .entry(caller.to_string())
+        .or_insert(Bucket { tokens: burst, updated: now });
+    bucket.tokens = refilled(bucket);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        return Ok(());
+    }
+    Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+}
@@ -0,0 +1,70 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use rand::RngCore;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied id that is kept, anything longer gets a fresh one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// Id of the request the current task is serving, read by the log formatter
+    pub static REQUEST_ID: String;
+}
+
+/// Stored in the request extensions, handlers read it with `web::ReqData<RequestId>`
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Keeps a well-formed `X-Request-Id` from the client, typically a scanner tagging its
+/// retries, or generates one. It is echoed on the response and attached to every log line
+/// written while the request is handled.
+pub async fn assign_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map_or_else(generate, str::to_string);
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let value = HeaderValue::from_str(&id).ok();
+    match REQUEST_ID.scope(id, next.call(req)).await {
+        Ok(mut res) => {
+            if let Some(value) = value {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(res)
+        }
+        // rejections from inner middleware are rendered here so they get the header too
+        Err(err) => {
+            let mut res = err.error_response();
+            if let Some(value) = value {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Err(InternalError::from_response(err, res).into())
+        }
+    }
+}
+
+// visible ASCII only, so it can't break a log line or a header
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The current request's id, None outside of one
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
@@ -0,0 +1,102 @@
+use actix_web::http::Method;
+use serde::{Deserialize, Serialize};
+
+/// The `role` claim of a bearer token, a token without one is read-only
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // reads everything and records scans, what the badge scanners are issued
+    #[default]
+    ReadOnly,
+    // also manages locations and residents
+    Staff,
+    // also records scans into a location that is locked down
+    Supervisor,
+    // also reviews the audit log
+    Admin,
+}
+
+impl Role {
+    /// The claim's value as it appears in a token
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::Staff => "staff",
+            Role::Supervisor => "supervisor",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// The role a claim or a stored user names, None for anything else
+    pub fn parse(value: &str) -> Option<Role> {
+        [Role::ReadOnly, Role::Staff, Role::Supervisor, Role::Admin]
+            .into_iter()
+            .find(|role| role.as_str() == value)
+    }
+}
+
+/// Role each endpoint needs, keyed by method and route pattern. A read left off this list
+/// needs ReadOnly and a write needs Staff, so a new mutating route is closed by default.
+pub const ROUTE_ROLES: &[(Method, &str, Role)] = &[
+    (Method::GET, "/api/audit", Role::Admin),
+    (Method::POST, "/api/locations", Role::Staff),
+    (Method::POST, "/api/locations/import", Role::Staff),
+    (Method::PUT, "/api/locations/{location_id}", Role::Staff),
+    (Method::PUT, "/api/locations/{location_id}/status", Role::Staff),
+    (Method::POST, "/api/locations/status/batch", Role::Staff),
+    (Method::DELETE, "/api/locations/{location_id}", Role::Staff),
+    (Method::POST, "/api/locations/{src_id}/merge/{dst_id}", Role::Admin),
+    (
+        Method::POST,
+        "/api/locations/{location_id}/timestamps",
+        Role::ReadOnly,
+    ),
+    (
+        Method::POST,
+        "/api/locations/{location_id}/timestamps/batch",
+        Role::ReadOnly,
+    ),
+    (Method::POST, "/api/timestamps", Role::ReadOnly),
+    (Method::POST, "/api/timestamps/backfill", Role::Admin),
+    (Method::PATCH, "/api/timestamps/{timestamp_id}", Role::Supervisor),
+    (Method::DELETE, "/api/timestamps/{timestamp_id}", Role::Supervisor),
+    (Method::DELETE, "/api/timestamps", Role::Admin),
+    (Method::GET, "/api/timestamps/export.csv", Role::Admin),
+    (Method::GET, "/api/reports/timestamps/export", Role::Admin),
+    (Method::POST, "/api/residents", Role::Staff),
+    (Method::POST, "/api/residents/import", Role::Staff),
+    (Method::PATCH, "/api/residents/{rfid}", Role::Staff),
+    (Method::DELETE, "/api/residents/{rfid}", Role::Staff),
+    (Method::POST, "/api/residents/{rfid}/transfer", Role::Staff),
+    (Method::POST, "/api/residents/{rfid}/status", Role::Staff),
+    (Method::POST, "/api/residents/{rfid}/restrictions", Role::Staff),
+    (Method::DELETE, "/api/residents/{rfid}/restrictions", Role::Staff),
+];
+
+pub fn required_role(method: &Method, pattern: Option<&str>) -> Role {
+    let listed = ROUTE_ROLES
+        .iter()
+        .find(|(route_method, route, _)| route_method == method && Some(*route) == pattern)
+        .map(|(_, _, role)| *role);
+    match listed {
+        Some(role) => role,
+        None if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) => Role::ReadOnly,
+        None => Role::Staff,
+    }
+}
+
+/// The scan uploads, the only routes a scanner's X-Api-Key is accepted on
+pub const SCAN_INGEST_ROUTES: &[(Method, &str)] = &[
+    (Method::POST, "/api/locations/{location_id}/timestamps"),
+    (
+        Method::POST,
+        "/api/locations/{location_id}/timestamps/batch",
+    ),
+    (Method::POST, "/api/timestamps"),
+];
+
+pub fn is_scan_ingest(method: &Method, pattern: Option<&str>) -> bool {
+    SCAN_INGEST_ROUTES
+        .iter()
+        .any(|(route_method, route)| route_method == method && Some(*route) == pattern)
+}
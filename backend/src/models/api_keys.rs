@@ -0,0 +1,34 @@
+use crate::models::facilities::Facility;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// A scanner device's API key as stored. Only the SHA-256 of the key itself is kept,
+/// so a leaked database doesn't hand out working keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    pub revoked: bool,
+    // the facility whose locations the scanner uploads scans for
+    pub facility_id: Facility,
+}
+
+/// Keys the test seed issues, one live and one revoked
+pub const TEST_SCANNER_KEY: &str = "test-scanner-key";
+pub const TEST_REVOKED_KEY: &str = "test-revoked-key";
+
+impl ApiKey {
+    /// Hex SHA-256 of a key as presented in X-Api-Key, what the api_keys table is searched by
+    pub fn hash(key: &str) -> String {
+        Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// 32 random bytes as hex, shown once when issued
+    pub fn generate() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
@@ -0,0 +1,48 @@
+use crate::models::facilities::Facility;
+use crate::models::timestamps::serialize_opt_time;
+use serde::{Deserialize, Serialize};
+
+/// One recorded mutation: who did it, to which route and record, and what they sent
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AuditEntry {
+    #[serde(default)]
+    pub id: usize,
+    // the token's `sub`, or `anonymous` when AUTH=off
+    pub actor: String,
+    pub role: Option<String>,
+    pub method: String,
+    // the route pattern, e.g. /api/locations/{location_id}
+    pub endpoint: String,
+    pub path: String,
+    // the id from the path, or the one a create responded with in its Location header
+    pub target: Option<String>,
+    pub status: u16,
+    // the record as it was, kept by the handlers that change one in place
+    #[serde(default)]
+    pub before: Option<serde_json::Value>,
+    // the JSON body of the request, the state the caller asked for
+    pub after: Option<serde_json::Value>,
+    // set by the database when the row is written
+    #[serde(serialize_with = "serialize_opt_time")]
+    pub time: Option<String>,
+    // the caller's, the log only shows a facility its own entries
+    #[serde(default)]
+    pub facility_id: Facility,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditResponse {
+    pub success: bool,
+    pub message: String,
+    pub data: Vec<AuditEntry>,
+}
+
+impl AuditResponse {
+    pub fn from_entries(entries: Vec<AuditEntry>) -> Self {
+        Self {
+            success: true,
+            message: "Audit log successfully retrieved".to_string(),
+            data: entries,
+        }
+    }
+}
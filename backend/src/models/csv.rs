@@ -0,0 +1,99 @@
+/// A row type that can be written as CSV, the header row comes from `HEADER` and each
+/// value from `csv_fields`, in the same order
+pub trait ToCsv {
+    const HEADER: &'static [&'static str];
+
+    fn csv_fields(&self) -> Vec<String>;
+}
+
+/// The header row followed by one line per item, lines end in CRLF per RFC 4180
+pub fn to_csv<T: ToCsv>(rows: &[T]) -> String {
+    let mut out = csv_header::<T>();
+    for row in rows {
+        out.push_str(&csv_record(row));
+    }
+    out
+}
+
+/// Just the header row, for a body written a line at a time
+pub fn csv_header<T: ToCsv>() -> String {
+    csv_line(T::HEADER.iter().copied())
+}
+
+/// One item's line, escaped and ending in CRLF like every line of `to_csv`
+pub fn csv_record<T: ToCsv>(row: &T) -> String {
+    csv_line(row.csv_fields().iter().map(String::as_str))
+}
+
+fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+    let mut line = fields.map(escape).collect::<Vec<_>>().join(",");
+    line.push_str("\r\n");
+    line
+}
+
+// quoted only when it has to be, with any quotes inside doubled. A leading = + - or @ is
+// prefixed with a quote so spreadsheets don't evaluate a resident's name as a formula.
+fn escape(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// The trimmed value in column `col` of an imported row, a blank cell is the same as a
+/// missing one
+pub fn field(row: &[String], col: Option<usize>) -> Option<&str> {
+    col.and_then(|col| row.get(col))
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// Splits CSV text into records of fields, the inverse of `to_csv`. Quoted fields may hold
+/// commas, doubled quotes and line breaks, records end in LF or CRLF and blank lines are
+/// dropped. Fields aren't trimmed, that's left to whoever interprets them.
+pub fn parse_csv(input: &str) -> Result<Vec<Vec<String>>, String> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                end_record(&mut records, std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("record {} has an unterminated quote", records.len() + 1));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        end_record(&mut records, record);
+    }
+    Ok(records)
+}
+
+fn end_record(records: &mut Vec<Vec<String>>, record: Vec<String>) {
+    let blank = record.len() == 1 && record[0].trim().is_empty();
+    if !blank {
+        records.push(record);
+    }
+}
@@ -0,0 +1,79 @@
+use serde::Serialize;
+
+/// The media type a client sends in Accept to opt in to the envelope, and the Content-Type of
+/// every enveloped response
+pub const ENVELOPE_MIME: &str = "application/vnd.mvscanner.v2+json";
+
+/// The v2 response shape, `{"data": ..., "meta": {...}, "error": null}`, sent to clients that
+/// ask for it with ENVELOPE_MIME in their Accept header. Every other client keeps
+/// getting each endpoint's original body. A failed request has `data: null` and the error.
+#[derive(Debug, Serialize)]
+pub struct Envelope<T: Serialize> {
+    pub data: Option<T>,
+    pub meta: Meta,
+    pub error: Option<EnvelopeError>,
+}
+
+/// Paging for a list, left empty for a single item or an error
+#[derive(Debug, Default, Serialize)]
+pub struct Meta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnvelopeError {
+    pub message: String,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            data: Some(data),
+            meta: Meta::default(),
+            error: None,
+        }
+    }
+
+    /// A page of `limit` items from `offset` out of `total`
+    pub fn paged(data: T, total: usize, limit: usize, offset: usize) -> Self {
+        Self {
+            meta: Meta {
+                total: Some(total),
+                limit: Some(limit),
+                offset: Some(offset),
+            },
+            ..Self::new(data)
+        }
+    }
+}
+
+impl<T: Serialize> Envelope<Vec<T>> {
+    /// A whole, unpaged list, meta only carries its length
+    pub fn list(items: Vec<T>) -> Self {
+        Self {
+            meta: Meta {
+                total: Some(items.len()),
+                ..Meta::default()
+            },
+            ..Self::new(items)
+        }
+    }
+}
+
+impl Envelope<()> {
+    pub fn from_error(error: EnvelopeError) -> Self {
+        Self {
+            data: None,
+            meta: Meta::default(),
+            error: Some(error),
+        }
+    }
+}
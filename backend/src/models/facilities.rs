@@ -0,0 +1,56 @@
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The facility tokens and scanner keys without one belong to, and where every location,
+/// resident and key a database held before facilities were added ends up
+pub const DEFAULT_FACILITY: usize = 1;
+
+/// The tenant a location, resident, scanner key or audit entry belongs to. Each request is
+/// scoped to the one named by the caller's token or scanner key, see middleware::facility.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Facility(pub usize);
+
+impl Default for Facility {
+    fn default() -> Self {
+        Facility(DEFAULT_FACILITY)
+    }
+}
+
+impl Display for Facility {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "facility {}", self.0)
+    }
+}
+
+impl ToSql for Facility {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for Facility {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        usize::column_result(value).map(Facility)
+    }
+}
+
+/// A record a request names by its id, which has to belong to the caller's facility
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record<'a> {
+    Location(usize),
+    Resident(&'a str),
+    // a scan is its resident's facility's, even one at the shared SIGNED_OUT
+    Timestamp(usize),
+}
+
+impl Display for Record<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Record::Location(id) => write!(f, "location {}", id),
+            Record::Resident(rfid) => write!(f, "resident {}", rfid),
+            Record::Timestamp(id) => write!(f, "timestamp {}", id),
+        }
+    }
+}
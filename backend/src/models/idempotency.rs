@@ -0,0 +1,42 @@
+/// Header a scanner sends a client-generated UUID in, so a retried upload isn't stored twice
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Set on a response that was replayed from an earlier request with the same key
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+
+/// The key from an Idempotency-Key header, a UUID in its 8-4-4-4-12 hex form, lowercased so
+/// the same UUID in either case is the same key
+pub fn parse_key(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let groups: Vec<&str> = value.split('-').collect();
+    let well_formed = groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(group, len)| group.len() == len && group.chars().all(|c| c.is_ascii_hexdigit()));
+    if !well_formed {
+        return Err(format!("{}: '{}' is not a UUID", IDEMPOTENCY_KEY, value));
+    }
+    Ok(value.to_ascii_lowercase())
+}
+
+/// The response the first request with a key got, replayed as it was to any retry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub location: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// What claiming a key before the upload runs found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyClaim {
+    /// The key is new, or had expired, and is now held for this request
+    Claimed,
+    /// An earlier request with the key hasn't finished yet
+    InFlight,
+    /// The key was used for an upload to a different path
+    Reused(String),
+    Replay(StoredResponse),
+}
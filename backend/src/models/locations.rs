@@ -1,12 +1,180 @@
+use crate::models::csv::{field, ToCsv};
+use crate::models::facilities::Facility;
+use crate::models::timestamps::{serialize_opt_time, serialize_time};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Formatter;
 use std::path::PathBuf;
 use std::{env::current_dir, fmt::Display};
 
 #[derive(Serialize, Clone, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct Location {
+    // left out or 0 on create to have one assigned
+    #[serde(default)]
     pub id: usize,
     pub name: String,
+    // allowed headcount, scans past it are still stored but flagged
+    #[serde(default)]
+    pub capacity: Option<u32>,
+    // only set on create, afterwards it changes through PUT /api/locations/{id}/status
+    #[serde(default)]
+    pub status: LocationStatus,
+    // when DELETE hid the location, it's only listed with ?include_deleted=true
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none", serialize_with = "serialize_opt_time")]
+    pub deleted_at: Option<String>,
+    // always the caller's, whatever a request body says
+    #[serde(default, skip_deserializing)]
+    pub facility_id: Facility,
+    // the location this one is inside, a cell's wing or a wing's building
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<usize>,
+    // where the location is on the facility's floor plan grid, either both are set or neither
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<i64>,
+    // 1 when created and one more after each PUT, a PUT has to send the version it edited
+    #[serde(default)]
+    pub version: u32,
+}
+
+/// Whether a location takes scans. Closed ones still do but each is logged as a warning,
+/// a lockdown refuses them unless the caller is a supervisor.
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationStatus {
+    #[default]
+    Open,
+    Closed,
+    Lockdown,
+}
+
+impl LocationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LocationStatus::Open => "open",
+            LocationStatus::Closed => "closed",
+            LocationStatus::Lockdown => "lockdown",
+        }
+    }
+}
+
+impl ToSql for LocationStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for LocationStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "open" => Ok(LocationStatus::Open),
+            "closed" => Ok(LocationStatus::Closed),
+            "lockdown" => Ok(LocationStatus::Lockdown),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Body of PUT /api/locations/{id}/status
+#[derive(Serialize, Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+pub struct StatusPayload {
+    pub status: LocationStatus,
+}
+
+/// What the locations index can be ordered by with ?sort=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationSort {
+    /// Alphabetical, regardless of case
+    Name,
+    /// Residents present, counted as /api/locations/{id}/occupancy does
+    Occupancy,
+}
+
+impl LocationSort {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "name" => Ok(LocationSort::Name),
+            "occupancy" => Ok(LocationSort::Occupancy),
+            _ => Err(format!("sort: '{}' isn't one of name, occupancy", value)),
+        }
+    }
+}
+
+/// Direction of a ?sort=, ?order=asc (the default) or ?order=desc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_lowercase().as_str() {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!("order: '{}' isn't one of asc, desc", value)),
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Body of POST /api/locations/status/batch
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct StatusBatchPayload {
+    pub ids: Vec<usize>,
+    pub status: LocationStatus,
+}
+
+/// Which of a batch's ids were set to `status` and which aren't a location of the caller's
+/// facility. Each id is listed once, in the order it was first given.
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct StatusBatchResult {
+    pub status: LocationStatus,
+    pub updated: Vec<usize>,
+    pub not_found: Vec<usize>,
+}
+
+/// The locations a batch show asked for, in the order their ids were given, and the ids
+/// that aren't a location of the caller's facility
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct LocationBatch {
+    pub locations: Vec<Location>,
+    pub missing: Vec<usize>,
+}
+
+impl LocationBatch {
+    /// Puts `found` in the order of `ids`, which are distinct, and lists the ids it doesn't have
+    pub fn arrange(ids: &[usize], found: Vec<Location>) -> Self {
+        let mut found = found.into_iter().map(|location| (location.id, location)).collect::<HashMap<_, _>>();
+        let mut batch = LocationBatch { locations: Vec::new(), missing: Vec::new() };
+        for id in ids {
+            match found.remove(id) {
+                Some(location) => batch.locations.push(location),
+                None => batch.missing.push(*id),
+            }
+        }
+        batch
+    }
+}
+
+impl ToCsv for Location {
+    const HEADER: &'static [&'static str] = &["id", "name", "capacity"];
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.name.clone(),
+            self.capacity.map(|capacity| capacity.to_string()).unwrap_or_default(),
+        ]
+    }
 }
 
 impl Display for Location {
@@ -14,18 +182,241 @@ impl Display for Location {
         write!(f, "Location: {}", self.name)
     }
 }
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+/// How many residents are assigned to a location, zero for empty rooms
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct ResidentCount {
+    pub location_id: usize,
+    pub name: String,
+    pub count: usize,
+}
+
+/// A location's expected headcount, the residents assigned to it, against its actual one,
+/// the residents present there by current occupancy
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct Headcount {
+    pub location_id: usize,
+    pub name: String,
+    pub expected: usize,
+    pub actual: usize,
+    /// actual - expected, negative when residents are missing
+    pub delta: i64,
+    pub discrepancy: bool,
+}
+
+impl Headcount {
+    pub fn new(location_id: usize, name: String, expected: usize, actual: usize) -> Self {
+        let delta = actual as i64 - expected as i64;
+        Self { location_id, name, expected, actual, delta, discrepancy: delta != 0 }
+    }
+}
+
+/// Residents presently at a location and everywhere inside it
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct OccupancyRollup {
+    pub location_id: usize,
+    pub name: String,
+    // at the location itself rather than one of the locations inside it
+    pub present: usize,
+    // present plus every child's total
+    pub total: usize,
+    pub children: Vec<ChildOccupancy>,
+}
+
+/// A direct child's share of an OccupancyRollup, its own subtree's total
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct ChildOccupancy {
+    pub location_id: usize,
+    pub name: String,
+    pub total: usize,
+}
+
+/// Who was where across a facility at one instant, for shift handover
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct OccupancySnapshot {
+    #[serde(serialize_with = "serialize_time")]
+    pub at: String,
+    // only the locations someone was at, by id
+    pub locations: Vec<SnapshotLocation>,
+}
+
+/// One occupied location of an OccupancySnapshot
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct SnapshotLocation {
+    pub location_id: usize,
+    pub name: String,
+    pub residents: Vec<SnapshotResident>,
+}
+
+/// A resident in a SnapshotLocation and the scan that put them there
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct SnapshotResident {
+    pub rfid: String,
+    pub name: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub since: String,
+}
+
+/// Outcome of one CSV row of a location import
+#[derive(Serialize, Clone, Deserialize, Debug, Eq, PartialEq)]
+pub struct LocationImportResult {
+    // the row's number in the file, the header is row 1
+    pub row: usize,
+    pub created: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl LocationImportResult {
+    pub fn created(row: usize, location: Location) -> Self {
+        Self {
+            row,
+            created: true,
+            location: Some(location),
+            reason: None,
+        }
+    }
+    pub fn skipped(row: usize, reason: String) -> Self {
+        Self {
+            row,
+            created: false,
+            location: None,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// Reads a location import, a header row naming a `name` column and optionally `id` and
+/// `capacity` in any order, then one location per row. Each row is parsed on its own, one
+/// that can't be comes back as its error message next to its row number.
+pub fn locations_from_csv(records: &[Vec<String>]) -> Result<Vec<ImportRow>, String> {
+    let (header, rows) = records.split_first().ok_or("the file is empty")?;
+    let column = |name: &str| header.iter().position(|col| col.trim().eq_ignore_ascii_case(name));
+    let columns = ImportColumns {
+        name: column("name").ok_or("the header row has no name column")?,
+        id: column("id"),
+        capacity: column("capacity"),
+    };
+    Ok(rows
+        .iter()
+        .enumerate()
+        .map(|(pos, row)| (pos + 2, columns.location(row)))
+        .collect())
+}
+
+/// A row number and the location read from it, or why it couldn't be
+pub type ImportRow = (usize, Result<Location, String>);
+
+struct ImportColumns {
+    name: usize,
+    id: Option<usize>,
+    capacity: Option<usize>,
+}
+
+impl ImportColumns {
+    fn location(&self, row: &[String]) -> Result<Location, String> {
+        let id = match field(row, self.id) {
+            Some(id) => id.parse().map_err(|_| format!("id: '{}' is not a number", id))?,
+            None => 0,
+        };
+        let capacity = match field(row, self.capacity) {
+            Some(cap) => Some(cap.parse().map_err(|_| format!("capacity: '{}' is not a number", cap))?),
+            None => None,
+        };
+        let name = field(row, Some(self.name)).unwrap_or_default();
+        Ok(Location::with_capacity(id, name.to_string(), capacity))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct LocationsResponse {
     pub success: bool,
     pub message: String,
     pub data: LocationData,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum LocationData {
     Index(Vec<Location>),
     Show(Location),
+    Counts(Vec<ResidentCount>),
+    Import(Vec<LocationImportResult>),
+    Rollup(OccupancyRollup),
+    Merge(LocationMerge),
+    StatusBatch(StatusBatchResult),
+    Reconcile(Vec<Headcount>),
+    Nearby(Vec<NearbyLocation>),
+    Path(Vec<Location>),
+    Batch(LocationBatch),
+}
+
+/// A location and its straight-line distance, in grid units, from the one the search
+/// started at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NearbyLocation {
+    #[serde(flatten)]
+    pub location: Location,
+    pub distance: f64,
+}
+
+/// What merging `source` into `destination` moved. The source is soft-deleted afterwards.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct LocationMerge {
+    pub source: usize,
+    pub destination: usize,
+    /// Scans re-pointed to the destination, voided ones included
+    pub timestamps: usize,
+    /// Residents whose unit or current location was the source
+    pub residents: usize,
+    /// Locations directly inside the source, now inside the destination
+    pub children: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Merged(LocationMerge),
+    NoSource,
+    NoDestination,
+    // the destination is inside the source, re-parenting the source's children would loop
+    Nested,
+}
+/// What a PUT of a location found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    // the location as it was, then as it is now
+    Updated(Box<Location>, Location),
+    NoLocation,
+    // someone else's PUT got there first, the location is at this version now
+    Stale(u32),
 }
+/// What resolving the route between two locations found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathOutcome {
+    /// From the start up to the nearest location both are inside, then down to the end
+    Route(Vec<Location>),
+    NoFrom,
+    NoTo,
+    // the two are in separate trees, nothing contains them both
+    NoCommonAncestor,
+}
+
+/// The route between two locations given the ancestor chain of each, a location followed by
+/// its parent, then its parent's parent and so on. It climbs `from`'s chain to the first
+/// location that is also on `to`'s, then walks `to`'s chain back down. None when the chains
+/// never meet.
+pub fn route_between(from: Vec<Location>, mut to: Vec<Location>) -> Option<Vec<Location>> {
+    let (up, down) = from
+        .iter()
+        .enumerate()
+        .find_map(|(up, loc)| to.iter().position(|other| other.id == loc.id).map(|down| (up, down)))?;
+    let mut route = from;
+    route.truncate(up + 1);
+    to.truncate(down);
+    route.extend(to.into_iter().rev());
+    Some(route)
+}
+
 impl LocationsResponse {
     pub fn from_success(message: &str) -> Self {
         Self {
@@ -48,6 +439,78 @@ impl LocationsResponse {
             data: LocationData::Index(locations),
         }
     }
+    pub fn from_path(route: Vec<Location>) -> Self {
+        Self {
+            success: true,
+            message: format!("Route through {} locations", route.len()),
+            data: LocationData::Path(route),
+        }
+    }
+    pub fn from_nearby(nearby: Vec<NearbyLocation>) -> Self {
+        Self {
+            success: true,
+            message: "Nearby locations successfully retrieved".to_string(),
+            data: LocationData::Nearby(nearby),
+        }
+    }
+    pub fn from_created(location: Location) -> Self {
+        Self {
+            success: true,
+            message: "Location successfully added".to_string(),
+            data: LocationData::Show(location),
+        }
+    }
+    pub fn from_counts(counts: Vec<ResidentCount>) -> Self {
+        Self {
+            success: true,
+            message: "Resident counts successfully retrieved".to_string(),
+            data: LocationData::Counts(counts),
+        }
+    }
+    pub fn from_reconciliation(headcounts: Vec<Headcount>) -> Self {
+        let discrepancies = headcounts.iter().filter(|count| count.discrepancy).count();
+        Self {
+            success: true,
+            message: format!("{} of {} locations have a discrepancy", discrepancies, headcounts.len()),
+            data: LocationData::Reconcile(headcounts),
+        }
+    }
+    pub fn from_rollup(rollup: OccupancyRollup) -> Self {
+        Self {
+            success: true,
+            message: "Occupancy successfully retrieved".to_string(),
+            data: LocationData::Rollup(rollup),
+        }
+    }
+    pub fn from_import(results: Vec<LocationImportResult>) -> Self {
+        let created = results.iter().filter(|res| res.created).count();
+        Self {
+            success: true,
+            message: format!("Imported {} of {} locations", created, results.len()),
+            data: LocationData::Import(results),
+        }
+    }
+    pub fn from_merge(merge: LocationMerge) -> Self {
+        Self {
+            success: true,
+            message: format!("Location {} merged into location {}", merge.source, merge.destination),
+            data: LocationData::Merge(merge),
+        }
+    }
+    pub fn from_status_batch(result: StatusBatchResult) -> Self {
+        Self {
+            success: true,
+            message: format!("Set {} locations to {}", result.updated.len(), result.status.as_str()),
+            data: LocationData::StatusBatch(result),
+        }
+    }
+    pub fn from_batch(batch: LocationBatch) -> Self {
+        Self {
+            success: true,
+            message: format!("{} locations successfully retrieved, {} not found", batch.locations.len(), batch.missing.len()),
+            data: LocationData::Batch(batch),
+        }
+    }
     pub fn from_location(location: Location) -> Self {
         Self {
             success: true,
@@ -57,25 +520,115 @@ impl LocationsResponse {
     }
 }
 
+/// Upper bound on the length of a location name, in characters
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Furthest a grid coordinate may be from 0 either way
+pub const MAX_COORDINATE: i64 = 1_000_000;
+
 impl Location {
     pub fn new(id: usize, name: String) -> Self {
-        Self { id, name }
+        Self {
+            id,
+            name,
+            capacity: None,
+            status: LocationStatus::Open,
+            deleted_at: None,
+            facility_id: Facility::default(),
+            parent_id: None,
+            x: None,
+            y: None,
+            version: 1,
+        }
+    }
+
+    pub fn with_capacity(id: usize, name: String, capacity: Option<u32>) -> Self {
+        Self {
+            id,
+            name,
+            capacity,
+            status: LocationStatus::Open,
+            deleted_at: None,
+            facility_id: Facility::default(),
+            parent_id: None,
+            x: None,
+            y: None,
+            version: 1,
+        }
+    }
+
+    pub fn with_status(mut self, status: LocationStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_facility(mut self, facility_id: Facility) -> Self {
+        self.facility_id = facility_id;
+        self
+    }
+
+    pub fn with_parent(mut self, parent_id: Option<usize>) -> Self {
+        self.parent_id = parent_id;
+        self
+    }
+
+    pub fn with_coordinates(mut self, x: Option<i64>, y: Option<i64>) -> Self {
+        self.x = x;
+        self.y = y;
+        self
+    }
+
+    /// The grid position, None unless both coordinates are set
+    pub fn coordinates(&self) -> Option<(i64, i64)> {
+        self.x.zip(self.y)
+    }
+
+    /// Checks the payload before it is written, returning a message naming the failed field
+    pub fn validate(&self) -> Result<(), String> {
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("name: must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_NAME_LEN {
+            return Err(format!("name: must be at most {} characters", MAX_NAME_LEN));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("name: must not contain control characters".to_string());
+        }
+        if self.capacity == Some(0) {
+            return Err("capacity: must be greater than zero when set".to_string());
+        }
+        if self.parent_id == Some(0) {
+            return Err("parent_id: SIGNED_OUT can't contain other locations".to_string());
+        }
+        if self.x.is_some() != self.y.is_some() {
+            return Err("x: must be set together with y".to_string());
+        }
+        for (field, value) in [("x", self.x), ("y", self.y)] {
+            if value.is_some_and(|value| value.abs() > MAX_COORDINATE) {
+                return Err(format!("{}: must be between -{} and {}", field, MAX_COORDINATE, MAX_COORDINATE));
+            }
+        }
+        Ok(())
     }
 
+    /// This reads the ENV VAR that will determine what location the particular instance
+    /// of the scannner/server is running in
     pub fn get_env() -> Self {
         Self {
-            /// This reads the ENV VAR that will determine what location the particular instance
-            /// of the scannner/server is running in
             id: std::env::var("LOCATION_ID")
-                .is_ok()
-                .then(|| {
-                    std::env::var("LOCATION_ID")
-                        .unwrap()
-                        .parse::<usize>()
-                        .unwrap()
-                })
+                .ok()
+                .and_then(|id| id.parse::<usize>().ok())
                 .unwrap_or(14),
             name: std::env::var("LOCATION_NAME").unwrap_or(String::from("DELTA UNIT")),
+            capacity: None,
+            status: LocationStatus::Open,
+            deleted_at: None,
+            facility_id: Facility::default(),
+            parent_id: None,
+            x: None,
+            y: None,
+            version: 1,
         }
     }
 
@@ -3,3 +3,19 @@ pub mod residents;
 pub mod timestamps;
 
 pub mod locations;
+
+pub mod api_keys;
+
+pub mod users;
+
+pub mod csv;
+
+pub mod xlsx;
+
+pub mod audit;
+
+pub mod facilities;
+
+pub mod envelope;
+
+pub mod idempotency;
@@ -1,6 +1,10 @@
 use actix_web::error::BlockingError;
 use actix_web::ResponseError;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Serialize};
+use crate::models::csv::field;
+use crate::models::facilities::Facility;
+use crate::models::timestamps::{serialize_opt_time, serialize_time};
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct Resident {
@@ -10,6 +14,95 @@ pub struct Resident {
     pub room: String,
     pub unit: usize,
     pub current_location: usize,
+    // always the caller's, whatever a request body says
+    #[serde(default, skip_deserializing)]
+    pub facility_id: Facility,
+    // only changed through POST /api/residents/{rfid}/status
+    #[serde(default, skip_deserializing)]
+    pub status: ResidentStatus,
+}
+
+/// Where a resident is in their stay. Only active residents are counted as present at a
+/// location or reported missing, the others have left the facility.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResidentStatus {
+    #[default]
+    Active,
+    Released,
+    Transferred,
+}
+
+impl ResidentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResidentStatus::Active => "active",
+            ResidentStatus::Released => "released",
+            ResidentStatus::Transferred => "transferred",
+        }
+    }
+
+    /// Whether a resident can go from this status to `next`. An active resident can be
+    /// released or transferred out, but one who has left only comes back as active with
+    /// `reactivate`, and can't go straight from released to transferred or back. Setting the
+    /// status a resident already has changes nothing and is allowed.
+    pub fn transition(self, next: ResidentStatus, reactivate: bool) -> Result<(), String> {
+        match (self, next) {
+            (from, to) if from == to => Ok(()),
+            (ResidentStatus::Active, _) => Ok(()),
+            (_, ResidentStatus::Active) if reactivate => Ok(()),
+            (from, ResidentStatus::Active) => Err(format!(
+                "a {} resident has to be reactivated, send \"reactivate\": true", from.as_str()
+            )),
+            (from, to) => Err(format!(
+                "a {} resident can't be {}, reactivate them first", from.as_str(), to.as_str()
+            )),
+        }
+    }
+}
+
+impl ToSql for ResidentStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ResidentStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "active" => Ok(ResidentStatus::Active),
+            "released" => Ok(ResidentStatus::Released),
+            "transferred" => Ok(ResidentStatus::Transferred),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Body of POST /api/residents/{rfid}/status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub status: ResidentStatus,
+    // required to make a released or transferred resident active again
+    #[serde(default)]
+    pub reactivate: bool,
+}
+
+/// Optional ?doc=&unit= narrowing a list of residents to a DOC number and the unit they're
+/// assigned to, a blank doc is the same as leaving it out
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct ResidentFilter {
+    pub doc: Option<String>,
+    pub unit: Option<usize>,
+}
+
+impl ResidentFilter {
+    pub fn doc(&self) -> Option<&str> {
+        self.doc.as_deref().map(str::trim).filter(|doc| !doc.is_empty())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc().is_none() && self.unit.is_none()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
@@ -32,6 +125,31 @@ impl ResidentResponse {
             data: Some(ResidentData::Show(resident)),
         }
     }
+    pub fn from_created(resident: Resident) -> Self {
+        Self {
+            success: true,
+            message: "Resident successfully added".to_string(),
+            data: Some(ResidentData::Show(resident)),
+        }
+    }
+    pub fn from_status(resident: Resident) -> Self {
+        Self {
+            success: true,
+            message: format!("Resident is now {}", resident.status.as_str()),
+            data: Some(ResidentData::Status(resident)),
+        }
+    }
+    pub fn from_import(results: Vec<ResidentImportResult>) -> Self {
+        let count = |outcome| results.iter().filter(|res| res.outcome == outcome).count();
+        Self {
+            success: true,
+            message: format!(
+                "Imported {} of {} residents, {} duplicates and {} invalid rows skipped",
+                count(ImportOutcome::Created), results.len(), count(ImportOutcome::Duplicate), count(ImportOutcome::Invalid)
+            ),
+            data: Some(ResidentData::Import(results)),
+        }
+    }
     pub fn from_success(s: &str) -> Self {
         Self {
             success: true,
@@ -46,6 +164,34 @@ impl ResidentResponse {
             data: Some(ResidentData::Index(residents)),
         }
     }
+    pub fn from_last_seen(last_seen: Vec<LastSeen>) -> Self {
+        Self {
+            success: true,
+            message: "Last seen locations successfully retrieved".to_string(),
+            data: Some(ResidentData::LastSeen(last_seen)),
+        }
+    }
+    pub fn from_most_active(residents: Vec<ActiveResident>) -> Self {
+        Self {
+            success: true,
+            message: format!("{} most active residents", residents.len()),
+            data: Some(ResidentData::MostActive(residents)),
+        }
+    }
+    pub fn from_transfer(transfer: Transfer) -> Self {
+        Self {
+            success: true,
+            message: "Resident successfully transferred".to_string(),
+            data: Some(ResidentData::Transfer(transfer)),
+        }
+    }
+    pub fn from_restrictions(restrictions: Vec<Restriction>, msg: &str) -> Self {
+        Self {
+            success: true,
+            message: msg.to_string(),
+            data: Some(ResidentData::Restrictions(restrictions)),
+        }
+    }
     pub fn from_error(msg: &str) -> Self {
         Self {
             success: false,
@@ -54,16 +200,267 @@ impl ResidentResponse {
         }
     }
 }
+/// A resident's most recent scan, the location and time are null if they've never scanned
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSeen {
+    pub rfid: String,
+    pub name: String,
+    pub location: Option<usize>,
+    pub location_name: Option<String>,
+    #[serde(serialize_with = "serialize_opt_time")]
+    pub time: Option<String>,
+}
+
+/// A resident and how many times they were scanned over the leaderboard's range
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActiveResident {
+    pub rfid: String,
+    pub name: String,
+    pub scans: usize,
+}
+
+/// Body of POST /api/residents/{rfid}/transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferPayload {
+    pub to_location_id: usize,
+}
+
+/// Body of POST and DELETE /api/residents/{rfid}/restrictions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestrictionPayload {
+    pub location_id: usize,
+}
+
+/// A location a resident is barred from. Their scans there are still stored, flagged
+/// `restricted` and sent to the webhook as an alert.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Restriction {
+    pub rfid: String,
+    pub location_id: usize,
+    pub location_name: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub created_at: String,
+}
+
+/// What adding a restriction came to
+pub enum RestrictionOutcome {
+    Added(Vec<Restriction>),
+    AlreadyRestricted,
+    NoResident,
+    NoLocation,
+}
+
+/// A completed transfer, both scans were stored at `time`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub rfid: String,
+    pub from_location: usize,
+    pub to_location: usize,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: String,
+}
+
+/// What happened to one CSV row of a resident import
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportOutcome {
+    Created,
+    // a resident with the rfid, or the doc number in the same facility, is already stored,
+    // so uploading a file again adds nobody twice
+    Duplicate,
+    Invalid,
+}
+
+/// Outcome of one CSV row of a resident import
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResidentImportResult {
+    // the row's number in the file, the header is row 1
+    pub row: usize,
+    pub outcome: ImportOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resident: Option<Resident>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ResidentImportResult {
+    pub fn created(row: usize, resident: Resident) -> Self {
+        Self {
+            row,
+            outcome: ImportOutcome::Created,
+            resident: Some(resident),
+            reason: None,
+        }
+    }
+    pub fn duplicate(row: usize, reason: String) -> Self {
+        Self {
+            row,
+            outcome: ImportOutcome::Duplicate,
+            resident: None,
+            reason: Some(reason),
+        }
+    }
+    pub fn invalid(row: usize, reason: String) -> Self {
+        Self {
+            row,
+            outcome: ImportOutcome::Invalid,
+            resident: None,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// A row number and the resident read from it, or why it couldn't be
+pub type ResidentImportRow = (usize, Result<Resident, String>);
+
+/// Reads a resident import, a header row naming `rfid`, `name`, `doc`, `room` and `unit`
+/// columns and optionally `current_location`, in any order, then one resident per row. A
+/// resident whose current_location is left out starts at their unit.
+pub fn residents_from_csv(records: &[Vec<String>]) -> Result<Vec<ResidentImportRow>, String> {
+    let (header, rows) = records.split_first().ok_or("the file is empty")?;
+    let column = |name: &str| header.iter().position(|col| col.trim().eq_ignore_ascii_case(name));
+    let required = |name: &str| column(name).ok_or(format!("the header row has no {} column", name));
+    let columns = ResidentColumns {
+        rfid: required("rfid")?,
+        name: required("name")?,
+        doc: required("doc")?,
+        room: required("room")?,
+        unit: required("unit")?,
+        current_location: column("current_location"),
+    };
+    Ok(rows
+        .iter()
+        .enumerate()
+        .map(|(pos, row)| (pos + 2, columns.resident(row)))
+        .collect())
+}
+
+struct ResidentColumns {
+    rfid: usize,
+    name: usize,
+    doc: usize,
+    room: usize,
+    unit: usize,
+    current_location: Option<usize>,
+}
+
+impl ResidentColumns {
+    fn resident(&self, row: &[String]) -> Result<Resident, String> {
+        let text = |col| field(row, Some(col)).unwrap_or_default().to_string();
+        let location = |name: &str, value: &str| value.parse::<usize>().map_err(|_| format!("{}: '{}' is not a location id", name, value));
+        let unit = match field(row, Some(self.unit)) {
+            Some(unit) => location("unit", unit)?,
+            None => return Err("unit: must not be empty".to_string()),
+        };
+        let current_location = match field(row, self.current_location) {
+            Some(current) => location("current_location", current)?,
+            None => unit,
+        };
+        Ok(Resident::new(text(self.rfid), text(self.name), text(self.doc), text(self.room), unit, current_location))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferOutcome {
+    Moved(Transfer),
+    NoResident,
+    NoLocation,
+    // the resident is away (location 0), so there's nowhere to record them leaving
+    LocationUnknown,
+    AlreadyThere,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResidentData {
     Index(Vec<Resident>),
+    LastSeen(Vec<LastSeen>),
+    MostActive(Vec<ActiveResident>),
+    Transfer(Transfer),
+    Restrictions(Vec<Restriction>),
+    Status(Resident),
+    Import(Vec<ResidentImportResult>),
     Post(Resident),
     Update(Resident),
     Delete(Resident),
     Show(Resident),
 }
 
+/// Most characters a resident's name may hold
+pub const MAX_RESIDENT_NAME_LEN: usize = 128;
+
+/// Least similarity, from 0 to 1, a fuzzy search match needs. A misspelling by a letter
+/// or two of a surname passes, an unrelated name doesn't.
+pub const FUZZY_MATCH_THRESHOLD: f64 = 0.7;
+
+/// How alike a search term is to a resident's name from 0 (nothing alike) to 1 (the same),
+/// ignoring case and punctuation. The term is held against the whole name in either order
+/// and each word of it, so `jonson` finds `Johnson, Mark` and `mark jonson` finds it too.
+pub fn name_similarity(term: &str, name: &str) -> f64 {
+    let words = |text: &str| {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<String>>()
+    };
+    let term = words(term).join(" ");
+    let mut name = words(name);
+    let mut candidates = name.clone();
+    candidates.push(name.join(" "));
+    name.reverse();
+    candidates.push(name.join(" "));
+    candidates
+        .iter()
+        .map(|candidate| {
+            let longest = term.chars().count().max(candidate.chars().count());
+            if longest == 0 {
+                return 0.0;
+            }
+            1.0 - levenshtein(&term, candidate) as f64 / longest as f64
+        })
+        .fold(0.0, f64::max)
+}
+
+// single character insertions, deletions and substitutions between the two
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
 impl Resident {
+    /// Checks the payload before it is written, returning a message naming the failed field
+    pub fn validate(&self) -> Result<(), String> {
+        let rfid = self.rfid.trim();
+        if rfid.is_empty() {
+            return Err("rfid: must not be empty".to_string());
+        }
+        if rfid.len() != self.rfid.len() || rfid.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err("rfid: must not contain spaces or control characters".to_string());
+        }
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("name: must not be empty".to_string());
+        }
+        if name.chars().count() > MAX_RESIDENT_NAME_LEN {
+            return Err(format!("name: must be at most {} characters", MAX_RESIDENT_NAME_LEN));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("name: must not contain control characters".to_string());
+        }
+        if self.doc.trim().is_empty() {
+            return Err("doc: must not be empty".to_string());
+        }
+        Ok(())
+    }
+
     pub fn new(
         rfid: String,
         name: String,
@@ -79,6 +476,8 @@ impl Resident {
             room,
             unit,
             current_location,
+            facility_id: Facility::default(),
+            status: ResidentStatus::default(),
         }
     }
 
@@ -114,31 +513,21 @@ impl UpdateResident {
             room: self.room.unwrap_or(resident.room),
             unit: self.unit.unwrap_or(resident.unit),
             current_location: self.current_location.unwrap_or(resident.current_location),
+            facility_id: resident.facility_id,
+            status: resident.status,
         }
     }
 }
 
 use std::fmt::{Display, Formatter};
 
-use chrono::NaiveDate;
-use serde::Deserializer;
 
+/// Raw range segments, resolved with models::timestamps::parse_range
 #[derive(Debug, Deserialize)]
 pub struct PathParams {
     pub rfid: String,
-    #[serde(deserialize_with = "deserialize_date")]
-    pub start_date: NaiveDate,
-    #[serde(deserialize_with = "deserialize_date")]
-    pub end_date: NaiveDate,
-}
-
-// Deserialize date strings into NaiveDate
-fn deserialize_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let date_str = String::deserialize(deserializer)?;
-    NaiveDate::parse_from_str(&date_str, "%Y-%m-%d").map_err(serde::de::Error::custom)
+    pub start_date: String,
+    pub end_date: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
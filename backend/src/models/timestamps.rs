@@ -1,29 +1,331 @@
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
 
 use actix_web::ResponseError;
-use serde::{de::Error, Deserialize, Serialize};
+use crate::app_config::config;
+use crate::models::csv::ToCsv;
+use crate::models::xlsx::{ToXlsx, XlsxCell};
+use serde::{de::Error, Deserialize, Serialize, Serializer};
 
 #[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
 pub struct PostTimestamp {
+    // set on responses to the id the scan was stored under, for correcting it later
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub id: Option<usize>,
     pub rfid: String,
     pub location: usize,
+    // explicit scan time, the server uses the current time when this is omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    // set on responses when the scan repeated the previous one and wasn't stored
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub duplicate: bool,
+    // set on responses when this scan left the location over its capacity
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub over_capacity: bool,
+    // set on responses when the resident is barred from the location scanned at
+    #[serde(default, skip_deserializing, skip_serializing_if = "std::ops::Not::not")]
+    pub restricted: bool,
 }
 
 impl PostTimestamp {
     pub fn new(rfid: String, location: usize) -> Self {
-        Self { rfid, location }
+        Self {
+            id: None,
+            rfid,
+            location,
+            time: None,
+            duplicate: false,
+            over_capacity: false,
+            restricted: false,
+        }
+    }
+    pub fn with_time(rfid: String, location: usize, time: Option<String>) -> Self {
+        Self {
+            id: None,
+            rfid,
+            location,
+            time,
+            duplicate: false,
+            over_capacity: false,
+            restricted: false,
+        }
+    }
+    /// The body of POST /api/timestamps is checked like a ScanPayload
+    pub fn validate(&self) -> Result<(), String> {
+        validate_scan(&self.rfid, self.time.as_deref())
+    }
+    pub fn duplicate_of(rfid: String, location: usize) -> Self {
+        Self {
+            id: None,
+            rfid,
+            location,
+            time: None,
+            duplicate: true,
+            over_capacity: false,
+            restricted: false,
+        }
+    }
+}
+
+/// Body for POST /api/locations/{id}/timestamps, the location comes from the path
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct ScanPayload {
+    pub rfid: String,
+    pub time: Option<String>,
+}
+
+impl ScanPayload {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_scan(&self.rfid, self.time.as_deref())
+    }
+}
+
+// a scan needs an rfid, and a time it's given has to be one the scans are stored in
+fn validate_scan(rfid: &str, time: Option<&str>) -> Result<(), String> {
+    if rfid.trim().is_empty() {
+        return Err("rfid: must not be empty".to_string());
+    }
+    if let Some(time) = time {
+        NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT).map_err(|_| {
+            format!("time: invalid time '{}', expected YYYY-MM-DD HH:MM:SS", time)
+        })?;
+    }
+    Ok(())
+}
+
+/// One row of POST /api/timestamps/backfill, a historical scan that has to carry the time it
+/// was made
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct BackfillScan {
+    pub rfid: String,
+    pub location: usize,
+    pub time: String,
+}
+
+impl BackfillScan {
+    pub fn validate(&self, now: NaiveDateTime) -> Result<(), String> {
+        if self.rfid.trim().is_empty() {
+            return Err("rfid: must not be empty".to_string());
+        }
+        let time = NaiveDateTime::parse_from_str(&self.time, TIMESTAMP_FORMAT).map_err(|_| {
+            format!("time: invalid time '{}', expected YYYY-MM-DD HH:MM:SS", self.time)
+        })?;
+        if time > now {
+            return Err(format!("time: {} is in the future", self.time));
+        }
+        Ok(())
+    }
+}
+
+/// What DELETE /api/timestamps?before= removed
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct PurgeResult {
+    /// The local day scans before which were deleted, YYYY-MM-DD
+    pub before: String,
+    pub deleted: usize,
+}
+
+/// Most characters a correction's reason may hold
+pub const MAX_REASON_LEN: usize = 500;
+
+/// Body of PATCH /api/timestamps/{id}, a fix to the time or location one scan was recorded with
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TimestampCorrection {
+    pub time: Option<String>,
+    pub location: Option<usize>,
+    // why the scan was wrong, required and kept with the change in the audit log
+    #[serde(default)]
+    pub reason: String,
+}
+
+// a correction or void has to say why, the reason is what the audit log keeps of it
+fn validate_reason(reason: &str) -> Result<(), String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("reason: is required".to_string());
     }
+    if reason.chars().count() > MAX_REASON_LEN {
+        return Err(format!("reason: must be at most {} characters", MAX_REASON_LEN));
+    }
+    Ok(())
 }
 
-use chrono::NaiveDate;
-use serde::Deserializer;
+impl TimestampCorrection {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_reason(&self.reason)?;
+        if self.time.is_none() && self.location.is_none() {
+            return Err("a correction needs a new time or location".to_string());
+        }
+        if let Some(time) = &self.time {
+            NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT).map_err(|_| {
+                format!("time: invalid time '{}', expected YYYY-MM-DD HH:MM:SS", time)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Body of DELETE /api/timestamps/{id}, why a spurious scan is being voided
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TimestampVoid {
+    #[serde(default)]
+    pub reason: String,
+}
 
+impl TimestampVoid {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_reason(&self.reason)
+    }
+}
+
+/// A corrected scan as it was recorded and as it is now
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct CorrectedTimestamp {
+    pub id: usize,
+    pub before: TimeStamp,
+    pub after: TimeStamp,
+}
+
+/// Outcome of a single scan within a batch upload
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct BatchScanResult {
+    pub index: usize,
+    pub rfid: String,
+    pub accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub duplicate: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub over_capacity: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub restricted: bool,
+}
+
+impl BatchScanResult {
+    pub fn accepted(index: usize, ts: PostTimestamp) -> Self {
+        Self {
+            index,
+            rfid: ts.rfid,
+            accepted: true,
+            location: Some(ts.location),
+            error: None,
+            duplicate: ts.duplicate,
+            over_capacity: ts.over_capacity,
+            restricted: ts.restricted,
+        }
+    }
+    pub fn rejected(index: usize, rfid: String, error: String) -> Self {
+        Self {
+            index,
+            rfid,
+            accepted: false,
+            location: None,
+            error: Some(error),
+            duplicate: false,
+            over_capacity: false,
+            restricted: false,
+        }
+    }
+}
+
+/// How urgently a receiver should look at a ScanEvent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    // a resident scanned where they're restricted from
+    Alert,
+}
+
+/// A recorded scan as it's sent to the webhook and the live location sockets
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanEvent {
+    pub rfid: String,
+    // where the badge was scanned, not where that left the resident
+    pub location: usize,
+    pub time: String,
+    pub restricted: bool,
+    pub severity: Severity,
+}
+
+impl ScanEvent {
+    pub fn from_scan(scan: &PostTimestamp) -> Self {
+        let time = scan
+            .time
+            .clone()
+            .unwrap_or_else(|| Utc::now().naive_utc().format(TIMESTAMP_FORMAT).to_string());
+        Self {
+            rfid: scan.rfid.clone(),
+            location: scan.location,
+            time: render_time(&time),
+            restricted: scan.restricted,
+            severity: if scan.restricted { Severity::Alert } else { Severity::Info },
+        }
+    }
+}
+
+/// Format scan times are stored and accepted in
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// A stored scan time, UTC in TIMESTAMP_FORMAT, as RFC 3339: `2023-11-12T15:04:05Z`, or in
+/// FACILITY_TZ with its offset under TIMESTAMP_TZ=local. Anything that doesn't parse as a
+/// stored time is passed through as it is.
+pub fn render_time(stored: &str) -> String {
+    let utc = NaiveDateTime::parse_from_str(stored, "%Y-%m-%d %H:%M:%S%.f")
+        .map(|time| Utc.from_utc_datetime(&time))
+        .or_else(|_| DateTime::parse_from_rfc3339(stored).map(|time| time.with_timezone(&Utc)));
+    match utc {
+        Ok(time) if config().timestamps_local => time.with_timezone(&config().facility_tz).to_rfc3339_opts(SecondsFormat::Secs, false),
+        Ok(time) => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        Err(_) => stored.to_string(),
+    }
+}
+
+/// `serialize_with` for the scan time fields, see render_time
+pub fn serialize_time<S: Serializer>(time: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&render_time(time))
+}
+
+pub fn serialize_opt_time<S: Serializer>(time: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize_time(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+
+/// Optional ?rfid=&doc= narrowing a list of scans to one resident, by their badge or their DOC
+/// number. Blank values are the same as leaving them out.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct ScanFilter {
+    pub rfid: Option<String>,
+    pub doc: Option<String>,
+}
+
+impl ScanFilter {
+    pub fn rfid(&self) -> Option<&str> {
+        self.rfid.as_deref().map(str::trim).filter(|rfid| !rfid.is_empty())
+    }
+
+    pub fn doc(&self) -> Option<&str> {
+        self.doc.as_deref().map(str::trim).filter(|doc| !doc.is_empty())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rfid().is_none() && self.doc().is_none()
+    }
+}
+
+/// Raw range segments, resolved with parse_range
 #[derive(Debug, Deserialize)]
 pub struct RangeParams {
-    #[serde(deserialize_with = "deserialize_date")]
-    pub start_date: NaiveDate,
-    #[serde(deserialize_with = "deserialize_date")]
-    pub end_date: NaiveDate,
+    pub start_date: String,
+    pub end_date: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -44,17 +346,43 @@ impl ResponseError for TimestampResponse {}
 #[derive(Debug, Deserialize, Serialize)]
 pub enum TimestampData {
     Get(Vec<TimeStamp>),
+    Show(TimeStamp),
     Post(PostTimestamp),
+    Movements(Vec<Movement>),
+    Visited(Vec<VisitedLocation>),
+    Batch(Vec<BatchScanResult>),
+    Dwell(BTreeMap<String, i64>),
+    Visits(Vec<Visit>),
+    Hourly(Vec<HourlyCount>),
+    Daily(Vec<DailyCount>),
+    Peak(PeakOccupancy),
+    Corrected(CorrectedTimestamp),
 }
 
 impl TimestampResponse {
     pub fn from_ts(ts: &PostTimestamp) -> Self {
+        let message = if ts.duplicate {
+            "Duplicate scan ignored"
+        } else if ts.restricted {
+            "Timestamp stored, the resident is restricted from this location"
+        } else if ts.over_capacity {
+            "Timestamp stored, location is over capacity"
+        } else {
+            "Timestamp successfully retrieved"
+        };
         Self {
             success: true,
-            message: "Timestamp successfully retrieved".to_string(),
+            message: message.to_string(),
             data: Some(TimestampData::Post(ts.clone())),
         }
     }
+    pub fn from_timestamp(ts: TimeStamp) -> Self {
+        Self {
+            success: true,
+            message: "Timestamp successfully retrieved".to_string(),
+            data: Some(TimestampData::Show(ts)),
+        }
+    }
     pub fn from_db(ts: Vec<TimeStamp>) -> Self {
         Self {
             success: true,
@@ -62,6 +390,70 @@ impl TimestampResponse {
             data: Some(TimestampData::Get(ts)),
         }
     }
+    pub fn from_movements(movements: Vec<Movement>) -> Self {
+        Self {
+            success: true,
+            message: "Movements successfully retrieved".to_string(),
+            data: Some(TimestampData::Movements(movements)),
+        }
+    }
+    pub fn from_visited(visited: Vec<VisitedLocation>) -> Self {
+        Self {
+            success: true,
+            message: "Visited locations successfully retrieved".to_string(),
+            data: Some(TimestampData::Visited(visited)),
+        }
+    }
+    pub fn from_batch(results: Vec<BatchScanResult>) -> Self {
+        let accepted = results.iter().filter(|res| res.accepted).count();
+        Self {
+            success: true,
+            message: format!("{} of {} timestamps stored", accepted, results.len()),
+            data: Some(TimestampData::Batch(results)),
+        }
+    }
+    pub fn from_dwell(dwell: BTreeMap<String, i64>) -> Self {
+        Self {
+            success: true,
+            message: "Dwell times successfully retrieved".to_string(),
+            data: Some(TimestampData::Dwell(dwell)),
+        }
+    }
+    pub fn from_visits(visits: Vec<Visit>) -> Self {
+        Self {
+            success: true,
+            message: "Visits successfully retrieved".to_string(),
+            data: Some(TimestampData::Visits(visits)),
+        }
+    }
+    pub fn from_hourly(hours: Vec<HourlyCount>) -> Self {
+        Self {
+            success: true,
+            message: "Hourly occupancy successfully retrieved".to_string(),
+            data: Some(TimestampData::Hourly(hours)),
+        }
+    }
+    pub fn from_daily(days: Vec<DailyCount>) -> Self {
+        Self {
+            success: true,
+            message: "Daily scan counts successfully retrieved".to_string(),
+            data: Some(TimestampData::Daily(days)),
+        }
+    }
+    pub fn from_peak(peak: PeakOccupancy) -> Self {
+        Self {
+            success: true,
+            message: "Peak occupancy successfully retrieved".to_string(),
+            data: Some(TimestampData::Peak(peak)),
+        }
+    }
+    pub fn from_correction(corrected: CorrectedTimestamp) -> Self {
+        Self {
+            success: true,
+            message: "Timestamp successfully corrected".to_string(),
+            data: Some(TimestampData::Corrected(corrected)),
+        }
+    }
     pub fn from_error(msg: &str) -> Self {
         Self {
             success: false,
@@ -71,22 +463,412 @@ impl TimestampResponse {
     }
 }
 
-// Deserialize date strings into NaiveDate
-fn deserialize_date<'de, D>(deserializer: D) -> Result<NaiveDate, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let date_str = String::deserialize(deserializer)?;
-    NaiveDate::parse_from_str(&date_str, "%Y-%m-%d").map_err(serde::de::Error::custom)
+/// Resolve a single range segment, either a YYYY-MM-DD date or one of the keywords
+/// `today`, `yesterday`, `last7days` or `thismonth`. Keywords name a span of days, so the
+/// start of a range takes the first day of the span and the end takes the last.
+fn resolve_date(segment: &str, today: NaiveDate, is_start: bool) -> Result<NaiveDate, String> {
+    let (first, last) = match segment.to_lowercase().as_str() {
+        "today" => (today, today),
+        "yesterday" => {
+            let yesterday = today - Duration::days(1);
+            (yesterday, yesterday)
+        }
+        "last7days" => (today - Duration::days(6), today),
+        "thismonth" => (today.with_day(1).unwrap_or(today), today),
+        _ => {
+            let date = NaiveDate::parse_from_str(segment, "%Y-%m-%d").map_err(|_| {
+                format!(
+                    "invalid date '{}', expected YYYY-MM-DD, today, yesterday, last7days or thismonth",
+                    segment
+                )
+            })?;
+            (date, date)
+        }
+    };
+    Ok(if is_start { first } else { last })
+}
+
+/// Parse raw start/end path segments, rejecting bad dates, backwards ranges and ranges
+/// longer than MAX_RANGE_DAYS. Keywords are resolved against the facility's local date.
+pub fn parse_range(start: &str, end: &str) -> Result<(NaiveDate, NaiveDate), String> {
+    let (start, end) = parse_uncapped_range(start, end)?;
+    if (end - start).num_days() > config().max_range_days {
+        return Err(format!(
+            "range {} to {} spans {} days, it may span at most {}",
+            start,
+            end,
+            (end - start).num_days(),
+            config().max_range_days
+        ));
+    }
+    Ok((start, end))
+}
+
+/// parse_range without the MAX_RANGE_DAYS cap, for the exports that are asked for in bulk
+/// on purpose
+pub fn parse_uncapped_range(start: &str, end: &str) -> Result<(NaiveDate, NaiveDate), String> {
+    let today = facility_today();
+    let (start, end) = (
+        resolve_date(start, today, true)?,
+        resolve_date(end, today, false)?,
+    );
+    if start > end {
+        return Err(format!("start date {} is after end date {}", start, end));
+    }
+    Ok((start, end))
+}
+
+/// Scans at a location during one hour of the facility's local time
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct HourlyCount {
+    // start of the hour, RFC 3339 with the local offset so the repeated hour of a DST change is unambiguous
+    pub hour_bucket: String,
+    pub count: usize,
+}
+
+/// Scans at a location during one day of the facility's local time
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct DailyCount {
+    // YYYY-MM-DD
+    pub day: String,
+    pub count: usize,
+}
+
+/// Scans at one of the facility's locations during one day of its local time, a row of the
+/// count report
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct LocationDailyCount {
+    // YYYY-MM-DD
+    pub day: String,
+    pub location_id: usize,
+    pub location_name: String,
+    pub scans: usize,
+}
+
+impl ToCsv for LocationDailyCount {
+    const HEADER: &'static [&'static str] = &["day", "location_id", "location_name", "scans"];
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![self.day.clone(), self.location_id.to_string(), self.location_name.clone(), self.scans.to_string()]
+    }
+}
+
+impl ToXlsx for LocationDailyCount {
+    fn xlsx_cells(&self) -> Vec<XlsxCell> {
+        vec![
+            XlsxCell::Text(self.day.clone()),
+            XlsxCell::Number(self.location_id as f64),
+            XlsxCell::Text(self.location_name.clone()),
+            XlsxCell::Number(self.scans as f64),
+        ]
+    }
+}
+
+/// Every day from `start` to `end` with the number of scans `counts` holds for it, keyed by
+/// the day as YYYY-MM-DD. Days without scans are zero.
+pub fn daily_counts(start: NaiveDate, end: NaiveDate, counts: &BTreeMap<String, usize>) -> Vec<DailyCount> {
+    start
+        .iter_days()
+        .take_while(|day| *day <= end)
+        .map(|day| {
+            let day = day.format("%Y-%m-%d").to_string();
+            let count = counts.get(&day).copied().unwrap_or(0);
+            DailyCount { day, count }
+        })
+        .collect()
+}
+
+/// Today's date in the facility's timezone
+pub fn facility_today() -> NaiveDate {
+    Utc::now().with_timezone(&config().facility_tz).date_naive()
+}
+
+/// The instant `date` starts in the facility's timezone. Where a DST change skips midnight
+/// the day starts at the first local hour that exists.
+pub fn local_midnight(date: NaiveDate) -> DateTime<Utc> {
+    (0..3)
+        .find_map(|hour| {
+            date.and_hms_opt(hour, 0, 0)
+                .and_then(|time| config().facility_tz.from_local_datetime(&time).earliest())
+        })
+        .map(|time| time.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap_or_default()))
+}
+
+/// The stored times the facility's local days from `start` to `end` lie between, from the
+/// first inclusive to the second exclusive, to compare against a `ts` column
+pub fn day_bounds(start: NaiveDate, end: NaiveDate) -> (String, String) {
+    (
+        local_midnight(start).format(TIMESTAMP_FORMAT).to_string(),
+        local_midnight(end + Duration::days(1)).format(TIMESTAMP_FORMAT).to_string(),
+    )
+}
+
+/// Every local hour from the start of `start` to the end of `end`, with the number of scans
+/// `counts` holds for it keyed by hours since `local_midnight(start)`. Hours without scans
+/// are zero, and a day a DST change shortens or lengthens has 23 or 25 buckets.
+pub fn hourly_counts(start: NaiveDate, end: NaiveDate, counts: &BTreeMap<i64, usize>) -> Vec<HourlyCount> {
+    let from = local_midnight(start);
+    let hours = (local_midnight(end + Duration::days(1)) - from).num_hours();
+    (0..hours)
+        .map(|hour| HourlyCount {
+            hour_bucket: (from + Duration::hours(hour))
+                .with_timezone(&config().facility_tz)
+                .to_rfc3339_opts(SecondsFormat::Secs, false),
+            count: counts.get(&hour).copied().unwrap_or(0),
+        })
+        .collect()
+}
+
+/// Seconds a resident spent at `location` between `window_start` and `window_end`.
+///
+/// `prior` is the location of the resident's last scan before the window, so a first scan
+/// at `location` inside the window is treated as them scanning out. Scans are replayed the
+/// same way store_timestamp moves residents: a scan at the location they're already at
+/// sends them away, any other scan moves them there. Time is counted while they're at
+/// `location`, and an interval that is still open at the end of the window runs to `window_end`.
+pub fn dwell_seconds(
+    location: usize,
+    prior: Option<usize>,
+    scans: &[(usize, NaiveDateTime)],
+    window_start: NaiveDateTime,
+    window_end: NaiveDateTime,
+) -> i64 {
+    let mut current = prior;
+    let mut since = window_start;
+    let mut total = 0;
+    for (scanned_at, time) in scans {
+        if current == Some(location) {
+            total += (*time - since).num_seconds();
+        }
+        current = if current == Some(*scanned_at) {
+            None
+        } else {
+            Some(*scanned_at)
+        };
+        since = *time;
+    }
+    if current == Some(location) && window_end > since {
+        total += (window_end - since).num_seconds();
+    }
+    total
+}
+
+/// One stay at a location, from the scan that brought the resident in to the one that took
+/// them out. A stay that began before the range starts at the range's start, and one still
+/// open when it ends stops there, `clipped` marks either. A resident who hasn't scanned out
+/// yet is `ongoing`, with no `left` and the seconds counted up to now.
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct Visit {
+    pub rfid: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub arrived: String,
+    #[serde(serialize_with = "serialize_opt_time")]
+    pub left: Option<String>,
+    pub seconds: i64,
+    pub clipped: bool,
+    pub ongoing: bool,
+}
+
+/// Each of a resident's stays at `location` between `window_start` and `window_end`, the
+/// intervals dwell_seconds adds up, replayed the same way. `now` is when the window would end
+/// if it ran to the present, a stay still open then hasn't ended yet.
+pub fn visit_intervals(
+    location: usize,
+    rfid: &str,
+    prior: Option<usize>,
+    scans: &[(usize, NaiveDateTime)],
+    window: (NaiveDateTime, NaiveDateTime),
+    now: NaiveDateTime,
+) -> Vec<Visit> {
+    let (window_start, window_end) = window;
+    let visit = |arrived: NaiveDateTime, left: Option<NaiveDateTime>, clipped: bool| Visit {
+        rfid: rfid.to_string(),
+        arrived: arrived.format(TIMESTAMP_FORMAT).to_string(),
+        left: left.map(|left| left.format(TIMESTAMP_FORMAT).to_string()),
+        seconds: (left.unwrap_or(window_end) - arrived).num_seconds(),
+        clipped,
+        ongoing: left.is_none(),
+    };
+    let mut visits = Vec::new();
+    let mut current = prior;
+    // when the open stay began and whether that was before the window
+    let mut open = (current == Some(location)).then_some((window_start, true));
+    for (scanned_at, time) in scans {
+        current = if current == Some(*scanned_at) { None } else { Some(*scanned_at) };
+        match (open, current == Some(location)) {
+            (Some((arrived, clipped)), false) => {
+                visits.push(visit(arrived, Some(*time), clipped));
+                open = None;
+            }
+            (None, true) => open = Some((*time, false)),
+            _ => {}
+        }
+    }
+    if let Some((arrived, clipped)) = open {
+        if window_end < now {
+            visits.push(visit(arrived, Some(window_end), true));
+        } else {
+            visits.push(visit(arrived, None, clipped));
+        }
+    }
+    visits
+}
+
+/// The highest headcount a location reached in a range and the first moment it did
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct PeakOccupancy {
+    pub peak: usize,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: String,
+}
+
+/// One resident's scans at one location, or which of the two doesn't exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResidentScansOutcome {
+    Scans(Vec<TimeStamp>),
+    NoLocation,
+    NoResident,
+}
+
+/// The parts of the day's summary, each counted by its own query so they can run at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryPart {
+    TotalScans,
+    BusiestLocation,
+    ResidentsMoved,
+    OverCapacityEvents,
+}
+
+/// The location scanned at most in a day and how many times
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct BusiestLocation {
+    pub id: usize,
+    pub name: String,
+    pub scans: usize,
+}
+
+/// One local day of scans across a facility's locations, for the morning briefing
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct TodaySummary {
+    /// The day in the facility's timezone, YYYY-MM-DD
+    pub date: String,
+    pub total_scans: usize,
+    /// None on a day nothing was scanned
+    pub busiest_location: Option<BusiestLocation>,
+    /// Distinct residents with a scan in the day
+    pub residents_moved: usize,
+    /// Scans that left their location over its capacity
+    pub over_capacity_events: usize,
+}
+
+/// The headcount changes a resident's scans make at `location`, +1 for each arrival and -1
+/// for each departure, replayed the same way as dwell_seconds. Being there already going
+/// into the window isn't a change, the caller counts it in the starting headcount.
+pub fn presence_changes(
+    location: usize,
+    prior: Option<usize>,
+    scans: &[(usize, NaiveDateTime)],
+) -> Vec<(NaiveDateTime, i64)> {
+    let mut current = prior;
+    let mut changes = Vec::new();
+    for (scanned_at, time) in scans {
+        let next = if current == Some(*scanned_at) { None } else { Some(*scanned_at) };
+        match (current == Some(location), next == Some(location)) {
+            (false, true) => changes.push((*time, 1)),
+            (true, false) => changes.push((*time, -1)),
+            _ => {}
+        }
+        current = next;
+    }
+    changes
+}
+
+/// Sweeps the headcount changes in time order, starting from the `present` residents at
+/// `window_start`, to find the peak. A departure in the same second as an arrival is counted
+/// first, so a handover isn't taken for a moment with both residents there.
+pub fn peak_occupancy(
+    present: usize,
+    mut changes: Vec<(NaiveDateTime, i64)>,
+    window_start: NaiveDateTime,
+) -> PeakOccupancy {
+    changes.sort_unstable();
+    let mut headcount = present as i64;
+    let (mut peak, mut at) = (headcount, window_start);
+    for (time, delta) in changes {
+        headcount += delta;
+        if headcount > peak {
+            peak = headcount;
+            at = time;
+        }
+    }
+    PeakOccupancy {
+        peak: peak.max(0) as usize,
+        time: at.format(TIMESTAMP_FORMAT).to_string(),
+    }
+}
+
+/// A single scan joined with the name of the location it was made at
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct Movement {
+    pub rfid: String,
+    pub location: usize,
+    pub location_name: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub time: String,
+}
+
+/// A location a resident scanned at during a range, with their first and last scan there
+#[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
+pub struct VisitedLocation {
+    pub location: usize,
+    pub location_name: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub first_seen: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub last_seen: String,
+    pub scans: usize,
 }
 
 #[derive(Debug, Serialize, Clone, Deserialize, Eq, PartialEq)]
 pub struct TimeStamp {
     pub rfid: String,
     pub location: usize,
+    #[serde(serialize_with = "serialize_opt_time")]
     pub time: Option<String>,
 }
 
+impl ToCsv for TimeStamp {
+    const HEADER: &'static [&'static str] = &["rfid", "location", "time"];
+
+    fn csv_fields(&self) -> Vec<String> {
+        vec![
+            self.rfid.clone(),
+            self.location.to_string(),
+            self.time.as_deref().map(render_time).unwrap_or_default(),
+        ]
+    }
+}
+
+// the same moment render_time gives, as the plain date and time a spreadsheet cell holds
+impl ToXlsx for TimeStamp {
+    fn xlsx_cells(&self) -> Vec<XlsxCell> {
+        let time = self
+            .time
+            .as_deref()
+            .and_then(|time| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f").ok())
+            .map(|time| match config().timestamps_local {
+                true => Utc.from_utc_datetime(&time).with_timezone(&config().facility_tz).naive_local(),
+                false => time,
+            });
+        vec![
+            XlsxCell::Text(self.rfid.clone()),
+            XlsxCell::Number(self.location as f64),
+            time.map_or(XlsxCell::Empty, XlsxCell::Time),
+        ]
+    }
+}
+
 impl TimeStamp {
     pub fn new(rfid: String, location: usize, time: Option<String>) -> Self {
         Self {
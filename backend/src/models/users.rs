@@ -0,0 +1,104 @@
+use crate::middleware::roles::Role;
+use crate::models::facilities::Facility;
+use rand::RngCore;
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::num::NonZeroU32;
+
+/// PBKDF2-HMAC-SHA256 rounds a new password is hashed with. Stored hashes record their own,
+/// so raising this only affects passwords set afterwards.
+pub const PASSWORD_ITERATIONS: u32 = 100_000;
+
+/// The user the test seed creates, with a staff role
+pub const TEST_USERNAME: &str = "test-officer";
+pub const TEST_PASSWORD: &str = "test-password";
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// A member of staff who logs in for a bearer token, as stored. Only a salted hash of the
+/// password is kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
+    // the facility the issued tokens are scoped to
+    pub facility_id: Facility,
+}
+
+/// Body of POST /api/auth/login
+#[derive(Clone, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// so a logged payload never carries the password
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).finish_non_exhaustive()
+    }
+}
+
+/// A token a login issued, sent as `Authorization: Bearer <token>` until it expires
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LoginResponse {
+    pub token: String,
+    pub token_type: String,
+    // RFC 3339, in UTC
+    pub expires_at: String,
+    pub role: Role,
+    pub facility_id: Facility,
+}
+
+/// `pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>` of a password with a fresh random salt
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let iterations = NonZeroU32::new(PASSWORD_ITERATIONS).expect("iterations are not zero");
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+    format!("pbkdf2-sha256${}${}${}", PASSWORD_ITERATIONS, to_hex(&salt), to_hex(&hash))
+}
+
+/// Whether the password is the one `stored` was hashed from, compared in constant time. A
+/// stored value that doesn't parse matches nothing.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let (Some(iterations), Some(salt), Some(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        from_hex(salt),
+        from_hex(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok()
+}
+
+/// 18 random bytes as hex, printed once when a user is created
+pub fn generate_password() -> String {
+    let mut bytes = [0u8; 18];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| hex.get(at..at + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,48 @@
+use crate::models::csv::ToCsv;
+use chrono::NaiveDateTime;
+use rust_xlsxwriter::{Format, Workbook, XlsxError};
+
+/// Content-Type of an .xlsx workbook
+pub const XLSX_MIME: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// One typed cell, so a spreadsheet sorts and filters numbers and times as what they are
+/// rather than as text
+#[derive(Debug, Clone, PartialEq)]
+pub enum XlsxCell {
+    Text(String),
+    Number(f64),
+    Time(NaiveDateTime),
+    Empty,
+}
+
+/// A row type that can be written to a worksheet, under the same header row as its CSV
+pub trait ToXlsx: ToCsv {
+    fn xlsx_cells(&self) -> Vec<XlsxCell>;
+}
+
+/// A workbook of one sheet, the header row in bold and frozen above one row per item
+pub fn to_xlsx<T: ToXlsx>(sheet_name: &str, rows: &[T]) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name(sheet_name)?;
+    let bold = Format::new().set_bold();
+    let time = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    for (col, title) in T::HEADER.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *title, &bold)?;
+        sheet.set_column_width(col as u16, 20)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    for (index, item) in rows.iter().enumerate() {
+        let row = index as u32 + 1;
+        for (col, cell) in item.xlsx_cells().into_iter().enumerate() {
+            let col = col as u16;
+            match cell {
+                XlsxCell::Text(text) => sheet.write_string(row, col, text)?,
+                XlsxCell::Number(number) => sheet.write_number(row, col, number)?,
+                XlsxCell::Time(at) => sheet.write_datetime_with_format(row, col, at, &time)?,
+                XlsxCell::Empty => sheet,
+            };
+        }
+    }
+    workbook.save_to_buffer()
+}
@@ -0,0 +1,128 @@
+use crate::controllers::negotiation::xlsx_response;
+use crate::database::db::StreamItem;
+use crate::errors::ApiError;
+use crate::models::csv::{csv_header, csv_record, ToCsv};
+use crate::models::timestamps::{parse_range, parse_uncapped_range};
+use crate::models::xlsx::ToXlsx;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use chrono::NaiveDate;
+use futures_util::{future, stream, Stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+
+/// What an export is downloaded as, ?format= with CSV when it's left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    // written a row at a time as they're read
+    Csv,
+    // a workbook is only written once it's whole, so its rows are held until then
+    Xlsx,
+}
+
+impl ExportFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, String> {
+        match format.map(str::trim).map(str::to_lowercase).as_deref() {
+            None | Some("") | Some("csv") => Ok(ExportFormat::Csv),
+            Some("xlsx") => Ok(ExportFormat::Xlsx),
+            Some(other) => Err(format!("format: '{}' isn't one, use csv or xlsx", other)),
+        }
+    }
+
+    /// The days from `start` to `end`. A CSV streams however many rows there are, so
+    /// MAX_RANGE_DAYS doesn't apply; a workbook is built in memory, so it does.
+    pub fn range(&self, start: &str, end: &str) -> Result<(NaiveDate, NaiveDate), String> {
+        match self {
+            ExportFormat::Csv => parse_uncapped_range(start, end),
+            ExportFormat::Xlsx => parse_range(start, end),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+/// Rows a workbook export holds at most. The whole sheet is in memory before the first byte
+/// goes out, so past this the export is refused and the CSV, which streams, is the way.
+pub const MAX_XLSX_ROWS: usize = 100_000;
+
+/// ?format= on an export whose range is in its path
+#[derive(Debug, Deserialize)]
+pub struct FormatParams {
+    format: Option<String>,
+}
+
+impl FormatParams {
+    pub fn format(&self) -> Result<ExportFormat, String> {
+        ExportFormat::parse(self.format.as_deref())
+    }
+}
+
+/// ?start=&end=&location_id=&format= of the facility-wide exports
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    start: Option<String>,
+    end: Option<String>,
+    pub location_id: Option<usize>,
+    format: Option<String>,
+}
+
+impl ExportParams {
+    pub fn format(&self) -> Result<ExportFormat, String> {
+        ExportFormat::parse(self.format.as_deref())
+    }
+
+    /// The days to export, both required, see ExportFormat::range
+    pub fn range(&self, format: ExportFormat) -> Result<(NaiveDate, NaiveDate), String> {
+        let required = |value: &Option<String>, name: &str| match value.as_deref().map(str::trim) {
+            Some(value) if !value.is_empty() => Ok(value.to_string()),
+            _ => Err(format!("{}: is required, a date as YYYY-MM-DD", name)),
+        };
+        format.range(&required(&self.start, "start")?, &required(&self.end, "end")?)
+    }
+}
+
+/// The header row and then a line per row as the stream yields it. A row that fails to read
+/// ends the body there, the client sees a cut off download rather than a short one.
+pub fn csv_body<T: ToCsv>(rows: impl Stream<Item = StreamItem<T>>) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let header = stream::once(future::ready(Ok(Bytes::from(csv_header::<T>()))));
+    header.chain(rows.map(|row| {
+        let row = row.map_err(actix_web::error::ErrorInternalServerError)?;
+        Ok(Bytes::from(csv_record(&row)))
+    }))
+}
+
+/// `rows` as a download named `name` plus the format's extension. A CSV is streamed, a
+/// workbook is written once every row has been read, and is refused with a 400 when there
+/// are more than MAX_XLSX_ROWS of them.
+pub async fn export_response<T, S>(format: ExportFormat, name: &str, rows: S) -> Result<HttpResponse, ApiError>
+where
+    T: ToXlsx + 'static,
+    S: Stream<Item = StreamItem<T>> + 'static,
+{
+    let filename = format!("{}.{}", name, format.extension());
+    match format {
+        ExportFormat::Csv => Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header(header::ContentDisposition::attachment(filename))
+            .streaming(csv_body(rows))),
+        ExportFormat::Xlsx => {
+            // one past the cap is enough to know it's over, without reading the rest
+            let rows = rows.take(MAX_XLSX_ROWS + 1).try_collect::<Vec<T>>().await.map_err(|err| {
+                log::error!("Unable to read the rows of {}: {}", filename, err);
+                ApiError::Db(format!("Unable to export {}", filename))
+            })?;
+            if rows.len() > MAX_XLSX_ROWS {
+                return Err(ApiError::Validation(format!(
+                    "format: {} would have more than {} rows, narrow the range or use format=csv",
+                    filename, MAX_XLSX_ROWS
+                )));
+            }
+            xlsx_response(HttpResponse::Ok(), &filename, &rows)
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::live::LiveScans;
+use actix_web::dev::ServerHandle;
+use std::sync::Arc;
+
+/// Stops the server gracefully on SIGTERM or SIGINT: it stops accepting connections and lets
+/// the requests already running finish, for up to SHUTDOWN_TIMEOUT_SECONDS. The live feeds
+/// never finish on their own, so they're closed first. actix's own handling treats SIGINT as
+/// a forced stop, so the server runs with it disabled.
+pub async fn stop_on_signal(server: ServerHandle, live: Arc<LiveScans>) {
+    let signal = terminated().await;
+    log::info!("{} received, closing live feeds and draining in-flight requests", signal);
+    live.close();
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn terminated() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = match signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(err) => {
+            log::error!("unable to listen for SIGTERM: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
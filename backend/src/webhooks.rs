@@ -0,0 +1,103 @@
+use crate::app_config::config;
+use crate::models::timestamps::{PostTimestamp, ScanEvent};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>` when WEBHOOK_SECRET is set
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// How long one delivery attempt may take before it counts as failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Log target of deliveries that ran out of retries, so they can be collected and replayed
+pub const DEAD_LETTER_TARGET: &str = "webhook_dead_letter";
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
+
+/// Sends a stored scan to WEBHOOK_URL on a spawned task, so the response never waits on the
+/// receiver. Failed deliveries are retried WEBHOOK_RETRIES times, backing off from
+/// WEBHOOK_RETRY_BACKOFF_MS, and then logged to DEAD_LETTER_TARGET with the body.
+pub fn notify_scan(scan: &PostTimestamp) {
+    let Some(url) = config().webhook_url.as_ref() else {
+        return;
+    };
+    let event = ScanEvent::from_scan(scan);
+    let url = url.clone();
+    actix_web::rt::spawn(async move {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(err) => return log::error!("Unable to encode webhook for {:?}: {}", event, err),
+        };
+        let mut attempt = 0;
+        loop {
+            match deliver(&url, &body).await {
+                Ok(()) => return,
+                Err(err) if attempt < config().webhook_retries => {
+                    let backoff = config().webhook_retry_backoff_ms.saturating_mul(2u64.saturating_pow(attempt));
+                    attempt += 1;
+                    log::warn!(
+                        "Webhook delivery failed, retrying in {}ms (attempt {}/{}): {}",
+                        backoff, attempt, config().webhook_retries, err
+                    );
+                    tokio::time::sleep(Duration::from_millis(backoff)).await;
+                }
+                Err(err) => {
+                    return log::error!(
+                        target: DEAD_LETTER_TARGET,
+                        "Webhook to {} gave up after {} attempts: {} body={}",
+                        url,
+                        attempt + 1,
+                        err,
+                        String::from_utf8_lossy(&body)
+                    );
+                }
+            }
+        }
+    });
+}
+
+// a response outside 2xx is as much a failure as not reaching the receiver
+async fn deliver(url: &str, body: &[u8]) -> Result<(), String> {
+    let mut request = CLIENT
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_vec());
+    if let Some(secret) = config().webhook_secret.as_ref() {
+        request = request.header(SIGNATURE_HEADER, signature(secret.as_bytes(), body));
+    }
+    let response = request.send().await.map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("receiver responded {}", response.status()));
+    }
+    Ok(())
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body` under `secret`, what a receiver recomputes
+/// to check the call came from us
+pub fn signature(secret: &[u8], body: &[u8]) -> String {
+    let mac: String = hmac_sha256(secret, body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", mac)
+}
+
+// RFC 2104 over SHA-256, which has a 64 byte block
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|k| k ^ byte);
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+    Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
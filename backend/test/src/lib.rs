@@ -1,15 +1,58 @@
-use reqwest::blocking::Response;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::Serialize;
 use serde_json::Value;
 use std::collections::HashMap;
 
 const BASE_URL: &str = "http://localhost:8080/api";
 
+#[derive(Serialize)]
+struct Claims<'a> {
+    sub: &'a str,
+    exp: u64,
+    role: &'a str,
+    facility_id: usize,
+}
+
+// signed with the same JWT_SECRET the server was started with, "test-secret" by default
+fn token(role: &str) -> String {
+    token_in_facility(role, 1)
+}
+
+fn token_in_facility(role: &str, facility_id: usize) -> String {
+    let secret = std::env::var("JWT_SECRET").unwrap_or("test-secret".to_string());
+    let exp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 600;
+    let claims = Claims { sub: "apitest", exp, role, facility_id };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+// every request carries a valid staff token unless the test builds its own client
+fn client() -> Client {
+    client_with_role("staff")
+}
+
+fn client_with_role(role: &str) -> Client {
+    client_with_token(&token(role))
+}
+
+fn client_with_token(token: &str) -> Client {
+    let mut headers = HeaderMap::new();
+    let bearer = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+    headers.insert(AUTHORIZATION, bearer);
+    Client::builder().default_headers(headers).build().unwrap()
+}
+
 fn make_request(
     endpoint: &str,
     method: reqwest::Method,
     body: Option<HashMap<&str, &str>>,
 ) -> Response {
-    let client = reqwest::blocking::Client::new();
+    let client = client();
     let url = format!("{}/{}", BASE_URL, endpoint);
     let request_builder = match method {
         reqwest::Method::GET => client.get(&url),
@@ -45,7 +88,7 @@ pub mod tests {
     #[test]
     fn test_residents_create() {
         let fake_location = json!({"rfid": "338888222889999", "name": "Fake resident", "doc": "29752", "room": "C-8", "unit": 4});
-        let resp = reqwest::blocking::Client::new()
+        let resp = client()
             .post(format!("{}/residents", BASE_URL))
             .json(&fake_location)
             .send()
@@ -65,6 +108,64 @@ pub mod tests {
         assert_eq!(response.status().as_u16(), 200);
     }
 
+    #[test]
+    fn test_residents_update_validated() {
+        let rfid = "403403403403403";
+        let resident = json!({"rfid": rfid, "name": "Patched Resident", "doc": "42008", "room": "P-1", "unit": 1, "current_location": 1});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let patch = |rfid: &str, body: Value| {
+            client()
+                .patch(format!("{}/residents/{}", BASE_URL, rfid))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        for (body, field) in [(json!({"name": "   "}), "name:"), (json!({"doc": ""}), "doc:")] {
+            let response = patch(rfid, body);
+            assert_eq!(response.status().as_u16(), 400);
+            assert!(response.json::<Value>().unwrap()["error"].as_str().unwrap().contains(field));
+        }
+        assert_eq!(patch("404404404404405", json!({"name": "Nobody"})).status().as_u16(), 404);
+        let response = patch(rfid, json!({"room": "P-2"}));
+        assert_eq!(response.status().as_u16(), 200);
+        let response = make_request(&format!("residents/{}", rfid), reqwest::Method::GET, None);
+        let body = response.json::<Value>().unwrap().to_string();
+        assert!(body.contains("Patched Resident") && body.contains("P-2"), "{}", body);
+    }
+
+    #[test]
+    fn test_residents_doc_unique() {
+        let store = |rfid: &str, doc: &str| {
+            let resident = json!({"rfid": rfid, "name": "Numbered Resident", "doc": doc, "room": "D-1", "unit": 1, "current_location": 1});
+            client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&resident)
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(store("404404404404406", "42009").status().as_u16(), 201);
+        // the doc number as a single resident, the same rule the import holds to
+        let response = store("404404404404407", "42009");
+        assert_eq!(response.status().as_u16(), 409);
+        assert!(response.json::<Value>().unwrap()["error"].as_str().unwrap().contains("doc 42009"));
+        assert_eq!(store("404404404404407", "42010").status().as_u16(), 201);
+        let patch = |doc: &str| {
+            client()
+                .patch(format!("{}/residents/404404404404407", BASE_URL))
+                .json(&json!({"doc": doc}))
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(patch("42009").status().as_u16(), 409);
+        // keeping their own doc number isn't a clash
+        assert_eq!(patch("42010").status().as_u16(), 200);
+    }
+
     #[test]
     fn test_residents_delete() {
         let resident_id = "338888222889999";
@@ -77,77 +178,2933 @@ pub mod tests {
     }
 
     #[test]
-    fn test_locations_index() {
-        let response = make_request("locations", reqwest::Method::GET, None);
+    fn test_residents_last_seen() {
+        let response = make_request("residents/last-seen", reqwest::Method::GET, None);
         assert_eq!(response.status().as_u16(), 200);
-        assert!(response.json::<Vec<Value>>().is_ok());
+        let body = response.json::<Value>().unwrap();
+        let residents = body["data"]["LastSeen"].as_array().unwrap();
+        assert!(!residents.is_empty());
+        assert!(residents.iter().all(|res| res.get("location").is_some() && res.get("time").is_some()));
     }
+
     #[test]
-    fn test_locations_show() {
-        let response = make_request("locations/4", reqwest::Method::GET, None);
+    fn test_residents_missing() {
+        // some seeded residents have never scanned
+        let response = make_request("residents/missing?hours=1", reqwest::Method::GET, None);
         assert_eq!(response.status().as_u16(), 200);
-        assert_eq!(response.json::<Value>().unwrap()["name"], "ASU");
+        let body = response.json::<Value>().unwrap();
+        assert!(!body["data"]["LastSeen"].as_array().unwrap().is_empty());
+        let response = make_request("residents/missing?hours=-3", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 400);
     }
+
     #[test]
-    fn test_locations_create() {
-        let fake_location = json!({"id": 69, "name": "Fake Location"});
-        let resp = reqwest::blocking::Client::new()
-            .post(format!("{}/locations", BASE_URL))
-            .json(&fake_location)
+    fn test_body_size_limits() {
+        let name = "x".repeat(70 * 1024);
+        let body = json!({"rfid": "202202202202202", "name": name, "doc": "41987", "room": "L-1", "unit": 1, "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&body)
             .send()
             .expect("Failed to execute request");
-        assert_eq!(resp.status().as_u16(), 201);
+        assert_eq!(response.status().as_u16(), 413);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["kind"], "payload_too_large");
+        assert!(body["error"].as_str().unwrap().contains("65536 bytes"));
+
+        // a chunked body has no Content-Length, it's cut off once it passes the limit
+        let chunked = reqwest::blocking::Body::new(std::io::Cursor::new(vec![b'{'; 70 * 1024]));
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .header("Content-Type", "application/json")
+            .body(chunked)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 413);
+
+        // the imports take more, these rows are only refused for their overlong names
+        let mut csv = "name\r\n".to_string();
+        for row in 0..800 {
+            csv.push_str(&format!("Oversized {} {}\r\n", row, "y".repeat(150)));
+        }
+        assert!(csv.len() > 65536);
+        let response = client()
+            .post(format!("{}/locations/import", BASE_URL))
+            .header("Content-Type", "text/csv")
+            .body(csv)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let rows = response.json::<Value>().unwrap()["data"]["Import"].as_array().unwrap().clone();
+        assert_eq!(rows.len(), 800);
+        assert!(rows.iter().all(|row| row["created"] == false));
     }
+    #[test]
+    fn test_residents_import() {
+        let ids = create_tree(&[("Import Dorm", None)]);
+        let csv = format!(
+            "rfid,name,doc,room,unit\r\n161616161616161,\"Imported, One\",41982,K-1,{unit}\r\n\
+             171717171717171,Imported Two,41983,K-2,{unit}\r\n181818181818181,,41984,K-3,{unit}\r\n\
+             161616161616161,Imported Again,41985,K-4,{unit}\r\n",
+            unit = ids[0]
+        );
+        let upload = || {
+            let response = client()
+                .post(format!("{}/residents/import", BASE_URL))
+                .header("Content-Type", "text/csv")
+                .body(csv.clone())
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Import"].as_array().unwrap().clone()
+        };
+        let rows = upload();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0]["row"], 2);
+        assert_eq!(rows[0]["outcome"], "created");
+        assert_eq!(rows[0]["resident"]["name"], "Imported, One");
+        // left out, the current location is the unit
+        assert_eq!(rows[0]["resident"]["current_location"], ids[0]);
+        assert_eq!(rows[1]["outcome"], "created");
+        assert_eq!(rows[2]["outcome"], "invalid");
+        assert!(rows[2]["reason"].as_str().unwrap().starts_with("name"));
+        // an earlier row of the same file already has the rfid
+        assert_eq!(rows[3]["outcome"], "duplicate");
+
+        // the same file again stores nobody twice
+        let rows = upload();
+        let outcomes = rows.iter().map(|row| row["outcome"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(outcomes, ["duplicate", "duplicate", "invalid", "duplicate"]);
+        let response = make_request("residents/171717171717171", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
 
+        let response = client()
+            .post(format!("{}/residents/import", BASE_URL))
+            .header("Content-Type", "text/csv")
+            .body("rfid,name,doc\r\n191919191919191,No Room,41986\r\n")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+        for rfid in ["161616161616161", "171717171717171"] {
+            let response = make_request(&format!("residents/{}", rfid), reqwest::Method::DELETE, None);
+            assert_eq!(response.status().as_u16(), 204);
+        }
+    }
     #[test]
-    fn test_locations_timestamps() {
-        let response = make_request("locations/8/timestamps", reqwest::Method::GET, None);
+    fn test_residents_status() {
+        let ids = create_tree(&[("Discharge Hall", None)]);
+        let rfid = "151515151515151";
+        let resident = json!({"rfid": rfid, "name": "Leaving Resident", "doc": "41981", "room": "J-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let set_status = |body: Value| {
+            client()
+                .post(format!("{}/residents/{}/status", BASE_URL, rfid))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        let missing = || {
+            let response = make_request("residents/missing?hours=1", reqwest::Method::GET, None);
+            let body = response.json::<Value>().unwrap();
+            body["data"]["LastSeen"].as_array().unwrap().iter().any(|seen| seen["rfid"] == rfid)
+        };
+        let present = || {
+            let response = make_request(&format!("locations/{}/occupancy", ids[0]), reqwest::Method::GET, None);
+            response.json::<Value>().unwrap()["data"]["Index"].as_array().unwrap().len()
+        };
+        // never scanned, so missing until they're released
+        assert!(missing());
+        let response = set_status(json!({"status": "released"}));
         assert_eq!(response.status().as_u16(), 200);
-        assert!(response.json::<Vec<Value>>().is_ok());
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Status"]["status"], "released");
+        assert!(!missing());
+
+        assert_eq!(set_status(json!({"status": "transferred"})).status().as_u16(), 409);
+        assert_eq!(set_status(json!({"status": "active"})).status().as_u16(), 409);
+        assert_eq!(set_status(json!({"status": "paroled"})).status().as_u16(), 400);
+        assert_eq!(set_status(json!({"status": "active", "reactivate": true})).status().as_u16(), 200);
+        assert!(missing());
+
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": rfid}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        assert_eq!(present(), 1);
+        assert_eq!(set_status(json!({"status": "transferred"})).status().as_u16(), 200);
+        assert_eq!(present(), 0);
+
+        let response = client()
+            .post(format!("{}/residents/404404404404404/status", BASE_URL))
+            .json(&json!({"status": "released"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 404);
     }
 
     #[test]
-    fn test_locations_timestamps_between() {
-        let response = make_request(
-            "locations/11/timestamps/2023-11-10/2023-11-19",
-            reqwest::Method::GET,
-            None,
-        );
+    fn test_residents_search() {
+        let residents = |q: &str| {
+            let response = make_request(&format!("residents?q={}", q), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Index"].as_array().unwrap().clone()
+        };
+        let smiths = residents("SMITH");
+        assert!(smiths.len() >= 2);
+        assert!(smiths.iter().all(|r| r["name"].as_str().unwrap().to_lowercase().contains("smith")));
+        let prefixed = residents("555555");
+        assert!(prefixed.len() >= 3);
+        assert!(prefixed.iter().all(|r| r["rfid"].as_str().unwrap().starts_with("555555")));
+        // a LIKE wildcard is matched literally, and no match is an empty list
+        assert!(residents("%25").is_empty());
+    }
+
+    #[test]
+    fn test_residents_most_active() {
+        let ids = create_tree(&[("Leaderboard Yard", None)]);
+        let residents = [("323323323323323", "41998"), ("323323323323324", "41999"), ("323323323323325", "42000")];
+        for (rfid, doc) in residents {
+            let resident = json!({"rfid": rfid, "name": "Active Resident", "doc": doc, "room": "W-1", "unit": ids[0], "current_location": 0});
+            let response = client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&resident)
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        // the third ties the first, the tie goes to the lower rfid
+        let mut scans = Vec::new();
+        for (rfid, count) in [(residents[2].0, 3), (residents[1].0, 1), (residents[0].0, 3)] {
+            for n in 0..count {
+                scans.push(json!({"rfid": rfid, "location": ids[0], "time": format!("2020-05-1{} 09:00:00", n)}));
+            }
+        }
+        scans.push(json!({"rfid": residents[1].0, "location": ids[0], "time": "2020-06-15 09:00:00"}));
+        let response = client_with_role("admin")
+            .post(format!("{}/timestamps/backfill", BASE_URL))
+            .json(&scans)
+            .send()
+            .expect("Failed to execute request");
         assert_eq!(response.status().as_u16(), 200);
-        assert!(response.json::<Vec<Value>>().is_ok());
+
+        let response = make_request("residents/most-active/2020-05-01/2020-05-31", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let board = body["data"]["MostActive"].as_array().unwrap();
+        assert_eq!(board.len(), 3, "{}", body);
+        assert_eq!(board[0], json!({"rfid": residents[0].0, "name": "Active Resident", "scans": 3}));
+        assert_eq!(board[1]["rfid"], residents[2].0);
+        assert_eq!(board[1]["scans"], 3);
+        assert_eq!(board[2]["rfid"], residents[1].0);
+        assert_eq!(board[2]["scans"], 1);
+
+        let response = make_request("residents/most-active/2020-05-01/2020-05-31?limit=2", reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["MostActive"].as_array().unwrap().len(), 2);
+        for path in [
+            "residents/most-active/2020-05-31/2020-05-01",
+            "residents/most-active/2020-01-01/2020-12-31",
+            "residents/most-active/May/2020-05-31",
+            "residents/most-active/2020-05-01/2020-05-31?limit=0",
+            "residents/most-active/2020-05-01/2020-05-31?limit=101",
+            "residents/most-active/2020-05-01/2020-05-31?limit=ten",
+        ] {
+            assert_eq!(make_request(path, reqwest::Method::GET, None).status().as_u16(), 400, "{}", path);
+        }
     }
+
     #[test]
-    fn test_locations_residents() {
-        let response = make_request("locations/8/residents", reqwest::Method::GET, None);
+    fn test_residents_restrictions() {
+        let ids = create_tree(&[("Restricted Vault", None), ("Open Commons", None)]);
+        let rfid = "301301301301301";
+        let resident = json!({"rfid": rfid, "name": "Restricted Resident", "doc": "41996", "room": "U-1", "unit": ids[1], "current_location": ids[1]});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let restrictions = format!("{}/residents/{}/restrictions", BASE_URL, rfid);
+        let restrict = |location: u64| {
+            client().post(&restrictions).json(&json!({"location_id": location})).send().expect("Failed to execute request")
+        };
+        let response = restrict(ids[0]);
+        assert_eq!(response.status().as_u16(), 201);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Restrictions"][0]["location_id"], ids[0]);
+        assert_eq!(body["data"]["Restrictions"][0]["location_name"], "Restricted Vault");
+        assert_eq!(restrict(ids[0]).status().as_u16(), 409);
+        assert_eq!(restrict(0).status().as_u16(), 400);
+        assert_eq!(restrict(999999).status().as_u16(), 404);
+        let response = client()
+            .post(format!("{}/residents/000000000000404/restrictions", BASE_URL))
+            .json(&json!({"location_id": ids[0]}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 404);
+
+        // still recorded, and flagged
+        let scan = |location: u64, time: &str| {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": rfid, "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+            response.json::<Value>().unwrap()["data"]["Post"]["restricted"].as_bool().unwrap_or(false)
+        };
+        assert!(scan(ids[0], "2017-04-01 08:00:00"));
+        assert!(!scan(ids[1], "2017-04-01 09:00:00"));
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps/batch", BASE_URL, ids[0]))
+            .json(&json!([{"rfid": rfid, "time": "2017-04-01 10:00:00"}]))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Batch"][0]["restricted"], true);
+
+        let response = make_request(&format!("residents/{}/restrictions", rfid), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Restrictions"].as_array().unwrap().len(), 1);
+
+        let lift = || client().delete(&restrictions).json(&json!({"location_id": ids[0]})).send().expect("Failed to execute request");
+        let response = lift();
         assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Value>().unwrap()["data"]["Restrictions"].as_array().unwrap().is_empty());
+        assert_eq!(lift().status().as_u16(), 404);
+        assert!(!scan(ids[0], "2017-04-01 11:00:00"));
     }
 
     #[test]
-    fn test_timestamps() {
-        // TestTimestampsController
-        let response = make_request("timestamps", reqwest::Method::GET, None);
+    fn test_residents_search_fuzzy() {
+        let resident = json!({"rfid": "267267267267267", "name": "Fuzzington, Bartholomew", "doc": "41993", "room": "R-1", "unit": 6, "current_location": 6});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let residents = |q: &str| {
+            let response = make_request(&format!("residents?{}", q), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Index"].as_array().unwrap().clone()
+        };
+        // the default stays an exact match
+        assert!(residents("q=Fuzingtin").is_empty());
+        let found = residents("q=Fuzingtin&fuzzy=true");
+        assert_eq!(found[0]["rfid"], "267267267267267");
+        assert_eq!(residents("q=bartolomew%20fuzzington&fuzzy=true")[0]["rfid"], "267267267267267");
+        // a substring match is returned as it is, with no approximate ones after it
+        let exact = residents("q=Fuzzington&fuzzy=true");
+        assert!(exact.iter().all(|r| r["name"].as_str().unwrap().contains("Fuzzington")));
+        // too unlike any name to be returned
+        assert!(residents("q=Qrxvwz&fuzzy=true").is_empty());
+    }
+
+    fn transfer(rfid: &str, to: usize) -> Response {
+        client()
+            .post(format!("{}/residents/{}/transfer", BASE_URL, rfid))
+            .json(&json!({ "to_location_id": to }))
+            .send()
+            .expect("Failed to execute request")
+    }
+
+    #[test]
+    fn test_residents_transfer() {
+        // seeded at location 6 and not scanned by any other test
+        let response = transfer("999999999999999", 8);
         assert_eq!(response.status().as_u16(), 200);
-        assert!(response.json::<Vec<Value>>().is_ok());
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Transfer"]["from_location"], 6);
+        assert_eq!(body["data"]["Transfer"]["to_location"], 8);
+        assert_eq!(transfer("999999999999999", 8).status().as_u16(), 409);
+        assert_eq!(transfer("999999999999999", 99999).status().as_u16(), 404);
+        assert_eq!(transfer("000000000000000", 8).status().as_u16(), 404);
     }
 
     #[test]
-    fn test_timestamps_post() {
-        let data = json!({"rfid": "888888222888777", "dest": 1});
-        let response = reqwest::blocking::Client::new()
-            .post(format!("{}/timestamps", BASE_URL))
-            .json(&data)
+    fn test_residents_concurrent_transfers() {
+        let ids = create_tree(&[("Race Hall", None), ("Race East", None), ("Race West", None)]);
+        let rfid = "234234234234234";
+        let resident = json!({"rfid": rfid, "name": "Racing Resident", "doc": "41990", "room": "N-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
             .send()
             .expect("Failed to execute request");
         assert_eq!(response.status().as_u16(), 201);
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": rfid}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+
+        // both go through, one after the other, so the second moves them from where the first left them
+        let moves: Vec<Value> = std::thread::scope(|scope| {
+            let racers: Vec<_> = [ids[1], ids[2]]
+                .into_iter()
+                .map(|to| scope.spawn(move || transfer(rfid, to as usize)))
+                .collect();
+            racers
+                .into_iter()
+                .map(|racer| {
+                    let response = racer.join().unwrap();
+                    assert_eq!(response.status().as_u16(), 200);
+                    response.json::<Value>().unwrap()["data"]["Transfer"].clone()
+                })
+                .collect()
+        });
+        let from: Vec<u64> = moves.iter().map(|moved| moved["from_location"].as_u64().unwrap()).collect();
+        assert!(from.contains(&ids[0]), "{:?}", moves);
+        let (first, last) = if moves[0]["from_location"] == ids[0] { (&moves[0], &moves[1]) } else { (&moves[1], &moves[0]) };
+        assert_eq!(last["from_location"], first["to_location"]);
+
+        // the resident record and the latest scan agree on a single place
+        let final_location = last["to_location"].as_u64().unwrap();
+        let response = make_request(&format!("residents/{}", rfid), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["current_location"], final_location);
+        for location in &ids {
+            let response = make_request(&format!("locations/{}/occupancy", location), reqwest::Method::GET, None);
+            let present = response.json::<Value>().unwrap()["data"]["Index"].as_array().unwrap().len();
+            assert_eq!(present, usize::from(*location == final_location), "location {}", location);
+        }
     }
+
     #[test]
-    fn test_timestamps_between() {
+    fn test_residents_movements() {
         let response = make_request(
-            "timestamps/2023-11-18/2023-11-19",
+            "residents/888888222888888/movements/2023-11-01/2023-11-30",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let response = make_request(
+            "residents/888888222888888/movements/2023-01-01/2023-12-31",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_residents_locations() {
+        // a resident of its own, so no other test's scans land in the range
+        let resident = json!({"rfid": "445544554455445", "name": "Visiting Resident", "doc": "29753", "room": "C-9", "unit": 4, "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let ids = create_tree(&[("Law Library", None), ("Barber Shop", None)]);
+        for (location, time) in [(ids[0], "2023-10-10 09:00:00"), (ids[1], "2023-10-10 10:00:00"), (ids[0], "2023-10-11 09:00:00")] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": "445544554455445", "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let response = make_request(
+            "residents/445544554455445/locations/2023-10-01/2023-10-31",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let visited = response.json::<Value>().unwrap()["data"]["Visited"].clone();
+        let visited = visited.as_array().unwrap();
+        assert_eq!(visited.len(), 2);
+        assert_eq!(visited[0]["location"], ids[0]);
+        assert_eq!(visited[0]["location_name"], "Law Library");
+        assert_eq!(visited[0]["first_seen"], "2023-10-10T09:00:00Z");
+        assert_eq!(visited[0]["last_seen"], "2023-10-11T09:00:00Z");
+        assert_eq!(visited[0]["scans"], 2);
+        assert_eq!(visited[1]["location"], ids[1]);
+        let response = make_request(
+            "residents/445544554455445/locations/2023-01-01/2023-12-31",
             reqwest::Method::GET,
             None,
         );
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_locations_index() {
+        let response = make_request("locations", reqwest::Method::GET, None);
         assert_eq!(response.status().as_u16(), 200);
         assert!(response.json::<Vec<Value>>().is_ok());
     }
+    #[test]
+    fn test_locations_index_paged() {
+        for query in ["offset=18446744073709551615", "limit=18446744073709551615", "limit=1&offset=9223372036854775808"] {
+            let response = make_request(&format!("locations?{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+        let response = make_request("locations?limit=1&offset=9223372036854775807", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let response = make_request("locations?limit=5&offset=0", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers().contains_key("X-Total-Count"));
+        let link = response.headers()["link"].to_str().unwrap();
+        assert!(link.contains(r#"</api/locations?limit=5&offset=5>; rel="next""#));
+        assert!(link.contains(r#"rel="first""#) && link.contains(r#"rel="last""#));
+        assert!(!link.contains(r#"rel="prev""#));
+    }
+    #[test]
+    fn test_locations_search() {
+        let response = make_request("locations?q=asu", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        assert!(body["data"]["Index"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|loc| loc["name"] == "ASU"));
+    }
+    #[test]
+    fn test_locations_index_etag() {
+        let response = make_request("locations?q=asu", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let etag = response.headers()["etag"].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/"));
+        let response = client()
+            .get(format!("{}/locations?q=asu", BASE_URL))
+            .header("If-None-Match", etag)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 304);
+        assert!(response.text().unwrap().is_empty());
+    }
+    #[test]
+    fn test_cache_control() {
+        let cache_control = |endpoint: &str, status: u16| {
+            let response = make_request(endpoint, reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), status, "{}", endpoint);
+            let vary = response.headers().get("vary").map(|vary| vary.to_str().unwrap().to_string());
+            (response.headers()["cache-control"].to_str().unwrap().to_string(), vary)
+        };
+        for endpoint in ["locations", "locations?q=asu", "locations/6"] {
+            let (directive, vary) = cache_control(endpoint, 200);
+            assert_eq!(directive, "public, max-age=30", "{}", endpoint);
+            assert!(vary.unwrap().contains("Authorization"), "{}", endpoint);
+        }
+        for endpoint in ["locations/6/occupancy", "locations/6/timestamps", "timestamps", "residents", "summary/today"] {
+            assert_eq!(cache_control(endpoint, 200).0, "no-store", "{}", endpoint);
+        }
+        // a missing location isn't cached as one
+        assert_eq!(cache_control("locations/999999", 404).0, "no-store");
+    }
+    #[test]
+    fn test_locations_index_stable() {
+        for name in ["zeta Ordering Room", "Alpha Ordering Room"] {
+            let response = client()
+                .post(format!("{}/locations", BASE_URL))
+                .json(&json!({ "name": name }))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        let listed = |query: &str| {
+            let response = make_request(&format!("locations?{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Index"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|loc| (loc["name"].as_str().unwrap().to_lowercase(), loc["id"].as_u64().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let index = || listed("");
+        let first = index();
+        let ids = first.iter().map(|(_, id)| *id).collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), first.len());
+        // by name ignoring case, then by id
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert_eq!(first, sorted);
+        let alpha = first.iter().position(|(name, _)| name == "alpha ordering room").unwrap();
+        let zeta = first.iter().position(|(name, _)| name == "zeta ordering room").unwrap();
+        assert!(alpha < zeta);
+        for _ in 0..2 {
+            assert_eq!(index(), first);
+        }
+        // paged, searched or with the deleted ones, it's still that order
+        for query in ["limit=1000", "q=ordering room", "include_deleted=true"] {
+            let rows = listed(query);
+            let mut sorted = rows.clone();
+            sorted.sort();
+            assert_eq!(rows, sorted, "{}", query);
+        }
+    }
+    #[test]
+    fn test_locations_index_never_fails_with_200() {
+        for query in ["", "limit=5", "q=room", "include_deleted=true", "sort=name"] {
+            let response = make_request(&format!("locations?{}", query), reqwest::Method::GET, None);
+            let status = response.status().as_u16();
+            let body = response.json::<Value>().unwrap();
+            if status == 200 {
+                assert_eq!(body["success"], true, "{}", query);
+                assert!(body["data"]["Index"].is_array(), "{}", query);
+            } else {
+                assert!(status >= 500, "{} answered {}", query, status);
+                assert!(body["error"].is_string(), "{}", query);
+            }
+        }
+    }
+    #[test]
+    fn test_locations_index_csv() {
+        let response = client()
+            .get(format!("{}/locations?q=asu", BASE_URL))
+            .header("Accept", "text/csv")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/csv"));
+        let body = response.text().unwrap();
+        assert!(body.starts_with("id,name,capacity\r\n"));
+        assert!(body.contains(",ASU,"));
+    }
+    #[test]
+    fn test_locations_index_not_acceptable() {
+        let response = client()
+            .get(format!("{}/locations", BASE_URL))
+            .header("Accept", "application/xml")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 406);
+    }
+    #[test]
+    fn test_locations_bad_headers() {
+        let store = |content_type: Option<&str>, accept: Option<&str>| {
+            let mut request = client()
+                .post(format!("{}/locations", BASE_URL))
+                .body(r#"{"name": "Header Hall"}"#);
+            if let Some(content_type) = content_type {
+                request = request.header("Content-Type", content_type);
+            }
+            if let Some(accept) = accept {
+                request = request.header("Accept", accept);
+            }
+            request.send().expect("Failed to execute request")
+        };
+        let index = |accept: &str| {
+            client()
+                .get(format!("{}/locations", BASE_URL))
+                .header("Accept", accept)
+                .send()
+                .expect("Failed to execute request")
+        };
+        let kind = |response: reqwest::blocking::Response| response.json::<Value>().unwrap()["kind"].clone();
+
+        // a body that isn't sent as JSON is refused before it's read
+        for content_type in [Some("text/plain"), Some("application/x-www-form-urlencoded"), None] {
+            let response = store(content_type, None);
+            assert_eq!(response.status().as_u16(), 415, "{:?}", content_type);
+            assert_eq!(kind(response), "unsupported_media_type");
+        }
+        let response = store(Some("not a media type"), None);
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(kind(response), "validation");
+
+        // store only answers with JSON
+        for accept in ["text/csv", "application/json;q=0, */*;q=0"] {
+            let response = store(Some("application/json"), Some(accept));
+            assert_eq!(response.status().as_u16(), 406, "{}", accept);
+            assert_eq!(kind(response), "not_acceptable");
+        }
+        let response = store(Some("application/json"), Some("nonsense"));
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(kind(response), "validation");
+
+        // index negotiates, but a garbled Accept isn't taken as no preference
+        for accept in ["nonsense", "text/csv;q=high", "application/json, text"] {
+            let response = index(accept);
+            assert_eq!(response.status().as_u16(), 400, "{}", accept);
+            assert_eq!(kind(response), "validation");
+        }
+        let response = index("image/png");
+        assert_eq!(response.status().as_u16(), 406);
+        assert_eq!(kind(response), "not_acceptable");
+        assert_eq!(index("image/png, application/json;q=0.1").status().as_u16(), 200);
+
+        // none of the refused stores went through
+        let response = store(Some("application/json; charset=utf-8"), Some("application/json"));
+        assert_eq!(response.status().as_u16(), 201);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["name"], "Header Hall");
+
+        // an import takes the file as CSV or a form, not as JSON
+        let response = client()
+            .post(format!("{}/locations/import", BASE_URL))
+            .header("Content-Type", "application/json")
+            .body(r#"{"name": "Header Annex"}"#)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 415);
+    }
+    #[test]
+    fn test_locations_show() {
+        let response = make_request("locations/4", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["name"], "ASU");
+    }
+    #[test]
+    fn test_locations_show_not_found() {
+        let response = make_request("locations/99999", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+    }
+    #[test]
+    fn test_locations_invalid_id() {
+        for id in ["0", "abc", "-4", "9223372036854775808", "99999999999999999999999"] {
+            for endpoint in [format!("locations/{}", id), format!("locations/{}/timestamps/2023-11-10/2023-11-19", id)] {
+                let response = make_request(&endpoint, reqwest::Method::GET, None);
+                assert_eq!(response.status().as_u16(), 400, "{}", endpoint);
+                assert_eq!(response.json::<Value>().unwrap()["kind"], "validation");
+            }
+        }
+    }
+    #[test]
+    fn test_lists_envelope() {
+        const V2: &str = "application/vnd.mvscanner.v2+json";
+        let ids = create_tree(&[("Envelope Dayroom", None)]);
+        let resident = json!({"rfid": "404404404404408", "name": "Enveloped Resident", "doc": "42011", "room": "E-1", "unit": ids[0], "current_location": ids[0]});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": "404404404404408", "time": "2022-03-01 09:00:00"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let get = |endpoint: &str| {
+            let response = client()
+                .get(format!("{}/{}", BASE_URL, endpoint))
+                .header("Accept", V2)
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200, "{}", endpoint);
+            assert_eq!(response.headers()["Content-Type"], V2, "{}", endpoint);
+            response.json::<Value>().unwrap()
+        };
+        let lists = [
+            "residents?doc=42011".to_string(),
+            format!("locations/{}/residents", ids[0]),
+            format!("locations/{}/timestamps/2022-03-01/2022-03-01", ids[0]),
+            "timestamps/2022-03-01/2022-03-01?rfid=404404404404408".to_string(),
+        ];
+        for endpoint in &lists {
+            let body = get(endpoint);
+            assert_eq!(body["data"].as_array().unwrap().len(), 1, "{}", endpoint);
+            assert_eq!(body["meta"]["total"], 1, "{}", endpoint);
+            assert!(body["error"].is_null(), "{}", endpoint);
+        }
+        for endpoint in ["residents?limit=1&offset=0", "timestamps?limit=1", &format!("locations/{}/timestamps?limit=1", ids[0])] {
+            let body = get(endpoint);
+            assert!(body["data"].as_array().unwrap().len() <= 1, "{}", endpoint);
+            assert_eq!(body["meta"]["limit"], 1, "{}", endpoint);
+            assert_eq!(body["meta"]["offset"], 0, "{}", endpoint);
+        }
+        // the plain bodies are unchanged
+        let body = make_request(&lists[0], reqwest::Method::GET, None).json::<Value>().unwrap();
+        assert_eq!(body["data"]["Index"].as_array().unwrap().len(), 1);
+        let body = make_request(&lists[3], reqwest::Method::GET, None).json::<Value>().unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_locations_envelope() {
+        const V2: &str = "application/vnd.mvscanner.v2+json";
+        let ids = create_tree(&[("Greenhouse", None)]);
+        let get = |endpoint: &str, accept: &str| {
+            client()
+                .get(format!("{}/{}", BASE_URL, endpoint))
+                .header("Accept", accept)
+                .send()
+                .expect("Failed to execute request")
+        };
+
+        let response = get(&format!("locations/{}", ids[0]), V2);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], V2);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["id"].as_u64(), Some(ids[0]));
+        assert_eq!(body["data"]["name"], "Greenhouse");
+        assert_eq!(body["meta"], json!({}));
+        assert!(body["error"].is_null());
+
+        let body = get("locations?limit=2&offset=1", V2).json::<Value>().unwrap();
+        assert!(body["data"].as_array().unwrap().len() <= 2);
+        assert_eq!(body["meta"]["limit"], 2);
+        assert_eq!(body["meta"]["offset"], 1);
+        assert!(body["meta"]["total"].as_u64().unwrap() >= 1);
+
+        let body = get("locations", V2).json::<Value>().unwrap();
+        assert_eq!(body["meta"]["total"].as_u64(), Some(body["data"].as_array().unwrap().len() as u64));
+
+        // errors share the envelope, from the extractor as well as the handler
+        for endpoint in ["locations/0", "locations/999999"] {
+            let response = get(endpoint, V2);
+            assert_eq!(response.headers()["Content-Type"], V2);
+            let body = response.json::<Value>().unwrap();
+            assert!(body["data"].is_null(), "{}", endpoint);
+            assert!(body["error"]["message"].is_string(), "{}", endpoint);
+        }
+        assert_eq!(get("locations/0", V2).json::<Value>().unwrap()["error"]["kind"], "validation");
+        assert_eq!(get("locations/999999", V2).json::<Value>().unwrap()["error"]["kind"], "not_found");
+
+        // clients that don't ask for it keep the original bodies
+        let body = get(&format!("locations/{}", ids[0]), "application/json").json::<Value>().unwrap();
+        assert_eq!(body["data"]["Show"]["id"].as_u64(), Some(ids[0]));
+        assert_eq!(get("locations/0", "*/*").json::<Value>().unwrap()["kind"], "validation");
+
+        let response = make_request(&format!("locations/{}", ids[0]), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+    }
+
+    #[test]
+    fn test_locations_merge() {
+        let ids = create_tree(&[("Rollout Dorm", None), ("Rollout Dorm (dup)", None), ("Rollout Bunk", Some(1))]);
+        let (dst, src, child) = (ids[0], ids[1], ids[2]);
+        let resident = json!({"rfid": "556655665566556", "name": "Merged Resident", "doc": "31864", "room": "D-2", "unit": src, "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        for time in ["2023-08-01 09:00:00", "2023-08-01 10:00:00", "2023-08-01 11:00:00"] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, src))
+                .json(&json!({"rfid": "556655665566556", "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let merge = |role: &str, src: u64, dst: u64| {
+            client_with_role(role)
+                .post(format!("{}/locations/{}/merge/{}", BASE_URL, src, dst))
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(merge("staff", src, dst).status().as_u16(), 403);
+        assert_eq!(merge("admin", src, src).status().as_u16(), 400);
+        // the child can't take in its own parent
+        assert_eq!(merge("admin", src, child).status().as_u16(), 400);
+
+        let response = merge("admin", src, dst);
+        assert_eq!(response.status().as_u16(), 200);
+        let summary = response.json::<Value>().unwrap()["data"]["Merge"].clone();
+        assert_eq!(summary["source"].as_u64(), Some(src));
+        assert_eq!(summary["destination"].as_u64(), Some(dst));
+        assert_eq!(summary["timestamps"], 3);
+        assert_eq!(summary["residents"], 1);
+        assert_eq!(summary["children"], 1);
+
+        let response = make_request(&format!("locations/{}", src), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+        let response = make_request(&format!("locations/{}/timestamps/2023-08-01/2023-08-01", dst), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().len(), 3);
+        let resident = make_request("residents/556655665566556", reqwest::Method::GET, None).json::<Value>().unwrap();
+        assert_eq!(resident["data"]["Show"]["unit"].as_u64(), Some(dst));
+        let children = make_request(&format!("locations/{}/children", dst), reqwest::Method::GET, None).json::<Value>().unwrap();
+        assert_eq!(children["data"]["Index"][0]["id"].as_u64(), Some(child));
+        assert_eq!(merge("admin", src, dst).status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_locations_status_batch() {
+        let ids = create_tree(&[("Gym North", None), ("Gym South", None)]);
+        let batch = |role: &str, body: Value| {
+            client_with_role(role)
+                .post(format!("{}/locations/status/batch", BASE_URL))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        let lockdown = json!({"ids": [ids[0], ids[1], ids[0], 999999], "status": "lockdown"});
+        assert_eq!(batch("read_only", lockdown.clone()).status().as_u16(), 403);
+        assert_eq!(batch("staff", json!({"ids": [], "status": "lockdown"})).status().as_u16(), 400);
+
+        let response = batch("staff", lockdown);
+        assert_eq!(response.status().as_u16(), 200);
+        let result = response.json::<Value>().unwrap()["data"]["StatusBatch"].clone();
+        assert_eq!(result["updated"], json!([ids[0], ids[1]]));
+        assert_eq!(result["not_found"], json!([999999]));
+        for id in &ids {
+            let location = make_request(&format!("locations/{}", id), reqwest::Method::GET, None).json::<Value>().unwrap();
+            assert_eq!(location["data"]["Show"]["status"], "lockdown");
+        }
+        let response = batch("staff", json!({"ids": ids, "status": "open"}));
+        assert_eq!(response.json::<Value>().unwrap()["data"]["StatusBatch"]["updated"], json!(ids));
+    }
+
+    #[test]
+    fn test_locations_reconcile() {
+        let ids = create_tree(&[("Annex Dorm", None)]);
+        let resident = json!({"rfid": "667766776677667", "name": "Counted Resident", "doc": "41975", "room": "E-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let headcount = || {
+            let response = make_request("locations/reconcile", reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            let counts = response.json::<Value>().unwrap()["data"]["Reconcile"].as_array().unwrap().clone();
+            let deltas: Vec<i64> = counts.iter().map(|count| count["delta"].as_i64().unwrap().abs()).collect();
+            assert!(deltas.windows(2).all(|pair| pair[0] >= pair[1]));
+            counts.into_iter().find(|count| count["location_id"].as_u64() == Some(ids[0])).unwrap()
+        };
+        let count = headcount();
+        assert_eq!(count["expected"], 1);
+        assert_eq!(count["actual"], 0);
+        assert_eq!(count["delta"], -1);
+        assert_eq!(count["discrepancy"], true);
+
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": "667766776677667"}))
+            .send()
+            .expect("Failed to execute request");
+        assert!(response.status().is_success());
+        let count = headcount();
+        assert_eq!(count["actual"], 1);
+        assert_eq!(count["delta"], 0);
+        assert_eq!(count["discrepancy"], false);
+    }
+
+    #[test]
+    fn test_locations_timestamps_paged() {
+        let ids = create_tree(&[("Commissary", None)]);
+        let resident = json!({"rfid": "778877887788778", "name": "Paged Resident", "doc": "52086", "room": "F-3", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        for time in ["2023-07-02 08:00:00", "2023-07-02 09:00:00", "2023-07-02 10:00:00"] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+                .json(&json!({"rfid": "778877887788778", "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let range = format!("locations/{}/timestamps/2023-07-01/2023-07-03", ids[0]);
+        let times = |response: Response| -> Vec<Value> {
+            let body = response.json::<Value>().unwrap();
+            body["data"]["Get"].as_array().unwrap().iter().map(|ts| ts["time"].clone()).collect()
+        };
+
+        let response = make_request(&format!("{}?limit=2", range), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["X-Total-Count"], "3");
+        assert!(response.headers()["Link"].to_str().unwrap().contains("rel=\"next\""));
+        assert_eq!(times(response), vec!["2023-07-02T10:00:00Z", "2023-07-02T09:00:00Z"]);
+        let response = make_request(&format!("{}?limit=2&offset=2", range), reqwest::Method::GET, None);
+        assert_eq!(times(response), vec!["2023-07-02T08:00:00Z"]);
+
+        // without paging params every row comes back, as before
+        let response = make_request(&range, reqwest::Method::GET, None);
+        assert!(response.headers().get("X-Total-Count").is_none());
+        assert_eq!(times(response).len(), 3);
+        let response = make_request(&format!("locations/{}/timestamps?limit=5", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["X-Total-Count"], "0");
+    }
+
+    #[test]
+    fn test_creates_locations() {
+        let host = BASE_URL.trim_end_matches("/api");
+        let location = |response: &reqwest::blocking::Response| {
+            format!("{}{}", host, response.headers()["Location"].to_str().unwrap())
+        };
+        let ids = create_tree(&[("Intake Hall", None)]);
+        let resident = json!({"rfid": "990099009900990", "name": "Located Resident", "doc": "41976", "room": "F-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let url = location(&response);
+        assert!(url.ends_with("/api/residents/990099009900990"));
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["rfid"], "990099009900990");
+        assert_eq!(client().get(url).send().unwrap().status().as_u16(), 200);
+
+        let scan = || {
+            client()
+                .post(format!("{}/timestamps", BASE_URL))
+                .json(&json!({"rfid": "990099009900990", "location": ids[0]}))
+                .send()
+                .expect("Failed to execute request")
+        };
+        let response = scan();
+        assert_eq!(response.status().as_u16(), 201);
+        let url = location(&response);
+        let shown = client().get(url).send().unwrap();
+        assert_eq!(shown.status().as_u16(), 200);
+        assert_eq!(shown.json::<Value>().unwrap()["data"]["Show"]["rfid"], "990099009900990");
+        // the repeat isn't stored, so there's nothing for a Location to point at
+        let response = scan();
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers().get("Location").is_none());
+    }
+
+    #[test]
+    fn test_locations_sorted() {
+        let names = |query: &str| -> Vec<String> {
+            let response = make_request(&format!("locations?{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200, "{}", query);
+            let body = response.json::<Value>().unwrap();
+            body["data"]["Index"].as_array().unwrap().iter().map(|loc| loc["name"].as_str().unwrap().to_lowercase()).collect()
+        };
+        let ascending = names("sort=name");
+        assert!(ascending.windows(2).all(|pair| pair[0] <= pair[1]));
+        let descending = names("sort=NAME&order=desc");
+        assert!(descending.windows(2).all(|pair| pair[0] >= pair[1]));
+
+        let ids = create_tree(&[("Sorted Busy Room", None), ("Sorted Empty Room", None)]);
+        let resident = json!({"rfid": "889988998899889", "name": "Sorted Resident", "doc": "63197", "room": "G-4", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": "889988998899889"}))
+            .send()
+            .expect("Failed to execute request");
+        assert!(response.status().is_success());
+        let by_occupancy = names("sort=occupancy&order=desc");
+        let position = |name: &str| by_occupancy.iter().position(|n| n == name).unwrap();
+        assert!(position("sorted busy room") < position("sorted empty room"));
+
+        let response = make_request("locations?sort=occupancy&limit=2", reqwest::Method::GET, None);
+        assert!(response.headers().get("X-Total-Count").is_some());
+        for query in ["sort=capacity", "order=desc", "sort=name&order=sideways", "sort=name&q=dorm"] {
+            let response = make_request(&format!("locations?{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_locations_create() {
+        let fake_location = json!({"name": "Fake Location"});
+        let resp = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&fake_location)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(resp.status().as_u16(), 201);
+        let location = resp.headers()["Location"].to_str().unwrap().to_string();
+        let id = resp.json::<Value>().unwrap()["data"]["Show"]["id"].clone();
+        assert_eq!(location, format!("/api/locations/{}", id));
+
+        let response = make_request(&format!("locations/{}", id), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+    }
+
+    #[test]
+    fn test_locations_create_blank_name() {
+        let fake_location = json!({"id": 71, "name": "   "});
+        let resp = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&fake_location)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_locations_create_duplicate_name() {
+        // ASU is seeded, names are compared without case
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "asu"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 409);
+        let body = response.json::<Value>().unwrap();
+        assert!(body["error"].as_str().unwrap().contains("named asu"));
+    }
+    #[test]
+    fn test_locations_create_malformed_json() {
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .header("Content-Type", "application/json")
+            .body(r#"{"name": 5}"#)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["kind"], "malformed_json");
+        assert_eq!(body["details"]["problem"], "wrong_type");
+        assert_eq!(body["details"]["line"], 1);
+    }
+    #[test]
+    fn test_locations_import() {
+        let csv = "name,capacity\r\nImported Wing,12\r\n\"\",3\r\nasu,\r\n";
+        let response = client()
+            .post(format!("{}/locations/import", BASE_URL))
+            .header("Content-Type", "text/csv")
+            .body(csv)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let rows = body["data"]["Import"].as_array().unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["created"], true);
+        assert_eq!(rows[0]["location"]["capacity"], 12);
+        assert_eq!(rows[1]["created"], false);
+        assert!(rows[1]["reason"].as_str().unwrap().starts_with("name"));
+        assert_eq!(rows[2]["created"], false);
+        assert!(rows[2]["reason"].as_str().unwrap().contains("already exists"));
+
+        let id = rows[0]["location"]["id"].clone();
+        let response = make_request(&format!("locations/{}", id), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+    }
+    #[test]
+    fn test_locations_update() {
+        let client = client();
+        let body = json!({"id": 4, "name": "ASU", "version": 1});
+        let response = client
+            .put(format!("{}/locations/4", BASE_URL))
+            .json(&body)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+
+        let response = client
+            .put(format!("{}/locations/99999", BASE_URL))
+            .json(&body)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_auth_login() {
+        let login = |body: Value| {
+            Client::new()
+                .post(format!("{}/auth/login", BASE_URL))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        let response = login(json!({"username": "test-officer", "password": "test-password"}));
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["token_type"], "Bearer");
+        assert_eq!(body["role"], "staff");
+        assert_eq!(body["facility_id"], 1);
+        assert!(body["expires_at"].as_str().unwrap().ends_with('Z'));
+
+        // the token carries the user's role, enough for staff writes and no more
+        let issued = client_with_token(body["token"].as_str().unwrap());
+        let response = issued.get(format!("{}/residents", BASE_URL)).send().expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let response = issued.get(format!("{}/audit", BASE_URL)).send().expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 403);
+
+        // a wrong password and an unknown user are told the same thing
+        let wrong = login(json!({"username": "test-officer", "password": "not-it"}));
+        assert_eq!(wrong.status().as_u16(), 401);
+        let unknown = login(json!({"username": "nobody-here", "password": "test-password"}));
+        assert_eq!(unknown.status().as_u16(), 401);
+        assert_eq!(wrong.json::<Value>().unwrap()["error"], unknown.json::<Value>().unwrap()["error"]);
+        assert_eq!(login(json!({"username": "test-officer"})).status().as_u16(), 400);
+
+        // logins aren't mutations, their passwords never reach the audit log
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let response = client_with_role("admin")
+            .get(format!("{}/audit?limit=500", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        let entries = response.json::<Value>().unwrap()["data"].as_array().unwrap().clone();
+        assert!(entries.iter().all(|entry| entry["endpoint"] != "/api/auth/login"));
+    }
+
+    #[test]
+    fn test_locations_show_batch() {
+        let ids = create_tree(&[("Batch Room One", None), ("Batch Room Two", None), ("Batch Room Gone", None)]);
+        let response = make_request(&format!("locations/{}", ids[2]), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+
+        // in the order asked for, a repeat once, and the ids that aren't a location listed apart
+        let path = format!("locations/batch?ids={},999999,{},{},{}", ids[1], ids[0], ids[1], ids[2]);
+        let response = make_request(&path, reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let locations = body["data"]["Batch"]["locations"].as_array().unwrap();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0]["id"], ids[1]);
+        assert_eq!(locations[0]["name"], "Batch Room Two");
+        assert_eq!(locations[1]["id"], ids[0]);
+        assert_eq!(body["data"]["Batch"]["missing"], json!([999999, ids[2]]));
+
+        let too_many = (1..=201).map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+        for query in ["", "?ids=", "?ids=0", "?ids=1,two", "?ids=1,,2", &format!("?ids={}", too_many)] {
+            let response = make_request(&format!("locations/batch{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_locations_update_version() {
+        let ids = create_tree(&[("Versioned Hall", None)]);
+        let response = make_request(&format!("locations/{}", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["version"], 1);
+        let put = |body: Value| {
+            client()
+                .put(format!("{}/locations/{}", BASE_URL, ids[0]))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(put(json!({"name": "Versioned Hall"})).status().as_u16(), 400);
+
+        // the first of two edits made from version 1 wins, the second is told to reload
+        let response = put(json!({"name": "Versioned Hall East", "version": 1}));
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Show"]["version"], 2);
+        let response = put(json!({"name": "Versioned Hall West", "version": 1}));
+        assert_eq!(response.status().as_u16(), 409);
+        let body = response.json::<Value>().unwrap();
+        assert!(body["error"].as_str().unwrap().contains("version 2"), "{}", body);
+        let response = make_request(&format!("locations/{}", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["name"], "Versioned Hall East");
+
+        let response = put(json!({"name": "Versioned Hall West", "version": 2}));
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["version"], 3);
+    }
+
+    #[test]
+    fn test_locations_delete() {
+        let fake_location = json!({"id": 70, "name": "Disposable Location"});
+        let resp = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&fake_location)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(resp.status().as_u16(), 201);
+
+        let response = make_request("locations/70", reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+        let response = make_request("locations/70", reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 404);
+
+        // soft deleted, hidden by default but kept for the audit listing
+        let response = make_request("locations/70", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+        let listed = |client: Client, query: &str| {
+            let response = client
+                .get(format!("{}/locations?{}", BASE_URL, query))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Index"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|loc| loc["id"] == 70)
+                .cloned()
+        };
+        assert!(listed(client(), "q=disposable").is_none());
+        let deleted = listed(client(), "include_deleted=true").unwrap();
+        assert!(deleted["deleted_at"].is_string());
+        let response = client_with_role("read_only")
+            .get(format!("{}/locations?include_deleted=true", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 403);
+
+        // the name is free again, the id stays with the deleted location
+        let resp = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Disposable Location"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(resp.status().as_u16(), 201);
+        let id = resp.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap();
+        assert_ne!(id, 70);
+        let response = make_request(&format!("locations/{}", id), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+    }
+
+    #[test]
+    fn test_locations_timestamps() {
+        let response = make_request("locations/8/timestamps", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Vec<Value>>().is_ok());
+    }
+
+    #[test]
+    fn test_locations_timestamps_between() {
+        let response = make_request(
+            "locations/11/timestamps/2023-11-10/2023-11-19",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Vec<Value>>().is_ok());
+    }
+    #[test]
+    fn test_locations_timestamps_ndjson() {
+        let response = client()
+            .get(format!(
+                "{}/locations/11/timestamps/2023-11-10/2023-11-19",
+                BASE_URL
+            ))
+            .header("Accept", "application/x-ndjson")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+        let body = response.text().unwrap();
+        assert!(body
+            .lines()
+            .all(|line| serde_json::from_str::<Value>(line).is_ok()));
+    }
+    #[test]
+    fn test_locations_timestamps_invalid_range() {
+        let response = make_request(
+            "locations/5/timestamps/banana/2024-13-99",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+        let response = make_request(
+            "locations/5/timestamps/2023-11-19/2023-11-10",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+        // past MAX_RANGE_DAYS
+        let response = make_request(
+            "locations/5/timestamps/2000-01-01/2099-12-31",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    #[test]
+    fn test_locations_timestamps_post() {
+        let client = client();
+        let data = json!({"rfid": "888888222888777"});
+        let response = client
+            .post(format!("{}/locations/8/timestamps", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+
+        let response = client
+            .post(format!("{}/locations/99999/timestamps", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 404);
+    }
+    #[test]
+    fn test_locations_timestamps_keywords() {
+        let response = make_request(
+            "locations/11/timestamps/last7days/today",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let response = make_request(
+            "locations/11/timestamps/lastyear/today",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    #[test]
+    fn test_locations_timestamps_duplicate() {
+        let client = client();
+        let data = json!({"rfid": "555555666555555"});
+        let first = client
+            .post(format!("{}/locations/11/timestamps", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(first.status().as_u16(), 201);
+        let second = client
+            .post(format!("{}/locations/11/timestamps", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(second.status().as_u16(), 200);
+        let body = second.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Post"]["duplicate"], true);
+    }
+    #[test]
+    fn test_locations_timestamps_batch() {
+        let data = json!([
+            {"rfid": "888888222888777"},
+            {"rfid": "888888222888777", "time": "2023-11-12 10:00:00"},
+            {"rfid": "not-a-resident"},
+        ]);
+        let response = client()
+            .post(format!("{}/locations/8/timestamps/batch", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Batch"][2]["accepted"], false);
+    }
+    #[test]
+    fn test_locations_residents() {
+        let response = make_request("locations/8/residents", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    #[test]
+    fn test_locations_timestamps_export() {
+        let response = make_request("locations/8/timestamps/2023-11-10/2023-11-19/export.xlsx", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(
+            response.headers()["Content-Type"],
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        );
+        let disposition = response.headers()["Content-Disposition"].to_str().unwrap().to_string();
+        assert!(disposition.starts_with("attachment"));
+        assert!(disposition.contains("location-8-timestamps-2023-11-10-to-2023-11-19.xlsx"));
+        // an .xlsx is a zip archive
+        assert!(response.bytes().unwrap().starts_with(b"PK"));
+        let response = make_request("locations/8/timestamps/2023-11-19/2023-11-10/export.xlsx", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_locations_dwell() {
+        let response = make_request(
+            "locations/8/dwell/2023-11-10/2023-11-19",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Value>().unwrap()["data"]["Dwell"].is_object());
+    }
+
+    #[test]
+    fn test_timestamps_idempotency_key() {
+        let ids = create_tree(&[("Mail Room", None)]);
+        let resident = json!({"rfid": "121212121212121", "name": "Retried Resident", "doc": "41978", "room": "H-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let upload = |path: &str, key: &str| {
+            client()
+                .post(format!("{}/{}", BASE_URL, path))
+                .header("Idempotency-Key", key)
+                .json(&json!({"rfid": "121212121212121", "location": ids[0], "time": "2023-07-05 09:00:00"}))
+                .send()
+                .expect("Failed to execute request")
+        };
+        let key = "6f1c2d3e-4a5b-4c6d-8e9f-0a1b2c3d4e5f";
+        let first = upload("timestamps", key);
+        assert_eq!(first.status().as_u16(), 201);
+        assert!(first.headers().get("Idempotent-Replayed").is_none());
+        let location = first.headers()["Location"].clone();
+        let stored = first.json::<Value>().unwrap();
+        // the retry gets the same answer, in either case of the key
+        let retry = upload("timestamps", &key.to_uppercase());
+        assert_eq!(retry.status().as_u16(), 201);
+        assert_eq!(retry.headers()["Idempotent-Replayed"], "true");
+        assert_eq!(retry.headers()["Location"], location);
+        assert_eq!(retry.json::<Value>().unwrap(), stored);
+        let response = make_request(&format!("locations/{}/timestamps/2023-07-05/2023-07-05", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().len(), 1);
+
+        assert_eq!(upload(&format!("locations/{}/timestamps", ids[0]), key).status().as_u16(), 409);
+        assert_eq!(upload("timestamps", "not-a-uuid").status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_residents_status_codes() {
+        let create = |body: Value| {
+            client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        let resident = json!({"rfid": "131313131313131", "name": "Coded Resident", "doc": "41979", "room": "I-1", "unit": 4, "current_location": 0});
+        assert_eq!(create(resident.clone()).status().as_u16(), 201);
+        assert_eq!(create(resident).status().as_u16(), 409);
+        let blank = json!({"rfid": "141414141414141", "name": " ", "doc": "41980", "room": "I-2", "unit": 4, "current_location": 0});
+        let response = create(blank);
+        assert_eq!(response.status().as_u16(), 400);
+        assert_eq!(response.json::<Value>().unwrap()["kind"], "validation");
+
+        let response = make_request("residents/131313131313131", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["name"], "Coded Resident");
+        let response = make_request("residents/141414141414141", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_locations_visits() {
+        let ids = create_tree(&[("Records Room", None), ("Records Annex", None)]);
+        let resident = json!({"rfid": "101101101101101", "name": "Visiting Resident", "doc": "41977", "room": "G-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        // in and out, then back in late enough to still be there when the day ends
+        for time in ["2023-07-03 10:00:00", "2023-07-03 12:00:00", "2023-07-03 23:00:00"] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+                .json(&json!({"rfid": "101101101101101", "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        let visits = |range: &str| {
+            let response = make_request(&format!("locations/{}/visits/{}", ids[0], range), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            response.json::<Value>().unwrap()["data"]["Visits"].as_array().unwrap().clone()
+        };
+        let day = visits("2023-07-03/2023-07-03");
+        assert_eq!(day.len(), 2);
+        assert_eq!(day[0]["arrived"], "2023-07-03T10:00:00Z");
+        assert_eq!(day[0]["left"], "2023-07-03T12:00:00Z");
+        assert_eq!(day[0]["seconds"], 7200);
+        assert_eq!(day[0]["clipped"], false);
+        assert_eq!(day[1]["left"], "2023-07-04T00:00:00Z");
+        assert_eq!(day[1]["seconds"], 3600);
+        assert_eq!(day[1]["clipped"], true);
+        assert_eq!(day[1]["ongoing"], false);
+        // the next days start with the late visit already open and it runs past them
+        let after = visits("2023-07-04/2023-07-05");
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0]["arrived"], "2023-07-04T00:00:00Z");
+        assert_eq!(after[0]["left"], "2023-07-06T00:00:00Z");
+        assert_eq!(after[0]["clipped"], true);
+        // scanning in somewhere else now leaves a stay there that hasn't ended
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[1]))
+            .json(&json!({"rfid": "101101101101101"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let response = make_request(&format!("locations/{}/visits/today/today", ids[1]), reqwest::Method::GET, None);
+        let now = response.json::<Value>().unwrap()["data"]["Visits"].as_array().unwrap().clone();
+        assert_eq!(now.len(), 1);
+        assert_eq!(now[0]["ongoing"], true);
+        assert_eq!(now[0]["clipped"], false);
+        assert!(now[0]["left"].is_null());
+    }
+
+    #[test]
+    fn test_locations_occupancy() {
+        let response = make_request("locations/8/occupancy", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    #[test]
+    fn test_locations_occupancy_hourly() {
+        // no other test scans into location 13, and these days have no DST change
+        let response = client()
+            .post(format!("{}/locations/13/timestamps", BASE_URL))
+            .json(&json!({"rfid": "555555231555555", "time": "2023-06-01 12:30:00"}))
+            .send()
+            .expect("Failed to execute request");
+        assert!(response.status().is_success());
+        let response = make_request(
+            "locations/13/occupancy/hourly/2023-06-01/2023-06-02",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let hours = body["data"]["Hourly"].as_array().unwrap();
+        assert_eq!(hours.len(), 48);
+        let total: u64 = hours.iter().map(|hour| hour["count"].as_u64().unwrap()).sum();
+        assert_eq!(total, 1);
+    }
+
+    #[test]
+    fn test_locations_scans_daily() {
+        let ids = create_tree(&[("Visiting Room East", None)]);
+        // midday, so the day is the same in whatever timezone the server runs in
+        for time in ["2023-10-02 12:00:00", "2023-10-04 11:00:00", "2023-10-04 13:00:00"] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+                .json(&json!({"rfid": "36155470817688836", "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let response = make_request(
+            &format!("locations/{}/scans/daily/2023-10-01/2023-10-05", ids[0]),
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let days = body["data"]["Daily"].as_array().unwrap();
+        let counts: Vec<(&str, u64)> = days
+            .iter()
+            .map(|day| (day["day"].as_str().unwrap(), day["count"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(
+            counts,
+            [("2023-10-01", 0), ("2023-10-02", 1), ("2023-10-03", 0), ("2023-10-04", 2), ("2023-10-05", 0)]
+        );
+        let response = make_request(
+            &format!("locations/{}/scans/daily/2023-10-05/2023-10-01", ids[0]),
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_timestamps_rfc3339() {
+        // the server runs without TIMESTAMP_TZ, so times come back in UTC
+        let response = client()
+            .post(format!("{}/locations/13/timestamps", BASE_URL))
+            .json(&json!({"rfid": "333333339993333", "time": "2023-07-01 08:15:30"}))
+            .send()
+            .expect("Failed to execute request");
+        assert!(response.status().is_success());
+        let response = make_request(
+            "locations/13/timestamps/2023-07-01/2023-07-01",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let scan = body["data"]["Get"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|ts| ts["rfid"] == "333333339993333")
+            .cloned()
+            .unwrap();
+        assert_eq!(scan["time"], "2023-07-01T08:15:30Z");
+    }
+
+    #[test]
+    fn test_locations_peak() {
+        // a handover in the same second doesn't count both residents as there at once
+        let scans = [
+            ("222222333222222", 15, "08:00:00"),
+            ("444444777444444", 15, "09:00:00"),
+            ("222222333222222", 16, "10:00:00"),
+            ("666666000666666", 15, "10:00:00"),
+            ("222222333222222", 15, "11:00:00"),
+        ];
+        for (rfid, location, time) in scans {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": rfid, "time": format!("2023-08-01 {}", time)}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let response = make_request("locations/15/peak/2023-08-01/2023-08-01", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body["data"]["Peak"]["peak"], 3);
+        assert_eq!(body["data"]["Peak"]["time"], "2023-08-01T11:00:00Z");
+    }
+
+    #[test]
+    fn test_locations_resident_timestamps() {
+        let ids = create_tree(&[("Casework Office", None), ("Casework Hall", None), ("Casework Annex", None)]);
+        let rfid = "245245245245245";
+        let resident = json!({"rfid": rfid, "name": "Investigated Resident", "doc": "41991", "room": "P-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        // sent out of order, they come back oldest first
+        for (location, time) in [(ids[0], "2023-05-02 09:00:00"), (ids[1], "2023-05-01 12:00:00"), (ids[0], "2023-05-01 08:00:00")] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": rfid, "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        let scans = |endpoint: &str| {
+            let response = make_request(&format!("locations/{}", endpoint), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200, "{}", endpoint);
+            response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().clone()
+        };
+        let all = scans(&format!("{}/residents/{}/timestamps", ids[0], rfid));
+        let times: Vec<&str> = all.iter().map(|scan| scan["time"].as_str().unwrap()).collect();
+        assert_eq!(times, ["2023-05-01T08:00:00Z", "2023-05-02T09:00:00Z"]);
+        assert!(all.iter().all(|scan| scan["location"] == ids[0]));
+
+        let day = scans(&format!("{}/residents/{}/timestamps/2023-05-02/2023-05-02", ids[0], rfid));
+        assert_eq!(day.len(), 1);
+        assert_eq!(day[0]["time"], "2023-05-02T09:00:00Z");
+        assert_eq!(scans(&format!("{}/residents/{}/timestamps", ids[1], rfid)).len(), 1);
+
+        // never scanned there is no scans, not a 404
+        assert!(scans(&format!("{}/residents/{}/timestamps", ids[2], rfid)).is_empty());
+        for endpoint in [format!("999999/residents/{}/timestamps", rfid), format!("{}/residents/000000000000404/timestamps", ids[0])] {
+            let response = make_request(&format!("locations/{}", endpoint), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 404, "{}", endpoint);
+        }
+        let response = make_request(&format!("locations/{}/residents/{}/timestamps/2023-05-03/2023-05-01", ids[0], rfid), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let ids = create_tree(&[("Handover Dorm", None), ("Handover Yard", None)]);
+        let rfid = "256256256256256";
+        let resident = json!({"rfid": rfid, "name": "Handed Over Resident", "doc": "41992", "room": "Q-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        for (location, time) in [(ids[0], "2019-03-01 10:00:00"), (ids[1], "2019-03-01 12:00:00")] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": rfid, "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        // the location the resident is listed at, if any
+        let located = |at: Option<&str>| {
+            let mut request = client().get(format!("{}/snapshot", BASE_URL));
+            if let Some(at) = at {
+                request = request.query(&[("at", at)]);
+            }
+            let response = request.send().expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200);
+            let body = response.json::<Value>().unwrap();
+            let locations = body["locations"].as_array().unwrap();
+            assert!(locations.iter().all(|loc| !loc["residents"].as_array().unwrap().is_empty()));
+            locations
+                .iter()
+                .find(|loc| loc["residents"].as_array().unwrap().iter().any(|res| res["rfid"] == rfid))
+                .map(|loc| loc["location_id"].as_u64().unwrap())
+        };
+        assert_eq!(located(Some("2019-03-01 09:59:59")), None);
+        assert_eq!(located(Some("2019-03-01 11:00:00")), Some(ids[0]));
+        assert_eq!(located(Some("2019-03-01T11:00:00Z")), Some(ids[0]));
+        assert_eq!(located(Some("2019-03-01 12:00:00")), Some(ids[1]));
+        assert_eq!(located(None), Some(ids[1]));
+
+        let response = client()
+            .get(format!("{}/snapshot", BASE_URL))
+            .query(&[("at", "yesterday-ish")])
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    #[test]
+    fn test_summary_today() {
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Briefing Room", "capacity": 1}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let room = response.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap();
+        for (rfid, doc) in [("212212212212212", "41988"), ("223223223223223", "41989")] {
+            let resident = json!({"rfid": rfid, "name": "Briefed Resident", "doc": doc, "room": "M-1", "unit": room, "current_location": 0});
+            let response = client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&resident)
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        // the second arrival leaves the room over its capacity of one
+        let mut over_capacity = Vec::new();
+        for rfid in ["212212212212212", "223223223223223"] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, room))
+                .json(&json!({"rfid": rfid}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+            over_capacity.push(response.json::<Value>().unwrap()["message"].as_str().unwrap().contains("over capacity"));
+        }
+        assert_eq!(over_capacity, [false, true]);
+
+        let response = make_request("summary/today", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        // other tests scan today as well, so these are at least what this one added
+        assert_eq!(body["date"].as_str().unwrap().len(), 10);
+        assert!(body["total_scans"].as_u64().unwrap() >= 2);
+        assert!(body["residents_moved"].as_u64().unwrap() >= 2);
+        assert!(body["over_capacity_events"].as_u64().unwrap() >= 1);
+        assert!(body["busiest_location"]["scans"].as_u64().unwrap() >= 2);
+        assert!(body["busiest_location"]["name"].is_string());
+    }
+
+    #[test]
+    fn test_capacity_counts_active_residents() {
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Intake Cell", "capacity": 1}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let room = response.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap();
+        for (rfid, doc) in [("401401401401401", "42006"), ("402402402402402", "42007")] {
+            let resident = json!({"rfid": rfid, "name": "Intake Resident", "doc": doc, "room": "I-1", "unit": room, "current_location": 0});
+            let response = client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&resident)
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let scan = |rfid: &str| {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, room))
+                .json(&json!({"rfid": rfid}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+            response.json::<Value>().unwrap()["message"].as_str().unwrap().contains("over capacity")
+        };
+        assert!(!scan("401401401401401"));
+        let response = client()
+            .post(format!("{}/residents/401401401401401/status", BASE_URL))
+            .json(&json!({"status": "released"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        // the released resident is still recorded at the room but doesn't fill it
+        assert!(!scan("402402402402402"));
+    }
+
+    // creates nested locations without ids, so a concurrent test's assigned id can't collide
+    fn create_tree(locations: &[(&str, Option<usize>)]) -> Vec<u64> {
+        let mut ids: Vec<u64> = Vec::new();
+        for (name, parent) in locations {
+            let response = client()
+                .post(format!("{}/locations", BASE_URL))
+                .json(&json!({"name": name, "parent_id": parent.map(|index| ids[index])}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+            ids.push(response.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap());
+        }
+        ids
+    }
+
+    #[test]
+    fn test_locations_nearby() {
+        let create = |body: Value| {
+            let response = client()
+                .post(format!("{}/locations", BASE_URL))
+                .json(&body)
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+            response.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap()
+        };
+        let origin = create(json!({"name": "Nearby Origin", "x": 100, "y": 100}));
+        let medical = create(json!({"name": "Nearby Medical", "x": 103, "y": 104}));
+        let yard = create(json!({"name": "Nearby Yard", "x": 110, "y": 100}));
+        let far = create(json!({"name": "Nearby Far", "x": 200, "y": 200}));
+        let unmapped = create(json!({"name": "Nearby Unmapped"}));
+
+        let response = make_request(&format!("locations/{}/nearby?count=2", origin), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let nearby = body["data"]["Nearby"].as_array().unwrap();
+        assert_eq!(nearby.len(), 2);
+        assert_eq!(nearby[0]["id"], medical);
+        assert_eq!(nearby[0]["distance"], 5.0);
+        assert_eq!(nearby[1]["id"], yard);
+        assert_eq!(nearby[1]["distance"], 10.0);
+
+        // three by default, the location without coordinates is never one of them
+        let response = make_request(&format!("locations/{}/nearby", origin), reqwest::Method::GET, None);
+        let body = response.json::<Value>().unwrap();
+        let ids = body["data"]["Nearby"].as_array().unwrap().iter().map(|loc| loc["id"].as_u64().unwrap()).collect::<Vec<_>>();
+        assert_eq!(ids, [medical, yard, far]);
+
+        let response = make_request(&format!("locations/{}/nearby", unmapped), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 409);
+        let response = make_request(&format!("locations/{}/nearby?count=0", origin), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 400);
+        let response = make_request("locations/9999/nearby", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Nearby Half", "x": 5}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_locations_path() {
+        let ids = create_tree(&[
+            ("Path Campus", None),
+            ("Path North", Some(0)),
+            ("Path North Cell", Some(1)),
+            ("Path South", Some(0)),
+            ("Path Island", None),
+        ]);
+        let route = |from: u64, to: u64| {
+            let response = make_request(&format!("locations/{}/path/{}", from, to), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 200);
+            let body = response.json::<Value>().unwrap();
+            body["data"]["Path"].as_array().unwrap().iter().map(|loc| loc["id"].as_u64().unwrap()).collect::<Vec<u64>>()
+        };
+        // up to the campus that holds both wings, then down
+        assert_eq!(route(ids[2], ids[3]), [ids[2], ids[1], ids[0], ids[3]]);
+        assert_eq!(route(ids[3], ids[2]), [ids[3], ids[0], ids[1], ids[2]]);
+        assert_eq!(route(ids[2], ids[1]), [ids[2], ids[1]]);
+        assert_eq!(route(ids[0], ids[2]), [ids[0], ids[1], ids[2]]);
+        assert_eq!(route(ids[4], ids[4]), [ids[4]]);
+
+        let response = make_request(&format!("locations/{}/path/{}", ids[2], ids[4]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+        assert!(response.json::<Value>().unwrap()["error"].as_str().unwrap().contains("No path"));
+        let response = make_request(&format!("locations/{}/path/9999", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_locations_children() {
+        let ids = create_tree(&[("Building A", None), ("Wing A1", Some(0)), ("Cell A1-1", Some(1))]);
+        let response = make_request(&format!("locations/{}/children", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let children = body["data"]["Index"].as_array().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0]["id"], ids[1]);
+        assert_eq!(children[0]["parent_id"], ids[0]);
+        let response = make_request("locations/9999/children", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+
+        // a building can't move into its own cell, or into itself
+        for parent in [ids[2], ids[0]] {
+            let response = client()
+                .put(format!("{}/locations/{}", BASE_URL, ids[0]))
+                .json(&json!({"name": "Building A", "parent_id": parent, "version": 1}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 400);
+        }
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Orphan Wing", "parent_id": 9999}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn test_locations_occupancy_rollup() {
+        let ids = create_tree(&[("Building B", None), ("Wing B1", Some(0)), ("Cell B1-1", Some(1)), ("Wing B2", Some(0))]);
+        for (rfid, location) in [("123455623562354", ids[2]), ("987654321098765", ids[1]), ("555555111555555", ids[0])] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": rfid}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let response = make_request(&format!("locations/{}/occupancy/rollup", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let rollup = response.json::<Value>().unwrap()["data"]["Rollup"].clone();
+        assert_eq!(rollup["present"], 1);
+        assert_eq!(rollup["total"], 3);
+        assert_eq!(
+            rollup["children"],
+            json!([
+                {"location_id": ids[1], "name": "Wing B1", "total": 2},
+                {"location_id": ids[3], "name": "Wing B2", "total": 0}
+            ])
+        );
+        let response = make_request("locations/9999/occupancy/rollup", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_facility_scoping() {
+        let other = super::client_with_token(&super::token_in_facility("staff", 2));
+        let response = make_request("locations/11", reqwest::Method::GET, None);
+        let name = response.json::<Value>().unwrap()["data"]["Show"]["name"].clone();
+        // names only have to be unique within a facility
+        let response = other
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": name}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let created = response.json::<Value>().unwrap()["data"]["Show"]["id"].clone();
+
+        let response = other.get(format!("{}/locations", BASE_URL)).send().unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let ids = body["data"]["Index"].as_array().unwrap().iter().map(|loc| loc["id"].clone()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![created.clone()]);
+        let response = other.get(format!("{}/residents", BASE_URL)).send().unwrap();
+        assert!(response.json::<Value>().unwrap()["data"]["Index"].as_array().is_none_or(|residents| residents.is_empty()));
+
+        // each facility is refused the other's records
+        let response = other.get(format!("{}/locations/11", BASE_URL)).send().unwrap();
+        assert_eq!(response.status().as_u16(), 403);
+        let response = other.get(format!("{}/residents/123455623562354", BASE_URL)).send().unwrap();
+        assert_eq!(response.status().as_u16(), 403);
+        let response = other
+            .post(format!("{}/timestamps", BASE_URL))
+            .json(&json!({"rfid": "123455623562354", "location": 11}))
+            .send()
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 403);
+        let response = make_request(&format!("locations/{}", created), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 403);
+    }
+
+    #[test]
+    fn test_locations_live() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        let mut socket = std::net::TcpStream::connect("localhost:8080").unwrap();
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        write!(
+            socket,
+            "GET /api/locations/14/live HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nAuthorization: Bearer {}\r\n\r\n",
+            super::token("read_only")
+        )
+        .unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 101"), "{}", line);
+        let mut headers = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            headers.push_str(&line.to_lowercase());
+        }
+        // the accept value for the RFC 6455 sample key
+        assert!(headers.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+
+        // a scan elsewhere isn't sent, the one at this location is
+        for location in [11, 14] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": "777777888777777"}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        let mut head = [0u8; 2];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x81, "expected a text frame");
+        let mut frame = vec![0u8; (head[1] & 0x7f) as usize];
+        reader.read_exact(&mut frame).unwrap();
+        let scan = serde_json::from_slice::<Value>(&frame).unwrap();
+        assert_eq!(scan["rfid"], "777777888777777");
+        assert_eq!(scan["location"], 14);
+
+        // a close frame from the client, masked with a zero key, is answered with one
+        socket.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8]).unwrap();
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x88);
+    }
+
+    #[test]
+    fn test_locations_events() {
+        use std::io::{BufRead, BufReader, Write};
+        let ids = create_tree(&[("Event Dayroom", None), ("Event Hallway", None)]);
+        let resident = json!({"rfid": "356356356356356", "name": "Evented, Resident", "doc": "42002", "room": "V-3", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let response = client_with_role("read_only")
+            .get(format!("{}/locations/999999/events", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 404);
+
+        let mut socket = std::net::TcpStream::connect("localhost:8080").unwrap();
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        write!(
+            socket,
+            "GET /api/locations/{}/events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\
+             Accept-Encoding: gzip\r\nAuthorization: Bearer {}\r\n\r\n",
+            ids[0],
+            super::token("read_only")
+        )
+        .unwrap();
+        let mut reader = BufReader::new(socket);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        let mut headers = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            headers.push_str(&line.to_lowercase());
+        }
+        assert!(headers.contains("content-type: text/event-stream"), "{}", headers);
+        assert!(!headers.contains("content-encoding: gzip"), "{}", headers);
+        // the retry hint comes first, before any scan
+        let mut next_field = |reader: &mut BufReader<std::net::TcpStream>| loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if ["retry:", "event:", "data:"].iter().any(|field| line.starts_with(field)) {
+                return line.trim_end().to_string();
+            }
+        };
+        assert_eq!(next_field(&mut reader), "retry: 5000");
+
+        // a scan elsewhere isn't sent, the one at this location is
+        for location in [ids[1], ids[0]] {
+            let response = client()
+                .post(format!("{}/locations/{}/timestamps", BASE_URL, location))
+                .json(&json!({"rfid": "356356356356356"}))
+                .send()
+                .expect("Failed to execute request");
+            assert!(response.status().is_success());
+        }
+        assert_eq!(next_field(&mut reader), "event: scan");
+        let data = next_field(&mut reader);
+        let scan = serde_json::from_str::<Value>(data.trim_start_matches("data: ")).unwrap();
+        assert_eq!(scan["rfid"], "356356356356356");
+        assert_eq!(scan["location"], ids[0]);
+    }
+
+    #[test]
+    fn test_list_pagination() {
+        let ids = create_tree(&[("Pager Unit", None), ("Pager Annex", None)]);
+        let (alpha, bravo, charlie) = ("367367367367367", "378378378378378", "389389389389389");
+        for (rfid, name, doc, unit) in [
+            (alpha, "Pager, Alpha", "42003", ids[0]),
+            (bravo, "Pager, Bravo", "42004", ids[0]),
+            (charlie, "Pager, Charlie", "42005", ids[1]),
+        ] {
+            let resident = json!({"rfid": rfid, "name": name, "doc": doc, "room": "P-1", "unit": unit, "current_location": 0});
+            let response = client()
+                .post(format!("{}/residents", BASE_URL))
+                .json(&resident)
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 201);
+        }
+        let names = |response: Response| -> Vec<String> {
+            let body = response.json::<Value>().unwrap();
+            body["data"]["Index"].as_array().unwrap().iter().map(|r| r["name"].as_str().unwrap().to_string()).collect()
+        };
+
+        // residents, narrowed, ordered and paged
+        let response = make_request(&format!("residents?unit={}&order=desc", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers().get("X-Total-Count").is_none());
+        assert_eq!(names(response), vec!["Pager, Bravo", "Pager, Alpha"]);
+        let response = make_request(&format!("residents?unit={}&per_page=1&page=2", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.headers()["X-Total-Count"], "2");
+        let link = response.headers()["Link"].to_str().unwrap().to_string();
+        assert!(link.contains(&format!("</api/residents?page=1&per_page=1&unit={}>; rel=\"prev\"", ids[0])), "{}", link);
+        assert!(!link.contains("rel=\"next\""), "{}", link);
+        assert_eq!(names(response), vec!["Pager, Bravo"]);
+        let response = make_request("residents?doc=42004", reqwest::Method::GET, None);
+        assert_eq!(names(response), vec!["Pager, Bravo"]);
+        for query in ["page=0", "page=18446744073709551615&per_page=50", "page=1&offset=0", "per_page=5&limit=5", "order=sideways", "q=Pager&page=1", "q=Pager&doc=42004"] {
+            let response = make_request(&format!("residents?{}", query), reqwest::Method::GET, None);
+            assert_eq!(response.status().as_u16(), 400, "{}", query);
+        }
+        let response = make_request(&format!("locations/{}/residents?doc=42003", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(names(response), vec!["Pager, Alpha"]);
+        let response = make_request(&format!("locations/{}/residents?limit=1", ids[0]), reqwest::Method::GET, None);
+        assert_eq!(response.headers()["X-Total-Count"], "2");
+        assert_eq!(names(response), vec!["Pager, Alpha"]);
+
+        // scans, narrowed by rfid or doc, ordered by time and paged
+        let scans = json!([
+            {"rfid": alpha, "location": ids[0], "time": "2022-03-01 08:00:00"},
+            {"rfid": bravo, "location": ids[1], "time": "2022-03-01 09:00:00"},
+            {"rfid": charlie, "location": ids[0], "time": "2022-03-01 10:00:00"},
+            {"rfid": alpha, "location": ids[0], "time": "2022-03-01 11:00:00"},
+        ]);
+        let response = client_with_role("admin")
+            .post(format!("{}/timestamps/backfill", BASE_URL))
+            .json(&scans)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let times = |body: &Value| -> Vec<String> {
+            body.as_array().unwrap().iter().map(|ts| ts["time"].as_str().unwrap().to_string()).collect()
+        };
+        let range = "timestamps/2022-03-01/2022-03-01";
+        let response = make_request(&format!("{}?rfid={}&order=desc", range, alpha), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(times(&response.json::<Value>().unwrap()), vec!["2022-03-01T11:00:00Z", "2022-03-01T08:00:00Z"]);
+        let response = make_request(&format!("{}?doc=42005", range), reqwest::Method::GET, None);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["rfid"], charlie);
+        let response = make_request(&format!("{}?per_page=2&page=1", range), reqwest::Method::GET, None);
+        assert_eq!(response.headers()["X-Total-Count"], "4");
+        let link = response.headers()["Link"].to_str().unwrap().to_string();
+        assert!(link.contains("?page=2&per_page=2>; rel=\"next\""), "{}", link);
+        assert_eq!(times(&response.json::<Value>().unwrap()), vec!["2022-03-01T11:00:00Z", "2022-03-01T10:00:00Z"]);
+        assert_eq!(make_request(&format!("{}?order=up", range), reqwest::Method::GET, None).status().as_u16(), 400);
+        for list in ["locations", "audit", "timestamps", range] {
+            let response = client_with_role("admin")
+                .get(format!("{}/{}?page=18446744073709551615&per_page=50", BASE_URL, list))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 400, "{}", list);
+        }
+
+        let location = format!("locations/{}/timestamps/2022-03-01/2022-03-01", ids[0]);
+        let response = make_request(&format!("{}?order=asc&limit=2", location), reqwest::Method::GET, None);
+        assert_eq!(response.headers()["X-Total-Count"], "3");
+        let link = response.headers()["Link"].to_str().unwrap().to_string();
+        assert!(link.contains("?limit=2&offset=2&order=asc>; rel=\"next\""), "{}", link);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(times(&body["data"]["Get"]), vec!["2022-03-01T08:00:00Z", "2022-03-01T10:00:00Z"]);
+        let response = make_request(&format!("{}?rfid={}", location, charlie), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().len(), 1);
+        let response = client()
+            .get(format!("{}/{}?rfid={}", BASE_URL, location, alpha))
+            .header("Accept", "application/x-ndjson")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+        let response = make_request(&format!("timestamps?rfid={}&limit=5", alpha), reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["X-Total-Count"], "0");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        // TestTimestampsController
+        let response = make_request("timestamps", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Vec<Value>>().is_ok());
+    }
+
+    #[test]
+    fn test_timestamps_post() {
+        let data = json!({"rfid": "888888222888777", "dest": 1});
+        let response = client()
+            .post(format!("{}/timestamps", BASE_URL))
+            .json(&data)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+    }
+    #[test]
+    fn test_timestamps_post_time() {
+        let ids = create_tree(&[("Timed Post Hall", None)]);
+        for time in ["not a time", "2023-13-01 08:00:00", "2023-09-01"] {
+            let response = client()
+                .post(format!("{}/timestamps", BASE_URL))
+                .json(&json!({"rfid": "888888222888777", "location": ids[0], "time": time}))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 400, "{}", time);
+            assert!(response.json::<Value>().unwrap()["error"].as_str().unwrap().contains("time:"));
+        }
+        let response = client()
+            .post(format!("{}/timestamps", BASE_URL))
+            .json(&json!({"rfid": " ", "location": ids[0]}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 400);
+        let response = make_request(&format!("locations/{}/timestamps", ids[0]), reqwest::Method::GET, None);
+        assert!(response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().is_empty());
+    }
+    #[test]
+    fn test_timestamps_between() {
+        let response = make_request(
+            "timestamps/2023-11-18/2023-11-19",
+            reqwest::Method::GET,
+            None,
+        );
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.json::<Vec<Value>>().is_ok());
+    }
+
+    fn correct_timestamp(role: &str, id: u64, body: Value) -> Response {
+        client_with_role(role)
+            .patch(format!("{}/timestamps/{}", BASE_URL, id))
+            .json(&body)
+            .send()
+            .expect("Failed to execute request")
+    }
+
+    #[test]
+    fn test_timestamps_backfill() {
+        let ids = create_tree(&[("Archive Block", None), ("Archive Yard", None)]);
+        let rfid = "278278278278278";
+        let resident = json!({"rfid": rfid, "name": "Backfilled Resident", "doc": "41994", "room": "S-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let scans = json!([
+            {"rfid": rfid, "location": ids[0], "time": "2018-02-01 09:00:00"},
+            // seconds apart, a repeat the dedup would have dropped
+            {"rfid": rfid, "location": ids[1], "time": "2018-02-01 09:00:10"},
+            {"rfid": rfid, "location": ids[1], "time": "2999-01-01 00:00:00"},
+            {"rfid": "289289289289289", "location": ids[0], "time": "2018-02-01 09:00:00"},
+            {"rfid": rfid, "location": 999999, "time": "2018-02-01 09:00:00"},
+            {"rfid": rfid, "location": ids[0], "time": "yesterday"},
+        ]);
+        let backfill = |role: &str, body: &Value| {
+            client_with_role(role)
+                .post(format!("{}/timestamps/backfill", BASE_URL))
+                .json(body)
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(backfill("staff", &scans).status().as_u16(), 403);
+        let response = backfill("admin", &scans);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        let accepted: Vec<bool> = body["data"]["Batch"].as_array().unwrap().iter().map(|res| res["accepted"].as_bool().unwrap()).collect();
+        assert_eq!(accepted, [true, true, false, false, false, false]);
+        assert!(body["data"]["Batch"][2]["error"].as_str().unwrap().contains("future"));
+
+        let response = make_request(&format!("locations/{}/residents/{}/timestamps", ids[1], rfid), reqwest::Method::GET, None);
+        let stored = response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().clone();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0]["time"], "2018-02-01T09:00:10Z");
+        // history doesn't move the resident
+        let response = make_request(&format!("residents/{}", rfid), reqwest::Method::GET, None);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["current_location"], 0);
+
+        // the time can't be left to the server
+        let response = backfill("admin", &json!([{"rfid": rfid, "location": ids[0]}]));
+        assert_eq!(response.status().as_u16(), 400);
+    }
+    #[test]
+    fn test_timestamps_purge() {
+        let ids = create_tree(&[("Retention Wing", None)]);
+        let rfid = "290290290290290";
+        let resident = json!({"rfid": rfid, "name": "Retained Resident", "doc": "41995", "room": "T-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let scans = json!([
+            {"rfid": rfid, "location": ids[0], "time": "2015-12-31 23:59:59"},
+            {"rfid": rfid, "location": ids[0], "time": "2016-01-15 10:00:00"},
+            {"rfid": rfid, "location": ids[0], "time": "2016-03-01 00:00:00"},
+        ]);
+        let response = client_with_role("admin")
+            .post(format!("{}/timestamps/backfill", BASE_URL))
+            .json(&scans)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+
+        let purge = |role: &str, query: &str| {
+            client_with_role(role)
+                .delete(format!("{}/timestamps{}", BASE_URL, query))
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(purge("supervisor", "?before=2016-03-01").status().as_u16(), 403);
+        for query in ["", "?before=", "?before=March", "?before=2999-01-01"] {
+            assert_eq!(purge("admin", query).status().as_u16(), 400, "{}", query);
+        }
+        let response = purge("admin", "?before=2016-03-01");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.json::<Value>().unwrap();
+        assert_eq!(body, json!({"before": "2016-03-01", "deleted": 2}));
+        let response = make_request(&format!("locations/{}/residents/{}/timestamps", ids[0], rfid), reqwest::Method::GET, None);
+        let left = response.json::<Value>().unwrap()["data"]["Get"].as_array().unwrap().clone();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0]["time"], "2016-03-01T00:00:00Z");
+        assert_eq!(purge("admin", "?before=2016-03-01").json::<Value>().unwrap()["deleted"], 0);
+
+        // the audit entry says what was purged, entries are written after the response
+        let mut entry = None;
+        for _ in 0..20 {
+            let response = client_with_role("admin")
+                .get(format!("{}/audit?limit=500", BASE_URL))
+                .send()
+                .expect("Failed to execute request");
+            entry = response.json::<Value>().unwrap()["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|entry| entry["endpoint"] == "/api/timestamps" && entry["after"]["deleted"] == 2)
+                .cloned();
+            if entry.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let entry = entry.expect("no audit entry for the purge");
+        assert_eq!(entry["method"], "DELETE");
+        assert_eq!(entry["role"], "admin");
+        assert_eq!(entry["after"]["before"], "2016-03-01");
+    }
+    #[test]
+    fn test_timestamps_export_csv() {
+        let ids = create_tree(&[("Export Hall", None), ("Export Annex", None)]);
+        let rfid = "312312312312312";
+        let resident = json!({"rfid": rfid, "name": "Exported, Resident", "doc": "41997", "room": "V-1", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let scans = json!([
+            {"rfid": rfid, "location": ids[0], "time": "2016-06-01 08:00:00"},
+            {"rfid": rfid, "location": ids[1], "time": "2016-09-01 08:00:00"},
+            {"rfid": rfid, "location": ids[0], "time": "2016-12-31 23:00:00"},
+        ]);
+        let response = client_with_role("admin")
+            .post(format!("{}/timestamps/backfill", BASE_URL))
+            .json(&scans)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+
+        let export = |role: &str, query: &str| {
+            client_with_role(role)
+                .get(format!("{}/timestamps/export.csv{}", BASE_URL, query))
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(export("supervisor", "?start=2016-06-01&end=2016-12-31").status().as_u16(), 403);
+        for query in ["", "?start=2016-06-01", "?end=2016-12-31", "?start=June&end=2016-12-31", "?start=2016-12-31&end=2016-06-01"] {
+            assert_eq!(export("admin", query).status().as_u16(), 400, "{}", query);
+        }
+
+        // longer than MAX_RANGE_DAYS, the export isn't capped
+        let response = export("admin", &format!("?start=2016-06-01&end=2016-12-31&location_id={}", ids[0]));
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/csv"));
+        let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.starts_with("attachment"), "{}", disposition);
+        assert!(disposition.contains(&format!("location-{}-timestamps-2016-06-01-to-2016-12-31.csv", ids[0])), "{}", disposition);
+        let body = response.text().unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "rfid,location,time");
+        assert_eq!(lines.len(), 3, "{}", body);
+        assert_eq!(lines[1], format!("{},{},2016-06-01T08:00:00Z", rfid, ids[0]));
+        assert_eq!(lines[2], format!("{},{},2016-12-31T23:00:00Z", rfid, ids[0]));
+
+        // without a location every one of the facility's locations is in it
+        let response = export("admin", "?start=2016-06-01&end=2016-12-31");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.text().unwrap();
+        assert!(body.starts_with("rfid,location,time\r\n"));
+        assert_eq!(body.lines().filter(|line| line.starts_with(rfid)).count(), 3, "{}", body);
+        let response = export("admin", "?start=2016-07-01&end=2016-07-31");
+        assert_eq!(response.text().unwrap().lines().filter(|line| line.starts_with(rfid)).count(), 0);
+    }
+    #[test]
+    fn test_reports_export() {
+        let ids = create_tree(&[("Report Hall", None), ("Report Annex", None)]);
+        let rfid = "345345345345345";
+        let resident = json!({"rfid": rfid, "name": "Reported, Resident", "doc": "42001", "room": "V-2", "unit": ids[0], "current_location": 0});
+        let response = client()
+            .post(format!("{}/residents", BASE_URL))
+            .json(&resident)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let scans = json!([
+            {"rfid": rfid, "location": ids[0], "time": "2021-02-01 12:00:00"},
+            {"rfid": rfid, "location": ids[1], "time": "2021-02-01 13:00:00"},
+            {"rfid": rfid, "location": ids[0], "time": "2021-02-01 14:00:00"},
+            {"rfid": rfid, "location": ids[0], "time": "2021-08-01 12:00:00"},
+        ]);
+        let response = client_with_role("admin")
+            .post(format!("{}/timestamps/backfill", BASE_URL))
+            .json(&scans)
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let get = |role: &str, path: &str| {
+            client_with_role(role)
+                .get(format!("{}{}", BASE_URL, path))
+                .send()
+                .expect("Failed to execute request")
+        };
+
+        // a location's range, CSV unless asked otherwise and uncapped
+        let location = format!("/locations/{}/timestamps/2021-01-01/2021-12-31/export", ids[0]);
+        for query in ["", "?format=csv"] {
+            let response = get("staff", &format!("{}{}", location, query));
+            assert_eq!(response.status().as_u16(), 200);
+            assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/csv"));
+            let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+            assert!(disposition.contains(&format!("location-{}-timestamps-2021-01-01-to-2021-12-31.csv", ids[0])), "{}", disposition);
+            let body = response.text().unwrap();
+            assert_eq!(body.lines().collect::<Vec<_>>().len(), 4, "{}", body);
+        }
+        assert_eq!(get("staff", &format!("{}?format=pdf", location)).status().as_u16(), 400);
+        // a workbook is held to MAX_RANGE_DAYS
+        assert_eq!(get("staff", &format!("{}?format=xlsx", location)).status().as_u16(), 400);
+        let response = get("staff", &format!("/locations/{}/timestamps/2021-02-01/2021-02-28/export?format=xlsx", ids[0]));
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response.headers()["content-type"].to_str().unwrap().contains("spreadsheetml"));
+        let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.contains(&format!("location-{}-timestamps-2021-02-01-to-2021-02-28.xlsx", ids[0])), "{}", disposition);
+        assert_eq!(&response.bytes().unwrap()[..2], b"PK");
+
+        // the facility's scans
+        let facility = "/reports/timestamps/export?start=2021-01-01&end=2021-12-31";
+        assert_eq!(get("supervisor", facility).status().as_u16(), 403);
+        assert_eq!(get("admin", "/reports/timestamps/export?start=2021-01-01").status().as_u16(), 400);
+        let response = get("admin", facility);
+        assert_eq!(response.status().as_u16(), 200);
+        let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.contains("timestamps-2021-01-01-to-2021-12-31.csv"), "{}", disposition);
+        let body = response.text().unwrap();
+        assert!(body.starts_with("rfid,location,time\r\n"));
+        assert_eq!(body.lines().filter(|line| line.starts_with(rfid)).count(), 4, "{}", body);
+        let response = get("admin", &format!("/reports/timestamps/export?start=2021-02-01&end=2021-02-28&location_id={}&format=xlsx", ids[1]));
+        assert_eq!(response.status().as_u16(), 200);
+        let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.contains(&format!("location-{}-timestamps-2021-02-01-to-2021-02-28.xlsx", ids[1])), "{}", disposition);
+        let response = get("admin", "/reports/timestamps/export?start=2021-01-01&end=2021-12-31&location_id=999999");
+        assert_eq!(response.text().unwrap(), "rfid,location,time\r\n");
+
+        // scans per location and day, only the days they were scanned
+        let response = get("read_only", "/reports/counts/export?start=2021-01-01&end=2021-12-31");
+        assert_eq!(response.status().as_u16(), 200);
+        let disposition = response.headers()["content-disposition"].to_str().unwrap().to_string();
+        assert!(disposition.contains("scan-counts-2021-01-01-to-2021-12-31.csv"), "{}", disposition);
+        let body = response.text().unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "day,location_id,location_name,scans");
+        assert!(lines.contains(&format!("2021-02-01,{},Report Hall,2", ids[0]).as_str()), "{}", body);
+        assert!(lines.contains(&format!("2021-02-01,{},Report Annex,1", ids[1]).as_str()), "{}", body);
+        assert!(lines.contains(&format!("2021-08-01,{},Report Hall,1", ids[0]).as_str()), "{}", body);
+        assert!(!body.contains("2021-03-01"), "{}", body);
+        let response = get("read_only", "/reports/counts/export?start=2021-02-01&end=2021-02-28&format=xlsx");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(&response.bytes().unwrap()[..2], b"PK");
+    }
+    #[test]
+    fn test_timestamps_correction() {
+        let ids = create_tree(&[("Sally Port", None), ("Infirmary Annex", None)]);
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": "333333333333333", "time": "2023-09-01 08:00:00"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let id = response.json::<Value>().unwrap()["data"]["Post"]["id"].as_u64().unwrap();
+        let fix = json!({"time": "2023-09-01 08:30:00", "location": ids[1], "reason": "scanned at the wrong door"});
+
+        assert_eq!(correct_timestamp("staff", id, fix.clone()).status().as_u16(), 403);
+        let no_reason = json!({"time": "2023-09-01 08:30:00"});
+        assert_eq!(correct_timestamp("supervisor", id, no_reason).status().as_u16(), 400);
+        assert_eq!(correct_timestamp("supervisor", 999999, fix.clone()).status().as_u16(), 404);
+        let response = correct_timestamp("supervisor", id, fix);
+        assert_eq!(response.status().as_u16(), 200);
+        let corrected = response.json::<Value>().unwrap()["data"]["Corrected"].clone();
+        assert_eq!(corrected["before"]["location"], ids[0]);
+        assert_eq!(corrected["before"]["time"], "2023-09-01T08:00:00Z");
+        assert_eq!(corrected["after"]["location"], ids[1]);
+        assert_eq!(corrected["after"]["time"], "2023-09-01T08:30:00Z");
+
+        // written with the correction, so it's there by the time the response is, and only once
+        let response = client_with_role("admin")
+            .get(format!("{}/audit?limit=500", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        let entries = response.json::<Value>().unwrap()["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["path"] == format!("/api/timestamps/{}", id) && entry["status"] == 200)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        let entry = &entries[0];
+        assert_eq!(entry["actor"], "apitest");
+        assert_eq!(entry["role"], "supervisor");
+        assert_eq!(entry["target"], id.to_string());
+        assert_eq!(entry["before"]["location"], ids[0]);
+        assert_eq!(entry["before"]["time"], "2023-09-01T08:00:00Z");
+        assert_eq!(entry["after"]["reason"], "scanned at the wrong door");
+    }
+
+    fn void_timestamp(role: &str, id: u64, body: Value) -> Response {
+        client_with_role(role)
+            .delete(format!("{}/timestamps/{}", BASE_URL, id))
+            .json(&body)
+            .send()
+            .expect("Failed to execute request")
+    }
+
+    #[test]
+    fn test_timestamps_void() {
+        let ids = create_tree(&[("Test Badge Reader", None)]);
+        let response = client()
+            .post(format!("{}/locations/{}/timestamps", BASE_URL, ids[0]))
+            .json(&json!({"rfid": "7982377777777777", "time": "2023-09-02 08:00:00"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let id = response.json::<Value>().unwrap()["data"]["Post"]["id"].as_u64().unwrap();
+        let scans = || {
+            client()
+                .get(format!("{}/locations/{}/timestamps/2023-09-02/2023-09-02", BASE_URL, ids[0]))
+                .header("Accept", "application/x-ndjson")
+                .send()
+                .expect("Failed to execute request")
+                .text()
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(scans(), 1);
+        let reason = json!({"reason": "test badge"});
+
+        assert_eq!(void_timestamp("staff", id, reason.clone()).status().as_u16(), 403);
+        assert_eq!(void_timestamp("supervisor", id, json!({"reason": " "})).status().as_u16(), 400);
+        assert_eq!(void_timestamp("supervisor", 999999, reason.clone()).status().as_u16(), 404);
+        assert_eq!(void_timestamp("supervisor", id, reason.clone()).status().as_u16(), 204);
+        assert_eq!(scans(), 0);
+        // written with the void, before the response
+        let response = client_with_role("admin")
+            .get(format!("{}/audit?limit=500", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        let entries = response.json::<Value>().unwrap()["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["path"] == format!("/api/timestamps/{}", id) && entry["status"] == 204)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        assert_eq!(entries[0]["method"], "DELETE");
+        assert_eq!(entries[0]["before"]["location"], ids[0]);
+        assert_eq!(entries[0]["after"]["reason"], "test badge");
+        // already voided, and a voided scan can't be corrected
+        assert_eq!(void_timestamp("supervisor", id, reason.clone()).status().as_u16(), 404);
+        let fix = json!({"time": "2023-09-02 08:30:00", "reason": "clock drift"});
+        assert_eq!(correct_timestamp("supervisor", id, fix).status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_healthz() {
+        let response = reqwest::blocking::get("http://localhost:8080/healthz")
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["status"], "ok");
+    }
+
+    #[test]
+    fn test_locations_resident_counts() {
+        let response = make_request("locations/residents/counts", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+        let counts = response.json::<Value>().unwrap()["data"]["Counts"].clone();
+        let counts = counts.as_array().expect("counts should be an array");
+        // seeded locations without residents still show up
+        assert!(counts.iter().any(|count| count["count"] == 0));
+        assert!(counts.iter().all(|count| count["location_id"].is_number()));
+    }
+
+    #[test]
+    fn test_missing_token() {
+        let response = Client::new()
+            .get(format!("{}/locations", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(response.json::<Value>().unwrap()["kind"], "unauthorized");
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        let response = Client::new()
+            .get(format!("{}/locations", BASE_URL))
+            .bearer_auth("not.a.token")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    #[test]
+    fn test_read_only_cannot_mutate_locations() {
+        let client = client_with_role("read_only");
+        let response = client
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "READ_ONLY_ATTEMPT"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 403);
+        assert_eq!(response.json::<Value>().unwrap()["kind"], "forbidden");
+        let response = client
+            .delete(format!("{}/locations/11", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 403);
+        let response = client
+            .get(format!("{}/locations/11", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    #[test]
+    fn test_read_only_can_post_timestamps() {
+        let response = client_with_role("read_only")
+            .post(format!("{}/locations/4/timestamps", BASE_URL))
+            .json(&json!({"rfid": "111111111111111"}))
+            .send()
+            .expect("Failed to execute request");
+        assert!(response.status().is_success());
+    }
+
+    fn set_status(role: &str, location: usize, status: &str) -> Response {
+        client_with_role(role)
+            .put(format!("{}/locations/{}/status", BASE_URL, location))
+            .json(&json!({ "status": status }))
+            .send()
+            .expect("Failed to execute request")
+    }
+
+    #[test]
+    fn test_locations_lockdown() {
+        // no other test scans into location 12 or moves this resident
+        let scan = |role: &str| {
+            client_with_role(role)
+                .post(format!("{}/locations/12/timestamps", BASE_URL))
+                .json(&json!({"rfid": "666688866666666"}))
+                .send()
+                .expect("Failed to execute request")
+        };
+        assert_eq!(set_status("read_only", 12, "lockdown").status().as_u16(), 403);
+        let response = set_status("staff", 12, "lockdown");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.json::<Value>().unwrap()["data"]["Show"]["status"], "lockdown");
+
+        let response = scan("staff");
+        assert_eq!(response.status().as_u16(), 423);
+        assert_eq!(response.json::<Value>().unwrap()["kind"], "locked");
+        assert!(scan("supervisor").status().is_success());
+
+        assert_eq!(set_status("staff", 12, "closed").status().as_u16(), 200);
+        assert!(scan("staff").status().is_success());
+        assert_eq!(set_status("staff", 12, "open").status().as_u16(), 200);
+        assert_eq!(set_status("staff", 99999, "open").status().as_u16(), 404);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let response = client()
+            .post(format!("{}/locations", BASE_URL))
+            .json(&json!({"name": "Audited Location"}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+        let id = response.json::<Value>().unwrap()["data"]["Show"]["id"].as_u64().unwrap();
+        let staff = client()
+            .get(format!("{}/audit", BASE_URL))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(staff.status().as_u16(), 403);
+
+        // entries are written after the response, give it a moment
+        let mut entry = None;
+        for _ in 0..20 {
+            let response = client_with_role("admin")
+                .get(format!("{}/audit?limit=500", BASE_URL))
+                .send()
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200);
+            entry = response.json::<Value>().unwrap()["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|entry| entry["after"]["name"] == "Audited Location")
+                .cloned();
+            if entry.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        let entry = entry.expect("no audit entry for the create");
+        assert_eq!(entry["actor"], "apitest");
+        assert_eq!(entry["role"], "staff");
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["endpoint"], "/api/locations");
+        assert_eq!(entry["target"], id.to_string());
+
+        // a PUT and the DELETE keep the location as it was
+        let response = client()
+            .put(format!("{}/locations/{}", BASE_URL, id))
+            .json(&json!({"name": "Audited Location Renamed", "version": 1}))
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let response = make_request(&format!("locations/{}", id), reqwest::Method::DELETE, None);
+        assert_eq!(response.status().as_u16(), 204);
+        let entry_for = |method: &str| {
+            for _ in 0..20 {
+                let response = client_with_role("admin")
+                    .get(format!("{}/audit?limit=500", BASE_URL))
+                    .send()
+                    .expect("Failed to execute request");
+                let found = response.json::<Value>().unwrap()["data"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|entry| entry["method"] == method && entry["target"] == id.to_string())
+                    .cloned();
+                if let Some(entry) = found {
+                    return entry;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            panic!("no audit entry for the {}", method);
+        };
+        let put = entry_for("PUT");
+        assert_eq!(put["before"]["name"], "Audited Location");
+        assert_eq!(put["before"]["version"], 1);
+        assert_eq!(put["after"]["name"], "Audited Location Renamed");
+        let delete = entry_for("DELETE");
+        assert_eq!(delete["before"]["name"], "Audited Location Renamed");
+        assert_eq!(delete["before"]["version"], 2);
+    }
+
+    fn post_with_key(endpoint: &str, key: &str, body: Value) -> Response {
+        Client::new()
+            .post(format!("{}/{}", BASE_URL, endpoint))
+            .header("X-Api-Key", key)
+            .json(&body)
+            .send()
+            .expect("Failed to execute request")
+    }
+
+    #[test]
+    fn test_scanner_key() {
+        // issued by --test-seed
+        let scan = json!({"rfid": "111111111111111"});
+        let response = post_with_key("locations/11/timestamps", "test-scanner-key", scan.clone());
+        assert!(response.status().is_success());
+        let response = post_with_key("locations", "test-scanner-key", json!({"name": "KEYED"}));
+        assert_eq!(response.status().as_u16(), 401);
+        let response = post_with_key("locations/11/timestamps", "test-revoked-key", scan.clone());
+        assert_eq!(response.status().as_u16(), 401);
+        let response = post_with_key("locations/11/timestamps", "not-a-key", scan);
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    #[test]
+    fn test_scan_rate_limit() {
+        // an address of its own, so the flood only drains its own bucket. A new key each time
+        // doesn't get it a new bucket.
+        let client = Client::builder()
+            .local_address("127.0.0.2".parse::<std::net::IpAddr>().unwrap())
+            .build()
+            .unwrap();
+        let flood = |n: usize| {
+            client
+                .post(format!("{}/locations/11/timestamps", BASE_URL))
+                .header("X-Api-Key", format!("rate-limit-flood-key-{}", n))
+                .json(&json!({"rfid": "111111111111111"}))
+                .send()
+                .expect("Failed to execute request")
+        };
+        let limited = (0..5000).map(flood).find(|response| response.status().as_u16() == 429);
+        let response = limited.expect("the flood was never limited");
+        let retry_after = response.headers()["retry-after"].to_str().unwrap().parse::<u64>().unwrap();
+        assert!(retry_after >= 1);
+        assert_eq!(response.json::<Value>().unwrap()["kind"], "rate_limited");
+
+        // other callers and the read endpoints aren't held back by it
+        let response = post_with_key("locations/11/timestamps", "not-a-key", json!({"rfid": "111111111111111"}));
+        assert_eq!(response.status().as_u16(), 401);
+        let response = make_request("locations/11", reqwest::Method::GET, None);
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    #[test]
+    fn test_locations_preflight() {
+        let response = Client::new()
+            .request(reqwest::Method::OPTIONS, format!("{}/locations/11", BASE_URL))
+            .header("Origin", "http://frontend.test")
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "authorization, content-type")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let headers = response.headers();
+        assert_eq!(headers["access-control-allow-origin"], "http://frontend.test");
+        assert!(headers["access-control-allow-methods"].to_str().unwrap().contains("PUT"));
+        assert!(headers.contains_key("access-control-allow-headers"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        make_request("locations/11", reqwest::Method::GET, None);
+        let response = reqwest::blocking::get("http://localhost:8080/metrics")
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.text().unwrap();
+        assert!(body.contains(r#"route="/api/locations/{location_id}""#));
+        assert!(body.contains("mvscanner_http_request_duration_seconds_bucket"));
+        assert!(body.contains(r#"mvscanner_db_query_duration_seconds_bucket{query="ShowLocation""#));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let response = client()
+            .get(format!("{}/locations/11", BASE_URL))
+            .header("X-Request-Id", "apitest-request-id")
+            .send()
+            .expect("Failed to execute request");
+        assert_eq!(response.headers()["x-request-id"], "apitest-request-id");
+        // one is generated when the client doesn't send it
+        let response = make_request("locations/11", reqwest::Method::GET, None);
+        assert!(!response.headers()["x-request-id"].is_empty());
+    }
 }